@@ -0,0 +1,9 @@
+// Runs flows compiled with `flow compile --target bytecode` without the parser, the AST or an
+// allocator, for firmware. Build it for a microcontroller with e.g.
+//
+//     cargo build -p flow-runtime --target thumbv7em-none-eabihf
+
+#![no_std]
+
+pub mod program;
+pub mod vm;
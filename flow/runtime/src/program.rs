@@ -0,0 +1,557 @@
+use core::convert::TryInto;
+use core::fmt;
+use core::str;
+
+// A flow's bytecode, as written by `flow compile --target bytecode`, read in place: nothing is
+// copied out of the bytes, names are borrowed from them and ops are decoded as they're run.
+// Everything is checked when it's loaded, so running it can't index out of bounds or go round a
+// loop without getting to a statement.
+//
+// The format, little-endian throughout, with strings as a u16 length and UTF-8 and lists as a u16
+// count followed by their items:
+//
+//     "FLBC", version (u8)
+//     sensors: name
+//     actuators: name, init (u8 flag, f64 if set), failsafe (u8 flag, f64 if set)
+//     timers: name
+//     blocks: name, first op (u32)
+//     first block (u16), latch, edge and held slots (u16 each)
+//     interlocks: trigger (u8 flag, u32 op if set), requirement (u32 op), shown
+//     invariants: the same, without a trigger
+//     op count (u32), then each op's offset from the first (u32), then the ops
+//
+// Each op is a u8 opcode followed by its operands, with comparisons as a u8 (<, <=, =, >, >=, ~)
+// and a tolerance (f64) after ~.
+
+// the flow crate writes files with these too, so the two can't drift apart
+pub const MAGIC: &[u8; 4] = b"FLBC";
+pub const VERSION: u8 = 3;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparator {
+    LT,
+    LTEQ,
+    EQ,
+    GT,
+    GTEQ,
+    APPROX(f64),
+}
+
+impl Comparator {
+    pub fn holds(self, lhs: f64, rhs: f64) -> bool {
+        match self {
+            Comparator::LT => lhs < rhs,
+            Comparator::LTEQ => lhs <= rhs,
+            Comparator::EQ => lhs == rhs,
+            Comparator::GT => lhs > rhs,
+            Comparator::GTEQ => lhs >= rhs,
+            // f64::abs isn't in core
+            Comparator::APPROX(tol) => lhs - rhs <= tol && rhs - lhs <= tol,
+        }
+    }
+}
+
+// what the flow crate's compiler writes, with addresses as op indices
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    // conditions, leaving whether they hold in the flag
+    Sensor {
+        sensor: u16,
+        comp: Comparator,
+        value: f64,
+    },
+    Actuator {
+        actuator: u16,
+        comp: Comparator,
+        value: f64,
+    },
+    Elapsed {
+        comp: Comparator,
+        value: f64,
+    },
+    Now {
+        comp: Comparator,
+        value: f64,
+    },
+    Timer {
+        timer: u16,
+        comp: Comparator,
+        value: f64,
+    },
+    Latch {
+        sensor: u16,
+        rising: bool,
+        threshold: f64,
+        band: f64,
+        slot: u16,
+    },
+    // whether the flag changed since this op last saw it
+    Edge {
+        rising: bool,
+        slot: u16,
+    },
+    // whether the flag has been set for long enough
+    Held {
+        duration: f64,
+        slot: u16,
+    },
+    // past the rest of an all or an any once it's settled
+    JumpIfFalse(u32),
+    JumpIfTrue(u32),
+    // the end of a condition checked on its own, for interlocks and invariants
+    Done,
+
+    // statements
+    Set {
+        actuator: u16,
+        value: f64,
+    },
+    // carry on if the flag is set, otherwise wait, going back to the condition starting there
+    // on the next step
+    Wait(u32),
+    Delay(f64),
+    Start(u16),
+    // an if: carry on into its arm if the flag is set, otherwise go to the address
+    Branch(u32),
+    // past an if's else arm, at the end of its if arm
+    Jump(u32),
+    Goto(u16),
+    // start an edge or held op's slot afresh, as a block using it is entered
+    ForgetEdge(u16),
+    ForgetHeld(u16),
+    Finish,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoadError {
+    NotBytecode,
+    Version(u8),
+    Truncated,
+    // a bad string, flag, comparison or opcode, by byte offset
+    Malformed(usize),
+    // an op that refers to something the program doesn't have, or isn't where the offsets say
+    BadOp(u32),
+    TooLong,
+    // a block or check starting past the end of the code, or the first block missing
+    BadStart,
+    Unterminated,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::NotBytecode => write!(f, "not a flow bytecode file"),
+            LoadError::Version(version) => write!(
+                f,
+                "bytecode is version {}, this reads version {}",
+                version, VERSION
+            ),
+            LoadError::Truncated => write!(f, "bytecode ends partway through"),
+            LoadError::Malformed(at) => write!(f, "bytecode is malformed at byte {}", at),
+            LoadError::BadOp(idx) => write!(f, "bad op at {} in bytecode", idx),
+            LoadError::TooLong => write!(f, "bytecode carries on past the end of the program"),
+            LoadError::BadStart => write!(f, "bytecode refers to code it doesn't have"),
+            LoadError::Unterminated => write!(f, "bytecode doesn't end with a finish or a done"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Output<'a> {
+    pub name: &'a str,
+    pub init: Option<f64>,
+    pub failsafe: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Entry<'a> {
+    pub name: &'a str,
+    pub start: u32,
+}
+
+// an interlock or invariant, by the ops its conditions start at, and how it's shown when broken
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Check<'a> {
+    pub trigger: Option<u32>,
+    pub requirement: u32,
+    pub shown: &'a str,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+        let end = self.at.checked_add(len).ok_or(LoadError::Truncated)?;
+        let taken = self.bytes.get(self.at..end).ok_or(LoadError::Truncated)?;
+        self.at = end;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, LoadError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64, LoadError> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<&'a str, LoadError> {
+        let at = self.at;
+        let len = self.u16()? as usize;
+        str::from_utf8(self.take(len)?).map_err(|_| LoadError::Malformed(at))
+    }
+
+    fn bool(&mut self) -> Result<bool, LoadError> {
+        let at = self.at;
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(LoadError::Malformed(at)),
+        }
+    }
+
+    fn optional(&mut self) -> Result<Option<f64>, LoadError> {
+        Ok(if self.bool()? {
+            Some(self.f64()?)
+        } else {
+            None
+        })
+    }
+
+    fn comp(&mut self) -> Result<Comparator, LoadError> {
+        let at = self.at;
+        Ok(match self.u8()? {
+            0 => Comparator::LT,
+            1 => Comparator::LTEQ,
+            2 => Comparator::EQ,
+            3 => Comparator::GT,
+            4 => Comparator::GTEQ,
+            5 => Comparator::APPROX(self.f64()?),
+            _ => return Err(LoadError::Malformed(at)),
+        })
+    }
+
+    fn output(&mut self) -> Result<Output<'a>, LoadError> {
+        Ok(Output {
+            name: self.string()?,
+            init: self.optional()?,
+            failsafe: self.optional()?,
+        })
+    }
+
+    fn entry(&mut self) -> Result<Entry<'a>, LoadError> {
+        Ok(Entry {
+            name: self.string()?,
+            start: self.u32()?,
+        })
+    }
+
+    fn check(&mut self) -> Result<Check<'a>, LoadError> {
+        Ok(Check {
+            trigger: if self.bool()? {
+                Some(self.u32()?)
+            } else {
+                None
+            },
+            requirement: self.u32()?,
+            shown: self.string()?,
+        })
+    }
+
+    fn op(&mut self) -> Result<Op, LoadError> {
+        let at = self.at;
+        Ok(match self.u8()? {
+            0 => Op::Sensor {
+                sensor: self.u16()?,
+                comp: self.comp()?,
+                value: self.f64()?,
+            },
+            1 => Op::Actuator {
+                actuator: self.u16()?,
+                comp: self.comp()?,
+                value: self.f64()?,
+            },
+            2 => Op::Elapsed {
+                comp: self.comp()?,
+                value: self.f64()?,
+            },
+            3 => Op::Now {
+                comp: self.comp()?,
+                value: self.f64()?,
+            },
+            4 => Op::Timer {
+                timer: self.u16()?,
+                comp: self.comp()?,
+                value: self.f64()?,
+            },
+            5 => Op::Latch {
+                sensor: self.u16()?,
+                rising: self.bool()?,
+                threshold: self.f64()?,
+                band: self.f64()?,
+                slot: self.u16()?,
+            },
+            6 => Op::Edge {
+                rising: self.bool()?,
+                slot: self.u16()?,
+            },
+            7 => Op::Held {
+                duration: self.f64()?,
+                slot: self.u16()?,
+            },
+            8 => Op::JumpIfFalse(self.u32()?),
+            9 => Op::JumpIfTrue(self.u32()?),
+            10 => Op::Done,
+            11 => Op::Set {
+                actuator: self.u16()?,
+                value: self.f64()?,
+            },
+            12 => Op::Wait(self.u32()?),
+            13 => Op::Delay(self.f64()?),
+            14 => Op::Start(self.u16()?),
+            15 => Op::Branch(self.u32()?),
+            16 => Op::Jump(self.u32()?),
+            17 => Op::Goto(self.u16()?),
+            18 => Op::Finish,
+            19 => Op::ForgetEdge(self.u16()?),
+            20 => Op::ForgetHeld(self.u16()?),
+            _ => return Err(LoadError::Malformed(at)),
+        })
+    }
+
+    // a list's length and where its first item starts, reading past the items
+    fn table(
+        &mut self,
+        item: impl Fn(&mut Self) -> Result<(), LoadError>,
+    ) -> Result<Table, LoadError> {
+        let len = self.u16()?;
+        let at = self.at;
+        for _ in 0..len {
+            item(self)?;
+        }
+        Ok(Table { len, at })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Table {
+    len: u16,
+    at: usize,
+}
+
+// a list's items, decoded as they're iterated over
+pub struct Items<'a, T> {
+    reader: Reader<'a>,
+    left: u16,
+    item: fn(&mut Reader<'a>) -> Result<T, LoadError>,
+}
+
+impl<'a, T> Iterator for Items<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.left == 0 {
+            return None;
+        }
+        self.left -= 1;
+        Some((self.item)(&mut self.reader).expect("checked when loaded"))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.left as usize, Some(self.left as usize))
+    }
+}
+
+impl<'a, T> ExactSizeIterator for Items<'a, T> {}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Program<'a> {
+    bytes: &'a [u8],
+    sensors: Table,
+    actuators: Table,
+    timers: Table,
+    blocks: Table,
+    first: u16,
+    latches: u16,
+    edges: u16,
+    held: u16,
+    interlocks: Table,
+    invariants: Table,
+    ops: u32,
+    // where each op's offset is, and where the offsets are from
+    offsets: usize,
+    code: usize,
+}
+
+impl<'a> Program<'a> {
+    pub fn load(bytes: &'a [u8]) -> Result<Program<'a>, LoadError> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(LoadError::NotBytecode);
+        }
+        let mut r = Reader {
+            bytes,
+            at: MAGIC.len(),
+        };
+        let version = r.u8()?;
+        if version != VERSION {
+            return Err(LoadError::Version(version));
+        }
+        let sensors = r.table(|r| r.string().map(drop))?;
+        let actuators = r.table(|r| r.output().map(drop))?;
+        let timers = r.table(|r| r.string().map(drop))?;
+        let blocks = r.table(|r| r.entry().map(drop))?;
+        let first = r.u16()?;
+        let latches = r.u16()?;
+        let edges = r.u16()?;
+        let held = r.u16()?;
+        let interlocks = r.table(|r| r.check().map(drop))?;
+        let invariants = r.table(|r| r.check().map(drop))?;
+        let ops = r.u32()?;
+        let offsets = r.at;
+        r.take((ops as usize).checked_mul(4).ok_or(LoadError::Truncated)?)?;
+        let program = Program {
+            bytes,
+            sensors,
+            actuators,
+            timers,
+            blocks,
+            first,
+            latches,
+            edges,
+            held,
+            interlocks,
+            invariants,
+            ops,
+            offsets,
+            code: r.at,
+        };
+        for idx in 0..ops {
+            if program.offset(idx) != r.at - program.code {
+                return Err(LoadError::BadOp(idx));
+            }
+            if !program.fits(idx, r.op()?) {
+                return Err(LoadError::BadOp(idx));
+            }
+        }
+        if r.at != bytes.len() {
+            return Err(LoadError::TooLong);
+        }
+        let starts_ok = first < blocks.len
+            && program.blocks().all(|b| b.start < ops)
+            && program
+                .interlocks()
+                .chain(program.invariants())
+                .all(|c| c.requirement < ops && c.trigger.is_none_or(|t| t < ops));
+        if !starts_ok {
+            return Err(LoadError::BadStart);
+        }
+        if !matches!(program.last(), Some(Op::Finish) | Some(Op::Done)) {
+            return Err(LoadError::Unterminated);
+        }
+        Ok(program)
+    }
+
+    fn last(&self) -> Option<Op> {
+        self.ops.checked_sub(1).map(|idx| self.op(idx))
+    }
+
+    // whether an op only refers to things the program has, and only jumps forwards
+    fn fits(&self, idx: u32, op: Op) -> bool {
+        match op {
+            Op::Sensor { sensor, .. } => sensor < self.sensors.len,
+            Op::Latch { sensor, slot, .. } => sensor < self.sensors.len && slot < self.latches,
+            Op::Actuator { actuator, .. } | Op::Set { actuator, .. } => {
+                actuator < self.actuators.len
+            }
+            Op::Timer { timer, .. } | Op::Start(timer) => timer < self.timers.len,
+            Op::Edge { slot, .. } | Op::ForgetEdge(slot) => slot < self.edges,
+            Op::Held { slot, .. } | Op::ForgetHeld(slot) => slot < self.held,
+            Op::JumpIfFalse(target)
+            | Op::JumpIfTrue(target)
+            | Op::Branch(target)
+            | Op::Jump(target) => target > idx && target < self.ops,
+            Op::Wait(start) => start <= idx,
+            Op::Goto(block) => block < self.blocks.len,
+            Op::Elapsed { .. } | Op::Now { .. } | Op::Done | Op::Delay(_) | Op::Finish => true,
+        }
+    }
+
+    fn items<T>(
+        &self,
+        table: Table,
+        item: fn(&mut Reader<'a>) -> Result<T, LoadError>,
+    ) -> Items<'a, T> {
+        Items {
+            reader: Reader {
+                bytes: self.bytes,
+                at: table.at,
+            },
+            left: table.len,
+            item,
+        }
+    }
+
+    pub fn sensors(&self) -> Items<'a, &'a str> {
+        self.items(self.sensors, Reader::string)
+    }
+
+    pub fn actuators(&self) -> Items<'a, Output<'a>> {
+        self.items(self.actuators, Reader::output)
+    }
+
+    pub fn timers(&self) -> Items<'a, &'a str> {
+        self.items(self.timers, Reader::string)
+    }
+
+    pub fn blocks(&self) -> Items<'a, Entry<'a>> {
+        self.items(self.blocks, Reader::entry)
+    }
+
+    pub fn interlocks(&self) -> Items<'a, Check<'a>> {
+        self.items(self.interlocks, Reader::check)
+    }
+
+    pub fn invariants(&self) -> Items<'a, Check<'a>> {
+        self.items(self.invariants, Reader::check)
+    }
+
+    // the block the flow starts in
+    pub fn first(&self) -> u16 {
+        self.first
+    }
+
+    // how much state latch, edge and held ops keep, each indexing its own slots
+    pub fn slots(&self) -> (u16, u16, u16) {
+        (self.latches, self.edges, self.held)
+    }
+
+    pub fn ops(&self) -> u32 {
+        self.ops
+    }
+
+    fn offset(&self, idx: u32) -> usize {
+        let at = self.offsets + idx as usize * 4;
+        u32::from_le_bytes(self.bytes[at..at + 4].try_into().unwrap()) as usize
+    }
+
+    pub fn op(&self, idx: u32) -> Op {
+        let mut r = Reader {
+            bytes: self.bytes,
+            at: self.code + self.offset(idx),
+        };
+        r.op().expect("checked when loaded")
+    }
+}
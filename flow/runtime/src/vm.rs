@@ -0,0 +1,471 @@
+use core::fmt;
+
+use crate::program::{Op, Program};
+
+// Runs a loaded program a step at a time, the way the flow crate's interpreter runs the AST: the
+// same statements count against the same per-step budget, and a flow stops, refuses and fails in
+// the same places with the same messages. All of its state is inline, sized by N, which has to be
+// at least the number of sensors, actuators, timers and latch, edge and held slots the program
+// needs.
+
+// upper bound on statements per step so a busy loop can't hang the caller
+pub const MAX_OPS_PER_STEP: usize = 10_000;
+
+// whatever the flow reads its sensors from and writes its actuators to, by index
+pub trait Devices {
+    type Error;
+
+    fn read_sensor(&mut self, sensor: usize) -> Result<f64, Self::Error>;
+    fn write_actuator(&mut self, actuator: usize, value: f64) -> Result<(), Self::Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    // ran out of the per-step budget without reaching a wait
+    Running,
+    Waiting,
+    Finished,
+}
+
+// something a condition read that has no value yet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unreadable {
+    Actuator(u16),
+    Timer(u16),
+}
+
+// why setting an actuator was refused, by interlock or invariant index
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Refusal {
+    Interlock(u16),
+    Unchecked(u16, Unreadable),
+    Invariant(u16),
+    UncheckedInvariant(u16, Unreadable),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault<E> {
+    Device(E),
+    // a wait or if condition couldn't be evaluated
+    Unreadable(Unreadable),
+    Refused {
+        actuator: u16,
+        value: f64,
+        reason: Refusal,
+    },
+    // the program needs more room than the vm has
+    TooBig,
+}
+
+pub struct Vm<'p, const N: usize> {
+    program: Program<'p>,
+    // seconds since the flow started, advanced by whoever drives the vm
+    pub time: f64,
+    // readings taken at the start of the current step
+    sensors: [f64; N],
+    // the last value written to each actuator, None while it's unset
+    actuators: [Option<f64>; N],
+    pc: u32,
+    block: u16,
+    // when the current block was entered
+    entered: f64,
+    // when the delay currently being waited on ends
+    deadline: Option<f64>,
+    // when each timer was last started
+    timers: [Option<f64>; N],
+    latches: [bool; N],
+    edges: [Option<bool>; N],
+    // since when each held op's condition has held
+    held: [Option<f64>; N],
+    // whether the condition just evaluated holds
+    flag: bool,
+}
+
+impl<'p, const N: usize> Vm<'p, N> {
+    pub fn new<D: Devices>(program: Program<'p>, io: &mut D) -> Result<Self, Fault<D::Error>> {
+        let (latches, edges, held) = program.slots();
+        let needed = [
+            program.sensors().len(),
+            program.actuators().len(),
+            program.timers().len(),
+            latches as usize,
+            edges as usize,
+            held as usize,
+        ];
+        if needed.iter().any(|&n| n > N) {
+            return Err(Fault::TooBig);
+        }
+        let first = program.blocks().nth(program.first() as usize).unwrap();
+        let mut vm = Vm {
+            program,
+            time: 0.0,
+            sensors: [0.0; N],
+            actuators: [None; N],
+            pc: first.start,
+            block: program.first(),
+            entered: 0.0,
+            deadline: None,
+            timers: [None; N],
+            latches: [false; N],
+            edges: [None; N],
+            held: [None; N],
+            flag: false,
+        };
+        for (idx, act) in program.actuators().enumerate() {
+            if let Some(val) = act.init {
+                io.write_actuator(idx, val).map_err(Fault::Device)?;
+                vm.actuators[idx] = Some(val);
+            }
+        }
+        Ok(vm)
+    }
+
+    // the name of the block the flow is in
+    pub fn block(&self) -> &'p str {
+        self.program.blocks().nth(self.block as usize).unwrap().name
+    }
+
+    // the last value written to an actuator, None while it's unset
+    pub fn actuator(&self, idx: usize) -> Option<f64> {
+        self.actuators[idx]
+    }
+
+    // drive actuators to their fail-safe values after an error or abort, bypassing the safety
+    // checks. every actuator is attempted even if writing an earlier one fails
+    pub fn fail_safe<D: Devices>(&mut self, io: &mut D) -> Result<(), D::Error> {
+        self.deadline = None;
+        let mut result = Ok(());
+        for (idx, act) in self.program.actuators().enumerate() {
+            if let Some(val) = act.failsafe {
+                match io.write_actuator(idx, val) {
+                    Ok(()) => self.actuators[idx] = Some(val),
+                    Err(e) if result.is_ok() => result = Err(e),
+                    Err(_) => {}
+                }
+            }
+        }
+        result
+    }
+
+    // why the flow can't carry on after a step, if it can't
+    pub fn halted<'v, E>(
+        &'v self,
+        status: &'v Result<Status, Fault<E>>,
+    ) -> Option<Halted<'v, 'p, E, N>> {
+        match status {
+            Ok(Status::Running) => Some(Halted {
+                vm: self,
+                fault: None,
+            }),
+            Err(fault) => Some(Halted {
+                vm: self,
+                fault: Some(fault),
+            }),
+            _ => None,
+        }
+    }
+
+    // run until the flow blocks on a wait, finishes, or uses up its budget
+    pub fn step<D: Devices>(&mut self, io: &mut D) -> Result<Status, Fault<D::Error>> {
+        for idx in 0..self.program.sensors().len() {
+            self.sensors[idx] = io.read_sensor(idx).map_err(Fault::Device)?;
+        }
+        let mut statements = 0;
+        // whether the op about to run starts a statement, which is where the budget is checked
+        let mut starting = true;
+        loop {
+            let op = self.program.op(self.pc);
+            // jumps and forgetting come along with the statement after them
+            if starting && !matches!(op, Op::Jump(_) | Op::ForgetEdge(_) | Op::ForgetHeld(_)) {
+                if statements == MAX_OPS_PER_STEP {
+                    return Ok(Status::Running);
+                }
+                statements += 1;
+                starting = false;
+            }
+            self.pc += 1;
+            match op {
+                Op::Set { actuator, value } => {
+                    self.set(actuator, value, io)?;
+                    starting = true;
+                }
+                Op::Wait(start) => {
+                    if !self.flag {
+                        self.pc = start;
+                        return Ok(Status::Waiting);
+                    }
+                    starting = true;
+                }
+                Op::Delay(duration) => {
+                    let deadline = *self.deadline.get_or_insert(self.time + duration);
+                    if self.time < deadline {
+                        self.pc -= 1;
+                        return Ok(Status::Waiting);
+                    }
+                    self.deadline = None;
+                    starting = true;
+                }
+                Op::Start(timer) => {
+                    self.timers[timer as usize] = Some(self.time);
+                    starting = true;
+                }
+                Op::Branch(target) => {
+                    if !self.flag {
+                        self.pc = target;
+                    }
+                    starting = true;
+                }
+                Op::Jump(target) => self.pc = target,
+                Op::ForgetEdge(slot) => self.edges[slot as usize] = None,
+                Op::ForgetHeld(slot) => self.held[slot as usize] = None,
+                Op::Goto(block) => {
+                    self.block = block;
+                    self.entered = self.time;
+                    self.pc = self.program.blocks().nth(block as usize).unwrap().start;
+                    starting = true;
+                }
+                // compiled code only has a done at the end of a check, but a flow that gets to
+                // one stops there all the same
+                Op::Finish | Op::Done => {
+                    self.pc -= 1;
+                    return Ok(Status::Finished);
+                }
+                _ => self.condition(op).map_err(Fault::Unreadable)?,
+            }
+        }
+    }
+
+    // evaluate a single condition op into the flag
+    fn condition(&mut self, op: Op) -> Result<(), Unreadable> {
+        self.flag = match op {
+            Op::Sensor {
+                sensor,
+                comp,
+                value,
+            } => comp.holds(self.sensors[sensor as usize], value),
+            Op::Actuator {
+                actuator,
+                comp,
+                value,
+            } => match self.actuators[actuator as usize] {
+                Some(current) => comp.holds(current, value),
+                None => return Err(Unreadable::Actuator(actuator)),
+            },
+            Op::Elapsed { comp, value } => comp.holds(self.time - self.entered, value),
+            Op::Now { comp, value } => comp.holds(self.time, value),
+            Op::Timer { timer, comp, value } => match self.timers[timer as usize] {
+                Some(started) => comp.holds(self.time - started, value),
+                None => return Err(Unreadable::Timer(timer)),
+            },
+            Op::Latch {
+                sensor,
+                rising,
+                threshold,
+                band,
+                slot,
+            } => {
+                let reading = self.sensors[sensor as usize];
+                let on = match (rising, self.latches[slot as usize]) {
+                    (true, false) => reading > threshold,
+                    (true, true) => reading >= threshold - band,
+                    (false, false) => reading < threshold,
+                    (false, true) => reading <= threshold + band,
+                };
+                self.latches[slot as usize] = on;
+                on
+            }
+            Op::Edge { rising, slot } => {
+                let now = self.flag;
+                let before = self.edges[slot as usize].replace(now).unwrap_or(now);
+                if rising {
+                    !before && now
+                } else {
+                    before && !now
+                }
+            }
+            Op::Held { duration, slot } => {
+                if !self.flag {
+                    self.held[slot as usize] = None;
+                    false
+                } else {
+                    let since = *self.held[slot as usize].get_or_insert(self.time);
+                    self.time - since >= duration
+                }
+            }
+            Op::JumpIfFalse(target) => {
+                if !self.flag {
+                    self.pc = target;
+                }
+                self.flag
+            }
+            Op::JumpIfTrue(target) => {
+                if self.flag {
+                    self.pc = target;
+                }
+                self.flag
+            }
+            // statements are handled by step, and end a check
+            _ => self.flag,
+        };
+        Ok(())
+    }
+
+    // whether the safety condition starting at the op holds, leaving the flow where it was
+    fn check(&mut self, start: u32) -> Result<bool, Unreadable> {
+        let pc = self.pc;
+        self.pc = start;
+        let result = loop {
+            let op = self.program.op(self.pc);
+            self.pc += 1;
+            if let Op::Sensor { .. }
+            | Op::Actuator { .. }
+            | Op::Elapsed { .. }
+            | Op::Now { .. }
+            | Op::Timer { .. }
+            | Op::Latch { .. }
+            | Op::Edge { .. }
+            | Op::Held { .. }
+            | Op::JumpIfFalse(_)
+            | Op::JumpIfTrue(_) = op
+            {
+                if let Err(e) = self.condition(op) {
+                    break Err(e);
+                }
+            } else {
+                break Ok(self.flag);
+            }
+        };
+        self.pc = pc;
+        result
+    }
+
+    fn set<D: Devices>(
+        &mut self,
+        actuator: u16,
+        value: f64,
+        io: &mut D,
+    ) -> Result<(), Fault<D::Error>> {
+        let idx = actuator as usize;
+        let previous = self.actuators[idx].replace(value);
+        if let Some(reason) = self.violation() {
+            self.actuators[idx] = previous;
+            return Err(Fault::Refused {
+                actuator,
+                value,
+                reason,
+            });
+        }
+        if let Err(e) = io.write_actuator(idx, value) {
+            self.actuators[idx] = previous;
+            return Err(Fault::Device(e));
+        }
+        Ok(())
+    }
+
+    // first interlock or invariant broken by the current device values, if any
+    fn violation(&mut self) -> Option<Refusal> {
+        let program = self.program;
+        for (idx, interlock) in program.interlocks().enumerate() {
+            let idx = idx as u16;
+            // an actuator that was never set can't trip its interlock
+            let tripped = match interlock.trigger {
+                Some(trigger) => self.check(trigger) == Ok(true),
+                None => true,
+            };
+            if tripped {
+                match self.check(interlock.requirement) {
+                    Ok(true) => {}
+                    Ok(false) => return Some(Refusal::Interlock(idx)),
+                    Err(e) => return Some(Refusal::Unchecked(idx, e)),
+                }
+            }
+        }
+        for (idx, invariant) in program.invariants().enumerate() {
+            match self.check(invariant.requirement) {
+                Ok(true) => {}
+                Ok(false) => return Some(Refusal::Invariant(idx as u16)),
+                Err(e) => return Some(Refusal::UncheckedInvariant(idx as u16, e)),
+            }
+        }
+        None
+    }
+}
+
+// why a flow halted, worded the way the interpreter words it
+pub struct Halted<'v, 'p, E, const N: usize> {
+    vm: &'v Vm<'p, N>,
+    // None when it timed out busy looping
+    fault: Option<&'v Fault<E>>,
+}
+
+impl<E: fmt::Display, const N: usize> fmt::Display for Halted<'_, '_, E, N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let program = &self.vm.program;
+        let block = self.vm.block();
+        let unreadable = |f: &mut fmt::Formatter, what: &Unreadable| match *what {
+            Unreadable::Actuator(idx) => write!(
+                f,
+                "actuator {} read before it was set",
+                program.actuators().nth(idx as usize).unwrap().name
+            ),
+            Unreadable::Timer(idx) => write!(
+                f,
+                "timer {} read before it was started",
+                program.timers().nth(idx as usize).unwrap()
+            ),
+        };
+        let fault = match self.fault {
+            Some(fault) => fault,
+            None => return write!(f, "timed out busy looping in block {}", block),
+        };
+        match fault {
+            Fault::Device(e) => write!(f, "{}", e),
+            Fault::Unreadable(what) => {
+                write!(f, "block {}: ", block)?;
+                unreadable(f, what)
+            }
+            Fault::Refused {
+                actuator,
+                value,
+                reason,
+            } => {
+                write!(
+                    f,
+                    "block {}: refused to set {} to {}: ",
+                    block,
+                    program.actuators().nth(*actuator as usize).unwrap().name,
+                    value
+                )?;
+                match *reason {
+                    Refusal::Interlock(idx) => write!(
+                        f,
+                        "violates interlock {}",
+                        program.interlocks().nth(idx as usize).unwrap().shown
+                    ),
+                    Refusal::Unchecked(idx, what) => {
+                        write!(
+                            f,
+                            "can't check interlock {}: ",
+                            program.interlocks().nth(idx as usize).unwrap().shown
+                        )?;
+                        unreadable(f, &what)
+                    }
+                    Refusal::Invariant(idx) => write!(
+                        f,
+                        "violates invariant {}",
+                        program.invariants().nth(idx as usize).unwrap().shown
+                    ),
+                    Refusal::UncheckedInvariant(idx, what) => {
+                        write!(
+                            f,
+                            "can't check invariant {}: ",
+                            program.invariants().nth(idx as usize).unwrap().shown
+                        )?;
+                        unreadable(f, &what)
+                    }
+                }
+            }
+            Fault::TooBig => write!(f, "flow needs more room than the vm has, {} of each", N),
+        }
+    }
+}
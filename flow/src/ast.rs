@@ -1,41 +1,217 @@
 use std::collections::HashMap;
+use std::fmt;
 
 use crate::token::Token;
+use crate::units::{self, Dimension, Unit};
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
 pub struct AST {
-    first_block_name: String,
-    devices: HashMap<String, Device>,
-    blocks: HashMap<String, Block>,
+    pub first_block_name: String,
+    pub devices: HashMap<String, Device>,
+    pub interlocks: Vec<Interlock>,
+    pub invariants: Vec<Condition>,
+    pub properties: Vec<Property>,
+    pub blocks: HashMap<String, Block>,
+}
+
+impl AST {
+    // devices sorted by name, so anything iterating them is deterministic
+    pub fn sensors(&self) -> Vec<&Sensor> {
+        let mut sensors: Vec<&Sensor> = self
+            .devices
+            .values()
+            .filter_map(|dev| match dev {
+                Device::Sensor(sens) => Some(sens),
+                _ => None,
+            })
+            .collect();
+        sensors.sort_by(|a, b| a.name.cmp(&b.name));
+        sensors
+    }
+
+    pub fn actuators(&self) -> Vec<&Actuator> {
+        let mut actuators: Vec<&Actuator> = self
+            .devices
+            .values()
+            .filter_map(|dev| match dev {
+                Device::Actuator(act) => Some(act),
+                _ => None,
+            })
+            .collect();
+        actuators.sort_by(|a, b| a.name.cmp(&b.name));
+        actuators
+    }
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Device {
     Actuator(Actuator),
     Sensor(Sensor),
+    Timer(String),
+}
+
+// names of the built-in clocks, which can't be used for devices
+const ELAPSED: &str = "elapsed";
+const NOW: &str = "now";
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Clock {
+    // seconds since the current block was entered
+    Elapsed,
+    // seconds since the flow started
+    Now,
+    // seconds since the timer was last started
+    Timer(String),
+}
+
+impl fmt::Display for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Clock::Elapsed => write!(f, "{}", ELAPSED),
+            Clock::Now => write!(f, "{}", NOW),
+            Clock::Timer(name) => write!(f, "{}", name),
+        }
+    }
 }
 
-// for now actuators/sensors can only be floats internally
-#[derive(Debug, Clone)]
+// values are floats internally: bools are 0 or 1 and enums are the index of their variant
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Kind {
+    Float,
+    Bool,
+    Enum(Vec<String>),
+}
+
+impl Kind {
+    pub fn show(&self, val: f64) -> String {
+        match self {
+            Kind::Float => val.to_string(),
+            Kind::Bool => (val != 0.0).to_string(),
+            Kind::Enum(variants) => match variants.get(val as usize) {
+                Some(variant) => variant.clone(),
+                None => val.to_string(),
+            },
+        }
+    }
+
+    pub fn parse(&self, text: &str) -> Option<f64> {
+        match self {
+            Kind::Float => text.parse().ok(),
+            Kind::Bool => match text {
+                "true" => Some(1.0),
+                "false" => Some(0.0),
+                _ => None,
+            },
+            Kind::Enum(variants) => variants.iter().position(|v| v == text).map(|i| i as f64),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
 pub struct Actuator {
-    name: String,
-    min: f64,
-    max: f64,
+    pub name: String,
+    pub kind: Kind,
+    pub unit: Option<Unit>,
+    pub min: f64,
+    pub max: f64,
+    pub init: Option<f64>,
+    pub failsafe: Option<f64>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
 pub struct Sensor {
-    name: String,
-    min: f64,
-    max: f64,
+    pub name: String,
+    pub kind: Kind,
+    pub unit: Option<Unit>,
+    pub min: f64,
+    pub max: f64,
+    // how far apart two readings can be and still count as equal under ~=
+    pub tol: Option<f64>,
+}
+
+fn show(kind: &Kind, unit: &Option<Unit>, val: f64) -> String {
+    match unit {
+        Some(unit) => format!("{}{}", kind.show(val), unit.name),
+        None => kind.show(val),
+    }
 }
 
+impl Actuator {
+    pub fn show(&self, val: f64) -> String {
+        show(&self.kind, &self.unit, val)
+    }
+}
+
+impl Sensor {
+    pub fn show(&self, val: f64) -> String {
+        show(&self.kind, &self.unit, val)
+    }
+}
+
+// whenever the trigger holds, the requirement has to hold as well
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct Interlock {
+    pub trigger: Condition,
+    pub requirement: Condition,
+}
+
+// something `flow verify` checks holds however the sensors behave
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Property {
+    // the condition holds whatever state the flow gets into
+    Always(Condition),
+    // the condition never holds
+    Never(Condition),
+    // every run enters the block, or every run enters it from wherever the condition holds
+    Reaches {
+        block: String,
+        after: Option<Condition>,
+    },
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
 pub struct Block {
-    ops: Vec<Operation>,
+    // the line the block is declared on
+    pub line: usize,
+    pub ops: Vec<Statement>,
+}
+
+// an operation and the line of the source it starts on
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct Statement {
+    pub line: usize,
+    pub op: Operation,
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Operation {
     Set {
         actuator: Actuator,
@@ -44,10 +220,18 @@ pub enum Operation {
     Wait {
         condition: Condition,
     },
+    // wait for a fixed number of seconds
+    Delay {
+        duration: f64,
+    },
+    // restart a timer from zero
+    Start {
+        timer: String,
+    },
     IfElse {
         if_condition: Condition,
-        if_actions: Vec<Operation>,
-        else_actions: Option<Vec<Operation>>,
+        if_actions: Vec<Statement>,
+        else_actions: Option<Vec<Statement>>,
     },
     Goto {
         dest: String,
@@ -55,23 +239,157 @@ pub enum Operation {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Condition {
     Base(Sensor, Comparator, f64),
+    Actuator(Actuator, Comparator, f64),
+    // compares a clock against a number of seconds
+    Time(Clock, Comparator, f64),
+    // schmitt trigger: true once the sensor crosses the threshold, and false again only once it
+    // has come back past the threshold by more than the band. id is unique to each condition.
+    Hysteresis {
+        sensor: Sensor,
+        rising: bool,
+        threshold: f64,
+        band: f64,
+        id: usize,
+    },
+    // the inner condition became true (rising) or false (falling) since it was last evaluated
+    Edge {
+        condition: Box<Condition>,
+        rising: bool,
+        id: usize,
+    },
+    // the inner condition has held for at least this many seconds
+    For {
+        condition: Box<Condition>,
+        duration: f64,
+        id: usize,
+    },
     All(Vec<Condition>),
     Any(Vec<Condition>),
 }
 
-#[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum Comparator {
     LT,
     LTEQ,
     EQ,
     GT,
     GTEQ,
+    // equal to within the sensor's tolerance
+    APPROX(f64),
+}
+
+impl Comparator {
+    pub fn holds(self, lhs: f64, rhs: f64) -> bool {
+        match self {
+            Comparator::LT => lhs < rhs,
+            Comparator::LTEQ => lhs <= rhs,
+            Comparator::EQ => lhs == rhs,
+            Comparator::GT => lhs > rhs,
+            Comparator::GTEQ => lhs >= rhs,
+            Comparator::APPROX(tol) => (lhs - rhs).abs() <= tol,
+        }
+    }
+}
+
+impl fmt::Display for Comparator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match self {
+            Comparator::LT => "<",
+            Comparator::LTEQ => "<=",
+            Comparator::EQ => "=",
+            Comparator::GT => ">",
+            Comparator::GTEQ => ">=",
+            Comparator::APPROX(_) => "~=",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Condition::Base(sensor, comp, val) => {
+                write!(f, "{} {} {}", sensor.name, comp, sensor.show(*val))
+            }
+            Condition::Actuator(act, comp, val) => {
+                write!(f, "{} {} {}", act.name, comp, act.show(*val))
+            }
+            Condition::Time(clock, comp, val) => write!(f, "{} {} {}s", clock, comp, val),
+            Condition::Hysteresis {
+                sensor,
+                rising,
+                threshold,
+                band,
+                ..
+            } => write!(
+                f,
+                "{} {} {} hyst {}",
+                sensor.name,
+                if *rising {
+                    "rises_above"
+                } else {
+                    "falls_below"
+                },
+                sensor.show(*threshold),
+                sensor.show(*band)
+            ),
+            Condition::Edge {
+                condition, rising, ..
+            } => write!(
+                f,
+                "{} {}",
+                condition,
+                if *rising { "rising" } else { "falling" }
+            ),
+            Condition::For {
+                condition,
+                duration,
+                ..
+            } => write!(f, "{} for {}s", condition, duration),
+            Condition::All(conditions) | Condition::Any(conditions) => {
+                let kind = if let Condition::All(_) = self {
+                    "all"
+                } else {
+                    "any"
+                };
+                let parts: Vec<String> = conditions.iter().map(|c| c.to_string()).collect();
+                write!(f, "{}({})", kind, parts.join(", "))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Interlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} requires {}", self.trigger, self.requirement)
+    }
+}
+
+impl fmt::Display for Property {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Property::Always(condition) => write!(f, "always {}", condition),
+            Property::Never(condition) => write!(f, "never {}", condition),
+            Property::Reaches { block, after: None } => write!(f, "reaches {}", block),
+            Property::Reaches {
+                block,
+                after: Some(condition),
+            } => write!(f, "reaches {} after {}", block, condition),
+        }
+    }
 }
 
 pub fn make_ast(tokens: &[Token]) -> Result<AST, &'static str> {
-    let (devices, mut idx) = make_devices(&tokens)?;
+    let (devices, mut idx) = make_devices(tokens)?;
 
     // consume newline
     if let Token::Newline = &tokens[idx] {
@@ -80,12 +398,26 @@ pub fn make_ast(tokens: &[Token]) -> Result<AST, &'static str> {
         return Err("Expected newline after end of device list");
     }
 
-    let (first_block_name, blocks, newidx) = make_blocks(&tokens, idx, &devices)?;
+    let (interlocks, invariants, properties, newidx) = make_safety(tokens, idx, &devices)?;
     idx = newidx;
 
+    let mut lines = Lines { idx: 0, line: 1 };
+    let (first_block_name, blocks, _) = make_blocks(tokens, idx, &devices, &mut lines)?;
+
+    for property in &properties {
+        if let Property::Reaches { block, .. } = property {
+            if !blocks.contains_key(block) {
+                return Err("Expected a declared block name after \"reaches\"");
+            }
+        }
+    }
+
     Ok(AST {
         first_block_name,
         devices,
+        interlocks,
+        invariants,
+        properties,
         blocks,
     })
 }
@@ -95,6 +427,31 @@ fn make_devices(tokens: &[Token]) -> Result<(HashMap<String, Device>, usize), &'
     let mut devices: HashMap<String, Device> = HashMap::new();
     while outsize < tokens.len() {
         match &tokens[outsize] {
+            Token::Timer => {
+                outsize += 1; // consume the timer
+
+                // consume name of timer
+                let timer_name: String;
+                if let Token::Identifier(name) = &tokens[outsize] {
+                    timer_name = name.clone();
+                    outsize += 1;
+                } else {
+                    return Err("Expected identifier after timer");
+                }
+
+                if timer_name == ELAPSED || timer_name == NOW {
+                    return Err("Expected a name other than the built-in elapsed and now");
+                }
+
+                // consume newline
+                if let Token::Newline = &tokens[outsize] {
+                    outsize += 1;
+                } else {
+                    return Err("Expected newline after timer declaration");
+                }
+
+                devices.insert(timer_name.clone(), Device::Timer(timer_name));
+            }
             Token::Actuator | Token::Sensor => {
                 let devkind = &tokens[outsize];
                 outsize += 1;
@@ -107,7 +464,102 @@ fn make_devices(tokens: &[Token]) -> Result<(HashMap<String, Device>, usize), &'
                 } else {
                     return Err("Expected identifier after device type");
                 }
-                // TODO: parse device ranges
+
+                if dev_name == ELAPSED || dev_name == NOW {
+                    return Err("Expected a name other than the built-in elapsed and now");
+                }
+
+                let mut kind = Kind::Float;
+                let mut min = f64::MIN;
+                let mut max = f64::MAX;
+                if let Token::Colon = &tokens[outsize] {
+                    // consume the device type
+                    outsize += 1;
+                    let (devtype, newidx) = make_kind(tokens, outsize)?;
+                    outsize = newidx;
+                    kind = devtype;
+                    min = 0.0;
+                    max = match &kind {
+                        Kind::Enum(variants) => (variants.len() - 1) as f64,
+                        _ => 1.0,
+                    };
+                } else if let Token::Value(lo) = &tokens[outsize] {
+                    // consume the optional device range
+                    min = *lo;
+                    outsize += 1;
+
+                    // consume range
+                    if let Token::Range = &tokens[outsize] {
+                        outsize += 1;
+                    } else {
+                        return Err("Expected \"..\" after device range minimum");
+                    }
+
+                    if let Token::Value(hi) = &tokens[outsize] {
+                        max = *hi;
+                        outsize += 1;
+                    } else {
+                        return Err("Expected device range maximum after \"..\"");
+                    }
+
+                    if min > max {
+                        return Err("Expected device range minimum to not exceed its maximum");
+                    }
+                }
+
+                // consume the optional unit
+                let mut unit: Option<Unit> = None;
+                if let Token::Identifier(name) = &tokens[outsize] {
+                    if kind != Kind::Float {
+                        return Err("Only numeric devices can have a unit");
+                    }
+                    unit = units::lookup(name);
+                    if unit.is_none() {
+                        return Err("Expected a known unit after device declaration");
+                    }
+                    outsize += 1;
+                }
+
+                // consume the optional tolerance
+                let mut tol: Option<f64> = None;
+                if let Token::Tol = &tokens[outsize] {
+                    outsize += 1;
+
+                    if let Token::Actuator = devkind {
+                        return Err("Only sensors can have a tolerance");
+                    }
+                    if kind != Kind::Float {
+                        return Err("Only numeric sensors can have a tolerance");
+                    }
+
+                    let (val, newidx) = make_delta(tokens, outsize, unit)?;
+                    outsize = newidx;
+                    tol = Some(val);
+                }
+
+                // consume the optional initial and fail-safe values
+                let mut init: Option<f64> = None;
+                let mut failsafe: Option<f64> = None;
+                while let Token::Init | Token::Failsafe = &tokens[outsize] {
+                    let which = &tokens[outsize];
+                    outsize += 1;
+
+                    if let Token::Sensor = devkind {
+                        return Err("Only actuators can have init or failsafe values");
+                    }
+
+                    let (val, newidx) = make_value(tokens, outsize, &kind, unit)?;
+                    outsize = newidx;
+
+                    if !(min..=max).contains(&val) {
+                        return Err("Expected init or failsafe value in range of device range");
+                    }
+
+                    match which {
+                        Token::Init => init = Some(val),
+                        _ => failsafe = Some(val),
+                    }
+                }
 
                 // consume newline
                 if let Token::Newline = &tokens[outsize] {
@@ -121,18 +573,20 @@ fn make_devices(tokens: &[Token]) -> Result<(HashMap<String, Device>, usize), &'
                     match devkind {
                         Token::Actuator => Device::Actuator(Actuator {
                             name: dev_name,
-                            min: f64::MIN,
-                            max: f64::MAX,
-                        }),
-                        Token::Sensor => Device::Sensor(Sensor {
-                            name: dev_name,
-                            min: f64::MIN,
-                            max: f64::MAX,
+                            kind,
+                            unit,
+                            min,
+                            max,
+                            init,
+                            failsafe,
                         }),
                         _ => Device::Sensor(Sensor {
-                            name: String::from(""),
-                            min: 0.0,
-                            max: 0.0,
+                            name: dev_name,
+                            kind,
+                            unit,
+                            min,
+                            max,
+                            tol,
                         }),
                     },
                 );
@@ -145,10 +599,287 @@ fn make_devices(tokens: &[Token]) -> Result<(HashMap<String, Device>, usize), &'
     Ok((devices, outsize))
 }
 
+fn make_kind(tokens: &[Token], start: usize) -> Result<(Kind, usize), &'static str> {
+    let mut idx = start;
+
+    match &tokens[idx] {
+        Token::Bool => Ok((Kind::Bool, idx + 1)),
+        Token::Enum => {
+            idx += 1; // consume the enum
+
+            // consume open brace
+            if let Token::OpenBrace = &tokens[idx] {
+                idx += 1;
+            } else {
+                return Err("Expected \"{\" after enum");
+            }
+
+            let mut variants: Vec<String> = Vec::new();
+            loop {
+                if let Token::Identifier(name) = &tokens[idx] {
+                    if variants.contains(name) {
+                        return Err("Expected enum variants to be unique");
+                    }
+                    variants.push(name.clone());
+                    idx += 1;
+                } else {
+                    return Err("Expected variant name in enum");
+                }
+
+                match &tokens[idx] {
+                    Token::Comma => idx += 1,
+                    Token::CloseBrace => {
+                        idx += 1;
+                        break;
+                    }
+                    _ => return Err("Expected \",\" or \"}\" after enum variant"),
+                }
+            }
+
+            Ok((Kind::Enum(variants), idx))
+        }
+        _ => Err("Expected \"bool\" or \"enum\" after colon in device declaration"),
+    }
+}
+
+// a literal for a device of the given kind: a number, true/false, or an enum variant
+fn make_value(
+    tokens: &[Token],
+    start: usize,
+    kind: &Kind,
+    unit: Option<Unit>,
+) -> Result<(f64, usize), &'static str> {
+    match (&tokens[start], kind) {
+        (Token::Value(val), Kind::Float) => make_quantity(tokens, start, *val, unit, false),
+        (Token::Identifier(name), Kind::Bool) => match kind.parse(name) {
+            Some(val) => Ok((val, start + 1)),
+            None => Err("Expected true or false for bool device"),
+        },
+        (Token::Identifier(name), Kind::Enum(_)) => match kind.parse(name) {
+            Some(val) => Ok((val, start + 1)),
+            None => Err("Expected one of the enum's variants"),
+        },
+        (_, Kind::Float) => Err("Expected numeric value for device"),
+        (_, Kind::Bool) => Err("Expected true or false for bool device"),
+        (_, Kind::Enum(_)) => Err("Expected one of the enum's variants"),
+    }
+}
+
+// a difference between two values of a numeric device, like a tolerance or band
+fn make_delta(
+    tokens: &[Token],
+    start: usize,
+    unit: Option<Unit>,
+) -> Result<(f64, usize), &'static str> {
+    if let Token::Value(val) = &tokens[start] {
+        if *val < 0.0 {
+            return Err("Expected a positive tolerance or band");
+        }
+        make_quantity(tokens, start, *val, unit, true)
+    } else {
+        Err("Expected a numeric tolerance or band")
+    }
+}
+
+// a number with an optional unit suffix, converted into the device's unit
+fn make_quantity(
+    tokens: &[Token],
+    start: usize,
+    val: f64,
+    unit: Option<Unit>,
+    delta: bool,
+) -> Result<(f64, usize), &'static str> {
+    let mut idx = start + 1;
+
+    // nothing else can follow a number directly, so any name there is meant as its unit
+    let literal_unit = match tokens.get(idx) {
+        Some(Token::Identifier(name)) => match units::lookup(name) {
+            Some(unit) => Some(unit),
+            None => return Err("Expected a known unit after value, such as degC, bar or ms"),
+        },
+        _ => None,
+    };
+    match (literal_unit, unit) {
+        (None, _) => Ok((val, idx)),
+        (Some(_), None) => Err("Expected no unit on value for a device without a unit"),
+        (Some(from), Some(to)) => {
+            idx += 1;
+            let converted = if delta {
+                from.convert_delta(val, &to)
+            } else {
+                from.convert(val, &to)
+            };
+            match (converted, to.dimension) {
+                (Some(converted), _) => Ok((converted, idx)),
+                (None, Dimension::Time) => {
+                    Err("Expected a time unit on value for a device in time")
+                }
+                (None, Dimension::Temperature) => {
+                    Err("Expected a temperature unit on value for a device in temperature")
+                }
+                (None, Dimension::Pressure) => {
+                    Err("Expected a pressure unit on value for a device in pressure")
+                }
+            }
+        }
+    }
+}
+
+// a time like 500ms or 2min, in seconds
+fn make_duration(tokens: &[Token], start: usize) -> Result<(f64, usize), &'static str> {
+    let val: f64;
+    if let Token::Value(v) = &tokens[start] {
+        val = *v;
+    } else {
+        return Err("Expected a duration");
+    }
+
+    match tokens.get(start + 1) {
+        Some(Token::Identifier(name)) => match units::lookup(name) {
+            Some(unit) if unit.dimension == Dimension::Time => {
+                Ok((unit.convert(val, &units::seconds()).unwrap(), start + 2))
+            }
+            _ => Err("Expected a time unit on duration"),
+        },
+        _ => Err("Expected a unit on duration, such as 500ms"),
+    }
+}
+
+// interlocks, invariants and the properties to verify, between the devices and the blocks
+type Safety = (Vec<Interlock>, Vec<Condition>, Vec<Property>, usize);
+
+fn make_safety(
+    tokens: &[Token],
+    start: usize,
+    devices: &HashMap<String, Device>,
+) -> Result<Safety, &'static str> {
+    let mut idx = start;
+    let mut interlocks: Vec<Interlock> = Vec::new();
+    let mut invariants: Vec<Condition> = Vec::new();
+    let mut properties: Vec<Property> = Vec::new();
+    while idx < tokens.len() {
+        match &tokens[idx] {
+            Token::Interlock => {
+                idx += 1; // consume the interlock
+
+                // consume colon
+                if let Token::Colon = &tokens[idx] {
+                    idx += 1
+                } else {
+                    return Err("Expected colon after interlock statement");
+                }
+
+                let (trigger, newidx) = make_comparison(tokens, idx, devices)?;
+                idx = newidx;
+
+                // consume requires
+                if let Token::Requires = &tokens[idx] {
+                    idx += 1;
+                } else {
+                    return Err("Expected \"requires\" after interlock trigger");
+                }
+
+                let (requirement, newidx) = make_condition(tokens, idx, devices, 0)?;
+                idx = newidx;
+
+                if remembers(&trigger) || remembers(&requirement) {
+                    return Err("Expected an interlock without rising, falling, for or hysteresis");
+                }
+                interlocks.push(Interlock {
+                    trigger,
+                    requirement,
+                });
+            }
+            Token::Invariant => {
+                idx += 1; // consume the invariant
+
+                // consume colon
+                if let Token::Colon = &tokens[idx] {
+                    idx += 1
+                } else {
+                    return Err("Expected colon after invariant statement");
+                }
+
+                let (condition, newidx) = make_condition(tokens, idx, devices, 0)?;
+                idx = newidx;
+                if remembers(&condition) {
+                    return Err("Expected an invariant without rising, falling, for or hysteresis");
+                }
+                invariants.push(condition);
+            }
+            Token::Always | Token::Never => {
+                let always = matches!(tokens[idx], Token::Always);
+                idx += 1; // consume the always/never
+
+                // consume colon
+                if let Token::Colon = &tokens[idx] {
+                    idx += 1
+                } else {
+                    return Err("Expected colon after always/never statement");
+                }
+
+                let (condition, newidx) = make_condition(tokens, idx, devices, 0)?;
+                idx = newidx;
+                properties.push(if always {
+                    Property::Always(condition)
+                } else {
+                    Property::Never(condition)
+                });
+            }
+            Token::Reaches => {
+                idx += 1; // consume the reaches
+
+                // consume colon
+                if let Token::Colon = &tokens[idx] {
+                    idx += 1
+                } else {
+                    return Err("Expected colon after reaches statement");
+                }
+
+                // consume the block name, which is checked once the blocks are parsed
+                let block: String;
+                if let Token::Identifier(name) = &tokens[idx] {
+                    block = name.clone();
+                    idx += 1;
+                } else {
+                    return Err("Expected a declared block name after \"reaches\"");
+                }
+
+                let mut after = None;
+                if let Token::After = &tokens[idx] {
+                    idx += 1;
+                    let (condition, newidx) = make_condition(tokens, idx, devices, 0)?;
+                    idx = newidx;
+                    after = Some(condition);
+                }
+                properties.push(Property::Reaches { block, after });
+            }
+            Token::Newline => {
+                idx += 1;
+            }
+            _ => {
+                break;
+            }
+        }
+    }
+    Ok((interlocks, invariants, properties, idx))
+}
+
+// whether a condition remembers anything between evaluations. Interlocks and invariants are
+// checked on every set rather than once a step, so they can't.
+fn remembers(condition: &Condition) -> bool {
+    match condition {
+        Condition::Hysteresis { .. } | Condition::Edge { .. } | Condition::For { .. } => true,
+        Condition::All(conditions) | Condition::Any(conditions) => conditions.iter().any(remembers),
+        _ => false,
+    }
+}
+
 fn make_blocks(
     tokens: &[Token],
     start: usize,
     devices: &HashMap<String, Device>,
+    lines: &mut Lines,
 ) -> Result<(String, HashMap<String, Block>, usize), &'static str> {
     let mut idx = start;
     let mut blocks: HashMap<String, Block> = HashMap::new();
@@ -161,9 +892,9 @@ fn make_blocks(
             return Err("Expected block declaration");
         }
 
-        let (block_name, block, newidx) = make_block(tokens, idx, &devices)?;
+        let (block_name, block, newidx) = make_block(tokens, idx, devices, lines)?;
         idx = newidx;
-        if let None = first_block_name {
+        if first_block_name.is_none() {
             first_block_name = Some(block_name.clone());
         }
         blocks.insert(block_name, block);
@@ -199,8 +930,10 @@ fn make_block(
     tokens: &[Token],
     start: usize,
     devices: &HashMap<String, Device>,
+    lines: &mut Lines,
 ) -> Result<(String, Block, usize), &'static str> {
     let mut idx = start;
+    let line = lines.at(tokens, idx);
 
     // consume the block name
     let block_name: String;
@@ -218,21 +951,22 @@ fn make_block(
         return Err("Expected newline after block name");
     }
 
-    let (ops, newidx) = make_statements(tokens, idx, devices, 1, false)?;
+    let (ops, newidx) = make_statements(tokens, idx, devices, lines, 1, false)?;
     idx = newidx;
 
-    Ok((block_name, Block { ops }, idx))
+    Ok((block_name, Block { line, ops }, idx))
 }
 
 fn make_statements(
     tokens: &[Token],
     start: usize,
     devices: &HashMap<String, Device>,
+    lines: &mut Lines,
     tabdepth: u8,
     ifelse: bool,
-) -> Result<(Vec<Operation>, usize), &'static str> {
+) -> Result<(Vec<Statement>, usize), &'static str> {
     let mut idx = start;
-    let mut ops: Vec<Operation> = Vec::new();
+    let mut ops: Vec<Statement> = Vec::new();
 
     while idx < tokens.len() {
         match &tokens[idx] {
@@ -251,13 +985,14 @@ fn make_statements(
         } else {
             break;
         }
+        let line = lines.at(tokens, idx);
 
         // println!("{:?}", &tokens[idx]);
         match &tokens[idx] {
             Token::Set => {
                 idx += 1; // consume the set token
-                let (set, newidx) = make_set(tokens, idx, &devices)?;
-                ops.push(set);
+                let (set, newidx) = make_set(tokens, idx, devices)?;
+                ops.push(Statement { line, op: set });
                 idx = newidx;
 
                 // consume newline
@@ -270,7 +1005,7 @@ fn make_statements(
             Token::Goto => {
                 idx += 1; // consume the goto token
                 let (goto, newidx) = make_goto(tokens, idx)?;
-                ops.push(goto);
+                ops.push(Statement { line, op: goto });
                 idx = newidx;
 
                 // consume newline
@@ -280,6 +1015,47 @@ fn make_statements(
                     return Err("Expected newline after set statement");
                 }
             }
+            // start is only a keyword here, so blocks and devices can still be called start
+            Token::Identifier(word) if word == "start" => {
+                idx += 1; // consume the start
+
+                // consume timer name
+                if let Token::Identifier(name) = &tokens[idx] {
+                    if let Some(Device::Timer(_)) = devices.get(name) {
+                        let op = Operation::Start {
+                            timer: name.clone(),
+                        };
+                        ops.push(Statement { line, op });
+                        idx += 1;
+                    } else {
+                        return Err("Expected declared timer name after \"start\"");
+                    }
+                } else {
+                    return Err("Expected timer name after \"start\"");
+                }
+
+                // consume newline
+                if let Token::Newline = &tokens[idx] {
+                    idx += 1;
+                } else {
+                    return Err("Expected newline after start statement");
+                }
+            }
+            Token::Wait if matches!(tokens.get(idx + 1), Some(Token::Value(_))) => {
+                idx += 1; // consume the wait
+
+                let (duration, newidx) = make_duration(tokens, idx)?;
+                let op = Operation::Delay { duration };
+                ops.push(Statement { line, op });
+                idx = newidx;
+
+                // consume newline
+                if let Token::Newline = &tokens[idx] {
+                    idx += 1;
+                } else {
+                    return Err("Expected newline after wait duration");
+                }
+            }
             Token::Wait => {
                 idx += 1; // consume the wait
 
@@ -298,7 +1074,7 @@ fn make_statements(
                 }
 
                 let (wait, newidx) = make_wait(tokens, idx, devices, tabdepth)?;
-                ops.push(wait);
+                ops.push(Statement { line, op: wait });
                 idx = newidx;
             }
             Token::If => {
@@ -318,17 +1094,12 @@ fn make_statements(
                     return Err("Expected newline after colon");
                 }
 
-                let (ifelse, newidx) = make_if(tokens, idx, devices, tabdepth)?;
-                ops.push(ifelse);
+                let (ifelse, newidx) = make_if(tokens, idx, devices, lines, tabdepth)?;
+                ops.push(Statement { line, op: ifelse });
                 idx = newidx;
             }
-            Token::Else => {
-                // println!("ifelse: {}", ifelse);
-                if ifelse {
-                    break;
-                } else {
-                    return Err("Unexpected token");
-                }
+            Token::Else if ifelse => {
+                break;
             }
             _ => {
                 // println!("{:?}", &tokens[idx..]);
@@ -368,13 +1139,8 @@ fn make_set(
     }
 
     // consume the value
-    let dev_val: f64;
-    if let Token::Value(val) = &tokens[idx] {
-        dev_val = val.clone();
-        idx += 1;
-    } else {
-        return Err("Expected valid device value after device name");
-    }
+    let (dev_val, newidx) = make_value(tokens, idx, &actuator.kind, actuator.unit)?;
+    idx = newidx;
 
     if !(dev_val <= actuator.max && dev_val >= actuator.min) {
         return Err("Expected value in range of device range");
@@ -422,6 +1188,7 @@ fn make_if(
     tokens: &[Token],
     start: usize,
     devices: &HashMap<String, Device>,
+    lines: &mut Lines,
     tabdepth: u8,
 ) -> Result<(Operation, usize), &'static str> {
     let mut idx = start;
@@ -429,7 +1196,7 @@ fn make_if(
     let (if_condition, newidx) = make_condition(tokens, idx, devices, tabdepth + 1)?;
     idx = newidx;
 
-    let (if_actions, newidx) = make_statements(tokens, idx, devices, tabdepth + 1, true)?;
+    let (if_actions, newidx) = make_statements(tokens, idx, devices, lines, tabdepth + 1, true)?;
     idx = newidx;
 
     // check if there is an else part
@@ -455,10 +1222,10 @@ fn make_if(
         }
     }
 
-    let mut else_actions: Option<Vec<Operation>> = None;
+    let mut else_actions: Option<Vec<Statement>> = None;
 
     if is_else {
-        let (actions, newidx) = make_statements(tokens, idx, devices, tabdepth + 1, false)?;
+        let (actions, newidx) = make_statements(tokens, idx, devices, lines, tabdepth + 1, false)?;
         idx = newidx;
         else_actions = Some(actions);
     }
@@ -521,7 +1288,7 @@ fn make_condition(
                 }
             }
 
-            if conditions.len() == 0 {
+            if conditions.is_empty() {
                 Err("Expected conditions after any/all statement")
             } else {
                 match kind {
@@ -531,55 +1298,9 @@ fn make_condition(
                 }
             }
         }
-        Token::Identifier(name) => {
-            let dev_name = name.clone();
-            idx += 1;
-
-            if !devices.contains_key(&dev_name) {
-                return Err("Expected valid device name after condition start");
-            }
-
-            let sensor: Sensor;
-            if let Device::Sensor(sens) = devices.get(&dev_name).unwrap() {
-                sensor = sens.clone();
-            } else {
-                return Err("Expected sensor device name after condition start");
-            }
-
-            let comparator: Comparator;
-            if let Token::Comparator(comp) = &tokens[idx] {
-                match comp.as_str() {
-                    "<" => {
-                        comparator = Comparator::LT;
-                    }
-                    "<=" => {
-                        comparator = Comparator::LTEQ;
-                    }
-                    "=" => {
-                        comparator = Comparator::EQ;
-                    }
-                    ">" => {
-                        comparator = Comparator::GT;
-                    }
-                    ">=" => {
-                        comparator = Comparator::GTEQ;
-                    }
-                    _ => {
-                        return Err("Error in parsing, please report this bug");
-                    }
-                }
-                idx += 1;
-            } else {
-                return Err("Expected comparator after device name in condition");
-            }
-
-            let val: f64;
-            if let Token::Value(v) = &tokens[idx] {
-                val = v.clone();
-                idx += 1;
-            } else {
-                return Err("Expected valid value after comparator in condition");
-            }
+        Token::Identifier(_) => {
+            let (condition, newidx) = make_qualified(tokens, idx, devices)?;
+            idx = newidx;
 
             // consume newline
             if let Token::Newline = &tokens[idx] {
@@ -588,12 +1309,185 @@ fn make_condition(
                 return Err("Expected newline after colon");
             }
 
-            Ok((Condition::Base(sensor, comparator, val), idx))
+            Ok((condition, idx))
         }
         _ => Err("Expected device name, any, or all after condition start"),
     }
 }
 
+// a comparison, optionally followed by an edge or duration qualifier
+fn make_qualified(
+    tokens: &[Token],
+    start: usize,
+    devices: &HashMap<String, Device>,
+) -> Result<(Condition, usize), &'static str> {
+    let (condition, mut idx) = make_comparison(tokens, start, devices)?;
+
+    match &tokens[idx] {
+        Token::Rising | Token::Falling => {
+            let rising = matches!(&tokens[idx], Token::Rising);
+            idx += 1;
+            let condition = Condition::Edge {
+                condition: Box::new(condition),
+                rising,
+                id: start,
+            };
+            Ok((condition, idx))
+        }
+        Token::For => {
+            idx += 1; // consume the for
+
+            let (duration, newidx) = make_duration(tokens, idx)?;
+            idx = newidx;
+            let condition = Condition::For {
+                condition: Box::new(condition),
+                duration,
+                id: start,
+            };
+            Ok((condition, idx))
+        }
+        _ => Ok((condition, idx)),
+    }
+}
+
+fn make_comparison(
+    tokens: &[Token],
+    start: usize,
+    devices: &HashMap<String, Device>,
+) -> Result<(Condition, usize), &'static str> {
+    let mut idx = start;
+
+    // consume device name
+    let dev_name: String;
+    if let Token::Identifier(name) = &tokens[idx] {
+        dev_name = name.clone();
+        idx += 1;
+    } else {
+        return Err("Expected device name in condition");
+    }
+
+    let clock = match devices.get(&dev_name) {
+        _ if dev_name == ELAPSED => Some(Clock::Elapsed),
+        _ if dev_name == NOW => Some(Clock::Now),
+        Some(Device::Timer(name)) => Some(Clock::Timer(name.clone())),
+        _ => None,
+    };
+    let (kind, unit) = match devices.get(&dev_name) {
+        _ if clock.is_some() => (&Kind::Float, Some(units::seconds())),
+        Some(Device::Sensor(sens)) => (&sens.kind, sens.unit),
+        Some(Device::Actuator(act)) => (&act.kind, act.unit),
+        _ => return Err("Expected valid device name after condition start"),
+    };
+
+    if let Token::RisesAbove | Token::FallsBelow = &tokens[idx] {
+        let rising = matches!(&tokens[idx], Token::RisesAbove);
+        idx += 1;
+
+        let sensor = match devices.get(&dev_name) {
+            Some(Device::Sensor(sens)) if sens.kind == Kind::Float => sens.clone(),
+            _ => return Err("Expected a numeric sensor before rises_above or falls_below"),
+        };
+
+        let (threshold, newidx) = make_value(tokens, idx, kind, unit)?;
+        idx = newidx;
+
+        // consume hyst
+        if let Token::Hyst = &tokens[idx] {
+            idx += 1;
+        } else {
+            return Err("Expected \"hyst\" after threshold");
+        }
+
+        let (band, newidx) = make_delta(tokens, idx, unit)?;
+        idx = newidx;
+
+        let condition = Condition::Hysteresis {
+            sensor,
+            rising,
+            threshold,
+            band,
+            id: start,
+        };
+        return Ok((condition, idx));
+    }
+
+    let comparator: Comparator;
+    let val: f64;
+    if let Token::Comparator(comp) = &tokens[idx] {
+        match comp.as_str() {
+            "<" => {
+                comparator = Comparator::LT;
+            }
+            "<=" => {
+                comparator = Comparator::LTEQ;
+            }
+            "=" => {
+                comparator = Comparator::EQ;
+            }
+            ">" => {
+                comparator = Comparator::GT;
+            }
+            ">=" => {
+                comparator = Comparator::GTEQ;
+            }
+            "~=" => match devices.get(&dev_name) {
+                Some(Device::Sensor(Sensor { tol: Some(tol), .. })) => {
+                    comparator = Comparator::APPROX(*tol);
+                }
+                _ => return Err("Expected a sensor with a tol before \"~=\""),
+            },
+            _ => {
+                return Err("Error in parsing, please report this bug");
+            }
+        }
+        idx += 1;
+
+        if *kind != Kind::Float && comparator != Comparator::EQ {
+            return Err("Expected \"=\" when comparing a bool or enum device");
+        }
+
+        let (v, newidx) = make_value(tokens, idx, kind, unit)?;
+        val = v;
+        idx = newidx;
+    } else if *kind == Kind::Bool {
+        // a bare bool device means it's true
+        comparator = Comparator::EQ;
+        val = 1.0;
+    } else {
+        return Err("Expected comparator after device name in condition");
+    }
+
+    let condition = match (clock, devices.get(&dev_name)) {
+        (Some(clock), _) => Condition::Time(clock, comparator, val),
+        (_, Some(Device::Sensor(sensor))) => Condition::Base(sensor.clone(), comparator, val),
+        (_, Some(Device::Actuator(actuator))) => {
+            Condition::Actuator(actuator.clone(), comparator, val)
+        }
+        _ => return Err("Error in parsing, please report this bug"),
+    };
+
+    Ok((condition, idx))
+}
+
+// every line ends in a newline token, so a token's line is one more than the newlines before it
+// the line each statement starts on, counted on from the last one asked for since the parser only
+// moves forwards through the tokens
+struct Lines {
+    idx: usize,
+    line: usize,
+}
+
+impl Lines {
+    fn at(&mut self, tokens: &[Token], idx: usize) -> usize {
+        self.line += tokens[self.idx..idx]
+            .iter()
+            .filter(|t| matches!(t, Token::Newline))
+            .count();
+        self.idx = idx;
+        self.line
+    }
+}
+
 fn check_tabs(tokens: &[Token], start: usize, tabdepth: u8) -> bool {
     let mut tab_ok = true;
 
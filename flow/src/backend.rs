@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::ast::{Actuator, Sensor};
+
+// set when a run is told to stop by Ctrl-C or a kill, so reads blocked waiting on a device give up
+pub static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+// a read cut short by a signal is tried again unless the signal was to stop
+fn retry(e: &io::Error) -> bool {
+    e.kind() == ErrorKind::Interrupted && !interrupted()
+}
+
+// checked before each read too, since a signal arriving just before it doesn't cut it short
+fn stopped() -> io::Result<()> {
+    if interrupted() {
+        Err(ErrorKind::Interrupted.into())
+    } else {
+        Ok(())
+    }
+}
+
+// Read::read_exact, but giving up on a read cut short by an interrupt
+pub fn read_exact(input: &mut impl Read, mut buf: &mut [u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+        stopped()?;
+        match input.read(buf) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => buf = &mut buf[n..],
+            Err(e) if retry(&e) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// BufRead::read_line, but giving up on a read cut short by an interrupt
+pub fn read_line(input: &mut impl BufRead, line: &mut String) -> io::Result<usize> {
+    let mut bytes = Vec::new();
+    loop {
+        stopped()?;
+        let available = match input.fill_buf() {
+            Ok(available) => available,
+            Err(e) if retry(&e) => continue,
+            Err(e) => return Err(e),
+        };
+        if available.is_empty() {
+            break;
+        }
+        let (used, done) = match available.iter().position(|b| *b == b'\n') {
+            Some(at) => (at + 1, true),
+            None => (available.len(), false),
+        };
+        bytes.extend_from_slice(&available[..used]);
+        input.consume(used);
+        if done {
+            break;
+        }
+    }
+    match String::from_utf8(bytes) {
+        Ok(text) => {
+            line.push_str(&text);
+            Ok(text.len())
+        }
+        Err(_) => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "stream did not contain valid UTF-8",
+        )),
+    }
+}
+
+// where the interpreter gets sensor readings from and sends actuator values to
+pub trait DeviceBackend {
+    fn read_sensor(&mut self, sensor: &Sensor) -> Result<f64, String>;
+
+    fn write_actuator(&mut self, actuator: &Actuator, value: f64) -> Result<(), String>;
+
+    // told the interpreter's clock before each round of readings, for backends that play back
+    // readings over time
+    fn seek(&mut self, _time: f64) {}
+
+    // when the readings will next change on their own, so a simulation can skip ahead to it
+    fn next_change(&self) -> Option<f64> {
+        None
+    }
+}
+
+// readings and outputs held in maps, for simulations and embedding
+#[derive(Debug, Default)]
+pub struct Memory {
+    pub sensors: HashMap<String, f64>,
+    pub actuators: HashMap<String, f64>,
+}
+
+impl DeviceBackend for Memory {
+    fn read_sensor(&mut self, sensor: &Sensor) -> Result<f64, String> {
+        match self.sensors.get(&sensor.name) {
+            Some(val) => Ok(*val),
+            None => Err(format!("no reading for sensor {}", sensor.name)),
+        }
+    }
+
+    fn write_actuator(&mut self, actuator: &Actuator, value: f64) -> Result<(), String> {
+        self.actuators.insert(actuator.name.clone(), value);
+        Ok(())
+    }
+}
+
+// sensor readings scripted over time, with each sensor holding its last sample until the next
+#[derive(Debug, Default)]
+pub struct Trace {
+    // (time, sensor, value), sorted by time
+    samples: Vec<(f64, String, f64)>,
+    time: f64,
+    pub actuators: HashMap<String, f64>,
+}
+
+impl Trace {
+    pub fn new(mut samples: Vec<(f64, String, f64)>) -> Trace {
+        samples.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Trace {
+            samples,
+            time: 0.0,
+            actuators: HashMap::new(),
+        }
+    }
+
+    // one sample per line as `<seconds> <sensor> <value>`, with # starting a comment
+    pub fn parse(text: &str, sensors: &[&Sensor]) -> Result<Trace, String> {
+        let mut samples: Vec<(f64, String, f64)> = Vec::new();
+        for (num, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let sample = match fields.as_slice() {
+                [time, name, value] => time.parse::<f64>().ok().and_then(|time| {
+                    let sens = sensors.iter().find(|s| s.name == *name)?;
+                    let value = sens.kind.parse(value)?;
+                    // NaN and infinities would never compare the way the flow expects
+                    if !time.is_finite() || time < 0.0 || !value.is_finite() {
+                        return None;
+                    }
+                    Some((time, sens.name.clone(), value))
+                }),
+                _ => None,
+            };
+            match sample {
+                Some(sample) => samples.push(sample),
+                None => return Err(format!("line {}: invalid sample \"{}\"", num + 1, line)),
+            }
+        }
+        Ok(Trace::new(samples))
+    }
+}
+
+impl DeviceBackend for Trace {
+    fn read_sensor(&mut self, sensor: &Sensor) -> Result<f64, String> {
+        let latest = self
+            .samples
+            .iter()
+            .take_while(|(time, _, _)| *time <= self.time)
+            .filter(|(_, name, _)| *name == sensor.name)
+            .last();
+        match latest {
+            Some((_, _, val)) => Ok(*val),
+            None => Err(format!(
+                "no reading for sensor {} at {}s in trace",
+                sensor.name, self.time
+            )),
+        }
+    }
+
+    fn write_actuator(&mut self, actuator: &Actuator, value: f64) -> Result<(), String> {
+        self.actuators.insert(actuator.name.clone(), value);
+        Ok(())
+    }
+
+    fn seek(&mut self, time: f64) {
+        self.time = time;
+    }
+
+    fn next_change(&self) -> Option<f64> {
+        self.samples
+            .iter()
+            .map(|(time, _, _)| *time)
+            .find(|time| *time > self.time)
+    }
+}
+
+// talks to another process over stdin and stdout, one line per request: `get <sensor>` expects
+// the reading back on its own line, and `set <actuator> <value>` expects nothing
+pub struct Stdio<R: BufRead, W: Write> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Stdio<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Stdio { input, output }
+    }
+}
+
+impl<R: BufRead, W: Write> DeviceBackend for Stdio<R, W> {
+    fn read_sensor(&mut self, sensor: &Sensor) -> Result<f64, String> {
+        writeln!(self.output, "get {}", sensor.name).map_err(|e| e.to_string())?;
+        self.output.flush().map_err(|e| e.to_string())?;
+
+        let mut line = String::new();
+        match read_line(&mut self.input, &mut line) {
+            Ok(0) => return Err(format!("input closed while reading sensor {}", sensor.name)),
+            Ok(_) => {}
+            Err(e) => return Err(e.to_string()),
+        }
+        match sensor.kind.parse(line.trim()) {
+            Some(val) => Ok(val),
+            None => Err(format!(
+                "invalid reading \"{}\" for sensor {}",
+                line.trim(),
+                sensor.name
+            )),
+        }
+    }
+
+    fn write_actuator(&mut self, actuator: &Actuator, value: f64) -> Result<(), String> {
+        writeln!(
+            self.output,
+            "set {} {}",
+            actuator.name,
+            actuator.kind.show(value)
+        )
+        .map_err(|e| e.to_string())?;
+        self.output.flush().map_err(|e| e.to_string())
+    }
+}
@@ -0,0 +1,576 @@
+use std::collections::HashMap;
+
+use flow_runtime::program as runtime;
+
+use crate::ast::{Clock, Comparator, Condition, AST};
+use crate::lower::{self, Instr};
+
+// A flow compiled down to a flat list of ops over device indices, for running without the AST,
+// and written out in the file format the flow-runtime crate loads, so firmware can run a flow
+// without the parser. The ops, the format's version and the decoder all live in flow-runtime;
+// this only writes it.
+//
+// Conditions leave whether they hold in a single flag, with all and any compiled to jumps past
+// their remaining parts once they're settled, so they stop exactly where the interpreter would.
+// Every block's code runs straight through to a statement op, and jumps only ever go forwards,
+// so nothing but a statement can take the flow back.
+
+pub use runtime::{Op, MAGIC, VERSION};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Output {
+    pub name: String,
+    pub init: Option<f64>,
+    pub failsafe: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub name: String,
+    pub start: u32,
+}
+
+// an interlock or invariant, by where its conditions start, and how it's shown when broken
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub trigger: Option<u32>,
+    pub requirement: u32,
+    pub shown: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub sensors: Vec<String>,
+    pub actuators: Vec<Output>,
+    pub timers: Vec<String>,
+    pub blocks: Vec<Entry>,
+    // the block the flow starts in
+    pub first: u16,
+    // how much state latch, edge and held ops keep, each indexing its own slots
+    pub latches: u16,
+    pub edges: u16,
+    pub held: u16,
+    pub interlocks: Vec<Check>,
+    pub invariants: Vec<Check>,
+    pub code: Vec<Op>,
+}
+
+impl Program {
+    pub fn sensor(&self, name: &str) -> Option<usize> {
+        self.sensors.iter().position(|s| s == name)
+    }
+
+    pub fn actuator(&self, name: &str) -> Option<usize> {
+        self.actuators.iter().position(|a| a.name == name)
+    }
+}
+
+struct Compiler {
+    code: Vec<Op>,
+    sensors: HashMap<String, u16>,
+    actuators: HashMap<String, u16>,
+    timers: HashMap<String, u16>,
+    blocks: HashMap<String, u16>,
+    // the slot for each stateful condition, by condition id
+    latches: HashMap<usize, u16>,
+    edges: HashMap<usize, u16>,
+    held: HashMap<usize, u16>,
+}
+
+// the runtime's comparison for one from the AST
+fn comparison(comp: Comparator) -> runtime::Comparator {
+    match comp {
+        Comparator::LT => runtime::Comparator::LT,
+        Comparator::LTEQ => runtime::Comparator::LTEQ,
+        Comparator::EQ => runtime::Comparator::EQ,
+        Comparator::GT => runtime::Comparator::GT,
+        Comparator::GTEQ => runtime::Comparator::GTEQ,
+        Comparator::APPROX(tol) => runtime::Comparator::APPROX(tol),
+    }
+}
+
+// the slot for a condition, taking the next one if it hasn't got one yet
+fn slot(slots: &mut HashMap<usize, u16>, id: usize) -> u16 {
+    let next = slots.len() as u16;
+    *slots.entry(id).or_insert(next)
+}
+
+fn indices<'a>(names: impl Iterator<Item = &'a String>) -> HashMap<String, u16> {
+    names
+        .enumerate()
+        .map(|(idx, name)| (name.clone(), idx as u16))
+        .collect()
+}
+
+impl Compiler {
+    fn condition(&mut self, condition: &Condition) {
+        let op = match condition {
+            Condition::Base(sensor, comp, value) => Op::Sensor {
+                sensor: self.sensors[&sensor.name],
+                comp: comparison(*comp),
+                value: *value,
+            },
+            Condition::Actuator(act, comp, value) => Op::Actuator {
+                actuator: self.actuators[&act.name],
+                comp: comparison(*comp),
+                value: *value,
+            },
+            Condition::Time(clock, comp, value) => match clock {
+                Clock::Elapsed => Op::Elapsed {
+                    comp: comparison(*comp),
+                    value: *value,
+                },
+                Clock::Now => Op::Now {
+                    comp: comparison(*comp),
+                    value: *value,
+                },
+                Clock::Timer(name) => Op::Timer {
+                    timer: self.timers[name],
+                    comp: comparison(*comp),
+                    value: *value,
+                },
+            },
+            Condition::Hysteresis {
+                sensor,
+                rising,
+                threshold,
+                band,
+                id,
+            } => Op::Latch {
+                sensor: self.sensors[&sensor.name],
+                rising: *rising,
+                threshold: *threshold,
+                band: *band,
+                slot: slot(&mut self.latches, *id),
+            },
+            Condition::Edge {
+                condition,
+                rising,
+                id,
+            } => {
+                self.condition(condition);
+                Op::Edge {
+                    rising: *rising,
+                    slot: slot(&mut self.edges, *id),
+                }
+            }
+            Condition::For {
+                condition,
+                duration,
+                id,
+            } => {
+                self.condition(condition);
+                Op::Held {
+                    duration: *duration,
+                    slot: slot(&mut self.held, *id),
+                }
+            }
+            Condition::All(conditions) | Condition::Any(conditions) => {
+                let all = matches!(condition, Condition::All(_));
+                let mut exits = Vec::new();
+                for (idx, c) in conditions.iter().enumerate() {
+                    self.condition(c);
+                    if idx + 1 < conditions.len() {
+                        exits.push(self.code.len());
+                        self.code.push(if all {
+                            Op::JumpIfFalse(0)
+                        } else {
+                            Op::JumpIfTrue(0)
+                        });
+                    }
+                }
+                let end = self.code.len() as u32;
+                for exit in exits {
+                    if let Op::JumpIfFalse(target) | Op::JumpIfTrue(target) = &mut self.code[exit] {
+                        *target = end;
+                    }
+                }
+                return;
+            }
+        };
+        self.code.push(op);
+    }
+
+    // a condition on its own, ending in Done, giving where it starts
+    fn check(&mut self, condition: &Condition) -> u32 {
+        let start = self.code.len() as u32;
+        self.condition(condition);
+        self.code.push(Op::Done);
+        start
+    }
+
+    fn block(&mut self, block: &lower::Lowered) {
+        // where each instruction starts, and the jumps to point at them once they're all placed
+        let mut starts = Vec::new();
+        let mut jumps = Vec::new();
+        for (_, instr) in &block.code {
+            starts.push(self.code.len() as u32);
+            match instr {
+                Instr::Set { actuator, value } => self.code.push(Op::Set {
+                    actuator: self.actuators[&actuator.name],
+                    value: *value,
+                }),
+                Instr::Wait { condition } => {
+                    let start = self.code.len() as u32;
+                    self.condition(condition);
+                    self.code.push(Op::Wait(start));
+                }
+                Instr::Branch { condition, target } => {
+                    self.condition(condition);
+                    jumps.push((self.code.len(), *target));
+                    self.code.push(Op::Branch(0));
+                }
+                Instr::Jump { target } => {
+                    jumps.push((self.code.len(), *target));
+                    self.code.push(Op::Jump(0));
+                }
+                Instr::Delay { duration } => self.code.push(Op::Delay(*duration)),
+                Instr::Start { timer } => self.code.push(Op::Start(self.timers[*timer])),
+                Instr::Goto { block } => self.code.push(Op::Goto(self.blocks[*block])),
+                Instr::Forget { conditions } => {
+                    for condition in conditions {
+                        match condition {
+                            Condition::Edge { id, .. } => {
+                                let slot = slot(&mut self.edges, *id);
+                                self.code.push(Op::ForgetEdge(slot));
+                            }
+                            Condition::For { id, .. } => {
+                                let slot = slot(&mut self.held, *id);
+                                self.code.push(Op::ForgetHeld(slot));
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Instr::Finish => self.code.push(Op::Finish),
+            }
+        }
+        for (at, target) in jumps {
+            if let Op::Branch(to) | Op::Jump(to) = &mut self.code[at] {
+                *to = starts[target];
+            }
+        }
+    }
+}
+
+pub fn compile(ast: &AST) -> Result<Program, String> {
+    let blocks = lower::lower(ast);
+    let sensors: Vec<String> = ast.sensors().iter().map(|s| s.name.clone()).collect();
+    let actuators: Vec<Output> = ast
+        .actuators()
+        .iter()
+        .map(|a| Output {
+            name: a.name.clone(),
+            init: a.init,
+            failsafe: a.failsafe,
+        })
+        .collect();
+    let mut timers: Vec<String> = ast
+        .devices
+        .iter()
+        .filter(|(_, device)| matches!(device, crate::ast::Device::Timer(_)))
+        .map(|(name, _)| name.clone())
+        .collect();
+    timers.sort();
+    for (what, count) in [
+        ("sensors", sensors.len()),
+        ("actuators", actuators.len()),
+        ("timers", timers.len()),
+        ("blocks", blocks.len()),
+    ] {
+        if count > u16::MAX as usize {
+            return Err(format!(
+                "too many {} for bytecode, {} at most",
+                what,
+                u16::MAX
+            ));
+        }
+    }
+    let mut compiler = Compiler {
+        code: Vec::new(),
+        sensors: indices(sensors.iter()),
+        actuators: indices(actuators.iter().map(|a| &a.name)),
+        timers: indices(timers.iter()),
+        blocks: blocks
+            .iter()
+            .enumerate()
+            .map(|(idx, block)| (block.name.to_string(), idx as u16))
+            .collect(),
+        latches: HashMap::new(),
+        edges: HashMap::new(),
+        held: HashMap::new(),
+    };
+    let mut entries = Vec::new();
+    for block in &blocks {
+        entries.push(Entry {
+            name: block.name.to_string(),
+            start: compiler.code.len() as u32,
+        });
+        compiler.block(block);
+    }
+    let interlocks = ast
+        .interlocks
+        .iter()
+        .map(|interlock| Check {
+            trigger: Some(compiler.check(&interlock.trigger)),
+            requirement: compiler.check(&interlock.requirement),
+            shown: interlock.to_string(),
+        })
+        .collect();
+    let invariants = ast
+        .invariants
+        .iter()
+        .map(|invariant| Check {
+            trigger: None,
+            requirement: compiler.check(invariant),
+            shown: invariant.to_string(),
+        })
+        .collect();
+    if compiler.code.len() > u32::MAX as usize
+        || [&compiler.latches, &compiler.edges, &compiler.held]
+            .iter()
+            .any(|slots| slots.len() > u16::MAX as usize)
+    {
+        return Err("flow too big for bytecode".to_string());
+    }
+    Ok(Program {
+        first: compiler.blocks[&ast.first_block_name],
+        latches: compiler.latches.len() as u16,
+        edges: compiler.edges.len() as u16,
+        held: compiler.held.len() as u16,
+        sensors,
+        actuators,
+        timers,
+        blocks: entries,
+        interlocks,
+        invariants,
+        code: compiler.code,
+    })
+}
+
+// little-endian throughout, with strings as a u16 length and UTF-8, and lists as a u16 count
+// followed by their items, except code, which has a u32 count and each op's offset from the start
+// of the first ahead of the ops themselves
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, val: u8) {
+        self.0.push(val);
+    }
+
+    fn u16(&mut self, val: u16) {
+        self.0.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn u32(&mut self, val: u32) {
+        self.0.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn f64(&mut self, val: f64) {
+        self.0.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn string(&mut self, text: &str) {
+        // names and conditions as shown are never anywhere near this long
+        let bytes = &text.as_bytes()[..text.len().min(u16::MAX as usize)];
+        self.u16(bytes.len() as u16);
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn comp(&mut self, comp: runtime::Comparator) {
+        match comp {
+            runtime::Comparator::LT => self.u8(0),
+            runtime::Comparator::LTEQ => self.u8(1),
+            runtime::Comparator::EQ => self.u8(2),
+            runtime::Comparator::GT => self.u8(3),
+            runtime::Comparator::GTEQ => self.u8(4),
+            runtime::Comparator::APPROX(tol) => {
+                self.u8(5);
+                self.f64(tol);
+            }
+        }
+    }
+
+    fn op(&mut self, op: &Op) {
+        match *op {
+            Op::Sensor {
+                sensor,
+                comp,
+                value,
+            } => {
+                self.u8(0);
+                self.u16(sensor);
+                self.comp(comp);
+                self.f64(value);
+            }
+            Op::Actuator {
+                actuator,
+                comp,
+                value,
+            } => {
+                self.u8(1);
+                self.u16(actuator);
+                self.comp(comp);
+                self.f64(value);
+            }
+            Op::Elapsed { comp, value } => {
+                self.u8(2);
+                self.comp(comp);
+                self.f64(value);
+            }
+            Op::Now { comp, value } => {
+                self.u8(3);
+                self.comp(comp);
+                self.f64(value);
+            }
+            Op::Timer { timer, comp, value } => {
+                self.u8(4);
+                self.u16(timer);
+                self.comp(comp);
+                self.f64(value);
+            }
+            Op::Latch {
+                sensor,
+                rising,
+                threshold,
+                band,
+                slot,
+            } => {
+                self.u8(5);
+                self.u16(sensor);
+                self.u8(rising as u8);
+                self.f64(threshold);
+                self.f64(band);
+                self.u16(slot);
+            }
+            Op::Edge { rising, slot } => {
+                self.u8(6);
+                self.u8(rising as u8);
+                self.u16(slot);
+            }
+            Op::Held { duration, slot } => {
+                self.u8(7);
+                self.f64(duration);
+                self.u16(slot);
+            }
+            Op::JumpIfFalse(target) => {
+                self.u8(8);
+                self.u32(target);
+            }
+            Op::JumpIfTrue(target) => {
+                self.u8(9);
+                self.u32(target);
+            }
+            Op::Done => self.u8(10),
+            Op::Set { actuator, value } => {
+                self.u8(11);
+                self.u16(actuator);
+                self.f64(value);
+            }
+            Op::Wait(start) => {
+                self.u8(12);
+                self.u32(start);
+            }
+            Op::Delay(duration) => {
+                self.u8(13);
+                self.f64(duration);
+            }
+            Op::Start(timer) => {
+                self.u8(14);
+                self.u16(timer);
+            }
+            Op::Branch(target) => {
+                self.u8(15);
+                self.u32(target);
+            }
+            Op::Jump(target) => {
+                self.u8(16);
+                self.u32(target);
+            }
+            Op::Goto(block) => {
+                self.u8(17);
+                self.u16(block);
+            }
+            Op::Finish => self.u8(18),
+            Op::ForgetEdge(slot) => {
+                self.u8(19);
+                self.u16(slot);
+            }
+            Op::ForgetHeld(slot) => {
+                self.u8(20);
+                self.u16(slot);
+            }
+        }
+    }
+
+    fn optional(&mut self, val: Option<f64>) {
+        match val {
+            Some(val) => {
+                self.u8(1);
+                self.f64(val);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn check(&mut self, check: &Check) {
+        match check.trigger {
+            Some(trigger) => {
+                self.u8(1);
+                self.u32(trigger);
+            }
+            None => self.u8(0),
+        }
+        self.u32(check.requirement);
+        self.string(&check.shown);
+    }
+}
+
+impl Program {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer(MAGIC.to_vec());
+        w.u8(VERSION);
+        w.u16(self.sensors.len() as u16);
+        for name in &self.sensors {
+            w.string(name);
+        }
+        w.u16(self.actuators.len() as u16);
+        for act in &self.actuators {
+            w.string(&act.name);
+            w.optional(act.init);
+            w.optional(act.failsafe);
+        }
+        w.u16(self.timers.len() as u16);
+        for name in &self.timers {
+            w.string(name);
+        }
+        w.u16(self.blocks.len() as u16);
+        for block in &self.blocks {
+            w.string(&block.name);
+            w.u32(block.start);
+        }
+        w.u16(self.first);
+        w.u16(self.latches);
+        w.u16(self.edges);
+        w.u16(self.held);
+        for checks in [&self.interlocks, &self.invariants] {
+            w.u16(checks.len() as u16);
+            for check in checks {
+                w.check(check);
+            }
+        }
+        // where each op starts, so they can be found by index without decoding the ones before
+        let mut code = Writer(Vec::new());
+        let mut offsets = Vec::new();
+        for op in &self.code {
+            offsets.push(code.0.len() as u32);
+            code.op(op);
+        }
+        w.u32(self.code.len() as u32);
+        for offset in offsets {
+            w.u32(offset);
+        }
+        w.0.extend(code.0);
+        w.0
+    }
+}
@@ -0,0 +1,1245 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::ast::{Actuator, Clock, Comparator, Condition, Kind, AST};
+use crate::interp::MAX_OPS_PER_STEP;
+use crate::lower::{
+    self, address, conditions, line, reads_elapsed, reads_sensors, timers_read, Instr,
+};
+
+// Generates a C99 header and source pair that run a flow without the interpreter, for small
+// targets: no heap, no stdio, just a flow_state_t the caller owns, flow_init() to reset it and a
+// flow_step() that runs the flow the way Interpreter::step does, stopping at waits rather than
+// blocking on them.
+
+const KEYWORDS: &[&str] = &[
+    "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else",
+    "enum", "extern", "float", "for", "goto", "if", "inline", "int", "long", "register",
+    "restrict", "return", "short", "signed", "sizeof", "static", "struct", "switch", "typedef",
+    "union", "unsigned", "void", "volatile", "while", "bool", "true", "false",
+];
+
+// names the generated code uses for itself, which types for enum devices mustn't take
+const RESERVED: &[&str] = &["state", "sensors", "actuators", "block"];
+
+fn ident(name: &str) -> String {
+    if KEYWORDS.contains(&name) {
+        format!("{}_", name)
+    } else {
+        name.to_string()
+    }
+}
+
+// a C string literal, with anything outside printable ASCII as octal escapes
+fn string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for byte in text.bytes() {
+        match byte {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(byte as char);
+            }
+            b' '..=b'~' => out.push(byte as char),
+            _ => out += &format!("\\{:03o}", byte),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn number(val: f64) -> String {
+    format!("{:?}", val)
+}
+
+fn enum_const(ty: &str, variant: &str) -> String {
+    format!("FLOW_{}_{}", ty.to_uppercase(), variant.to_uppercase())
+}
+
+fn block_const(name: &str) -> String {
+    format!("FLOW_BLOCK_{}", name.to_uppercase())
+}
+
+fn fault(act: &Actuator) -> String {
+    format!("actuator {} read before it was set", act.name)
+}
+
+fn timer_fault(name: &str) -> String {
+    format!("timer {} read before it was started", name)
+}
+
+// whether evaluating the condition can run into an error
+fn can_fail(condition: &Condition) -> bool {
+    match condition {
+        Condition::Actuator(act, ..) => act.init.is_none(),
+        Condition::Time(Clock::Timer(_), ..) => true,
+        Condition::Edge { condition, .. } | Condition::For { condition, .. } => can_fail(condition),
+        Condition::All(conditions) | Condition::Any(conditions) => conditions.iter().any(can_fail),
+        _ => false,
+    }
+}
+
+// whether the condition reads or keeps anything in the flow's state
+fn reads_state(condition: &Condition) -> bool {
+    match condition {
+        Condition::Base(..) => false,
+        Condition::All(conditions) | Condition::Any(conditions) => {
+            conditions.iter().any(reads_state)
+        }
+        _ => true,
+    }
+}
+
+// the helpers the conditions need, so only those get generated
+#[derive(Default)]
+struct Helpers {
+    latch: bool,
+    edge: bool,
+    held: bool,
+    near: bool,
+}
+
+fn helpers(condition: &Condition, out: &mut Helpers) {
+    match condition {
+        Condition::Base(_, Comparator::APPROX(_), _)
+        | Condition::Actuator(_, Comparator::APPROX(_), _)
+        | Condition::Time(_, Comparator::APPROX(_), _) => out.near = true,
+        Condition::Hysteresis { .. } => out.latch = true,
+        Condition::Edge { condition, .. } => {
+            out.edge = true;
+            helpers(condition, out);
+        }
+        Condition::For { condition, .. } => {
+            out.held = true;
+            helpers(condition, out);
+        }
+        Condition::All(conditions) | Condition::Any(conditions) => {
+            for c in conditions {
+                helpers(c, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+// the state conditions keep between steps, as (declaration, initialisation)
+fn memory(condition: &Condition, out: &mut Vec<(String, String)>) {
+    match condition {
+        Condition::Hysteresis { id, .. } => out.push((
+            format!("bool latch_{};", id),
+            format!("flow->latch_{} = false;", id),
+        )),
+        Condition::Edge { condition, id, .. } => {
+            out.push((
+                format!(
+                    "signed char edge_{}; /* -1 until it's first evaluated */",
+                    id
+                ),
+                format!("flow->edge_{} = -1;", id),
+            ));
+            memory(condition, out);
+        }
+        Condition::For { condition, id, .. } => {
+            out.push((
+                format!("bool held_{}_on;", id),
+                format!("flow->held_{}_on = false;", id),
+            ));
+            out.push((
+                format!("double held_{}; /* since when, while on */", id),
+                format!("flow->held_{} = 0.0;", id),
+            ));
+            memory(condition, out);
+        }
+        Condition::All(conditions) | Condition::Any(conditions) => {
+            for c in conditions {
+                memory(c, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+struct Generator<'a> {
+    ast: &'a AST,
+    // the type generated for each enum device
+    types: HashMap<String, String>,
+    // the function evaluating each condition, by its address in the AST
+    functions: HashMap<usize, String>,
+    // whether setting an actuator has any interlocks or invariants to check
+    guarded: bool,
+    // whether any condition can run into an error
+    fails: bool,
+    // timers some condition reads
+    timers: BTreeSet<String>,
+}
+
+impl Generator<'_> {
+    fn function(&self, condition: &Condition) -> &str {
+        &self.functions[&address(condition)]
+    }
+
+    fn kind_type(&self, name: &str, kind: &Kind) -> String {
+        match kind {
+            Kind::Float => "double".to_string(),
+            Kind::Bool => "bool".to_string(),
+            Kind::Enum(_) => format!("flow_{}_t", self.types[name]),
+        }
+    }
+
+    fn literal(&self, name: &str, kind: &Kind, val: f64) -> String {
+        match kind {
+            Kind::Float => number(val),
+            Kind::Bool => (val != 0.0).to_string(),
+            Kind::Enum(variants) => enum_const(&self.types[name], &variants[val as usize]),
+        }
+    }
+
+    fn compare(&self, lhs: &str, name: &str, kind: &Kind, comp: Comparator, val: f64) -> String {
+        let rhs = self.literal(name, kind, val);
+        match (kind, comp) {
+            // bools and enums can only be compared for equality
+            (Kind::Bool, _) if val != 0.0 => lhs.to_string(),
+            (Kind::Bool, _) => format!("!{}", lhs),
+            (Kind::Enum(_), _) => format!("{} == {}", lhs, rhs),
+            (_, Comparator::APPROX(tol)) => format!("flow_near({}, {}, {})", lhs, rhs, number(tol)),
+            (_, comp) => {
+                let symbol = match comp {
+                    Comparator::EQ => "==".to_string(),
+                    other => other.to_string(),
+                };
+                format!("{} {} {}", lhs, symbol, rhs)
+            }
+        }
+    }
+
+    fn expr(&self, condition: &Condition) -> String {
+        match condition {
+            Condition::Base(sensor, comp, val) => {
+                let lhs = format!("s->{}", ident(&sensor.name));
+                self.compare(&lhs, &sensor.name, &sensor.kind, *comp, *val)
+            }
+            Condition::Actuator(act, comp, val) => {
+                let lhs = format!("flow->actuators.{}", ident(&act.name));
+                let compared = self.compare(&lhs, &act.name, &act.kind, *comp, *val);
+                if act.init.is_some() {
+                    compared
+                } else {
+                    format!(
+                        "(flow->actuators.{}_set ? {} : flow_fail(flow, {}))",
+                        act.name,
+                        compared,
+                        string(&fault(act))
+                    )
+                }
+            }
+            Condition::Time(clock, comp, val) => match clock {
+                Clock::Elapsed => {
+                    self.compare("flow->time - flow->entered", "", &Kind::Float, *comp, *val)
+                }
+                Clock::Now => self.compare("flow->time", "", &Kind::Float, *comp, *val),
+                Clock::Timer(name) => format!(
+                    "(flow->timer_{}_started ? {} : flow_fail(flow, {}))",
+                    name,
+                    self.compare(
+                        &format!("flow->time - flow->timer_{}", name),
+                        "",
+                        &Kind::Float,
+                        *comp,
+                        *val
+                    ),
+                    string(&timer_fault(name))
+                ),
+            },
+            Condition::Hysteresis {
+                sensor,
+                rising,
+                threshold,
+                band,
+                id,
+            } => {
+                let reading = format!("s->{}", ident(&sensor.name));
+                let (on, stays) = if *rising {
+                    (
+                        format!("{} > {}", reading, number(*threshold)),
+                        format!("{} >= {}", reading, number(threshold - band)),
+                    )
+                } else {
+                    (
+                        format!("{} < {}", reading, number(*threshold)),
+                        format!("{} <= {}", reading, number(threshold + band)),
+                    )
+                };
+                format!("flow_latch(&flow->latch_{}, {}, {})", id, on, stays)
+            }
+            Condition::Edge {
+                condition,
+                rising,
+                id,
+            } => format!(
+                "flow_edge(&flow->edge_{}, {}, {})",
+                id,
+                self.expr(condition),
+                rising
+            ),
+            Condition::For {
+                condition,
+                duration,
+                id,
+            } => format!(
+                "flow_held(&flow->held_{id}_on, &flow->held_{id}, {}, flow->time, {})",
+                self.expr(condition),
+                number(*duration),
+                id = id
+            ),
+            Condition::All(conditions) | Condition::Any(conditions) => {
+                let joiner = if let Condition::All(_) = condition {
+                    " && "
+                } else {
+                    " || "
+                };
+                let parts: Vec<String> = conditions
+                    .iter()
+                    .map(|c| format!("({})", self.expr(c)))
+                    .collect();
+                parts.join(joiner)
+            }
+        }
+    }
+}
+
+// the most any reason for halting can take, so the buffer for it never cuts one short
+fn halted_size(gen: &Generator, blocks: &[lower::Lowered]) -> usize {
+    let ast = gen.ast;
+    let mut faults = vec![0];
+    for act in ast.actuators() {
+        if act.init.is_none() {
+            faults.push(fault(act).len());
+        }
+    }
+    for timer in &gen.timers {
+        faults.push(timer_fault(timer).len());
+    }
+    let fault = faults.into_iter().max().unwrap();
+    let mut reasons = vec![0];
+    for interlock in &ast.interlocks {
+        let shown = interlock.to_string();
+        reasons.push(format!("violates interlock {}", shown).len());
+        reasons.push(format!("can't check interlock {}: ", shown).len() + fault);
+    }
+    for invariant in &ast.invariants {
+        reasons.push(format!("violates invariant {}", invariant).len());
+        reasons.push(format!("can't check invariant {}: ", invariant).len() + fault);
+    }
+    let reason = reasons.into_iter().max().unwrap();
+    let mut longest = 0;
+    for block in blocks {
+        longest = longest
+            .max(format!("timed out busy looping in block {}", block.name).len())
+            .max(format!("block {}: ", block.name).len() + fault);
+        for (_, instr) in &block.code {
+            if let Instr::Set { actuator, value } = instr {
+                longest = longest.max(refused(block.name, actuator, *value).len() + reason);
+            }
+        }
+    }
+    longest + 1
+}
+
+// what a set that breaks an interlock or invariant halts with, ahead of the reason
+fn refused(block: &str, act: &Actuator, val: f64) -> String {
+    format!("block {}: refused to set {} to {}: ", block, act.name, val)
+}
+
+// the header and the source for a flow, with `header` the name the source includes the header by
+pub fn generate(ast: &AST, source: &str, header: &str) -> (String, String) {
+    let blocks = lower::lower(ast);
+    let mut types = HashMap::new();
+    for sens in ast.sensors() {
+        if let Kind::Enum(_) = sens.kind {
+            types.insert(sens.name.clone(), sens.name.clone());
+        }
+    }
+    for act in ast.actuators() {
+        if let Kind::Enum(_) = act.kind {
+            types.insert(act.name.clone(), act.name.clone());
+        }
+    }
+    for ty in types.values_mut() {
+        if RESERVED.contains(&ty.as_str()) {
+            *ty += "_value";
+        }
+    }
+    let found = conditions(ast, &blocks);
+    let functions = found
+        .iter()
+        .enumerate()
+        .map(|(idx, c)| (address(c), format!("flow_condition_{}", idx)))
+        .collect();
+    let gen = Generator {
+        ast,
+        types,
+        functions,
+        guarded: !ast.interlocks.is_empty() || !ast.invariants.is_empty(),
+        fails: found.iter().any(|c| can_fail(c)),
+        timers: found
+            .iter()
+            .flat_map(|c| {
+                let mut read = BTreeSet::new();
+                timers_read(c, &mut read);
+                read.into_iter().map(str::to_string).collect::<Vec<_>>()
+            })
+            .collect(),
+    };
+    let banner = format!(
+        "/* generated by `flow compile --target c` from {}; edit the flow, not this file */",
+        source
+    );
+    let mut h = String::new();
+    gen_header(&gen, &blocks, &found, &banner, header, &mut h);
+    let mut c = String::new();
+    line(&mut c, 0, &banner);
+    line(&mut c, 0, "");
+    line(&mut c, 0, "#include <stddef.h>");
+    line(&mut c, 0, "");
+    line(&mut c, 0, &format!("#include {}", string(header)));
+    gen_source(&gen, &blocks, &found, &mut c);
+    (h, c)
+}
+
+fn gen_header(
+    gen: &Generator,
+    blocks: &[lower::Lowered],
+    conditions: &[&Condition],
+    banner: &str,
+    header: &str,
+    o: &mut String,
+) {
+    let ast = gen.ast;
+    let sensors = ast.sensors();
+    let actuators = ast.actuators();
+    let guard: String = header
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let guard = format!("FLOW_{}", guard);
+    line(o, 0, banner);
+    line(o, 0, "");
+    line(o, 0, &format!("#ifndef {}", guard));
+    line(o, 0, &format!("#define {}", guard));
+    line(o, 0, "");
+    line(o, 0, "#include <stdbool.h>");
+
+    // enums for enum devices, in name order
+    let mut enums: Vec<(&String, &Vec<String>)> = sensors
+        .iter()
+        .filter_map(|s| match &s.kind {
+            Kind::Enum(variants) => Some((&s.name, variants)),
+            _ => None,
+        })
+        .chain(actuators.iter().filter_map(|a| match &a.kind {
+            Kind::Enum(variants) => Some((&a.name, variants)),
+            _ => None,
+        }))
+        .collect();
+    enums.sort();
+    for (name, variants) in enums {
+        line(o, 0, "");
+        line(o, 0, "typedef enum {");
+        for variant in variants {
+            line(o, 1, &format!("{},", enum_const(&gen.types[name], variant)));
+        }
+        line(o, 0, &format!("}} flow_{}_t;", gen.types[name]));
+    }
+
+    let unit = |unit: &Option<crate::units::Unit>| match unit {
+        Some(unit) => format!(" /* {} */", unit.name),
+        None => String::new(),
+    };
+    line(o, 0, "");
+    line(o, 0, "/* the readings a step runs with */");
+    line(o, 0, "typedef struct {");
+    for sens in &sensors {
+        line(
+            o,
+            1,
+            &format!(
+                "{} {};{}",
+                gen.kind_type(&sens.name, &sens.kind),
+                ident(&sens.name),
+                unit(&sens.unit)
+            ),
+        );
+    }
+    if sensors.is_empty() {
+        line(o, 1, "char none; /* C has no empty structs */");
+    }
+    line(o, 0, "} flow_sensors_t;");
+
+    line(o, 0, "");
+    line(
+        o,
+        0,
+        "/* what the actuators should be set to, with _set false for ones the flow hasn't set yet */",
+    );
+    line(o, 0, "typedef struct {");
+    for act in &actuators {
+        line(
+            o,
+            1,
+            &format!(
+                "{} {};{}",
+                gen.kind_type(&act.name, &act.kind),
+                ident(&act.name),
+                unit(&act.unit)
+            ),
+        );
+        if act.init.is_none() {
+            line(o, 1, &format!("bool {}_set;", act.name));
+        }
+    }
+    if actuators.is_empty() {
+        line(o, 1, "char none; /* C has no empty structs */");
+    }
+    line(o, 0, "} flow_actuators_t;");
+
+    line(o, 0, "");
+    line(o, 0, "typedef enum {");
+    for block in blocks {
+        line(o, 1, &format!("{},", block_const(block.name)));
+    }
+    line(o, 0, "} flow_block_t;");
+
+    let mut kept = Vec::new();
+    for condition in conditions {
+        memory(condition, &mut kept);
+    }
+    line(o, 0, "");
+    line(
+        o,
+        0,
+        &format!("#define FLOW_HALTED_SIZE {}", halted_size(gen, blocks)),
+    );
+    line(o, 0, "");
+    line(o, 0, "typedef struct {");
+    line(o, 1, "flow_block_t block;");
+    line(o, 1, "/* where in the block's code the flow is */");
+    line(o, 1, "unsigned pc;");
+    line(
+        o,
+        1,
+        "/* seconds since the flow started, advanced by the caller before each step */",
+    );
+    line(o, 1, "double time;");
+    if conditions.iter().any(|c| reads_elapsed(c)) {
+        line(o, 1, "/* when the current block was entered */");
+        line(o, 1, "double entered;");
+    }
+    if delays(blocks) {
+        line(
+            o,
+            1,
+            "/* when the delay being waited on ends, while delaying */",
+        );
+        line(o, 1, "bool delaying;");
+        line(o, 1, "double deadline;");
+    }
+    for timer in &gen.timers {
+        line(o, 1, &format!("bool timer_{}_started;", timer));
+        line(o, 1, &format!("double timer_{};", timer));
+    }
+    for (field, _) in &kept {
+        line(o, 1, field);
+    }
+    line(o, 1, "flow_actuators_t actuators;");
+    line(o, 1, "bool finished;");
+    if gen.fails {
+        line(
+            o,
+            1,
+            "/* the error the condition being evaluated ran into, if any */",
+        );
+        line(o, 1, "const char *fault;");
+    }
+    line(o, 1, "/* why the flow stopped, empty while it's running */");
+    line(o, 1, "char halted[FLOW_HALTED_SIZE];");
+    line(o, 0, "} flow_state_t;");
+
+    line(o, 0, "");
+    line(o, 0, "void flow_init(flow_state_t *flow);");
+    line(
+        o,
+        0,
+        "/* run until the flow waits, with the same readings throughout, and give what the actuators",
+    );
+    line(
+        o,
+        0,
+        "   should be set to. After an error the flow stops with actuators at their fail-safe values. */",
+    );
+    line(
+        o,
+        0,
+        "void flow_step(flow_state_t *flow, const flow_sensors_t *sensors, flow_actuators_t *actuators);",
+    );
+    line(o, 0, "const char *flow_block_name(flow_block_t block);");
+    line(
+        o,
+        0,
+        "/* why the flow stopped, if an error stopped it, and NULL if not */",
+    );
+    line(o, 0, "const char *flow_halted(const flow_state_t *flow);");
+    line(o, 0, "/* whether the flow ran off the end of a block */");
+    line(o, 0, "bool flow_finished(const flow_state_t *flow);");
+    line(o, 0, "");
+    line(o, 0, &format!("#endif /* {} */", guard));
+}
+
+fn delays(blocks: &[lower::Lowered]) -> bool {
+    blocks.iter().any(|b| {
+        b.code
+            .iter()
+            .any(|(_, instr)| matches!(instr, Instr::Delay { .. }))
+    })
+}
+
+fn gen_source(
+    gen: &Generator,
+    blocks: &[lower::Lowered],
+    conditions: &[&Condition],
+    o: &mut String,
+) {
+    let ast = gen.ast;
+    let mut kept = Vec::new();
+    let mut needed = Helpers::default();
+    for condition in conditions {
+        memory(condition, &mut kept);
+        helpers(condition, &mut needed);
+    }
+    let elapsed = conditions.iter().any(|c| reads_elapsed(c));
+    let gotos = blocks.iter().any(|b| {
+        b.code
+            .iter()
+            .any(|(_, instr)| matches!(instr, Instr::Goto { .. }))
+    });
+    // whether a wait or an if can halt the flow on an error
+    let faults = blocks.iter().any(|b| {
+        b.code.iter().any(|(_, instr)| match instr {
+            Instr::Wait { condition } | Instr::Branch { condition, .. } => can_fail(condition),
+            _ => false,
+        })
+    });
+
+    line(o, 0, "");
+    line(
+        o,
+        0,
+        &format!("#define FLOW_MAX_OPS_PER_STEP {}", MAX_OPS_PER_STEP),
+    );
+    line(o, 0, "");
+    line(o, 0, "static const char *const flow_block_names[] = {");
+    for block in blocks {
+        line(o, 1, &format!("{},", string(block.name)));
+    }
+    line(o, 0, "};");
+    line(o, 0, "");
+    line(o, 0, "const char *flow_block_name(flow_block_t block)");
+    line(o, 0, "{");
+    line(o, 1, "return flow_block_names[block];");
+    line(o, 0, "}");
+
+    line(o, 0, "");
+    line(
+        o,
+        0,
+        "/* add to why the flow halted, cutting it short rather than running off the end */",
+    );
+    line(
+        o,
+        0,
+        "static void flow_say(flow_state_t *flow, const char *text)",
+    );
+    line(o, 0, "{");
+    line(o, 1, "size_t len = 0;");
+    line(o, 1, "while (flow->halted[len] != '\\0')");
+    line(o, 2, "len++;");
+    line(o, 1, "while (*text != '\\0' && len + 1 < FLOW_HALTED_SIZE)");
+    line(o, 2, "flow->halted[len++] = *text++;");
+    line(o, 1, "flow->halted[len] = '\\0';");
+    line(o, 0, "}");
+
+    if gen.fails {
+        line(o, 0, "");
+        line(
+            o,
+            0,
+            "/* note an error evaluating a condition, which makes the condition false */",
+        );
+        line(
+            o,
+            0,
+            "static bool flow_fail(flow_state_t *flow, const char *fault)",
+        );
+        line(o, 0, "{");
+        line(o, 1, "if (flow->fault == NULL)");
+        line(o, 2, "flow->fault = fault;");
+        line(o, 1, "return false;");
+        line(o, 0, "}");
+    }
+    if faults {
+        line(o, 0, "");
+        line(o, 0, "/* halt on the error a condition ran into */");
+        line(o, 0, "static bool flow_fault(flow_state_t *flow)");
+        line(o, 0, "{");
+        line(o, 1, "flow_say(flow, \"block \");");
+        line(o, 1, "flow_say(flow, flow_block_name(flow->block));");
+        line(o, 1, "flow_say(flow, \": \");");
+        line(o, 1, "flow_say(flow, flow->fault);");
+        line(o, 1, "return false;");
+        line(o, 0, "}");
+    }
+    if needed.near {
+        line(o, 0, "");
+        line(
+            o,
+            0,
+            "static bool flow_near(double lhs, double rhs, double tol)",
+        );
+        line(o, 0, "{");
+        line(o, 1, "double diff = lhs - rhs;");
+        line(o, 1, "return diff <= tol && -diff <= tol;");
+        line(o, 0, "}");
+    }
+    if needed.latch {
+        line(o, 0, "");
+        line(
+            o,
+            0,
+            "/* a schmitt trigger, which goes on with `on` and stays on while `stays` */",
+        );
+        line(
+            o,
+            0,
+            "static bool flow_latch(bool *latch, bool on, bool stays)",
+        );
+        line(o, 0, "{");
+        line(o, 1, "*latch = *latch ? stays : on;");
+        line(o, 1, "return *latch;");
+        line(o, 0, "}");
+    }
+    if needed.edge {
+        line(o, 0, "");
+        line(
+            o,
+            0,
+            "/* whether `now` went on (rising) or off since the last time it was looked at */",
+        );
+        line(
+            o,
+            0,
+            "static bool flow_edge(signed char *edge, bool now, bool rising)",
+        );
+        line(o, 0, "{");
+        line(o, 1, "bool before = *edge < 0 ? now : *edge != 0;");
+        line(o, 1, "*edge = now;");
+        line(o, 1, "return rising ? !before && now : before && !now;");
+        line(o, 0, "}");
+    }
+    if needed.held {
+        line(o, 0, "");
+        line(
+            o,
+            0,
+            "/* whether `now` has been on for `duration` seconds, going by when it went on */",
+        );
+        line(
+            o,
+            0,
+            "static bool flow_held(bool *on, double *since, bool now, double time, double duration)",
+        );
+        line(o, 0, "{");
+        line(o, 1, "if (!now) {");
+        line(o, 2, "*on = false;");
+        line(o, 2, "return false;");
+        line(o, 1, "}");
+        line(o, 1, "if (!*on) {");
+        line(o, 2, "*on = true;");
+        line(o, 2, "*since = time;");
+        line(o, 1, "}");
+        line(o, 1, "return time - *since >= duration;");
+        line(o, 0, "}");
+    }
+
+    for condition in conditions {
+        line(o, 0, "");
+        line(o, 0, &format!("/* {} */", condition));
+        line(
+            o,
+            0,
+            &format!(
+                "static bool {}(flow_state_t *flow, const flow_sensors_t *s)",
+                gen.function(condition)
+            ),
+        );
+        line(o, 0, "{");
+        if !reads_state(condition) {
+            line(o, 1, "(void)flow;");
+        }
+        if !reads_sensors(condition) {
+            line(o, 1, "(void)s;");
+        }
+        if can_fail(condition) {
+            // an error partway through leaves the rest of the condition meaningless
+            line(o, 1, &format!("bool held = {};", gen.expr(condition)));
+            line(o, 1, "return held && flow->fault == NULL;");
+        } else {
+            line(o, 1, &format!("return {};", gen.expr(condition)));
+        }
+        line(o, 0, "}");
+    }
+
+    if gen.guarded {
+        gen_safety(gen, blocks, o);
+    }
+
+    if gotos {
+        line(o, 0, "");
+        line(
+            o,
+            0,
+            "static void flow_enter(flow_state_t *flow, flow_block_t block)",
+        );
+        line(o, 0, "{");
+        line(o, 1, "flow->block = block;");
+        line(o, 1, "flow->pc = 0;");
+        if elapsed {
+            line(o, 1, "flow->entered = flow->time;");
+        }
+        line(o, 0, "}");
+    }
+
+    let mut resets = Vec::new();
+    if delays(blocks) {
+        resets.push("flow->delaying = false;".to_string());
+    }
+    for act in ast.actuators() {
+        if let Some(val) = act.failsafe {
+            let field = ident(&act.name);
+            let val = gen.literal(&act.name, &act.kind, val);
+            resets.push(format!("flow->actuators.{} = {};", field, val));
+            if act.init.is_none() {
+                resets.push(format!("flow->actuators.{}_set = true;", act.name));
+            }
+        }
+    }
+    line(o, 0, "");
+    line(o, 0, "static void flow_fail_safe(flow_state_t *flow)");
+    line(o, 0, "{");
+    if resets.is_empty() {
+        line(o, 1, "(void)flow;");
+    }
+    for reset in &resets {
+        line(o, 1, reset);
+    }
+    line(o, 0, "}");
+
+    gen_run(gen, blocks, o);
+
+    line(o, 0, "");
+    line(o, 0, "void flow_init(flow_state_t *flow)");
+    line(o, 0, "{");
+    line(
+        o,
+        1,
+        &format!("flow->block = {};", block_const(&ast.first_block_name)),
+    );
+    line(o, 1, "flow->pc = 0;");
+    line(o, 1, "flow->time = 0.0;");
+    if elapsed {
+        line(o, 1, "flow->entered = 0.0;");
+    }
+    if delays(blocks) {
+        line(o, 1, "flow->delaying = false;");
+        line(o, 1, "flow->deadline = 0.0;");
+    }
+    for timer in &gen.timers {
+        line(o, 1, &format!("flow->timer_{}_started = false;", timer));
+        line(o, 1, &format!("flow->timer_{} = 0.0;", timer));
+    }
+    for (_, init) in &kept {
+        line(o, 1, init);
+    }
+    for act in ast.actuators() {
+        let field = ident(&act.name);
+        match act.init {
+            Some(val) => line(
+                o,
+                1,
+                &format!(
+                    "flow->actuators.{} = {};",
+                    field,
+                    gen.literal(&act.name, &act.kind, val)
+                ),
+            ),
+            None => {
+                // the value doesn't matter until it's set, but it shouldn't be left undefined
+                let zero = gen.literal(&act.name, &act.kind, 0.0);
+                line(o, 1, &format!("flow->actuators.{} = {};", field, zero));
+                line(o, 1, &format!("flow->actuators.{}_set = false;", act.name));
+            }
+        }
+    }
+    if ast.actuators().is_empty() {
+        line(o, 1, "flow->actuators.none = 0;");
+    }
+    line(o, 1, "flow->finished = false;");
+    if gen.fails {
+        line(o, 1, "flow->fault = NULL;");
+    }
+    line(o, 1, "flow->halted[0] = '\\0';");
+    line(o, 0, "}");
+
+    line(o, 0, "");
+    line(
+        o,
+        0,
+        "void flow_step(flow_state_t *flow, const flow_sensors_t *sensors, flow_actuators_t *actuators)",
+    );
+    line(o, 0, "{");
+    line(
+        o,
+        1,
+        "if (flow->halted[0] == '\\0' && !flow->finished && !flow_run(flow, sensors))",
+    );
+    line(o, 2, "flow_fail_safe(flow);");
+    line(o, 1, "*actuators = flow->actuators;");
+    line(o, 0, "}");
+
+    line(o, 0, "");
+    line(o, 0, "const char *flow_halted(const flow_state_t *flow)");
+    line(o, 0, "{");
+    line(
+        o,
+        1,
+        "return flow->halted[0] == '\\0' ? NULL : flow->halted;",
+    );
+    line(o, 0, "}");
+    line(o, 0, "");
+    line(o, 0, "bool flow_finished(const flow_state_t *flow)");
+    line(o, 0, "{");
+    line(o, 1, "return flow->finished;");
+    line(o, 0, "}");
+}
+
+fn gen_run(gen: &Generator, blocks: &[lower::Lowered], o: &mut String) {
+    // sets only need the readings to check interlocks and invariants
+    let uses = blocks.iter().any(|b| {
+        b.code.iter().any(|(_, instr)| match instr {
+            Instr::Set { .. } => gen.guarded,
+            Instr::Wait { .. } | Instr::Branch { .. } => true,
+            _ => false,
+        })
+    });
+    line(o, 0, "");
+    line(
+        o,
+        0,
+        "/* run until the flow waits or stops, and false if it halted */",
+    );
+    line(
+        o,
+        0,
+        "static bool flow_run(flow_state_t *flow, const flow_sensors_t *s)",
+    );
+    line(o, 0, "{");
+    line(o, 1, "long ops;");
+    if !uses {
+        line(o, 1, "(void)s;");
+    }
+    line(o, 1, "for (ops = 0; ops < FLOW_MAX_OPS_PER_STEP; ops++) {");
+    line(o, 2, "switch (flow->block) {");
+    for block in blocks {
+        line(o, 2, &format!("case {}:", block_const(block.name)));
+        line(o, 3, "switch (flow->pc) {");
+        let last = block.code.len() - 1;
+        for (pc, (num, instr)) in block.code.iter().enumerate() {
+            if let Instr::Finish = instr {
+                line(o, 3, "/* the end of the block */");
+            } else {
+                line(o, 3, &format!("/* line {} */", num));
+            }
+            if pc == last {
+                line(o, 3, "default:");
+            } else {
+                line(o, 3, &format!("case {}:", pc));
+            }
+            let next = format!("flow->pc = {};", pc + 1);
+            match instr {
+                Instr::Set { actuator, value } => {
+                    let val = gen.literal(&actuator.name, &actuator.kind, *value);
+                    if gen.guarded {
+                        line(
+                            o,
+                            4,
+                            &format!(
+                                "if (!flow_set_{}(flow, s, {}, {}))",
+                                actuator.name,
+                                val,
+                                string(&refused(block.name, actuator, *value))
+                            ),
+                        );
+                        line(o, 5, "return false;");
+                    } else {
+                        line(
+                            o,
+                            4,
+                            &format!("flow->actuators.{} = {};", ident(&actuator.name), val),
+                        );
+                        if actuator.init.is_none() {
+                            line(
+                                o,
+                                4,
+                                &format!("flow->actuators.{}_set = true;", actuator.name),
+                            );
+                        }
+                    }
+                    line(o, 4, &next);
+                }
+                Instr::Wait { condition } => {
+                    line(
+                        o,
+                        4,
+                        &format!("if (!{}(flow, s)) {{", gen.function(condition)),
+                    );
+                    if can_fail(condition) {
+                        line(o, 5, "if (flow->fault != NULL)");
+                        line(o, 6, "return flow_fault(flow);");
+                    }
+                    line(o, 5, "return true;");
+                    line(o, 4, "}");
+                    line(o, 4, &next);
+                }
+                Instr::Branch { condition, target } => {
+                    line(
+                        o,
+                        4,
+                        &format!(
+                            "flow->pc = {}(flow, s) ? {} : {};",
+                            gen.function(condition),
+                            pc + 1,
+                            target
+                        ),
+                    );
+                    if can_fail(condition) {
+                        line(o, 4, "if (flow->fault != NULL)");
+                        line(o, 5, "return flow_fault(flow);");
+                    }
+                }
+                Instr::Jump { target } => line(o, 4, &format!("flow->pc = {};", target)),
+                Instr::Delay { duration } => {
+                    line(o, 4, "if (!flow->delaying) {");
+                    line(o, 5, "flow->delaying = true;");
+                    line(
+                        o,
+                        5,
+                        &format!("flow->deadline = flow->time + {};", number(*duration)),
+                    );
+                    line(o, 4, "}");
+                    line(o, 4, "if (flow->time < flow->deadline)");
+                    line(o, 5, "return true;");
+                    line(o, 4, "flow->delaying = false;");
+                    line(o, 4, &next);
+                }
+                Instr::Start { timer } => {
+                    // a timer nothing reads doesn't need starting
+                    if gen.timers.contains(*timer) {
+                        line(o, 4, &format!("flow->timer_{}_started = true;", timer));
+                        line(o, 4, &format!("flow->timer_{} = flow->time;", timer));
+                    }
+                    line(o, 4, &next);
+                }
+                Instr::Goto { block } => {
+                    line(o, 4, &format!("flow_enter(flow, {});", block_const(block)));
+                }
+                Instr::Forget { conditions } => {
+                    for condition in conditions {
+                        match condition {
+                            Condition::Edge { id, .. } => {
+                                line(o, 4, &format!("flow->edge_{} = -1;", id))
+                            }
+                            Condition::For { id, .. } => {
+                                line(o, 4, &format!("flow->held_{}_on = false;", id))
+                            }
+                            _ => {}
+                        }
+                    }
+                    line(o, 4, &next);
+                }
+                Instr::Finish => {
+                    line(o, 4, "flow->finished = true;");
+                    line(o, 4, "return true;");
+                }
+            }
+            if !matches!(instr, Instr::Finish) {
+                line(o, 4, "break;");
+            }
+        }
+        line(o, 3, "}");
+        line(o, 3, "break;");
+    }
+    line(o, 2, "}");
+    line(o, 1, "}");
+    line(
+        o,
+        1,
+        "flow_say(flow, \"timed out busy looping in block \");",
+    );
+    line(o, 1, "flow_say(flow, flow_block_name(flow->block));");
+    line(o, 1, "return false;");
+    line(o, 0, "}");
+}
+
+// setters for the actuators the flow sets, which refuse values breaking an interlock or invariant
+fn gen_safety(gen: &Generator, blocks: &[lower::Lowered], o: &mut String) {
+    let ast = gen.ast;
+    line(o, 0, "");
+    line(
+        o,
+        0,
+        "/* whether the current values break an interlock or invariant, saying which after `refused` */",
+    );
+    line(
+        o,
+        0,
+        "static bool flow_violation(flow_state_t *flow, const flow_sensors_t *s, const char *refused)",
+    );
+    line(o, 0, "{");
+    for interlock in &ast.interlocks {
+        let shown = interlock.to_string();
+        let trigger = &interlock.trigger;
+        let requirement = &interlock.requirement;
+        line(o, 1, &format!("if ({}(flow, s)) {{", gen.function(trigger)));
+        line(
+            o,
+            2,
+            &format!("bool holds = {}(flow, s);", gen.function(requirement)),
+        );
+        if can_fail(requirement) {
+            line(o, 2, "if (flow->fault != NULL) {");
+            line(o, 3, "flow_say(flow, refused);");
+            line(
+                o,
+                3,
+                &format!(
+                    "flow_say(flow, {});",
+                    string(&format!("can't check interlock {}: ", shown))
+                ),
+            );
+            line(o, 3, "flow_say(flow, flow->fault);");
+            line(o, 3, "return true;");
+            line(o, 2, "}");
+        }
+        line(o, 2, "if (!holds) {");
+        line(o, 3, "flow_say(flow, refused);");
+        line(
+            o,
+            3,
+            &format!(
+                "flow_say(flow, {});",
+                string(&format!("violates interlock {}", shown))
+            ),
+        );
+        line(o, 3, "return true;");
+        line(o, 2, "}");
+        line(o, 1, "}");
+        if can_fail(trigger) {
+            // an interlock whose trigger can't be checked doesn't stop anything
+            line(o, 1, "flow->fault = NULL;");
+        }
+    }
+    for invariant in &ast.invariants {
+        let violated = string(&format!("violates invariant {}", invariant));
+        if !can_fail(invariant) {
+            line(
+                o,
+                1,
+                &format!("if (!{}(flow, s)) {{", gen.function(invariant)),
+            );
+            line(o, 2, "flow_say(flow, refused);");
+            line(o, 2, &format!("flow_say(flow, {});", violated));
+            line(o, 2, "return true;");
+            line(o, 1, "}");
+            continue;
+        }
+        // an invariant that can't be checked refuses the set, the same as one that's broken
+        line(
+            o,
+            1,
+            &format!(
+                "if (!{}(flow, s) || flow->fault != NULL) {{",
+                gen.function(invariant)
+            ),
+        );
+        line(o, 2, "flow_say(flow, refused);");
+        line(o, 2, "if (flow->fault != NULL) {");
+        line(
+            o,
+            3,
+            &format!(
+                "flow_say(flow, {});",
+                string(&format!("can't check invariant {}: ", invariant))
+            ),
+        );
+        line(o, 3, "flow_say(flow, flow->fault);");
+        line(o, 2, "} else {");
+        line(o, 3, &format!("flow_say(flow, {});", violated));
+        line(o, 2, "}");
+        line(o, 2, "return true;");
+        line(o, 1, "}");
+    }
+    line(o, 1, "return false;");
+    line(o, 0, "}");
+
+    let mut set: Vec<&Actuator> = Vec::new();
+    for block in blocks {
+        for (_, instr) in &block.code {
+            if let Instr::Set { actuator, .. } = instr {
+                if !set.iter().any(|a| a.name == actuator.name) {
+                    set.push(actuator);
+                }
+            }
+        }
+    }
+    set.sort_by(|a, b| a.name.cmp(&b.name));
+    for act in set {
+        line(o, 0, "");
+        line(
+            o,
+            0,
+            &format!(
+                "static bool flow_set_{}(flow_state_t *flow, const flow_sensors_t *s, {} value, const char *refused)",
+                act.name,
+                gen.kind_type(&act.name, &act.kind)
+            ),
+        );
+        line(o, 0, "{");
+        line(o, 1, "flow_actuators_t previous = flow->actuators;");
+        line(
+            o,
+            1,
+            &format!("flow->actuators.{} = value;", ident(&act.name)),
+        );
+        if act.init.is_none() {
+            line(o, 1, &format!("flow->actuators.{}_set = true;", act.name));
+        }
+        line(o, 1, "if (flow_violation(flow, s, refused)) {");
+        line(o, 2, "flow->actuators = previous;");
+        line(o, 2, "return false;");
+        line(o, 1, "}");
+        line(o, 1, "return true;");
+        line(o, 0, "}");
+    }
+}
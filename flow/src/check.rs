@@ -0,0 +1,655 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt;
+
+use crate::ast::{Comparator, Condition, Kind, Operation, Statement, AST};
+use crate::coverage;
+use crate::graph;
+use crate::interval::{Interval, Truth};
+
+#[derive(Debug, PartialEq)]
+pub enum Severity {
+    // worth knowing, but not a sign anything's wrong
+    Note,
+    Warning,
+    Error,
+}
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    // the lines of source involved, in order
+    pub lines: Vec<usize>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.severity {
+            Severity::Note => write!(f, "note: ")?,
+            Severity::Warning => write!(f, "warning: ")?,
+            Severity::Error => write!(f, "error: ")?,
+        }
+        match self.lines.as_slice() {
+            [] => {}
+            [line] => write!(f, "line {}: ", line)?,
+            lines => {
+                let lines: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+                write!(f, "lines {}: ", lines.join(", "))?
+            }
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+pub fn check(ast: &AST) -> Vec<Diagnostic> {
+    let mut diags: Vec<Diagnostic> = Vec::new();
+    let flow = Flow::analyze(ast);
+    flow.visit(&mut |block, stmt, state| {
+        let found = diags.len();
+        check_reads(block, &stmt.op, state, &mut diags);
+        check_safety(ast, block, &stmt.op, state, &mut diags);
+        check_conditions(ast, block, &stmt.op, state, &mut diags);
+        for diag in &mut diags[found..] {
+            diag.lines.push(stmt.line);
+        }
+    });
+
+    let mut blocks: Vec<&String> = ast.blocks.keys().collect();
+    blocks.sort();
+    for name in blocks {
+        check_chains(name, &ast.blocks[name].ops, &mut diags);
+    }
+    diags.extend(graph::analyze(ast));
+    diags
+}
+
+// possible values of every device at some point in the flow
+#[derive(Debug, Clone, PartialEq)]
+pub struct State {
+    pub ranges: HashMap<String, Interval>,
+    // actuators that may not have been given a value yet
+    pub unset: BTreeSet<String>,
+}
+
+impl State {
+    pub fn get(&self, name: &str) -> Interval {
+        self.ranges[name]
+    }
+
+    pub fn assign(&mut self, name: &str, range: Interval) {
+        self.ranges.insert(name.to_string(), range);
+        self.unset.remove(name);
+    }
+
+    fn join(&self, other: &State) -> State {
+        State {
+            ranges: self
+                .ranges
+                .iter()
+                .map(|(name, range)| (name.clone(), range.join(&other.ranges[name])))
+                .collect(),
+            unset: self.unset.union(&other.unset).cloned().collect(),
+        }
+    }
+}
+
+pub fn initial_state(ast: &AST) -> State {
+    let mut state = State {
+        ranges: HashMap::new(),
+        unset: BTreeSet::new(),
+    };
+    for sens in ast.sensors() {
+        state
+            .ranges
+            .insert(sens.name.clone(), Interval::new(sens.min, sens.max));
+    }
+    for act in ast.actuators() {
+        match act.init {
+            Some(val) => {
+                state.ranges.insert(act.name.clone(), Interval::point(val));
+            }
+            None => {
+                state
+                    .ranges
+                    .insert(act.name.clone(), Interval::new(act.min, act.max));
+                state.unset.insert(act.name.clone());
+            }
+        }
+    }
+    state
+}
+
+// every device anywhere in its declared range, whatever the flow has done
+fn declared_state(ast: &AST) -> State {
+    let mut state = initial_state(ast);
+    for act in ast.actuators() {
+        state.assign(&act.name, Interval::new(act.min, act.max));
+    }
+    state
+}
+
+// sensors may have changed whenever time can pass, so forget what we knew about them
+fn reset_sensors(ast: &AST, state: &mut State) {
+    for sens in ast.sensors() {
+        state.assign(&sens.name, Interval::new(sens.min, sens.max));
+    }
+}
+
+pub fn truth(state: &State, condition: &Condition) -> Truth {
+    match condition {
+        Condition::Base(sens, comp, val) => state.get(&sens.name).compare(*comp, *val),
+        Condition::Actuator(act, comp, val) => state.get(&act.name).compare(*comp, *val),
+        Condition::Time(..) => Truth::Unknown,
+        Condition::Hysteresis {
+            sensor,
+            rising,
+            threshold,
+            band,
+            ..
+        } => {
+            // past the threshold it's definitely on, past the band it's definitely off
+            let range = state.get(&sensor.name);
+            let (on, off) = hysteresis_bounds(*rising, *threshold, *band);
+            let always = range.compare(on, *threshold);
+            let never = range.compare(off.0, off.1);
+            match (always, never) {
+                (Truth::True, _) => Truth::True,
+                (_, Truth::True) => Truth::False,
+                _ => Truth::Unknown,
+            }
+        }
+        // an edge needs the inner condition to hold now, or not hold now when falling
+        Condition::Edge {
+            condition, rising, ..
+        } => match (truth(state, condition), rising) {
+            (Truth::False, true) | (Truth::True, false) => Truth::False,
+            _ => Truth::Unknown,
+        },
+        Condition::For { condition, .. } => match truth(state, condition) {
+            Truth::False => Truth::False,
+            _ => Truth::Unknown,
+        },
+        Condition::All(conditions) => conditions
+            .iter()
+            .fold(Truth::True, |acc, c| acc.and(truth(state, c))),
+        Condition::Any(conditions) => conditions
+            .iter()
+            .fold(Truth::False, |acc, c| acc.or(truth(state, c))),
+    }
+}
+
+// the comparison that switches a hysteresis condition on, and the one that switches it off
+fn hysteresis_bounds(rising: bool, threshold: f64, band: f64) -> (Comparator, (Comparator, f64)) {
+    if rising {
+        (Comparator::GT, (Comparator::LT, threshold - band))
+    } else {
+        (Comparator::LT, (Comparator::GT, threshold + band))
+    }
+}
+
+fn negate(comp: Comparator) -> Option<Comparator> {
+    match comp {
+        Comparator::LT => Some(Comparator::GTEQ),
+        Comparator::LTEQ => Some(Comparator::GT),
+        Comparator::GT => Some(Comparator::LTEQ),
+        Comparator::GTEQ => Some(Comparator::LT),
+        Comparator::EQ | Comparator::APPROX(_) => None,
+    }
+}
+
+fn restrict_leaf(state: &State, name: &str, comp: Comparator, val: f64) -> Option<State> {
+    let range = state.get(name).restrict(comp, val);
+    if range.is_empty() {
+        return None;
+    }
+    let mut out = state.clone();
+    out.ranges.insert(name.to_string(), range);
+    Some(out)
+}
+
+// narrow the state to where the condition is `holds`, None if that can never happen
+pub fn refine(state: &State, condition: &Condition, holds: bool) -> Option<State> {
+    let (name, comp, val) = match condition {
+        Condition::Base(sens, comp, val) => (&sens.name, *comp, *val),
+        Condition::Actuator(act, comp, val) => (&act.name, *comp, *val),
+        Condition::Hysteresis {
+            sensor,
+            rising,
+            threshold,
+            band,
+            ..
+        } => {
+            // on means it isn't past the band yet, off means it isn't past the threshold
+            let (on, off) = hysteresis_bounds(*rising, *threshold, *band);
+            return if holds {
+                restrict_leaf(state, &sensor.name, negate(off.0).unwrap(), off.1)
+            } else {
+                restrict_leaf(state, &sensor.name, negate(on).unwrap(), *threshold)
+            };
+        }
+        Condition::Time(..) => return Some(state.clone()),
+        Condition::Edge {
+            condition, rising, ..
+        } => {
+            return if holds {
+                refine(state, condition, *rising)
+            } else {
+                Some(state.clone())
+            };
+        }
+        Condition::For { condition, .. } => {
+            return if holds {
+                refine(state, condition, true)
+            } else {
+                Some(state.clone())
+            };
+        }
+        Condition::All(conditions) | Condition::Any(conditions) => {
+            let conjunction = matches!(condition, Condition::All(_)) == holds;
+            return if conjunction {
+                conditions
+                    .iter()
+                    .try_fold(state.clone(), |acc, c| refine(&acc, c, holds))
+            } else {
+                conditions
+                    .iter()
+                    .filter_map(|c| refine(state, c, holds))
+                    .reduce(|a, b| a.join(&b))
+            };
+        }
+    };
+    if holds {
+        restrict_leaf(state, name, comp, val)
+    } else {
+        match negate(comp) {
+            Some(negated) => restrict_leaf(state, name, negated, val),
+            None => Some(state.clone()),
+        }
+    }
+}
+
+// called for every operation reached by the analysis, with the state just before it runs
+type Visitor<'v> = dyn FnMut(&str, &Statement, &State) + 'v;
+
+pub struct Flow<'a> {
+    ast: &'a AST,
+    pub entries: HashMap<String, State>,
+}
+
+impl<'a> Flow<'a> {
+    // propagate device ranges over the block graph until nothing changes
+    pub fn analyze(ast: &'a AST) -> Flow<'a> {
+        let mut flow = Flow {
+            ast,
+            entries: HashMap::new(),
+        };
+        let mut queue: VecDeque<String> = VecDeque::new();
+        flow.entries
+            .insert(ast.first_block_name.clone(), initial_state(ast));
+        queue.push_back(ast.first_block_name.clone());
+
+        while let Some(name) = queue.pop_front() {
+            let mut gotos: Vec<(String, State)> = Vec::new();
+            flow.walk_block(&name, &mut |_, stmt, state| {
+                if let Operation::Goto { dest } = &stmt.op {
+                    gotos.push((dest.clone(), state.clone()));
+                }
+            });
+            for (dest, state) in gotos {
+                if !ast.blocks.contains_key(&dest) {
+                    continue;
+                }
+                let joined = match flow.entries.get(&dest) {
+                    Some(old) => old.join(&state),
+                    None => state,
+                };
+                if flow.entries.get(&dest) != Some(&joined) {
+                    flow.entries.insert(dest.clone(), joined);
+                    if !queue.contains(&dest) {
+                        queue.push_back(dest);
+                    }
+                }
+            }
+        }
+        flow
+    }
+
+    // visit every operation of every block the flow can reach
+    pub fn visit(&self, visit: &mut Visitor) {
+        let mut blocks: Vec<&String> = self.entries.keys().collect();
+        blocks.sort();
+        for name in blocks {
+            self.walk_block(name, visit);
+        }
+    }
+
+    pub fn walk_block(&self, name: &str, visit: &mut Visitor) {
+        let mut state = self.entries[name].clone();
+        reset_sensors(self.ast, &mut state);
+        self.walk(name, &self.ast.blocks[name].ops, state, visit);
+    }
+
+    // returns the state when falling off the end of `ops`, None if every path left via goto
+    fn walk(
+        &self,
+        block: &str,
+        ops: &[Statement],
+        state: State,
+        visit: &mut Visitor,
+    ) -> Option<State> {
+        let mut state = state;
+        for stmt in ops {
+            visit(block, stmt, &state);
+            match &stmt.op {
+                Operation::Set { actuator, value } => {
+                    state.assign(&actuator.name, Interval::point(*value));
+                }
+                Operation::Wait { condition } => {
+                    reset_sensors(self.ast, &mut state);
+                    state = refine(&state, condition, true)?;
+                }
+                Operation::Delay { .. } => {
+                    reset_sensors(self.ast, &mut state);
+                }
+                Operation::Start { .. } => {}
+                Operation::IfElse {
+                    if_condition,
+                    if_actions,
+                    else_actions,
+                } => {
+                    let taken = refine(&state, if_condition, true)
+                        .and_then(|s| self.walk(block, if_actions, s, visit));
+                    let not_taken =
+                        refine(&state, if_condition, false).and_then(|s| match else_actions {
+                            Some(actions) => self.walk(block, actions, s, visit),
+                            None => Some(s),
+                        });
+                    state = match (taken, not_taken) {
+                        (Some(a), Some(b)) => a.join(&b),
+                        (Some(a), None) => a,
+                        (None, Some(b)) => b,
+                        (None, None) => return None,
+                    };
+                }
+                Operation::Goto { .. } => {
+                    return None;
+                }
+            }
+        }
+        Some(state)
+    }
+}
+
+// like truth, but also catches conditions that can't hold, or can't fail, because of how their
+// parts rule each other out
+pub fn decide(state: &State, condition: &Condition) -> Truth {
+    if refine(state, condition, true).is_none() {
+        Truth::False
+    } else if refine(state, condition, false).is_none() {
+        Truth::True
+    } else {
+        truth(state, condition)
+    }
+}
+
+// every any and all in a condition, outermost first
+fn groups<'c>(condition: &'c Condition, out: &mut Vec<&'c Condition>) {
+    match condition {
+        Condition::All(conditions) | Condition::Any(conditions) => {
+            out.push(condition);
+            for c in conditions {
+                groups(c, out);
+            }
+        }
+        Condition::Edge { condition, .. } | Condition::For { condition, .. } => {
+            groups(condition, out);
+        }
+        _ => {}
+    }
+}
+
+// every comparison in the conditions an operation tests
+fn tested(op: &Operation) -> Vec<&Condition> {
+    let condition = match op {
+        Operation::Wait { condition } => condition,
+        Operation::IfElse { if_condition, .. } => if_condition,
+        _ => return Vec::new(),
+    };
+    coverage::leaves(condition)
+        .into_iter()
+        .map(coverage::compared)
+        .collect()
+}
+
+fn check_reads(block: &str, op: &Operation, state: &State, diags: &mut Vec<Diagnostic>) {
+    for leaf in tested(op) {
+        match leaf {
+            Condition::Actuator(act, _, _) if state.unset.contains(&act.name) => {
+                diags.push(Diagnostic {
+                    severity: Severity::Warning,
+                    message: format!(
+                        "block {}: actuator {} may be read before it is set, consider giving it an init value",
+                        block, act.name
+                    ),
+                    lines: Vec::new(),
+                });
+            }
+            Condition::Base(sens, Comparator::EQ, _) if sens.kind == Kind::Float => {
+                diags.push(Diagnostic {
+                    severity: Severity::Warning,
+                    message: format!(
+                        "block {}: {} compares a reading exactly and may never hold, consider a tol and ~=",
+                        block, leaf
+                    ),
+                    lines: Vec::new(),
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+// conditions that can't go more than one way: any and all groups that are decided by their
+// devices' declared ranges alone, and wait and if conditions decided by what's known on the way in
+fn check_conditions(
+    ast: &AST,
+    block: &str,
+    op: &Operation,
+    state: &State,
+    diags: &mut Vec<Diagnostic>,
+) {
+    let condition = match op {
+        Operation::Wait { condition } => condition,
+        Operation::IfElse { if_condition, .. } => if_condition,
+        _ => return,
+    };
+    let declared = declared_state(ast);
+    let mut found: Vec<&Condition> = Vec::new();
+    groups(condition, &mut found);
+    let mut contradictions = Vec::new();
+    for group in found {
+        let message = match (group, decide(&declared, group)) {
+            (Condition::All(_), Truth::False) => format!("{} can never hold", group),
+            (Condition::Any(_), Truth::True) => format!("{} always holds", group),
+            _ => continue,
+        };
+        contradictions.push(Diagnostic {
+            severity: Severity::Warning,
+            message: format!("block {}: {}", block, message),
+            lines: Vec::new(),
+        });
+    }
+    // whatever follows from a group that's decided on its own would only say the same again
+    if !contradictions.is_empty() {
+        diags.extend(contradictions);
+        return;
+    }
+
+    let message = match op {
+        Operation::Wait { .. } => {
+            // readings can change while waiting, but nothing sets actuators
+            let mut waiting = state.clone();
+            reset_sensors(ast, &mut waiting);
+            if decide(&waiting, condition) == Truth::False {
+                format!(
+                    "wait on {} can never be satisfied, so it waits forever",
+                    condition
+                )
+            } else if decide(state, condition) == Truth::True {
+                format!(
+                    "wait on {} already holds here, so it never waits",
+                    condition
+                )
+            } else {
+                return;
+            }
+        }
+        Operation::IfElse { else_actions, .. } => match (decide(state, condition), else_actions) {
+            (Truth::False, _) => format!(
+                "{} never holds here, so the if branch never runs",
+                condition
+            ),
+            (Truth::True, Some(_)) => {
+                format!(
+                    "{} always holds here, so the else branch never runs",
+                    condition
+                )
+            }
+            (Truth::True, None) => {
+                format!("{} always holds here, so the if is redundant", condition)
+            }
+            _ => return,
+        },
+        _ => return,
+    };
+    diags.push(Diagnostic {
+        severity: Severity::Warning,
+        message: format!("block {}: {}", block, message),
+        lines: Vec::new(),
+    });
+}
+
+fn check_safety(
+    ast: &AST,
+    block: &str,
+    op: &Operation,
+    state: &State,
+    diags: &mut Vec<Diagnostic>,
+) {
+    if let Operation::Set { actuator, value } = op {
+        let mut after = state.clone();
+        after.assign(&actuator.name, Interval::point(*value));
+        let at = format!("block {}: set {} {}", block, actuator.name, value);
+        check_set(ast, &after, &at, diags);
+    }
+}
+
+fn check_set(ast: &AST, after: &State, at: &str, diags: &mut Vec<Diagnostic>) {
+    for interlock in &ast.interlocks {
+        let trigger = truth(after, &interlock.trigger);
+        let requirement = truth(after, &interlock.requirement);
+        match (trigger, requirement) {
+            (Truth::False, _) | (_, Truth::True) => {}
+            (Truth::True, Truth::False) => diags.push(Diagnostic {
+                severity: Severity::Error,
+                message: format!("{} violates interlock {}", at, interlock),
+                lines: Vec::new(),
+            }),
+            _ => diags.push(Diagnostic {
+                severity: Severity::Warning,
+                message: format!("{} may violate interlock {}", at, interlock),
+                lines: Vec::new(),
+            }),
+        }
+    }
+
+    for invariant in &ast.invariants {
+        match truth(after, invariant) {
+            Truth::True => {}
+            Truth::False => diags.push(Diagnostic {
+                severity: Severity::Error,
+                message: format!("{} violates invariant {}", at, invariant),
+                lines: Vec::new(),
+            }),
+            Truth::Unknown => diags.push(Diagnostic {
+                severity: Severity::Warning,
+                message: format!("{} may violate invariant {}", at, invariant),
+                lines: Vec::new(),
+            }),
+        }
+    }
+}
+
+// the enum device and variant tested by `- dev = variant`, if that's what the condition is
+fn enum_test(condition: &Condition) -> Option<(&str, &Vec<String>, usize)> {
+    let (name, kind, comp, val) = match condition {
+        Condition::Base(sens, comp, val) => (&sens.name, &sens.kind, comp, val),
+        Condition::Actuator(act, comp, val) => (&act.name, &act.kind, comp, val),
+        _ => return None,
+    };
+    match (kind, comp) {
+        (Kind::Enum(variants), Comparator::EQ) => Some((name, variants, *val as usize)),
+        _ => None,
+    }
+}
+
+// warn about if/else-if chains over an enum that neither cover every variant nor end in an else
+fn check_chains(block: &str, ops: &[Statement], diags: &mut Vec<Diagnostic>) {
+    for stmt in ops {
+        let mut chain: Vec<&Condition> = Vec::new();
+        let mut link = &stmt.op;
+        let mut last_else: Option<&Vec<Statement>> = None;
+        while let Operation::IfElse {
+            if_condition,
+            if_actions,
+            else_actions,
+        } = link
+        {
+            chain.push(if_condition);
+            check_chains(block, if_actions, diags);
+            match else_actions {
+                Some(actions)
+                    if actions.len() == 1 && matches!(actions[0].op, Operation::IfElse { .. }) =>
+                {
+                    link = &actions[0].op;
+                }
+                _ => {
+                    last_else = else_actions.as_ref();
+                    break;
+                }
+            }
+        }
+        if let Some(actions) = last_else {
+            check_chains(block, actions, diags);
+            continue;
+        }
+        if chain.len() < 2 {
+            continue;
+        }
+
+        let tests: Vec<(&str, &Vec<String>, usize)> =
+            chain.iter().filter_map(|c| enum_test(c)).collect();
+        if tests.len() != chain.len() || tests.iter().any(|t| t.0 != tests[0].0) {
+            continue;
+        }
+        let (name, variants, _) = tests[0];
+        let missing: Vec<&str> = variants
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !tests.iter().any(|t| t.2 == *i))
+            .map(|(_, v)| v.as_str())
+            .collect();
+        if !missing.is_empty() {
+            diags.push(Diagnostic {
+                severity: Severity::Warning,
+                message: format!(
+                    "block {}: if/else chain over {} doesn't handle {}",
+                    block,
+                    name,
+                    missing.join(", ")
+                ),
+                lines: vec![stmt.line],
+            });
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+
+use crate::ast::{Condition, Operation, Statement, AST};
+use crate::interp::Explained;
+
+// what runs of a flow exercised, added up over however many runs
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Coverage {
+    // times each block was entered
+    pub blocks: HashMap<String, usize>,
+    // times each statement ran, by line, with a wait running each time its condition is checked
+    pub statements: HashMap<usize, usize>,
+    // times each if took its if and its else arm, by line
+    pub branches: HashMap<usize, [usize; 2]>,
+    // times each leaf of a wait or if condition held and didn't, by the statement's line and the
+    // leaf's place among the condition's leaves
+    pub leaves: HashMap<(usize, usize), [usize; 2]>,
+}
+
+// the parts of a condition as written, one per `- ` item, looking through any and all
+pub fn leaves(condition: &Condition) -> Vec<&Condition> {
+    match condition {
+        Condition::All(conditions) | Condition::Any(conditions) => {
+            conditions.iter().flat_map(leaves).collect()
+        }
+        _ => vec![condition],
+    }
+}
+
+// the comparison a leaf makes, looking through any edge or duration around it
+pub fn compared(leaf: &Condition) -> &Condition {
+    match leaf {
+        Condition::Edge { condition, .. } | Condition::For { condition, .. } => condition,
+        _ => leaf,
+    }
+}
+
+// every statement in a list, nested ones straight after the if they're in
+pub fn statements(ops: &[Statement]) -> Vec<&Statement> {
+    let mut all = Vec::new();
+    for stmt in ops {
+        all.push(stmt);
+        if let Operation::IfElse {
+            if_actions,
+            else_actions,
+            ..
+        } = &stmt.op
+        {
+            all.extend(statements(if_actions));
+            all.extend(statements(else_actions.as_deref().unwrap_or(&[])));
+        }
+    }
+    all
+}
+
+fn condition(op: &Operation) -> Option<&Condition> {
+    match op {
+        Operation::Wait { condition } => Some(condition),
+        Operation::IfElse { if_condition, .. } => Some(if_condition),
+        _ => None,
+    }
+}
+
+// how each leaf of a condition evaluated, None for leaves it never got to. The explanation has
+// the parts in the order they were evaluated, so a part is there only if the next entry is at its
+// depth; short-circuiting leaves out the rest of an any or all.
+fn outcomes(
+    condition: &Condition,
+    depth: usize,
+    explained: &[Explained],
+    next: &mut usize,
+    found: &mut Vec<Option<bool>>,
+) {
+    let reached = explained.get(*next).is_some_and(|e| e.depth == depth);
+    match condition {
+        Condition::All(conditions) | Condition::Any(conditions) => {
+            if reached {
+                *next += 1;
+            }
+            for c in conditions {
+                if reached {
+                    outcomes(c, depth + 1, explained, next, found);
+                } else {
+                    found.extend(leaves(c).iter().map(|_| None));
+                }
+            }
+        }
+        _ if reached => {
+            found.push(explained[*next].holds);
+            *next += 1;
+            // skip what edge and duration conditions evaluated inside themselves
+            while explained.get(*next).is_some_and(|e| e.depth > depth) {
+                *next += 1;
+            }
+        }
+        _ => found.push(None),
+    }
+}
+
+impl Coverage {
+    pub fn enter(&mut self, block: &str) {
+        *self.blocks.entry(block.to_string()).or_default() += 1;
+    }
+
+    // note a statement having run, with the explanation of the condition it evaluated if any
+    pub fn record(&mut self, stmt: &Statement, explanation: &[Explained]) {
+        *self.statements.entry(stmt.line).or_default() += 1;
+        let condition = match condition(&stmt.op) {
+            Some(condition) => condition,
+            None => return,
+        };
+        let mut found = Vec::new();
+        outcomes(condition, 0, explanation, &mut 0, &mut found);
+        for (idx, holds) in found.into_iter().enumerate() {
+            if let Some(holds) = holds {
+                self.leaves.entry((stmt.line, idx)).or_default()[!holds as usize] += 1;
+            }
+        }
+        if let (Operation::IfElse { .. }, Some(root)) = (&stmt.op, explanation.first()) {
+            if let Some(taken) = root.holds {
+                self.branches.entry(stmt.line).or_default()[!taken as usize] += 1;
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: &Coverage) {
+        for (block, hits) in &other.blocks {
+            *self.blocks.entry(block.clone()).or_default() += hits;
+        }
+        for (line, hits) in &other.statements {
+            *self.statements.entry(*line).or_default() += hits;
+        }
+        for (line, [taken, not]) in &other.branches {
+            let arms = self.branches.entry(*line).or_default();
+            arms[0] += taken;
+            arms[1] += not;
+        }
+        for (leaf, [held, not]) in &other.leaves {
+            let outcomes = self.leaves.entry(*leaf).or_default();
+            outcomes[0] += held;
+            outcomes[1] += not;
+        }
+    }
+
+    fn hits(&self, stmt: &Statement) -> usize {
+        self.statements.get(&stmt.line).copied().unwrap_or(0)
+    }
+
+    fn arms(&self, stmt: &Statement) -> [usize; 2] {
+        self.branches.get(&stmt.line).copied().unwrap_or_default()
+    }
+
+    fn outcomes(&self, stmt: &Statement, leaf: usize) -> [usize; 2] {
+        self.leaves
+            .get(&(stmt.line, leaf))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    // counts of what was covered out of what there is to cover
+    pub fn summary(&self, ast: &AST) -> String {
+        let (mut blocks, mut stmts, mut arms, mut outcomes) = ((0, 0), (0, 0), (0, 0), (0, 0));
+        for (name, block) in &ast.blocks {
+            blocks.0 += self.blocks.contains_key(name) as usize;
+            blocks.1 += 1;
+            for stmt in statements(&block.ops) {
+                stmts.0 += (self.hits(stmt) > 0) as usize;
+                stmts.1 += 1;
+                if let Operation::IfElse { .. } = stmt.op {
+                    arms.0 += self.arms(stmt).iter().filter(|n| **n > 0).count();
+                    arms.1 += 2;
+                }
+                if let Some(condition) = condition(&stmt.op) {
+                    for leaf in 0..leaves(condition).len() {
+                        outcomes.0 += self.outcomes(stmt, leaf).iter().filter(|n| **n > 0).count();
+                        outcomes.1 += 2;
+                    }
+                }
+            }
+        }
+        format!(
+            "blocks {}/{}, statements {}/{}, branches {}/{}, conditions {}/{}",
+            blocks.0, blocks.1, stmts.0, stmts.1, arms.0, arms.1, outcomes.0, outcomes.1
+        )
+    }
+
+    // the flow's source with how often each block was entered and each statement ran in front of
+    // it, ##### for ones that never did, and how ifs and condition leaves went underneath
+    pub fn annotate(&self, ast: &AST, source: &str) -> Vec<String> {
+        let mut counts: HashMap<usize, usize> = HashMap::new();
+        let mut notes: HashMap<usize, Vec<String>> = HashMap::new();
+        for (name, block) in &ast.blocks {
+            counts.insert(block.line, self.blocks.get(name).copied().unwrap_or(0));
+            for stmt in statements(&block.ops) {
+                counts.insert(stmt.line, self.hits(stmt));
+                let notes = notes.entry(stmt.line).or_default();
+                if let Operation::IfElse { .. } = stmt.op {
+                    let [taken, not] = self.arms(stmt);
+                    notes.push(format!("branch: if {}, else {}", taken, not));
+                }
+                if let Some(condition) = condition(&stmt.op) {
+                    for (idx, leaf) in leaves(condition).into_iter().enumerate() {
+                        let [held, not] = self.outcomes(stmt, idx);
+                        notes.push(format!("{}: true {}, false {}", leaf, held, not));
+                    }
+                }
+            }
+        }
+
+        let mut lines = Vec::new();
+        for (idx, text) in source.lines().enumerate() {
+            let num = idx + 1;
+            let count = match counts.get(&num) {
+                Some(0) => "#####".to_string(),
+                Some(count) => count.to_string(),
+                None => "-".to_string(),
+            };
+            lines.push(format!("{:>6}: {:>4}: {}", count, num, text));
+            for note in notes.get(&num).into_iter().flatten() {
+                lines.push(format!("{:14}{}", "", note));
+            }
+        }
+        lines
+    }
+
+    // an LCOV record for the flow at `path`, with blocks as functions, statements as lines, and
+    // each if and each condition leaf as a pair of branches
+    pub fn lcov(&self, ast: &AST, path: &str) -> String {
+        let mut blocks: Vec<(&String, usize)> =
+            ast.blocks.iter().map(|(n, b)| (n, b.line)).collect();
+        blocks.sort_by_key(|(_, line)| *line);
+        let mut stmts: Vec<&Statement> = ast
+            .blocks
+            .values()
+            .flat_map(|block| statements(&block.ops))
+            .collect();
+        stmts.sort_by_key(|stmt| stmt.line);
+
+        let mut out = format!("TN:\nSF:{}\n", path);
+        for (name, line) in &blocks {
+            out += &format!("FN:{},{}\n", line, name);
+        }
+        for (name, _) in &blocks {
+            out += &format!("FNDA:{},{}\n", self.blocks.get(*name).unwrap_or(&0), name);
+        }
+        out += &format!(
+            "FNF:{}\nFNH:{}\n",
+            blocks.len(),
+            blocks
+                .iter()
+                .filter(|(name, _)| self.blocks.contains_key(*name))
+                .count()
+        );
+
+        let (mut found, mut hit) = (0, 0);
+        for stmt in &stmts {
+            let ran = self.hits(stmt) > 0;
+            let mut pairs = Vec::new();
+            if let Operation::IfElse { .. } = stmt.op {
+                pairs.push((0, self.arms(stmt)));
+            }
+            if let Some(condition) = condition(&stmt.op) {
+                for leaf in 0..leaves(condition).len() {
+                    pairs.push((leaf + 1, self.outcomes(stmt, leaf)));
+                }
+            }
+            for (group, counts) in pairs {
+                for (branch, count) in counts.iter().enumerate() {
+                    // - for branches of statements that never ran, as LCOV expects
+                    let taken = if ran {
+                        count.to_string()
+                    } else {
+                        "-".to_string()
+                    };
+                    out += &format!("BRDA:{},{},{},{}\n", stmt.line, group, branch, taken);
+                    found += 1;
+                    hit += (*count > 0) as usize;
+                }
+            }
+        }
+        out += &format!("BRF:{}\nBRH:{}\n", found, hit);
+
+        for stmt in &stmts {
+            out += &format!("DA:{},{}\n", stmt.line, self.hits(stmt));
+        }
+        out += &format!(
+            "LF:{}\nLH:{}\nend_of_record\n",
+            stmts.len(),
+            stmts.iter().filter(|stmt| self.hits(stmt) > 0).count()
+        );
+        out
+    }
+}
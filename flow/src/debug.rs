@@ -0,0 +1,466 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, BufRead, Write};
+
+use crate::ast::{Actuator, Device, Operation, Sensor, Statement, AST};
+use crate::backend::DeviceBackend;
+use crate::interp::{Explained, Interpreter, Status, MAX_OPS_PER_STEP, MAX_SIMULATED_SECONDS};
+
+const HELP: &str = "commands:
+  step, s                   run the next statement
+  continue, c               run until a breakpoint, the end, or a wait nothing will satisfy
+  break, b <block|line>     stop before entering a block or running the statement on a line
+  delete, d <block|line>    remove a breakpoint
+  set <sensor> <value>      hold a sensor at a value instead of reading it
+  release <sensor>          go back to reading a sensor
+  advance <seconds>         move the clock forward
+  sensors                   show the current readings
+  actuators                 show the actuator table
+  where                     show the statement that runs next
+  quit, q";
+
+// sensor values typed in at the debugger, read in place of the backend's until released
+pub struct Overrides {
+    inner: Box<dyn DeviceBackend>,
+    pub values: HashMap<String, f64>,
+}
+
+impl Overrides {
+    pub fn new(inner: Box<dyn DeviceBackend>) -> Overrides {
+        Overrides {
+            inner,
+            values: HashMap::new(),
+        }
+    }
+}
+
+impl DeviceBackend for Overrides {
+    fn read_sensor(&mut self, sensor: &Sensor) -> Result<f64, String> {
+        match self.values.get(&sensor.name) {
+            Some(val) => Ok(*val),
+            None => self.inner.read_sensor(sensor),
+        }
+    }
+
+    fn write_actuator(&mut self, actuator: &Actuator, value: f64) -> Result<(), String> {
+        self.inner.write_actuator(actuator, value)
+    }
+
+    fn seek(&mut self, time: f64) {
+        self.inner.seek(time)
+    }
+
+    fn next_change(&self) -> Option<f64> {
+        self.inner.next_change()
+    }
+}
+
+pub struct Debugger<'a, W: Write> {
+    ast: &'a AST,
+    interp: Interpreter<'a>,
+    io: Overrides,
+    out: W,
+    lines: BTreeSet<usize>,
+    blocks: BTreeSet<String>,
+    // the last statement couldn't run yet, so readings are taken again before retrying it
+    blocked: bool,
+    // the flow finished or hit an error, so there's nothing left to run
+    stopped: bool,
+    shown_time: f64,
+}
+
+impl<'a, W: Write> Debugger<'a, W> {
+    pub fn new(
+        ast: &'a AST,
+        io: Box<dyn DeviceBackend>,
+        out: W,
+    ) -> Result<Debugger<'a, W>, String> {
+        let mut io = Overrides::new(io);
+        let mut interp = Interpreter::new(ast, &mut io)?;
+        interp.explain = true;
+        let mut debugger = Debugger {
+            ast,
+            interp,
+            io,
+            out,
+            lines: BTreeSet::new(),
+            blocks: BTreeSet::new(),
+            blocked: true,
+            stopped: false,
+            shown_time: 0.0,
+        };
+        debugger.report_events()?;
+        Ok(debugger)
+    }
+
+    // read commands until quit or the input runs out
+    pub fn repl(&mut self, input: impl BufRead) -> Result<(), String> {
+        self.show_where()?;
+        let mut lines = input.lines();
+        loop {
+            write!(self.out, "(flow) ").map_err(|e| e.to_string())?;
+            self.out.flush().map_err(|e| e.to_string())?;
+            let line = match lines.next() {
+                Some(line) => line.map_err(|e| e.to_string())?,
+                None => return Ok(()),
+            };
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                [] => {}
+                ["quit"] | ["q"] => return Ok(()),
+                ["help"] | ["h"] => self.say(HELP)?,
+                ["step"] | ["s"] => self.step()?,
+                ["continue"] | ["c"] => self.resume()?,
+                ["break", at] | ["b", at] => self.add_break(at)?,
+                ["delete", at] | ["d", at] => self.remove_break(at)?,
+                ["set", name, value] => self.hold(name, value)?,
+                ["release", name] => self.release(name)?,
+                ["advance", secs] => self.advance(secs)?,
+                ["sensors"] => self.show_sensors()?,
+                ["actuators"] => self.show_actuators()?,
+                ["where"] => self.show_where()?,
+                _ => self.say("unknown command, try help")?,
+            }
+        }
+    }
+
+    fn say(&mut self, text: &str) -> Result<(), String> {
+        writeln!(self.out, "{}", text).map_err(|e| e.to_string())
+    }
+
+    fn show_where(&mut self) -> Result<(), String> {
+        if self.stopped {
+            return self.say("the flow has stopped");
+        }
+        match self.interp.next_statement() {
+            Some(stmt) => {
+                let text = format!(
+                    "block {}, line {}: {}",
+                    self.interp.block,
+                    stmt.line,
+                    describe(&stmt.op)
+                );
+                self.say(&text)
+            }
+            None => self.say(&format!("finished in block {}", self.interp.block)),
+        }
+    }
+
+    // run one statement, returning what stopped the flow from going further if anything did
+    fn exec(&mut self) -> Result<Option<Status>, String> {
+        if self.blocked {
+            if let Err(e) = self.interp.sense(&mut self.io) {
+                return self.halt(e).map(|_| None);
+            }
+            self.blocked = false;
+        }
+        let stmt = self.interp.next_statement();
+        let result = self.interp.exec(&mut self.io);
+        self.report_events()?;
+        if let Some(stmt) = stmt {
+            if let Operation::Wait { .. } | Operation::IfElse { .. } = stmt.op {
+                if result.is_ok() {
+                    self.explain(stmt)?;
+                }
+            }
+        }
+        match result {
+            Ok(Some(Status::Finished)) => {
+                self.stopped = true;
+                self.say(&format!("finished in block {}", self.interp.block))?;
+                Ok(Some(Status::Finished))
+            }
+            Ok(Some(status)) => {
+                self.blocked = true;
+                Ok(Some(status))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => self.halt(e).map(|_| None),
+        }
+    }
+
+    fn halt(&mut self, error: String) -> Result<(), String> {
+        self.stopped = true;
+        self.say(&format!("error: {}", error))?;
+        let result = self.interp.fail_safe(&mut self.io);
+        self.report_events()?;
+        if let Err(e) = result {
+            self.say(&format!("couldn't apply fail-safe values: {}", e))?;
+        }
+        Ok(())
+    }
+
+    fn step(&mut self) -> Result<(), String> {
+        if self.stopped {
+            return self.say("the flow has stopped");
+        }
+        if let Some(Status::Waiting) = self.exec()? {
+            self.say("waiting")?;
+        }
+        if !self.stopped {
+            self.show_where()?;
+        }
+        Ok(())
+    }
+
+    // keep running, skipping the clock ahead through waits the way a simulated run does
+    fn resume(&mut self) -> Result<(), String> {
+        if self.stopped {
+            return self.say("the flow has stopped");
+        }
+        let mut ops = 0;
+        loop {
+            ops += 1;
+            match self.exec()? {
+                _ if self.stopped => return Ok(()),
+                _ if ops > MAX_OPS_PER_STEP => {
+                    self.say("busy looping without reaching a wait")?;
+                    return self.show_where();
+                }
+                Some(Status::Waiting) => {
+                    ops = 0;
+                    let next = self
+                        .interp
+                        .wakeup()
+                        .into_iter()
+                        .chain(self.io.next_change())
+                        .reduce(f64::min);
+                    match next {
+                        Some(time) if time <= MAX_SIMULATED_SECONDS => self.interp.time = time,
+                        Some(_) => {
+                            return self.say(&format!(
+                                "stopped after {}s of simulated time",
+                                MAX_SIMULATED_SECONDS
+                            ))
+                        }
+                        None => {
+                            self.say("waiting forever")?;
+                            return self.show_where();
+                        }
+                    }
+                    // a wait being retried isn't a new visit to its line
+                    continue;
+                }
+                _ => {}
+            }
+            if let Some(stmt) = self.interp.next_statement() {
+                if self.at_break(stmt) {
+                    self.say("breakpoint")?;
+                    return self.show_where();
+                }
+            }
+        }
+    }
+
+    fn at_break(&self, stmt: &Statement) -> bool {
+        if self.lines.contains(&stmt.line) {
+            return true;
+        }
+        // a block's breakpoint is hit on its first statement
+        self.blocks.contains(&self.interp.block)
+            && std::ptr::eq(stmt, &self.ast.blocks[&self.interp.block].ops[0])
+    }
+
+    fn add_break(&mut self, at: &str) -> Result<(), String> {
+        match at.parse::<usize>() {
+            Ok(line) if self.ast.blocks.values().any(|b| has_line(&b.ops, line)) => {
+                self.lines.insert(line);
+                self.say(&format!("breakpoint at line {}", line))
+            }
+            Ok(line) => self.say(&format!("no statement on line {}", line)),
+            Err(_) if self.ast.blocks.contains_key(at) => {
+                self.blocks.insert(at.to_string());
+                self.say(&format!("breakpoint at block {}", at))
+            }
+            Err(_) => self.say(&format!("no block or line {}", at)),
+        }
+    }
+
+    fn remove_break(&mut self, at: &str) -> Result<(), String> {
+        let removed = match at.parse::<usize>() {
+            Ok(line) => self.lines.remove(&line),
+            Err(_) => self.blocks.remove(at),
+        };
+        if removed {
+            self.say(&format!("deleted breakpoint at {}", at))
+        } else {
+            self.say(&format!("no breakpoint at {}", at))
+        }
+    }
+
+    fn sensor(&self, name: &str) -> Option<&'a Sensor> {
+        match self.ast.devices.get(name) {
+            Some(Device::Sensor(sens)) => Some(sens),
+            _ => None,
+        }
+    }
+
+    fn hold(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let sens = match self.sensor(name) {
+            Some(sens) => sens,
+            None => return self.say(&format!("no sensor {}", name)),
+        };
+        let val = match sens.kind.parse(value) {
+            Some(val) => val,
+            None => return self.say(&format!("invalid value {} for {}", value, name)),
+        };
+        self.io.values.insert(name.to_string(), val);
+        // the flow sees the new reading straight away rather than at its next wait
+        self.interp.sensors.insert(name.to_string(), val);
+        self.say(&format!("holding {} at {}", name, sens.show(val)))
+    }
+
+    fn release(&mut self, name: &str) -> Result<(), String> {
+        if self.io.values.remove(name).is_some() {
+            self.blocked = true;
+            self.say(&format!("released {}", name))
+        } else {
+            self.say(&format!("{} isn't being held", name))
+        }
+    }
+
+    fn advance(&mut self, secs: &str) -> Result<(), String> {
+        match secs.parse::<f64>() {
+            Ok(secs) if secs >= 0.0 => {
+                self.interp.time += secs;
+                self.blocked = true;
+                self.say(&format!("time {}s", self.interp.time))
+            }
+            _ => self.say(&format!("invalid number of seconds {}", secs)),
+        }
+    }
+
+    fn show_sensors(&mut self) -> Result<(), String> {
+        for sens in self.ast.sensors() {
+            let value = match self.interp.sensors.get(&sens.name) {
+                Some(val) => sens.show(*val),
+                None => "no reading".to_string(),
+            };
+            let held = if self.io.values.contains_key(&sens.name) {
+                " (held)"
+            } else {
+                ""
+            };
+            self.say(&format!("{:<16} {}{}", sens.name, value, held))?;
+        }
+        Ok(())
+    }
+
+    fn show_actuators(&mut self) -> Result<(), String> {
+        for act in self.ast.actuators() {
+            let value = match self.interp.actuators.get(&act.name) {
+                Some(val) => act.show(*val),
+                None => "unset".to_string(),
+            };
+            let failsafe = match act.failsafe {
+                Some(val) => format!("  failsafe {}", act.show(val)),
+                None => String::new(),
+            };
+            self.say(&format!("{:<16} {}{}", act.name, value, failsafe))?;
+        }
+        Ok(())
+    }
+
+    fn report_events(&mut self) -> Result<(), String> {
+        let ast = self.ast;
+        let events = self.interp.take_events();
+        for line in events.iter().filter_map(|e| e.show(ast)) {
+            if self.interp.time != self.shown_time {
+                self.shown_time = self.interp.time;
+                self.say(&format!("time {}s", self.interp.time))?;
+            }
+            self.say(&line)?;
+        }
+        Ok(())
+    }
+
+    // show how each part of the condition just evaluated came out, marking the parts that decided
+    // the outcome
+    fn explain(&mut self, stmt: &Statement) -> Result<(), String> {
+        let parts = self.interp.explanation.clone();
+        let outcome = match parts.first().and_then(|p| p.holds) {
+            Some(true) => "holds",
+            _ => "doesn't hold",
+        };
+        self.say(&format!("condition on line {} {}:", stmt.line, outcome))?;
+        let deciding = deciding(&parts);
+        for (idx, part) in parts.iter().enumerate() {
+            let holds = match part.holds {
+                Some(holds) => holds.to_string(),
+                None => "error".to_string(),
+            };
+            let is_leaf = parts
+                .get(idx + 1)
+                .is_none_or(|next| next.depth <= part.depth);
+            let mark = if is_leaf && deciding[idx] { "  <-" } else { "" };
+            let indent = "  ".repeat(part.depth + 1);
+            self.say(&format!("{}{}: {}{}", indent, part.condition, holds, mark))?;
+        }
+        Ok(())
+    }
+}
+
+// which parts of an explained condition decided its outcome: the one part that settled an any or
+// all early, or every part when all of them had to be looked at
+fn deciding(parts: &[Explained]) -> Vec<bool> {
+    let mut deciding = vec![false; parts.len()];
+    if !parts.is_empty() {
+        deciding[0] = true;
+    }
+    for idx in 0..parts.len() {
+        let part = &parts[idx];
+        let children: Vec<usize> = (idx + 1..parts.len())
+            .take_while(|&i| parts[i].depth > part.depth)
+            .filter(|&i| parts[i].depth == part.depth + 1)
+            .collect();
+        let settled_early = match (part.condition.as_str(), part.holds) {
+            ("all", Some(false)) | ("any", Some(true)) => part.holds,
+            _ => None,
+        };
+        for child in children {
+            deciding[child] = deciding[idx]
+                && match settled_early {
+                    Some(holds) => parts[child].holds == Some(holds),
+                    None => true,
+                };
+        }
+    }
+    deciding
+}
+
+fn has_line(ops: &[Statement], line: usize) -> bool {
+    ops.iter().any(|stmt| {
+        stmt.line == line
+            || match &stmt.op {
+                Operation::IfElse {
+                    if_actions,
+                    else_actions,
+                    ..
+                } => {
+                    has_line(if_actions, line)
+                        || else_actions.as_ref().is_some_and(|a| has_line(a, line))
+                }
+                _ => false,
+            }
+    })
+}
+
+fn describe(op: &Operation) -> String {
+    match op {
+        Operation::Set { actuator, value } => {
+            format!("set {} {}", actuator.name, actuator.show(*value))
+        }
+        Operation::Wait { condition } => format!("wait: {}", condition),
+        Operation::Delay { duration } => format!("wait {}s", duration),
+        Operation::Start { timer } => format!("start {}", timer),
+        Operation::IfElse { if_condition, .. } => format!("if: {}", if_condition),
+        Operation::Goto { dest } => format!("goto {}", dest),
+    }
+}
+
+// debug interactively on the terminal
+pub fn debug(ast: &AST, backend: Box<dyn DeviceBackend>) -> Result<(), String> {
+    let stdin = io::stdin();
+    let mut debugger = Debugger::new(ast, backend, io::stdout())?;
+    debugger.say("type help for commands")?;
+    debugger.repl(stdin.lock())
+}
@@ -0,0 +1,45 @@
+use crate::ast::{Operation, AST};
+use crate::coverage::{self, Coverage};
+
+const UNTESTED: &str = "color=red, style=\"filled,dashed\", fillcolor=\"#f4cccc\"";
+const TESTED: &str = "style=filled, fillcolor=\"#d9ead3\"";
+
+// the block graph as a Graphviz digraph: a node per block and an edge per goto, labelled with the
+// line it's on. With coverage, blocks never entered and gotos never taken are drawn in red.
+pub fn graph(ast: &AST, name: &str, coverage: Option<&Coverage>) -> String {
+    let mut blocks: Vec<(&String, usize)> = ast.blocks.iter().map(|(n, b)| (n, b.line)).collect();
+    blocks.sort_by_key(|(_, line)| *line);
+
+    let mut out = format!("digraph \"{}\" {{\n    node [shape=box];\n", name);
+    out += "    \"\" [shape=point];\n";
+    out += &format!("    \"\" -> \"{}\";\n", ast.first_block_name);
+    for (block, line) in &blocks {
+        let style = match coverage {
+            Some(coverage) if coverage.blocks.contains_key(*block) => format!(", {}", TESTED),
+            Some(_) => format!(", {}", UNTESTED),
+            None => String::new(),
+        };
+        out += &format!(
+            "    \"{}\" [label=\"{}\\nline {}\"{}];\n",
+            block, block, line, style
+        );
+    }
+    for (block, _) in &blocks {
+        for stmt in coverage::statements(&ast.blocks[*block].ops) {
+            if let Operation::Goto { dest } = &stmt.op {
+                let style = match coverage {
+                    Some(coverage) if !coverage.statements.contains_key(&stmt.line) => {
+                        ", color=red, style=dashed"
+                    }
+                    _ => "",
+                };
+                out += &format!(
+                    "    \"{}\" -> \"{}\" [label=\"line {}\"{}];\n",
+                    block, dest, stmt.line, style
+                );
+            }
+        }
+    }
+    out += "}\n";
+    out
+}
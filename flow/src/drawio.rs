@@ -0,0 +1,643 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::ast;
+use crate::check::{self, Severity};
+use crate::pretty;
+use crate::token::{self, Token};
+use crate::units;
+
+// a draw.io flowchart turned into a flow, and the shapes that couldn't be part of it
+pub struct Import {
+    pub flow: String,
+    pub untranslated: Vec<String>,
+}
+
+// what a shape on the diagram stands for
+enum Shape {
+    // lines of flow source, each indented relative to the box
+    Process(Vec<(usize, String)>),
+    // the condition, or None if it couldn't be translated
+    Decision(Option<String>),
+    Terminal,
+    Other,
+}
+
+struct Node {
+    id: String,
+    label: Vec<String>,
+    shape: Shape,
+    next: Option<usize>,
+    no: Option<usize>,
+}
+
+// a sensor, actuator or timer mentioned on the diagram, and how it's used
+#[derive(Default)]
+struct Usage {
+    set: bool,
+    started: bool,
+    numeric: bool,
+    unit: Option<&'static str>,
+    symbols: Vec<String>,
+}
+
+// process boxes are `set`, `wait` and `start` statements, one per line, diamonds are `if`s whose
+// yes and no arrows lead to the two branches, and ellipses or terminators mark where the flow
+// starts and ends. Anywhere arrows meet becomes its own block, reached with a goto.
+pub fn import(xml: &str) -> Result<Import, String> {
+    let cells = cells(xml)?;
+    let mut untranslated: Vec<String> = Vec::new();
+
+    // labels drawn on arrows are their own cells, tied to the arrow by their parent
+    let mut edge_labels: HashMap<&str, String> = HashMap::new();
+    for cell in &cells {
+        if cell.get("vertex").map(String::as_str) == Some("1") && shape_of(cell) == "edgeLabel" {
+            if let Some(parent) = cell.get("parent") {
+                edge_labels.insert(parent, label(cell).join(" "));
+            }
+        }
+    }
+
+    let mut nodes: Vec<Node> = Vec::new();
+    let mut index: HashMap<&str, usize> = HashMap::new();
+    for cell in &cells {
+        if cell.get("vertex").map(String::as_str) != Some("1") {
+            continue;
+        }
+        let shape = shape_of(cell);
+        let lines = label(cell);
+        let shape = match shape {
+            "edgeLabel" => continue,
+            "" | "process" | "mxgraph.flowchart.process" => Shape::Process(Vec::new()),
+            "rhombus" | "mxgraph.flowchart.decision" => Shape::Decision(None),
+            "ellipse"
+            | "doubleEllipse"
+            | "mxgraph.flowchart.terminator"
+            | "mxgraph.flowchart.start_1"
+            | "mxgraph.flowchart.start_2" => Shape::Terminal,
+            _ => Shape::Other,
+        };
+        index.insert(
+            cell.get("id").map(String::as_str).unwrap_or(""),
+            nodes.len(),
+        );
+        nodes.push(Node {
+            id: cell.get("id").cloned().unwrap_or_default(),
+            label: lines,
+            shape,
+            next: None,
+            no: None,
+        });
+    }
+    for node in &nodes {
+        if let Shape::Other = node.shape {
+            untranslated.push(format!(
+                "{}: isn't a process box, decision or terminator",
+                describe(node)
+            ));
+        }
+    }
+
+    // arrows out of diamonds are sorted into yes and no; anything else only has one way out
+    let mut unlabelled: Vec<(usize, usize, &str)> = Vec::new();
+    for cell in &cells {
+        if cell.get("edge").map(String::as_str) != Some("1") {
+            continue;
+        }
+        let id = cell.get("id").map(String::as_str).unwrap_or("");
+        let ends = (
+            cell.get("source").and_then(|s| index.get(s.as_str())),
+            cell.get("target").and_then(|t| index.get(t.as_str())),
+        );
+        let (from, to) = match ends {
+            (Some(from), Some(to)) => (*from, *to),
+            _ => {
+                untranslated.push(format!("arrow {}: isn't connected at both ends", id));
+                continue;
+            }
+        };
+        let text = match edge_labels.get(id) {
+            Some(text) => text.clone(),
+            None => label(cell).join(" "),
+        };
+        let node = &mut nodes[from];
+        if let Shape::Decision(_) = node.shape {
+            let slot = match text.to_lowercase().as_str() {
+                "yes" | "y" | "true" => &mut node.next,
+                "no" | "n" | "false" => &mut node.no,
+                "" => {
+                    unlabelled.push((from, to, id));
+                    continue;
+                }
+                _ => {
+                    untranslated.push(format!("arrow {}: isn't labelled yes or no", id));
+                    continue;
+                }
+            };
+            if slot.is_some() {
+                untranslated.push(format!("arrow {}: is a second way out of a decision", id));
+            } else {
+                *slot = Some(to);
+            }
+        } else if node.next.is_some() {
+            untranslated.push(format!("arrow {}: is a second way out of a shape", id));
+        } else {
+            node.next = Some(to);
+        }
+    }
+    // an unlabelled arrow out of a diamond takes whichever of yes and no is left
+    for (from, to, id) in unlabelled {
+        let node = &mut nodes[from];
+        match (node.next, node.no) {
+            (Some(_), None) => node.no = Some(to),
+            (None, Some(_)) => node.next = Some(to),
+            _ => untranslated.push(format!("arrow {}: isn't labelled yes or no", id)),
+        }
+    }
+
+    // work out the devices from everything on the diagram, then check each line against them
+    let mut devices: BTreeMap<String, Usage> = BTreeMap::new();
+    for node in &nodes {
+        match node.shape {
+            Shape::Process(_) => {
+                for line in &node.label {
+                    infer_statement(&tokenize(line), &mut devices);
+                }
+            }
+            Shape::Decision(_) => {
+                let condition = condition_text(&node.label.join(" "));
+                infer_condition(&tokenize(&condition), &mut devices);
+            }
+            _ => {}
+        }
+    }
+    let header = declarations(&devices);
+    for node in &mut nodes {
+        let translated = match node.shape {
+            Shape::Process(_) => {
+                let mut lines = Vec::new();
+                for line in &node.label {
+                    match statement(&header, line) {
+                        Ok(stmt) => lines.extend(stmt),
+                        Err(e) => untranslated.push(format!("{}: {}", describe(node), e)),
+                    }
+                }
+                Shape::Process(lines)
+            }
+            Shape::Decision(_) => {
+                let condition = condition_text(&node.label.join(" "));
+                match validate(
+                    &header,
+                    &[(0, String::from("wait:")), (1, dash(&condition))],
+                ) {
+                    Ok(()) => Shape::Decision(Some(condition)),
+                    Err(e) => {
+                        untranslated.push(format!("{}: {}", describe(node), e));
+                        Shape::Decision(None)
+                    }
+                }
+            }
+            _ => continue,
+        };
+        node.shape = translated;
+    }
+
+    // a start terminator points at the first shape, and otherwise it's the first with no way in,
+    // or failing that the first drawn
+    let mut incoming = vec![0; nodes.len()];
+    for node in &nodes {
+        for to in node.next.iter().chain(node.no.iter()) {
+            incoming[*to] += 1;
+        }
+    }
+    let terminal_start = nodes
+        .iter()
+        .enumerate()
+        .find(|(idx, node)| matches!(node.shape, Shape::Terminal) && incoming[*idx] == 0)
+        .and_then(|(_, node)| node.next);
+    let translatable: Vec<usize> = (0..nodes.len())
+        .filter(|idx| matches!(nodes[*idx].shape, Shape::Process(_) | Shape::Decision(_)))
+        .collect();
+    let start = terminal_start
+        .or_else(|| translatable.iter().copied().find(|idx| incoming[*idx] == 0))
+        .or_else(|| translatable.first().copied());
+    let start = match start {
+        Some(start) => start,
+        None => return Err(String::from("couldn't find where the flowchart starts")),
+    };
+
+    let mut builder = Builder {
+        nodes: &nodes,
+        incoming,
+        start,
+        names: HashMap::new(),
+        queue: Vec::new(),
+        emitted: vec![false; nodes.len()],
+    };
+    builder.name(start);
+    let mut blocks: Vec<String> = Vec::new();
+    let mut idx = 0;
+    while idx < builder.queue.len() {
+        let head = builder.queue[idx];
+        let mut lines: Vec<String> = Vec::new();
+        builder.chain(Some(head), 1, &mut lines, true);
+        blocks.push(format!(
+            "block {}\n{}endblock\n",
+            builder.names[&head],
+            lines.concat()
+        ));
+        idx += 1;
+    }
+    for idx in translatable {
+        if !builder.emitted[idx] {
+            let node = &nodes[idx];
+            untranslated.push(format!(
+                "{}: can't be reached from the start",
+                describe(node)
+            ));
+        }
+    }
+
+    let source = format!("{}{}", header, blocks.join("\n"));
+    let ast = ast::make_ast(&token::tokenize(source))
+        .map_err(|e| format!("couldn't turn the diagram into a flow: {}", e))?;
+    let errors: Vec<String> = check::check(&ast)
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .map(|d| d.to_string())
+        .collect();
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+    Ok(Import {
+        flow: pretty::print(&ast),
+        untranslated,
+    })
+}
+
+// lays the shapes out as blocks, starting a new one wherever arrows meet
+struct Builder<'a> {
+    nodes: &'a [Node],
+    incoming: Vec<usize>,
+    start: usize,
+    names: HashMap<usize, String>,
+    queue: Vec<usize>,
+    emitted: Vec<bool>,
+}
+
+impl Builder<'_> {
+    // the block starting at the shape, which gets built once everything before it is
+    fn name(&mut self, node: usize) -> String {
+        if !self.names.contains_key(&node) {
+            let name = match self.queue.len() {
+                0 => String::from("main"),
+                n => format!("step{}", n),
+            };
+            self.names.insert(node, name);
+            self.queue.push(node);
+        }
+        self.names[&node].clone()
+    }
+
+    // the shapes from here on, until the flow goes back to a block or comes to an end
+    fn chain(&mut self, mut node: Option<usize>, depth: usize, out: &mut Vec<String>, head: bool) {
+        let nodes = self.nodes;
+        let mut head = head;
+        while let Some(idx) = node {
+            if !head && (self.incoming[idx] > 1 || idx == self.start) {
+                out.push(format!("{}goto {}\n", indent(depth), self.name(idx)));
+                return;
+            }
+            head = false;
+            self.emitted[idx] = true;
+            match &nodes[idx].shape {
+                Shape::Process(lines) => {
+                    for (inner, line) in lines {
+                        out.push(format!("{}{}\n", indent(depth + inner), line));
+                    }
+                    node = nodes[idx].next;
+                }
+                Shape::Decision(Some(condition)) => {
+                    out.push(format!("{}if:\n", indent(depth)));
+                    out.push(format!("{}{}\n", indent(depth + 1), dash(condition)));
+                    self.chain(nodes[idx].next, depth + 1, out, false);
+                    if nodes[idx].no.is_some() {
+                        out.push(format!("{}else:\n", indent(depth)));
+                        self.chain(nodes[idx].no, depth + 1, out, false);
+                    }
+                    return;
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+fn indent(depth: usize) -> String {
+    "    ".repeat(depth)
+}
+
+fn dash(condition: &str) -> String {
+    format!("- {}", condition)
+}
+
+// the name of the shape the style draws, which is the bare word draw.io puts first or its shape
+fn shape_of(cell: &HashMap<String, String>) -> &str {
+    let style = cell.get("style").map(String::as_str).unwrap_or("");
+    let mut shape = "";
+    for part in style.split(';') {
+        match part.split_once('=') {
+            Some(("shape", name)) => return name,
+            Some(_) => {}
+            None if shape.is_empty() => shape = part,
+            None => {}
+        }
+    }
+    shape
+}
+
+// the lines of a cell's label, with any markup draw.io added taken out
+fn label(cell: &HashMap<String, String>) -> Vec<String> {
+    let value = cell.get("value").map(String::as_str).unwrap_or("");
+    let html = cell
+        .get("style")
+        .is_some_and(|style| style.split(';').any(|part| part == "html=1"));
+    let text = if html {
+        strip_html(value)
+    } else {
+        value.to_string()
+    };
+    text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+// how a shape shows up in the report
+fn describe(node: &Node) -> String {
+    if node.label.is_empty() {
+        format!("shape {}", node.id)
+    } else {
+        format!("shape {} \"{}\"", node.id, node.label.join(" / "))
+    }
+}
+
+// flowchart conditions tend to be questions, and drawn with the symbols for <= and >=
+fn condition_text(text: &str) -> String {
+    text.trim_end_matches('?')
+        .trim()
+        .replace('≥', ">=")
+        .replace('≤', "<=")
+}
+
+fn tokenize(line: &str) -> Vec<Token> {
+    token::tokenize(format!("{}\n", line))
+}
+
+// a line of a process box as lines of source, relative to the box's indent
+fn statement(header: &str, line: &str) -> Result<Vec<(usize, String)>, String> {
+    let line = condition_text(line);
+    let lines = match tokenize(&line).as_slice() {
+        [Token::Set, ..] | [Token::Wait, Token::Value(_), ..] => vec![(0, line)],
+        [Token::Identifier(word), ..] if word == "start" => vec![(0, line)],
+        [Token::Wait, ..] => {
+            let condition = line["wait".len()..].trim().to_string();
+            vec![(0, String::from("wait:")), (1, dash(&condition))]
+        }
+        _ => return Err(String::from("expected set, wait or start")),
+    };
+    validate(header, &lines)?;
+    Ok(lines)
+}
+
+// whether lines of source make sense with the devices found on the diagram
+fn validate(header: &str, lines: &[(usize, String)]) -> Result<(), String> {
+    let body: String = lines
+        .iter()
+        .map(|(depth, line)| format!("{}{}\n", indent(depth + 1), line))
+        .collect();
+    let source = format!("{}block check\n{}endblock\n", header, body);
+    match ast::make_ast(&token::tokenize(source)) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn infer_statement(tokens: &[Token], devices: &mut BTreeMap<String, Usage>) {
+    match tokens {
+        [Token::Set, Token::Identifier(name), rest @ ..] => {
+            let usage = devices.entry(name.clone()).or_default();
+            usage.set = true;
+            infer_value(rest, usage);
+        }
+        [Token::Identifier(word), Token::Identifier(name), ..] if word == "start" => {
+            devices.entry(name.clone()).or_default().started = true;
+        }
+        [Token::Wait, Token::Value(_), ..] => {}
+        [Token::Wait, rest @ ..] => infer_condition(rest, devices),
+        _ => {}
+    }
+}
+
+fn infer_condition(tokens: &[Token], devices: &mut BTreeMap<String, Usage>) {
+    if let [Token::Identifier(name), rest @ ..] = tokens {
+        if name == "elapsed" || name == "now" {
+            return;
+        }
+        let usage = devices.entry(name.clone()).or_default();
+        match rest {
+            [Token::Comparator(_) | Token::RisesAbove | Token::FallsBelow, value @ ..] => {
+                infer_value(value, usage)
+            }
+            // a bare device is a bool that's true
+            _ => infer_value(&[Token::Identifier(String::from("true"))], usage),
+        }
+    }
+}
+
+fn infer_value(tokens: &[Token], usage: &mut Usage) {
+    match tokens {
+        [Token::Value(_), rest @ ..] => {
+            usage.numeric = true;
+            if let [Token::Identifier(unit), ..] = rest {
+                if let (Some(unit), None) = (units::lookup(unit), usage.unit) {
+                    usage.unit = Some(unit.name);
+                }
+            }
+        }
+        [Token::Identifier(symbol), ..] if !usage.symbols.contains(symbol) => {
+            usage.symbols.push(symbol.clone());
+        }
+        _ => {}
+    }
+}
+
+// the device declarations for the flow: anything set is an actuator, anything started is a
+// timer, and everything else is a sensor. Values that aren't numbers make a bool or an enum.
+fn declarations(devices: &BTreeMap<String, Usage>) -> String {
+    let mut out = String::new();
+    for (name, usage) in devices {
+        if usage.started {
+            out += &format!("timer {}\n", name);
+            continue;
+        }
+        out += if usage.set { "actuator " } else { "sensor " };
+        out += name;
+        let boolean = usage.symbols.iter().all(|s| s == "true" || s == "false");
+        if !usage.numeric && boolean {
+            out += ": bool";
+        } else if !usage.numeric {
+            out += &format!(": enum {{{}}}", usage.symbols.join(", "));
+        } else if let Some(unit) = usage.unit {
+            out += &format!(" {}", unit);
+        }
+        out += "\n";
+    }
+    out + "\n"
+}
+
+// the attributes of every mxCell, with the id and label of any object wrapping it filled in.
+// Only what's needed of XML is understood: tags, attributes, entities and comments.
+fn cells(xml: &str) -> Result<Vec<HashMap<String, String>>, String> {
+    if xml.contains("<diagram") && !xml.contains("<mxGraphModel") {
+        return Err(String::from(
+            "the diagram is compressed; untick File > Properties > Compressed in draw.io and save it again",
+        ));
+    }
+    let mut cells = Vec::new();
+    let mut wrapper: Option<HashMap<String, String>> = None;
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        if let Some(comment) = rest.strip_prefix("!--") {
+            rest = match comment.find("-->") {
+                Some(end) => &comment[end + 3..],
+                None => return Err(String::from("unterminated comment in the diagram")),
+            };
+            continue;
+        }
+        if rest.starts_with(['/', '?', '!']) {
+            continue;
+        }
+        let name_end = rest
+            .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+            .unwrap_or(rest.len());
+        let name = &rest[..name_end];
+        let (mut attrs, len) = attributes(&rest[name_end..])?;
+        rest = &rest[name_end + len..];
+        match name {
+            "object" | "UserObject" => wrapper = Some(attrs),
+            "mxCell" => {
+                if let Some(mut wrapper) = wrapper.take() {
+                    if let Some(id) = wrapper.remove("id") {
+                        attrs.entry(String::from("id")).or_insert(id);
+                    }
+                    if let Some(label) = wrapper.remove("label") {
+                        attrs.entry(String::from("value")).or_insert(label);
+                    }
+                }
+                cells.push(attrs);
+            }
+            _ => {}
+        }
+    }
+    Ok(cells)
+}
+
+// the attributes of a tag, and how far it is to the end of the tag
+fn attributes(tag: &str) -> Result<(HashMap<String, String>, usize), String> {
+    let mut attrs = HashMap::new();
+    let mut rest = tag;
+    loop {
+        rest = rest.trim_start();
+        if rest.starts_with('>') {
+            return Ok((attrs, tag.len() - rest.len() + 1));
+        }
+        if rest.starts_with("/>") {
+            return Ok((attrs, tag.len() - rest.len() + 2));
+        }
+        let eq = match rest.find('=') {
+            Some(eq) => eq,
+            None => return Err(String::from("unterminated tag in the diagram")),
+        };
+        let name = rest[..eq].trim().to_string();
+        rest = rest[eq + 1..].trim_start();
+        let quote = match rest.chars().next() {
+            Some(quote @ ('"' | '\'')) => quote,
+            _ => {
+                return Err(format!(
+                    "expected a quoted value for {} in the diagram",
+                    name
+                ))
+            }
+        };
+        let end = match rest[1..].find(quote) {
+            Some(end) => end + 1,
+            None => return Err(format!("unterminated value for {} in the diagram", name)),
+        };
+        attrs.insert(name, unescape(&rest[1..end]));
+        rest = &rest[end + 1..];
+    }
+}
+
+fn unescape(text: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out += &rest[..amp];
+        rest = &rest[amp..];
+        let end = rest.find(';');
+        let entity = end.map(|end| &rest[1..end]);
+        let ch = match entity {
+            Some("lt") => Some('<'),
+            Some("gt") => Some('>'),
+            Some("amp") => Some('&'),
+            Some("quot") => Some('"'),
+            Some("apos") => Some('\''),
+            Some("nbsp") => Some(' '),
+            Some(code) if code.starts_with("#x") => u32::from_str_radix(&code[2..], 16)
+                .ok()
+                .and_then(char::from_u32),
+            Some(code) if code.starts_with('#') => code[1..].parse().ok().and_then(char::from_u32),
+            _ => None,
+        };
+        match (ch, end) {
+            (Some(ch), Some(end)) => {
+                out.push(ch);
+                rest = &rest[end + 1..];
+            }
+            _ => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out + rest
+}
+
+// labels with html=1 in their style are HTML, with a line break wherever one is drawn
+fn strip_html(text: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(open) = rest.find('<') {
+        out += &rest[..open];
+        let close = match rest[open..].find('>') {
+            Some(close) => open + close,
+            None => {
+                out += &rest[open..];
+                rest = "";
+                break;
+            }
+        };
+        let tag = rest[open + 1..close]
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("")
+            .to_lowercase();
+        if let "br" | "div" | "p" | "li" = tag.as_str() {
+            out.push('\n');
+        }
+        rest = &rest[close + 1..];
+    }
+    unescape(&(out + rest))
+}
@@ -0,0 +1,305 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::ast::{self, AST};
+use crate::backend::{DeviceBackend, Trace};
+use crate::check::{self, Severity};
+use crate::coverage::Coverage;
+use crate::interp::{Event, Interpreter, Status, MAX_OPS_PER_STEP, MAX_SIMULATED_SECONDS};
+use crate::token;
+
+// A test file names the flow it tests and holds any number of cases, one line each of:
+//   flow <file.fl>                          the flow under test, relative to the test file
+//   test <name>                             starts a case
+//   input <seconds> <sensor> <value>        a reading the sensor takes from then on
+//   expect <seconds> enter <block>          what the run should do, in order
+//   expect <seconds> set <actuator> <value>
+//   expect <seconds> error <message>        what should stop the run, followed by the sets that
+//                                           drove actuators to fail-safe values
+//   until <seconds>                         how much simulated time to run for
+// with # starting a comment. A case passes when the run does exactly what it expects, no more.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Case {
+    pub name: String,
+    pub line: usize,
+    // (time, sensor, value) as written, since values are only known once the flow is
+    pub inputs: Vec<(f64, String, String)>,
+    pub expected: Vec<String>,
+    pub until: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Suite {
+    pub flow: PathBuf,
+    pub cases: Vec<Case>,
+}
+
+fn seconds(text: &str) -> Option<f64> {
+    text.parse::<f64>().ok().filter(|time| *time >= 0.0)
+}
+
+// `dir` is where the test file lives, which the flow path is relative to
+pub fn parse_suite(text: &str, dir: &Path) -> Result<Suite, String> {
+    let mut flow = None;
+    let mut cases: Vec<Case> = Vec::new();
+    for (num, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let err = |msg: &str| format!("line {}: {}", num + 1, msg);
+        let (kind, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim();
+        match kind {
+            "flow" if flow.is_some() => return Err(err("flow given twice")),
+            "flow" if rest.is_empty() => return Err(err("expected a flow file")),
+            "flow" => flow = Some(dir.join(rest)),
+            "test" if rest.is_empty() => return Err(err("expected a test name")),
+            "test" => cases.push(Case {
+                name: rest.to_string(),
+                line: num + 1,
+                inputs: Vec::new(),
+                expected: Vec::new(),
+                until: MAX_SIMULATED_SECONDS,
+            }),
+            "input" | "expect" | "until" => {
+                let case = match cases.last_mut() {
+                    Some(case) => case,
+                    None => return Err(err(&format!("{} outside a test", kind))),
+                };
+                let fields: Vec<&str> = rest.splitn(3, ' ').collect();
+                match (kind, fields.as_slice()) {
+                    ("input", [time, name, value]) => match seconds(time) {
+                        Some(time) => {
+                            case.inputs
+                                .push((time, name.to_string(), value.trim().to_string()))
+                        }
+                        None => return Err(err(&format!("invalid time {}", time))),
+                    },
+                    ("expect", [time, what, ..]) if ["enter", "set", "error"].contains(what) => {
+                        match seconds(time) {
+                            // normalised the way the run's own lines are, so 2.0 matches 2
+                            Some(time) => {
+                                let (_, event) = rest.split_once(' ').unwrap();
+                                case.expected.push(format!("{} {}", time, event.trim()))
+                            }
+                            None => return Err(err(&format!("invalid time {}", time))),
+                        }
+                    }
+                    ("until", [time]) => match seconds(time) {
+                        Some(time) => case.until = time,
+                        None => return Err(err(&format!("invalid time {}", time))),
+                    },
+                    _ => return Err(err(&format!("invalid {} \"{}\"", kind, rest))),
+                }
+            }
+            _ => return Err(err(&format!("unknown entry \"{}\"", line))),
+        }
+    }
+    match flow {
+        Some(flow) => Ok(Suite { flow, cases }),
+        None => Err("no flow given".to_string()),
+    }
+}
+
+// parse and check a flow, failing on any check that's an error
+pub fn load(path: &Path) -> Result<AST, String> {
+    let code = match fs::read_to_string(path) {
+        Ok(code) => code,
+        Err(e) => return Err(format!("couldn't open {}: {}", path.display(), e)),
+    };
+    let ast = ast::make_ast(&token::tokenize(code)).map_err(|e| e.to_string())?;
+    let errors: Vec<String> = check::check(&ast)
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .map(|d| d.to_string())
+        .collect();
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+    Ok(ast)
+}
+
+fn record(ast: &AST, interp: &mut Interpreter, lines: &mut Vec<String>, coverage: &mut Coverage) {
+    let time = interp.time;
+    for event in interp.take_events() {
+        if let Event::Enter(block) = &event {
+            coverage.enter(block);
+        }
+        if let Some(line) = event.show(ast) {
+            lines.push(format!("{} {}", time, line));
+        }
+    }
+}
+
+// Interpreter::step a statement at a time, noting what each one did
+fn step(
+    interp: &mut Interpreter,
+    io: &mut dyn DeviceBackend,
+    coverage: &mut Coverage,
+) -> Result<Status, String> {
+    interp.sense(io)?;
+    for _ in 0..MAX_OPS_PER_STEP {
+        let stmt = interp.next_statement();
+        let result = interp.exec(io);
+        if let Some(stmt) = stmt {
+            coverage.record(stmt, &interp.explanation);
+        }
+        if let Some(status) = result? {
+            return Ok(status);
+        }
+    }
+    Ok(Status::Running)
+}
+
+// run a flow in simulated time up to `until`, giving what it did as `<time> <event>` lines and
+// adding what it exercised to `coverage`
+pub fn simulate(
+    ast: &AST,
+    io: &mut dyn DeviceBackend,
+    until: f64,
+    coverage: &mut Coverage,
+) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut interp = match Interpreter::new(ast, io) {
+        Ok(interp) => interp,
+        Err(e) => return vec![format!("0 error {}", e)],
+    };
+    interp.explain = true;
+    loop {
+        let status = step(&mut interp, io, coverage);
+        record(ast, &mut interp, &mut lines, coverage);
+        if let Some(error) = interp.halted(&status) {
+            lines.push(format!("{} error {}", interp.time, error));
+            // a write failing part way through still leaves the sets that went through
+            let _ = interp.fail_safe(io);
+            record(ast, &mut interp, &mut lines, coverage);
+            return lines;
+        }
+        if status != Ok(Status::Waiting) {
+            return lines;
+        }
+        let next = interp
+            .wakeup()
+            .into_iter()
+            .chain(io.next_change())
+            .reduce(f64::min);
+        match next {
+            Some(time) if time <= until => interp.time = time,
+            _ => return lines,
+        }
+    }
+}
+
+// what a case's run did differently from what it expected, or nothing if it passed
+pub fn run_case(ast: &AST, case: &Case, coverage: &mut Coverage) -> Result<Vec<String>, String> {
+    let sensors = ast.sensors();
+    let mut samples = Vec::new();
+    for (time, name, value) in &case.inputs {
+        let sample = sensors
+            .iter()
+            .find(|s| s.name == *name)
+            .and_then(|sens| Some((*time, name.clone(), sens.kind.parse(value)?)));
+        match sample {
+            Some(sample) => samples.push(sample),
+            None => return Err(format!("invalid input {} {}", name, value)),
+        }
+    }
+    let actual = simulate(ast, &mut Trace::new(samples), case.until, coverage);
+    if actual == case.expected {
+        return Ok(Vec::new());
+    }
+    Ok(diff(&case.expected, &actual))
+}
+
+// a line diff of expected against actual, with - for lines only expected and + for lines only
+// seen, keeping as many lines in common as it can
+pub fn diff(expected: &[String], actual: &[String]) -> Vec<String> {
+    // common[i][j] is the longest common run of expected[i..] and actual[j..]
+    let mut common = vec![vec![0; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            common[i][j] = if expected[i] == actual[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            lines.push(format!("  {}", expected[i]));
+            i += 1;
+            j += 1;
+        } else if i < expected.len() && (j == actual.len() || common[i + 1][j] >= common[i][j + 1])
+        {
+            lines.push(format!("- {}", expected[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+ {}", actual[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub name: String,
+    // why the case failed, as a diff or an error, or None if it passed
+    pub failure: Option<Vec<String>>,
+}
+
+// what running a test file found
+#[derive(Debug, Clone, PartialEq)]
+pub struct Results {
+    pub flow: PathBuf,
+    pub outcomes: Vec<Outcome>,
+    pub coverage: Coverage,
+}
+
+// run every case in a test file against its flow
+pub fn run_file(path: &Path) -> Result<Results, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => return Err(format!("couldn't open {}: {}", path.display(), e)),
+    };
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let suite = parse_suite(&text, dir)?;
+    let ast = load(&suite.flow).map_err(|e| format!("{}: {}", suite.flow.display(), e))?;
+    let mut outcomes = Vec::new();
+    let mut coverage = Coverage::default();
+    for case in &suite.cases {
+        let failure = match run_case(&ast, case, &mut coverage) {
+            Ok(diff) if diff.is_empty() => None,
+            Ok(diff) => Some(diff),
+            Err(e) => Some(vec![format!("line {}: {}", case.line, e)]),
+        };
+        outcomes.push(Outcome {
+            name: case.name.clone(),
+            failure,
+        });
+    }
+    Ok(Results {
+        flow: suite.flow,
+        outcomes,
+        coverage,
+    })
+}
+
+// the test files in a directory, in name order
+pub fn find(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => return Err(format!("couldn't read {}: {}", dir.display(), e)),
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "fltest"))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
@@ -0,0 +1,254 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::ast::{Operation, Statement, AST};
+use crate::check::{Diagnostic, Severity};
+
+// a goto from one block to another
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edge {
+    pub dest: String,
+    pub line: usize,
+    // whether some way of getting to the goto from the start of the block passes no wait
+    pub busy: bool,
+}
+
+// the blocks of a flow and the gotos between them, as written, whatever the conditions on them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Graph {
+    // block names in the order they're declared
+    pub blocks: Vec<String>,
+    pub lines: HashMap<String, usize>,
+    pub edges: HashMap<String, Vec<Edge>>,
+    // blocks some path through which runs off the end, finishing the flow
+    pub terminal: BTreeSet<String>,
+}
+
+// gather the gotos in `ops`, returning None if every path ends in one, or else whether every path
+// that runs off the end waits on the way
+fn walk(ops: &[Statement], waited: bool, edges: &mut Vec<Edge>) -> Option<bool> {
+    let mut waited = waited;
+    for stmt in ops {
+        match &stmt.op {
+            Operation::Wait { .. } | Operation::Delay { .. } => waited = true,
+            Operation::Goto { dest } => {
+                edges.push(Edge {
+                    dest: dest.clone(),
+                    line: stmt.line,
+                    busy: !waited,
+                });
+                return None;
+            }
+            Operation::IfElse {
+                if_actions,
+                else_actions,
+                ..
+            } => {
+                let taken = walk(if_actions, waited, edges);
+                let not_taken = match else_actions {
+                    Some(actions) => walk(actions, waited, edges),
+                    None => Some(waited),
+                };
+                waited = match (taken, not_taken) {
+                    (Some(a), Some(b)) => a && b,
+                    (Some(a), None) | (None, Some(a)) => a,
+                    (None, None) => return None,
+                };
+            }
+            Operation::Set { .. } | Operation::Start { .. } => {}
+        }
+    }
+    Some(waited)
+}
+
+impl Graph {
+    pub fn new(ast: &AST) -> Graph {
+        let mut blocks: Vec<String> = ast.blocks.keys().cloned().collect();
+        blocks.sort_by_key(|name| ast.blocks[name].line);
+        let mut graph = Graph {
+            lines: blocks
+                .iter()
+                .map(|name| (name.clone(), ast.blocks[name].line))
+                .collect(),
+            blocks,
+            edges: HashMap::new(),
+            terminal: BTreeSet::new(),
+        };
+        for name in &graph.blocks {
+            let mut edges = Vec::new();
+            if walk(&ast.blocks[name].ops, false, &mut edges).is_some() {
+                graph.terminal.insert(name.clone());
+            }
+            graph.edges.insert(name.clone(), edges);
+        }
+        graph
+    }
+
+    // blocks the flow can get to from `start` by following gotos
+    pub fn reachable(&self, start: &str) -> BTreeSet<String> {
+        let mut seen = BTreeSet::new();
+        let mut stack = vec![start.to_string()];
+        while let Some(name) = stack.pop() {
+            if !self.lines.contains_key(&name) || !seen.insert(name.clone()) {
+                continue;
+            }
+            stack.extend(self.edges[&name].iter().map(|e| e.dest.clone()));
+        }
+        seen
+    }
+
+    // strongly connected components over the edges `keep` lets through, in declaration order,
+    // leaving out single blocks that don't go to themselves
+    pub fn cycles(&self, keep: &dyn Fn(&Edge) -> bool) -> Vec<Vec<String>> {
+        let mut tarjan = Tarjan {
+            graph: self,
+            keep,
+            index: HashMap::new(),
+            low: HashMap::new(),
+            stack: Vec::new(),
+            found: Vec::new(),
+        };
+        for name in &self.blocks {
+            if !tarjan.index.contains_key(name) {
+                tarjan.visit(name);
+            }
+        }
+        let mut found: Vec<Vec<String>> = tarjan
+            .found
+            .into_iter()
+            .filter(|c| c.len() > 1 || self.out(&c[0], keep).any(|e| e.dest == c[0]))
+            .collect();
+        for component in &mut found {
+            component.sort_by_key(|name| self.lines[name]);
+        }
+        found.sort_by_key(|c| self.lines[&c[0]]);
+        found
+    }
+
+    fn out<'g>(
+        &'g self,
+        name: &str,
+        keep: &'g dyn Fn(&Edge) -> bool,
+    ) -> impl Iterator<Item = &'g Edge> + 'g {
+        self.edges[name]
+            .iter()
+            .filter(move |e| keep(e) && self.lines.contains_key(&e.dest))
+    }
+}
+
+struct Tarjan<'g> {
+    graph: &'g Graph,
+    keep: &'g dyn Fn(&Edge) -> bool,
+    index: HashMap<String, usize>,
+    low: HashMap<String, usize>,
+    stack: Vec<String>,
+    found: Vec<Vec<String>>,
+}
+
+impl Tarjan<'_> {
+    fn visit(&mut self, name: &str) {
+        let index = self.index.len();
+        self.index.insert(name.to_string(), index);
+        self.low.insert(name.to_string(), index);
+        self.stack.push(name.to_string());
+        let graph = self.graph;
+        for edge in graph.out(name, self.keep) {
+            if !self.index.contains_key(&edge.dest) {
+                self.visit(&edge.dest);
+                let low = self.low[name].min(self.low[&edge.dest]);
+                self.low.insert(name.to_string(), low);
+            } else if self.stack.contains(&edge.dest) {
+                let low = self.low[name].min(self.index[&edge.dest]);
+                self.low.insert(name.to_string(), low);
+            }
+        }
+        if self.low[name] == index {
+            let at = self.stack.iter().position(|n| n == name).unwrap();
+            self.found.push(self.stack.split_off(at));
+        }
+    }
+}
+
+fn list(names: &[String]) -> String {
+    names.join(", ")
+}
+
+// find blocks the flow can't get to, blocks it can finish in, and loops it can't get out of
+pub fn analyze(ast: &AST) -> Vec<Diagnostic> {
+    let graph = Graph::new(ast);
+    let mut diags = Vec::new();
+
+    for name in &graph.blocks {
+        for edge in &graph.edges[name] {
+            if !graph.lines.contains_key(&edge.dest) {
+                diags.push(Diagnostic {
+                    severity: Severity::Error,
+                    message: format!("block {}: goto to unknown block {}", name, edge.dest),
+                    lines: vec![edge.line],
+                });
+            }
+        }
+    }
+
+    let reachable = graph.reachable(&ast.first_block_name);
+    for name in &graph.blocks {
+        if !reachable.contains(name) {
+            diags.push(Diagnostic {
+                severity: Severity::Warning,
+                message: format!("block {} is never reached", name),
+                lines: vec![graph.lines[name]],
+            });
+        } else if graph.terminal.contains(name) {
+            diags.push(Diagnostic {
+                severity: Severity::Note,
+                message: format!("block {} can finish the flow by running off its end", name),
+                lines: vec![graph.lines[name]],
+            });
+        }
+    }
+
+    // gotos with no wait before them, going round in a circle, never let time pass
+    for cycle in graph.cycles(&|edge| edge.busy) {
+        if !reachable.contains(&cycle[0]) {
+            continue;
+        }
+        let mut lines: Vec<usize> = cycle.iter().map(|name| graph.lines[name]).collect();
+        for name in &cycle {
+            lines.extend(
+                graph.edges[name]
+                    .iter()
+                    .filter(|e| e.busy && cycle.contains(&e.dest))
+                    .map(|e| e.line),
+            );
+        }
+        lines.sort();
+        diags.push(Diagnostic {
+            severity: Severity::Warning,
+            message: format!(
+                "blocks {} can loop through gotos without waiting, which never lets time pass",
+                list(&cycle)
+            ),
+            lines,
+        });
+    }
+
+    // once in a loop nothing leads out of, the flow stays there; that's expected of the loop
+    // around the first block, but anywhere else leaves blocks before it never run again
+    let all = |_: &Edge| true;
+    for cycle in graph.cycles(&all) {
+        if !reachable.contains(&cycle[0]) || cycle.contains(&ast.first_block_name) {
+            continue;
+        }
+        let leaves = cycle.iter().any(|name| {
+            graph.terminal.contains(name)
+                || graph.edges[name].iter().any(|e| !cycle.contains(&e.dest))
+        });
+        if !leaves {
+            diags.push(Diagnostic {
+                severity: Severity::Warning,
+                message: format!("blocks {} form a loop the flow never leaves", list(&cycle)),
+                lines: cycle.iter().map(|name| graph.lines[name]).collect(),
+            });
+        }
+    }
+    diags
+}
@@ -0,0 +1,470 @@
+use std::collections::HashMap;
+
+use crate::ast::{Actuator, Clock, Comparator, Condition, Device, Operation, Statement, AST};
+use crate::backend::DeviceBackend;
+use crate::lower;
+
+#[derive(Debug)]
+pub enum Event {
+    Enter(String),
+    Set(String, f64),
+    // the outcome of a wait or if condition each time it's evaluated
+    Eval(String, bool),
+}
+
+impl Event {
+    // how the event reads in a run's output, None for events that aren't shown
+    pub fn show(&self, ast: &AST) -> Option<String> {
+        match self {
+            Event::Enter(block) => Some(format!("enter {}", block)),
+            Event::Set(name, val) => match ast.devices.get(name) {
+                Some(Device::Actuator(act)) => Some(format!("set {} {}", name, act.show(*val))),
+                _ => Some(format!("set {} {}", name, val)),
+            },
+            Event::Eval(..) => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    // ran out of the per-step budget without reaching a wait
+    Running,
+    Waiting,
+    Finished,
+}
+
+// one part of a condition and what it evaluated to, None if it couldn't be evaluated
+#[derive(Debug, Clone, PartialEq)]
+pub struct Explained {
+    pub depth: usize,
+    pub condition: String,
+    pub holds: Option<bool>,
+}
+
+// upper bound on operations per step so a busy loop can't hang the caller, the same one the
+// runtime holds bytecode to
+pub use flow_runtime::vm::MAX_OPS_PER_STEP;
+
+// how far a simulation lets the clock run before giving up on the flow finishing
+pub const MAX_SIMULATED_SECONDS: f64 = 3600.0;
+
+pub struct Interpreter<'a> {
+    ast: &'a AST,
+    pub block: String,
+    frames: Vec<(&'a [Statement], usize)>,
+    // readings taken at the start of the current step
+    pub sensors: HashMap<String, f64>,
+    // the last value written to each actuator
+    pub actuators: HashMap<String, f64>,
+    // seconds since the flow started, advanced by whoever drives the interpreter
+    pub time: f64,
+    // when the current block was entered
+    entered: f64,
+    // when each timer was last started
+    timers: HashMap<String, f64>,
+    // when the delay currently being waited on ends
+    deadline: Option<f64>,
+    // times at which a clock condition evaluated this step will change
+    alarms: Vec<f64>,
+    // whether each hysteresis condition is currently switched on, by condition id
+    latches: HashMap<usize, bool>,
+    // what each edge condition's inner condition was last time it was evaluated
+    edges: HashMap<usize, bool>,
+    // since when each duration condition's inner condition has held, and for how long it needs to
+    held: HashMap<usize, (f64, f64)>,
+    events: Vec<Event>,
+    // whether to note how each part of wait and if conditions evaluates
+    pub explain: bool,
+    // how the last wait or if condition evaluated, in the order its parts were evaluated
+    pub explanation: Vec<Explained>,
+    // how deep into the condition being explained evaluation is, None when not explaining
+    depth: Option<usize>,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(ast: &'a AST, io: &mut dyn DeviceBackend) -> Result<Interpreter<'a>, String> {
+        let mut interp = Interpreter {
+            ast,
+            block: String::new(),
+            frames: Vec::new(),
+            sensors: HashMap::new(),
+            actuators: HashMap::new(),
+            time: 0.0,
+            entered: 0.0,
+            timers: HashMap::new(),
+            deadline: None,
+            alarms: Vec::new(),
+            latches: HashMap::new(),
+            edges: HashMap::new(),
+            held: HashMap::new(),
+            events: Vec::new(),
+            explain: false,
+            explanation: Vec::new(),
+            depth: None,
+        };
+        for act in ast.actuators() {
+            if let Some(val) = act.init {
+                io.write_actuator(act, val)?;
+                interp.actuators.insert(act.name.clone(), val);
+                interp.events.push(Event::Set(act.name.clone(), val));
+            }
+        }
+        interp.enter(&ast.first_block_name)?;
+        Ok(interp)
+    }
+
+    // drive actuators to their fail-safe values after an error or abort, bypassing the safety checks
+    // every actuator is attempted even if writing an earlier one fails
+    pub fn fail_safe(&mut self, io: &mut dyn DeviceBackend) -> Result<(), String> {
+        self.frames.clear();
+        self.deadline = None;
+        let mut result = Ok(());
+        for act in self.ast.actuators() {
+            if let Some(val) = act.failsafe {
+                match io.write_actuator(act, val) {
+                    Ok(()) => {
+                        self.actuators.insert(act.name.clone(), val);
+                        self.events.push(Event::Set(act.name.clone(), val));
+                    }
+                    Err(e) if result.is_ok() => result = Err(e),
+                    Err(_) => {}
+                }
+            }
+        }
+        result
+    }
+
+    // the next time something changes even if no sensor does: a delay ending or a duration
+    // condition being met
+    pub fn wakeup(&self) -> Option<f64> {
+        self.held
+            .values()
+            .map(|(since, duration)| since + duration)
+            .chain(self.alarms.iter().copied())
+            .filter(|due| *due > self.time)
+            .chain(self.deadline)
+            .reduce(f64::min)
+    }
+
+    // why the flow can't carry on after a step, if it can't
+    pub fn halted(&self, status: &Result<Status, String>) -> Option<String> {
+        match status {
+            Ok(Status::Running) => Some(format!("timed out busy looping in block {}", self.block)),
+            Err(e) => Some(e.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    // run until the flow blocks on a wait, finishes, or uses up its budget
+    pub fn step(&mut self, io: &mut dyn DeviceBackend) -> Result<Status, String> {
+        self.sense(io)?;
+        for _ in 0..MAX_OPS_PER_STEP {
+            if let Some(status) = self.exec(io)? {
+                return Ok(status);
+            }
+        }
+        Ok(Status::Running)
+    }
+
+    // take fresh readings for the step about to run
+    pub fn sense(&mut self, io: &mut dyn DeviceBackend) -> Result<(), String> {
+        io.seek(self.time);
+        for sens in self.ast.sensors() {
+            let reading = io.read_sensor(sens)?;
+            self.sensors.insert(sens.name.clone(), reading);
+        }
+        self.alarms.clear();
+        Ok(())
+    }
+
+    // the statement that will run next, if the flow hasn't finished
+    pub fn next_statement(&mut self) -> Option<&'a Statement> {
+        while let Some(&(ops, pc)) = self.frames.last() {
+            if pc < ops.len() {
+                return Some(&ops[pc]);
+            }
+            self.frames.pop();
+        }
+        None
+    }
+
+    // run a single statement, returning the status if the flow can't go any further this step
+    pub fn exec(&mut self, io: &mut dyn DeviceBackend) -> Result<Option<Status>, String> {
+        let stmt = match self.next_statement() {
+            Some(stmt) => stmt,
+            None => return Ok(Some(Status::Finished)),
+        };
+        match &stmt.op {
+            Operation::Set { actuator, value } => {
+                self.set(actuator, *value, io)?;
+                self.advance();
+            }
+            Operation::Wait { condition } => {
+                if !self.eval_here(condition)? {
+                    return Ok(Some(Status::Waiting));
+                }
+                self.advance();
+            }
+            Operation::Delay { duration } => {
+                let deadline = *self.deadline.get_or_insert(self.time + duration);
+                if self.time < deadline {
+                    return Ok(Some(Status::Waiting));
+                }
+                self.deadline = None;
+                self.advance();
+            }
+            Operation::Start { timer } => {
+                self.timers.insert(timer.clone(), self.time);
+                self.advance();
+            }
+            Operation::IfElse {
+                if_condition,
+                if_actions,
+                else_actions,
+            } => {
+                let taken = self.eval_here(if_condition)?;
+                self.advance();
+                if taken {
+                    self.frames.push((if_actions.as_slice(), 0));
+                } else if let Some(actions) = else_actions {
+                    self.frames.push((actions.as_slice(), 0));
+                }
+            }
+            Operation::Goto { dest } => {
+                self.enter(dest)?;
+            }
+        }
+        Ok(None)
+    }
+
+    fn advance(&mut self) {
+        if let Some(frame) = self.frames.last_mut() {
+            frame.1 += 1;
+        }
+    }
+
+    fn enter(&mut self, name: &str) -> Result<(), String> {
+        let block = match self.ast.blocks.get(name) {
+            Some(block) => block,
+            None => return Err(format!("goto to unknown block {}", name)),
+        };
+        // edges and durations start afresh each time the block is entered
+        for condition in lower::remembered(&block.ops) {
+            match condition {
+                Condition::Edge { id, .. } => {
+                    self.edges.remove(id);
+                }
+                Condition::For { id, .. } => {
+                    self.held.remove(id);
+                }
+                _ => {}
+            }
+        }
+        self.block = name.to_string();
+        self.entered = self.time;
+        self.frames.clear();
+        self.frames.push((block.ops.as_slice(), 0));
+        self.events.push(Event::Enter(name.to_string()));
+        Ok(())
+    }
+
+    fn set(
+        &mut self,
+        actuator: &Actuator,
+        value: f64,
+        io: &mut dyn DeviceBackend,
+    ) -> Result<(), String> {
+        let name = &actuator.name;
+        let previous = self.actuators.insert(name.to_string(), value);
+        if let Some(reason) = self.violation() {
+            match previous {
+                Some(val) => self.actuators.insert(name.to_string(), val),
+                None => self.actuators.remove(name),
+            };
+            return Err(format!(
+                "block {}: refused to set {} to {}: {}",
+                self.block, name, value, reason
+            ));
+        }
+        if let Err(e) = io.write_actuator(actuator, value) {
+            match previous {
+                Some(val) => self.actuators.insert(name.to_string(), val),
+                None => self.actuators.remove(name),
+            };
+            return Err(e);
+        }
+        self.events.push(Event::Set(name.to_string(), value));
+        Ok(())
+    }
+
+    // first interlock or invariant broken by the current device values, if any
+    fn violation(&mut self) -> Option<String> {
+        violation(self.ast, |condition| self.eval(condition))
+    }
+
+    fn eval_here(&mut self, condition: &Condition) -> Result<bool, String> {
+        if self.explain {
+            self.explanation.clear();
+            self.depth = Some(0);
+        }
+        let result = self.eval(condition);
+        self.depth = None;
+        let holds = match result {
+            Ok(holds) => holds,
+            Err(e) => return Err(format!("block {}: {}", self.block, e)),
+        };
+        self.events.push(Event::Eval(condition.to_string(), holds));
+        Ok(holds)
+    }
+
+    pub fn eval(&mut self, condition: &Condition) -> Result<bool, String> {
+        let depth = match self.depth {
+            Some(depth) => depth,
+            None => return self.eval_part(condition),
+        };
+        let label = match condition {
+            Condition::All(_) => "all".to_string(),
+            Condition::Any(_) => "any".to_string(),
+            _ => condition.to_string(),
+        };
+        let slot = self.explanation.len();
+        self.explanation.push(Explained {
+            depth,
+            condition: label,
+            holds: None,
+        });
+        self.depth = Some(depth + 1);
+        let result = self.eval_part(condition);
+        self.depth = Some(depth);
+        self.explanation[slot].holds = result.as_ref().ok().copied();
+        result
+    }
+
+    fn eval_part(&mut self, condition: &Condition) -> Result<bool, String> {
+        match condition {
+            Condition::Base(sensor, comp, val) => match self.sensors.get(&sensor.name) {
+                Some(reading) => Ok(comp.holds(*reading, *val)),
+                None => Err(format!("no reading for sensor {}", sensor.name)),
+            },
+            Condition::Actuator(actuator, comp, val) => match self.actuators.get(&actuator.name) {
+                Some(current) => Ok(comp.holds(*current, *val)),
+                None => Err(format!("actuator {} read before it was set", actuator.name)),
+            },
+            Condition::Time(clock, comp, val) => {
+                let origin = match clock {
+                    Clock::Elapsed => self.entered,
+                    Clock::Now => 0.0,
+                    Clock::Timer(name) => match self.timers.get(name) {
+                        Some(started) => *started,
+                        None => return Err(format!("timer {} read before it was started", name)),
+                    },
+                };
+                let reading = self.time - origin;
+                let holds = comp.holds(reading, *val);
+                // clocks only go up, so note when one will get far enough to hold
+                if !holds && reading < *val {
+                    self.alarms.push(match comp {
+                        Comparator::GT => (origin + val).next_up(),
+                        Comparator::APPROX(tol) => origin + val - tol,
+                        _ => origin + val,
+                    });
+                }
+                Ok(holds)
+            }
+            Condition::Hysteresis {
+                sensor,
+                rising,
+                threshold,
+                band,
+                id,
+            } => {
+                let reading = match self.sensors.get(&sensor.name) {
+                    Some(reading) => *reading,
+                    None => return Err(format!("no reading for sensor {}", sensor.name)),
+                };
+                let on = self.latches.get(id).copied().unwrap_or(false);
+                let on = match (rising, on) {
+                    (true, false) => reading > *threshold,
+                    (true, true) => reading >= threshold - band,
+                    (false, false) => reading < *threshold,
+                    (false, true) => reading <= threshold + band,
+                };
+                self.latches.insert(*id, on);
+                Ok(on)
+            }
+            Condition::Edge {
+                condition,
+                rising,
+                id,
+            } => {
+                let now = self.eval(condition)?;
+                let before = self.edges.insert(*id, now).unwrap_or(now);
+                Ok(if *rising {
+                    !before && now
+                } else {
+                    before && !now
+                })
+            }
+            Condition::For {
+                condition,
+                duration,
+                id,
+            } => {
+                if !self.eval(condition)? {
+                    self.held.remove(id);
+                    return Ok(false);
+                }
+                let time = self.time;
+                let (since, _) = *self.held.entry(*id).or_insert((time, *duration));
+                Ok(time - since >= *duration)
+            }
+            Condition::All(conditions) => {
+                for c in conditions {
+                    if !self.eval(c)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            Condition::Any(conditions) => {
+                for c in conditions {
+                    if self.eval(c)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+}
+
+// the first interlock or invariant that doesn't hold, judged by eval. The verifier calls this
+// too, with its own eval, so both agree on what a set may not do.
+pub fn violation(
+    ast: &AST,
+    mut eval: impl FnMut(&Condition) -> Result<bool, String>,
+) -> Option<String> {
+    for interlock in &ast.interlocks {
+        // an actuator that was never set can't trip its interlock
+        if let Ok(true) = eval(&interlock.trigger) {
+            match eval(&interlock.requirement) {
+                Ok(true) => {}
+                Ok(false) => return Some(format!("violates interlock {}", interlock)),
+                Err(e) => return Some(format!("can't check interlock {}: {}", interlock, e)),
+            }
+        }
+    }
+    for invariant in &ast.invariants {
+        match eval(invariant) {
+            Ok(true) => {}
+            Ok(false) => return Some(format!("violates invariant {}", invariant)),
+            Err(e) => return Some(format!("can't check invariant {}: {}", invariant, e)),
+        }
+    }
+    None
+}
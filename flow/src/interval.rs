@@ -0,0 +1,100 @@
+use crate::ast::Comparator;
+
+// three-valued result of evaluating a condition over ranges instead of values
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Truth {
+    True,
+    False,
+    Unknown,
+}
+
+impl Truth {
+    pub fn and(self, other: Truth) -> Truth {
+        match (self, other) {
+            (Truth::False, _) | (_, Truth::False) => Truth::False,
+            (Truth::True, Truth::True) => Truth::True,
+            _ => Truth::Unknown,
+        }
+    }
+
+    pub fn or(self, other: Truth) -> Truth {
+        match (self, other) {
+            (Truth::True, _) | (_, Truth::True) => Truth::True,
+            (Truth::False, Truth::False) => Truth::False,
+            _ => Truth::Unknown,
+        }
+    }
+}
+
+// closed range of values a device can hold, empty when lo > hi
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interval {
+    pub lo: f64,
+    pub hi: f64,
+}
+
+impl Interval {
+    pub fn new(lo: f64, hi: f64) -> Interval {
+        Interval { lo, hi }
+    }
+
+    pub fn point(val: f64) -> Interval {
+        Interval { lo: val, hi: val }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lo > self.hi
+    }
+
+    pub fn join(&self, other: &Interval) -> Interval {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        Interval::new(self.lo.min(other.lo), self.hi.max(other.hi))
+    }
+
+    pub fn meet(&self, other: &Interval) -> Interval {
+        Interval::new(self.lo.max(other.lo), self.hi.min(other.hi))
+    }
+
+    // whether `x comp val` holds for every, no, or only some x in the interval
+    pub fn compare(&self, comp: Comparator, val: f64) -> Truth {
+        let (always, never) = match comp {
+            Comparator::LT => (self.hi < val, self.lo >= val),
+            Comparator::LTEQ => (self.hi <= val, self.lo > val),
+            Comparator::EQ => (
+                self.lo == val && self.hi == val,
+                val < self.lo || val > self.hi,
+            ),
+            Comparator::GT => (self.lo > val, self.hi <= val),
+            Comparator::GTEQ => (self.lo >= val, self.hi < val),
+            Comparator::APPROX(tol) => (
+                self.lo >= val - tol && self.hi <= val + tol,
+                self.hi < val - tol || self.lo > val + tol,
+            ),
+        };
+        if always {
+            Truth::True
+        } else if never {
+            Truth::False
+        } else {
+            Truth::Unknown
+        }
+    }
+
+    // the part of the interval where `x comp val` can hold, with strict bounds stepping to the
+    // next float over so that `x < 5` and `x >= 5` can't both hold
+    pub fn restrict(&self, comp: Comparator, val: f64) -> Interval {
+        match comp {
+            Comparator::LT => self.meet(&Interval::new(f64::MIN, val.next_down())),
+            Comparator::LTEQ => self.meet(&Interval::new(f64::MIN, val)),
+            Comparator::GT => self.meet(&Interval::new(val.next_up(), f64::MAX)),
+            Comparator::GTEQ => self.meet(&Interval::new(val, f64::MAX)),
+            Comparator::EQ => self.meet(&Interval::point(val)),
+            Comparator::APPROX(tol) => self.meet(&Interval::new(val - tol, val + tol)),
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::ast::{
+    self, Actuator, Clock, Comparator, Condition, Device, Kind, Operation, Property, Sensor, AST,
+};
+use crate::coverage;
+use crate::pretty;
+use crate::token;
+
+// the version of schema/flow.schema.json the JSON follows, bumped whenever its shape changes
+pub const VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct Document<'a> {
+    version: u32,
+    flow: &'a AST,
+}
+
+// read on its own first, so a flow from another version of the schema gets a useful error
+#[derive(Deserialize)]
+struct Header {
+    version: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Loaded {
+    // already checked by reading the header
+    #[serde(rename = "version")]
+    _version: u32,
+    flow: AST,
+}
+
+pub fn to_json(ast: &AST) -> String {
+    let document = Document {
+        version: VERSION,
+        flow: ast,
+    };
+    // going through a Value sorts the devices and blocks by name, so a flow always comes out the
+    // same way
+    let value = serde_json::to_value(&document).expect("flows are always valid JSON");
+    let mut out = serde_json::to_string_pretty(&value).expect("flows are always valid JSON");
+    out.push('\n');
+    out
+}
+
+// a flow written by to_json, held to everything parsing its source would have checked
+pub fn from_json(text: &str) -> Result<AST, String> {
+    let header: Header =
+        serde_json::from_str(text).map_err(|e| format!("couldn't read the flow: {}", e))?;
+    if header.version != VERSION {
+        return Err(format!(
+            "expected version {} of the flow schema, but the flow is version {}",
+            VERSION, header.version
+        ));
+    }
+    let ast = serde_json::from_str::<Loaded>(text)
+        .map_err(|e| format!("couldn't read the flow: {}", e))?
+        .flow;
+    consistent(&ast)?;
+    // whatever else the parser rejects shows up parsing the flow as source
+    ast::make_ast(&token::tokenize(pretty::print(&ast))).map_err(|e| e.to_string())?;
+    Ok(ast)
+}
+
+// what JSON can say that source can't: devices repeated in conditions and sets that don't
+// match their declarations, values between a bool's or enum's, and conditions sharing an id
+fn consistent(ast: &AST) -> Result<(), String> {
+    for (name, dev) in &ast.devices {
+        let (declared, kind, min, max) = match dev {
+            Device::Actuator(act) => (&act.name, &act.kind, act.min, act.max),
+            Device::Sensor(sens) => (&sens.name, &sens.kind, sens.min, sens.max),
+            Device::Timer(timer) => (timer, &Kind::Float, f64::MIN, f64::MAX),
+        };
+        if declared != name {
+            return Err(format!("device {} is declared as {}", name, declared));
+        }
+        let top = match kind {
+            Kind::Float => None,
+            Kind::Bool => Some(1.0),
+            Kind::Enum(variants) => Some(variants.len() as f64 - 1.0),
+        };
+        if top.is_some_and(|top| min != 0.0 || max != top) {
+            return Err(format!("device {} has a range its kind can't have", name));
+        }
+        if let Device::Actuator(act) = dev {
+            for val in act.init.iter().chain(act.failsafe.iter()) {
+                value(&act.name, &act.kind, *val)?;
+            }
+        }
+    }
+    if !ast.blocks.contains_key(&ast.first_block_name) {
+        return Err(format!(
+            "first block {} isn't declared",
+            ast.first_block_name
+        ));
+    }
+
+    let mut conditions: Vec<&Condition> = Vec::new();
+    for interlock in &ast.interlocks {
+        conditions.push(&interlock.trigger);
+        conditions.push(&interlock.requirement);
+    }
+    conditions.extend(&ast.invariants);
+    for property in &ast.properties {
+        match property {
+            Property::Always(cond) | Property::Never(cond) => conditions.push(cond),
+            Property::Reaches { after, .. } => conditions.extend(after),
+        }
+    }
+    for block in ast.blocks.values() {
+        for stmt in coverage::statements(&block.ops) {
+            match &stmt.op {
+                Operation::Set {
+                    actuator: act,
+                    value: val,
+                } => {
+                    actuator(ast, act)?;
+                    value(&act.name, &act.kind, *val)?;
+                }
+                Operation::Wait { condition } => conditions.push(condition),
+                Operation::IfElse { if_condition, .. } => conditions.push(if_condition),
+                _ => {}
+            }
+        }
+    }
+    let mut ids: HashSet<(&'static str, usize)> = HashSet::new();
+    for cond in conditions {
+        condition(ast, cond, &mut ids)?;
+    }
+    Ok(())
+}
+
+fn condition(
+    ast: &AST,
+    cond: &Condition,
+    ids: &mut HashSet<(&'static str, usize)>,
+) -> Result<(), String> {
+    // conditions that remember something between steps are told apart by their id
+    let mut unique = |what: &'static str, id: usize| {
+        if ids.insert((what, id)) {
+            Ok(())
+        } else {
+            Err(format!(
+                "more than one {} condition has the id {}",
+                what, id
+            ))
+        }
+    };
+    match cond {
+        Condition::Base(sens, comp, val) => {
+            sensor(ast, sens)?;
+            if let Comparator::APPROX(tol) = comp {
+                if sens.tol != Some(*tol) {
+                    return Err(format!(
+                        "sensor {} is compared with the wrong tol",
+                        sens.name
+                    ));
+                }
+            }
+            value(&sens.name, &sens.kind, *val)
+        }
+        Condition::Actuator(act, _, val) => {
+            actuator(ast, act)?;
+            value(&act.name, &act.kind, *val)
+        }
+        Condition::Time(Clock::Timer(name), ..) => match ast.devices.get(name) {
+            Some(Device::Timer(_)) => Ok(()),
+            _ => Err(format!("timer {} isn't declared", name)),
+        },
+        Condition::Time(..) => Ok(()),
+        Condition::Hysteresis {
+            sensor: sens, id, ..
+        } => {
+            sensor(ast, sens)?;
+            unique("hysteresis", *id)
+        }
+        Condition::Edge {
+            condition: inner,
+            id,
+            ..
+        } => {
+            unique("edge", *id)?;
+            condition(ast, inner, ids)
+        }
+        Condition::For {
+            condition: inner,
+            id,
+            ..
+        } => {
+            unique("for", *id)?;
+            condition(ast, inner, ids)
+        }
+        Condition::All(conditions) | Condition::Any(conditions) => {
+            for inner in conditions {
+                condition(ast, inner, ids)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn sensor(ast: &AST, sens: &Sensor) -> Result<(), String> {
+    match ast.devices.get(&sens.name) {
+        Some(Device::Sensor(declared)) if declared == sens => Ok(()),
+        _ => Err(format!(
+            "sensor {} doesn't match its declaration",
+            sens.name
+        )),
+    }
+}
+
+fn actuator(ast: &AST, act: &Actuator) -> Result<(), String> {
+    match ast.devices.get(&act.name) {
+        Some(Device::Actuator(declared)) if declared == act => Ok(()),
+        _ => Err(format!(
+            "actuator {} doesn't match its declaration",
+            act.name
+        )),
+    }
+}
+
+// bools are 0 or 1 and enums the index of a variant, since nothing else can be written in source
+fn value(name: &str, kind: &Kind, val: f64) -> Result<(), String> {
+    if *kind == Kind::Float || kind.parse(&kind.show(val)) == Some(val) {
+        Ok(())
+    } else {
+        Err(format!("{} can't be {}", name, val))
+    }
+}
@@ -0,0 +1,26 @@
+pub mod ast;
+pub mod backend;
+pub mod bytecode;
+pub mod c;
+pub mod check;
+pub mod coverage;
+pub mod debug;
+pub mod dot;
+pub mod drawio;
+pub mod fltest;
+pub mod graph;
+pub mod interp;
+pub mod interval;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod lower;
+pub mod modbus;
+pub mod mqtt;
+pub mod pretty;
+pub mod python;
+pub mod record;
+pub mod rust;
+pub mod st;
+pub mod token;
+pub mod units;
+pub mod verify;
@@ -0,0 +1,243 @@
+use std::collections::BTreeSet;
+
+use crate::ast::{Actuator, Block, Clock, Condition, Operation, Statement, AST};
+
+// A block's statements flattened into a list of instructions, with ifs turned into jumps, so code
+// generators can run a block a piece at a time: a step carries on from where the last one
+// stopped, at a wait, until it gets to another wait it has to stop at.
+
+#[derive(Debug)]
+pub enum Instr<'a> {
+    Set {
+        actuator: &'a Actuator,
+        value: f64,
+    },
+    // go on to `target` when the condition doesn't hold, and to the next instruction when it does
+    Branch {
+        condition: &'a Condition,
+        target: usize,
+    },
+    Jump {
+        target: usize,
+    },
+    Wait {
+        condition: &'a Condition,
+    },
+    Delay {
+        duration: f64,
+    },
+    Start {
+        timer: &'a str,
+    },
+    Goto {
+        block: &'a str,
+    },
+    // forget what the block's edge and duration conditions remembered on an earlier visit, first
+    // thing in a block that has any, so it happens every time the block is entered
+    Forget {
+        conditions: Vec<&'a Condition>,
+    },
+    // the end of a block nothing jumped away from, which finishes the flow
+    Finish,
+}
+
+#[derive(Debug)]
+pub struct Lowered<'a> {
+    pub name: &'a str,
+    pub block: &'a Block,
+    // each instruction with the line of the statement it came from
+    pub code: Vec<(usize, Instr<'a>)>,
+}
+
+fn lower_ops<'a>(ops: &'a [Statement], code: &mut Vec<(usize, Instr<'a>)>) {
+    for stmt in ops {
+        let instr = match &stmt.op {
+            Operation::Set { actuator, value } => Instr::Set {
+                actuator,
+                value: *value,
+            },
+            Operation::Wait { condition } => Instr::Wait { condition },
+            Operation::Delay { duration } => Instr::Delay {
+                duration: *duration,
+            },
+            Operation::Start { timer } => Instr::Start { timer },
+            Operation::Goto { dest } => Instr::Goto { block: dest },
+            Operation::IfElse {
+                if_condition,
+                if_actions,
+                else_actions,
+            } => {
+                // the branch target gets filled in once it's known where the if arm ends
+                let branch = code.len();
+                code.push((
+                    stmt.line,
+                    Instr::Branch {
+                        condition: if_condition,
+                        target: 0,
+                    },
+                ));
+                lower_ops(if_actions, code);
+                let mut jump = None;
+                if else_actions.is_some() {
+                    jump = Some(code.len());
+                    code.push((stmt.line, Instr::Jump { target: 0 }));
+                }
+                let after_if = code.len();
+                if let Instr::Branch { target, .. } = &mut code[branch].1 {
+                    *target = after_if;
+                }
+                lower_ops(else_actions.as_deref().unwrap_or(&[]), code);
+                let after_else = code.len();
+                if let Some((_, Instr::Jump { target })) = jump.map(|at| &mut code[at]) {
+                    *target = after_else;
+                }
+                continue;
+            }
+        };
+        code.push((stmt.line, instr));
+    }
+}
+
+pub fn lower_block<'a>(name: &'a str, block: &'a Block) -> Lowered<'a> {
+    let mut code = Vec::new();
+    let forgotten = remembered(&block.ops);
+    if !forgotten.is_empty() {
+        code.push((
+            block.line,
+            Instr::Forget {
+                conditions: forgotten,
+            },
+        ));
+    }
+    lower_ops(&block.ops, &mut code);
+    code.push((block.line, Instr::Finish));
+    Lowered { name, block, code }
+}
+
+// every block, in the order they're declared
+pub fn lower(ast: &AST) -> Vec<Lowered<'_>> {
+    let mut blocks: Vec<(&String, &Block)> = ast.blocks.iter().collect();
+    blocks.sort_by_key(|(_, block)| block.line);
+    blocks
+        .into_iter()
+        .map(|(name, block)| lower_block(name, block))
+        .collect()
+}
+
+// every condition the flow evaluates as it runs, safety ones first
+pub fn conditions<'a>(ast: &'a AST, blocks: &[Lowered<'a>]) -> Vec<&'a Condition> {
+    let mut found: Vec<&Condition> = Vec::new();
+    for interlock in &ast.interlocks {
+        found.push(&interlock.trigger);
+        found.push(&interlock.requirement);
+    }
+    for invariant in &ast.invariants {
+        found.push(invariant);
+    }
+    for block in blocks {
+        for (_, instr) in &block.code {
+            if let Instr::Wait { condition } | Instr::Branch { condition, .. } = instr {
+                found.push(condition);
+            }
+        }
+    }
+    found
+}
+
+// the edge and duration conditions a block's waits and ifs test, which remember something about
+// earlier steps in the block
+pub fn remembered(ops: &[Statement]) -> Vec<&Condition> {
+    fn find<'a>(condition: &'a Condition, out: &mut Vec<&'a Condition>) {
+        match condition {
+            Condition::Edge {
+                condition: inner, ..
+            }
+            | Condition::For {
+                condition: inner, ..
+            } => {
+                out.push(condition);
+                find(inner, out);
+            }
+            Condition::All(conditions) | Condition::Any(conditions) => {
+                for c in conditions {
+                    find(c, out);
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut out = Vec::new();
+    for stmt in ops {
+        match &stmt.op {
+            Operation::Wait { condition } => find(condition, &mut out),
+            Operation::IfElse {
+                if_condition,
+                if_actions,
+                else_actions,
+            } => {
+                find(if_condition, &mut out);
+                out.extend(remembered(if_actions));
+                out.extend(remembered(else_actions.as_deref().unwrap_or(&[])));
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+pub fn reads_sensors(condition: &Condition) -> bool {
+    match condition {
+        Condition::Base(..) | Condition::Hysteresis { .. } => true,
+        Condition::Edge { condition, .. } | Condition::For { condition, .. } => {
+            reads_sensors(condition)
+        }
+        Condition::All(conditions) | Condition::Any(conditions) => {
+            conditions.iter().any(reads_sensors)
+        }
+        _ => false,
+    }
+}
+
+pub fn timers_read<'a>(condition: &'a Condition, out: &mut BTreeSet<&'a str>) {
+    match condition {
+        Condition::Time(Clock::Timer(name), ..) => {
+            out.insert(name);
+        }
+        Condition::Edge { condition, .. } | Condition::For { condition, .. } => {
+            timers_read(condition, out)
+        }
+        Condition::All(conditions) | Condition::Any(conditions) => {
+            for c in conditions {
+                timers_read(c, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+pub fn reads_elapsed(condition: &Condition) -> bool {
+    match condition {
+        Condition::Time(Clock::Elapsed, ..) => true,
+        Condition::Edge { condition, .. } | Condition::For { condition, .. } => {
+            reads_elapsed(condition)
+        }
+        Condition::All(conditions) | Condition::Any(conditions) => {
+            conditions.iter().any(reads_elapsed)
+        }
+        _ => false,
+    }
+}
+
+// what code generators key a condition by, being where it sits in the AST
+pub fn address(condition: &Condition) -> usize {
+    condition as *const Condition as usize
+}
+
+// a line of generated code, `depth` levels in
+pub fn line(out: &mut String, depth: usize, text: &str) {
+    if text.is_empty() {
+        out.push('\n');
+    } else {
+        out.push_str(&format!("{:width$}{}\n", "", text, width = depth * 4));
+    }
+}
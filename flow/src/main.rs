@@ -1,21 +1,728 @@
-use std::{env, fs};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{env, fs, io, process, thread};
 
-mod ast;
-// mod check;
-mod token;
+use flow::ast::{self, AST};
+use flow::backend::{self, DeviceBackend, Memory, Stdio, Trace};
+use flow::bytecode;
+use flow::c;
+use flow::check::{self, Severity};
+use flow::coverage::Coverage;
+use flow::debug;
+use flow::dot;
+use flow::drawio;
+use flow::fltest;
+use flow::interp::{Event, Interpreter, Status, MAX_SIMULATED_SECONDS};
+#[cfg(feature = "json")]
+use flow::json;
+use flow::modbus::{self, Modbus};
+use flow::mqtt::{self, Mqtt};
+use flow::python;
+use flow::record::{self, Recorder, Replay, Tap};
+use flow::rust;
+use flow::st;
+use flow::token;
+use flow::verify;
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let code = fs::read_to_string(&args[1]).expect("Couldn't open file");
-    let toks = token::tokenize(code);
-    let ast = ast::make_ast(&toks);
+const USAGE: &str = "Usage: flow <file.fl>
+       flow run [--live] [--record <run.log>]
+                [--trace <file> | --stdio | --modbus <host:port> --map <file> | --mqtt <file>]
+                <file.fl> [sensor=value ...]
+       flow replay [--step] <run.log> <file.fl>
+       flow debug [--trace <file>] <file.fl> [sensor=value ...]
+       flow test [--coverage] [--lcov <file>] [--dot <file>] [<file.fltest> | <dir> ...]
+       flow dot <file.fl>
+       flow verify <file.fl>
+       flow compile --target rust|c|st|bytecode [-o <file>] <file.fl>
+       flow compile --target python [--generator] [-o <file>] <file.fl>
+       flow import drawio [-o <file>] <diagram.xml>
+       flow parse --emit json [-o <file>] <file.fl>";
 
-    match ast {
-        Ok(_) => {
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("run") => run(&args[1..]),
+        Some("replay") => replay(&args[1..]),
+        Some("debug") => debug(&args[1..]),
+        Some("test") => test(&args[1..]),
+        Some("dot") => graph(&args[1..]),
+        Some("verify") => verify(&args[1..]),
+        Some("compile") => compile(&args[1..]),
+        Some("import") => import(&args[1..]),
+        Some("parse") => parse(&args[1..]),
+        Some(path) if args.len() == 1 => {
+            load(path);
             println!("Everything checks out!");
         }
-        Err(e) => {
-            panic!("{}", e);
+        _ => fail(USAGE),
+    }
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    process::exit(1);
+}
+
+// a flow from its source, or from the JSON `flow parse --emit json` writes
+fn load(path: &str) -> AST {
+    let code = fs::read_to_string(path).expect("Couldn't open file");
+    let ast = if path.ends_with(".json") {
+        from_json(&code)
+    } else {
+        let toks = token::tokenize(code);
+        match ast::make_ast(&toks) {
+            Ok(ast) => ast,
+            Err(e) => fail(e),
+        }
+    };
+
+    let diags = check::check(&ast);
+    for diag in &diags {
+        eprintln!("{}", diag);
+    }
+    if diags.iter().any(|d| d.severity == Severity::Error) {
+        fail("Flow failed checks");
+    }
+    ast
+}
+
+#[cfg(feature = "json")]
+fn from_json(code: &str) -> AST {
+    json::from_json(code).unwrap_or_else(|e| fail(&e))
+}
+
+#[cfg(not(feature = "json"))]
+fn from_json(_: &str) -> AST {
+    fail("Reading flows from JSON needs flow built with the json feature")
+}
+
+// how often a live run looks at its sensors while waiting
+const LIVE_POLL_SECONDS: f64 = 0.1;
+
+// Ctrl-C or a kill stops a run at its fail-safe values. Reads aren't restarted after the signal,
+// so a run blocked waiting on a device stops too.
+#[cfg(unix)]
+fn catch_interrupts() {
+    use std::sync::atomic::Ordering;
+
+    extern "C" fn interrupted(_: libc::c_int) {
+        backend::INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+    for signum in [libc::SIGINT, libc::SIGTERM] {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = interrupted as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signum, &action, std::ptr::null_mut());
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn catch_interrupts() {}
+
+// sensors held at fixed readings given as name=value, 0 unless given
+fn fixed_readings(ast: &AST, readings: &[String]) -> Memory {
+    let mut sensors: HashMap<String, f64> = HashMap::new();
+    for sens in ast.sensors() {
+        sensors.insert(sens.name.clone(), 0.0);
+    }
+    for reading in readings {
+        let parsed = reading.split_once('=').and_then(|(name, val)| {
+            let sens = ast.sensors().into_iter().find(|s| s.name == name)?;
+            Some((name, sens.kind.parse(val)?))
+        });
+        match parsed {
+            Some((name, val)) => {
+                sensors.insert(name.to_string(), val);
+            }
+            None => fail(&format!("Invalid sensor reading {}", reading)),
+        }
+    }
+    Memory {
+        sensors,
+        actuators: HashMap::new(),
+    }
+}
+
+// run the flow on a virtual clock that skips ahead whenever the flow is waiting, or in real time
+// when live
+fn run(args: &[String]) {
+    let mut live = false;
+    let mut stdio = false;
+    let mut trace: Option<&String> = None;
+    let mut plc: Option<&String> = None;
+    let mut map: Option<&String> = None;
+    let mut broker: Option<&String> = None;
+    let mut record: Option<&String> = None;
+    let mut rest: Vec<&String> = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--live" => live = true,
+            "--stdio" => stdio = true,
+            "--trace" => trace = Some(iter.next().unwrap_or_else(|| fail(USAGE))),
+            "--modbus" => plc = Some(iter.next().unwrap_or_else(|| fail(USAGE))),
+            "--map" => map = Some(iter.next().unwrap_or_else(|| fail(USAGE))),
+            "--mqtt" => broker = Some(iter.next().unwrap_or_else(|| fail(USAGE))),
+            "--record" => record = Some(iter.next().unwrap_or_else(|| fail(USAGE))),
+            _ => rest.push(arg),
+        }
+    }
+    let (path, readings) = rest.split_first().unwrap_or_else(|| fail(USAGE));
+    let readings: Vec<String> = readings.iter().map(|r| r.to_string()).collect();
+    let ast = load(path);
+
+    let external = stdio || trace.is_some() || plc.is_some() || broker.is_some();
+    if external && !readings.is_empty() {
+        fail("Sensor readings can't be given along with another source of readings");
+    }
+    if plc.is_some() != map.is_some() {
+        fail("--modbus and --map must be given together");
+    }
+    let backend: Box<dyn DeviceBackend> = if let (Some(addr), Some(file)) = (plc, map) {
+        // the PLC's readings move in real time, so they're polled while waiting
+        live = true;
+        let text = fs::read_to_string(file).expect("Couldn't open map file");
+        let registers = modbus::parse_map(&text, &ast).unwrap_or_else(|e| fail(&e));
+        Box::new(Modbus::connect(addr, registers).unwrap_or_else(|e| fail(&e)))
+    } else if let Some(file) = broker {
+        live = true;
+        let text = fs::read_to_string(file).expect("Couldn't open MQTT config file");
+        let config = mqtt::Config::parse(&text, &ast).unwrap_or_else(|e| fail(&e));
+        Box::new(Mqtt::connect(config).unwrap_or_else(|e| fail(&e)))
+    } else if stdio {
+        // whoever is on the other end can change readings at any time
+        live = true;
+        Box::new(Stdio::new(io::stdin().lock(), io::stdout()))
+    } else if let Some(file) = trace {
+        let text = fs::read_to_string(file).expect("Couldn't open trace file");
+        match Trace::parse(&text, &ast.sensors()) {
+            Ok(trace) => Box::new(trace),
+            Err(e) => fail(&e),
+        }
+    } else {
+        Box::new(fixed_readings(&ast, &readings))
+    };
+    // the line protocol owns stdout, so the log goes to stderr
+    let log: fn(&str) = if stdio {
+        |line| eprintln!("{}", line)
+    } else {
+        |line| println!("{}", line)
+    };
+
+    let mut io = Tap::new(backend);
+    let mut recording = record.map(|file| match fs::File::create(file) {
+        Ok(file) => (Recorder::new(&ast), io::BufWriter::new(file)),
+        Err(e) => fail(&format!("Couldn't create {}: {}", file, e)),
+    });
+
+    let started = Instant::now();
+    let mut shown_time = 0.0;
+    let mut interp = match Interpreter::new(&ast, &mut io) {
+        Ok(interp) => interp,
+        Err(e) => fail(&e),
+    };
+    catch_interrupts();
+    loop {
+        if backend::interrupted() {
+            // stopped between steps, so the log still replays as far as it goes
+            let result = interp.fail_safe(&mut io);
+            let events = interp.take_events();
+            print_events(&ast, interp.time, &events, log, &mut shown_time);
+            if let Err(e) = result {
+                eprintln!("couldn't apply fail-safe values: {}", e);
+            }
+            fail("Interrupted");
+        }
+        let status = interp.step(&mut io);
+        let events = interp.take_events();
+        print_events(&ast, interp.time, &events, log, &mut shown_time);
+        // a read given up on because of an interrupt fails the step, but the interrupt is the news
+        let error = match interp.halted(&status) {
+            Some(_) if backend::interrupted() => Some("Interrupted".to_string()),
+            error => error,
+        };
+        let lost = io.failed.take();
+        if let Some((recorder, file)) = &mut recording {
+            let lines = recorder.step(
+                interp.time,
+                &interp.sensors,
+                lost.as_deref(),
+                &events,
+                error.as_deref(),
+            );
+            write_lines(file, &lines);
+        }
+
+        if let Some(error) = error {
+            let result = interp.fail_safe(&mut io);
+            let events = interp.take_events();
+            print_events(&ast, interp.time, &events, log, &mut shown_time);
+            if let Some((recorder, file)) = &mut recording {
+                write_lines(file, &recorder.fail_safe(&events));
+            }
+            if let Err(e) = result {
+                eprintln!("couldn't apply fail-safe values: {}", e);
+            }
+            fail(&error);
+        }
+
+        match status {
+            Ok(Status::Waiting) if live => {
+                let now = started.elapsed().as_secs_f64();
+                let next = match interp.wakeup() {
+                    Some(time) => time.min(now + LIVE_POLL_SECONDS),
+                    None => now + LIVE_POLL_SECONDS,
+                };
+                if next > now {
+                    thread::sleep(Duration::from_secs_f64(next - now));
+                }
+                interp.time = started.elapsed().as_secs_f64();
+            }
+            // nothing can happen until the clock or the readings change, so skip straight there
+            Ok(Status::Waiting) => {
+                let next = interp
+                    .wakeup()
+                    .into_iter()
+                    .chain(io.next_change())
+                    .reduce(f64::min);
+                match next {
+                    Some(time) if time <= MAX_SIMULATED_SECONDS => interp.time = time,
+                    Some(_) => {
+                        log(&format!(
+                            "stopped after {}s of simulated time",
+                            MAX_SIMULATED_SECONDS
+                        ));
+                        break;
+                    }
+                    None => {
+                        log(&format!("waiting forever in block {}", interp.block));
+                        break;
+                    }
+                }
+            }
+            _ => {
+                log(&format!("finished in block {}", interp.block));
+                break;
+            }
+        }
+    }
+}
+
+// step through the flow interactively against a trace or fixed readings
+fn debug(args: &[String]) {
+    let (trace, args) = match args {
+        [flag, file, rest @ ..] if flag == "--trace" => (Some(file), rest),
+        _ => (None, args),
+    };
+    let (path, readings) = args.split_first().unwrap_or_else(|| fail(USAGE));
+    let ast = load(path);
+    let backend: Box<dyn DeviceBackend> = match trace {
+        Some(_) if !readings.is_empty() => {
+            fail("Sensor readings can't be given along with --trace")
+        }
+        Some(file) => {
+            let text = fs::read_to_string(file).expect("Couldn't open trace file");
+            Box::new(Trace::parse(&text, &ast.sensors()).unwrap_or_else(|e| fail(&e)))
         }
+        None => Box::new(fixed_readings(&ast, readings)),
+    };
+    if let Err(e) = debug::debug(&ast, backend) {
+        fail(&e);
     }
 }
+
+fn write_lines(file: &mut impl Write, lines: &[String]) {
+    for line in lines {
+        writeln!(file, "{}", line).expect("Couldn't write to run log");
+    }
+    // keep the log complete up to the last step in case the run is killed
+    file.flush().expect("Couldn't write to run log");
+}
+
+// feed a run log back through the flow, checking it does exactly what was recorded
+fn replay(args: &[String]) {
+    let stepping = args.first().map(String::as_str) == Some("--step");
+    let args = if stepping { &args[1..] } else { args };
+    let (log_path, path) = match args {
+        [log_path, path] => (log_path, path),
+        _ => fail(USAGE),
+    };
+    let ast = load(path);
+    let text = fs::read_to_string(log_path).expect("Couldn't open run log");
+    let steps = record::parse_log(&text, &ast).unwrap_or_else(|e| fail(&e));
+    if steps.is_empty() {
+        fail("Run log has no steps");
+    }
+
+    let mut io = Replay::default();
+    io.load(&steps[0]);
+    let mut recorder = Recorder::new(&ast);
+    let mut interp = match Interpreter::new(&ast, &mut io) {
+        Ok(interp) => interp,
+        Err(e) => fail(&e),
+    };
+    let stdin = io::stdin();
+    for (num, step) in steps.iter().enumerate() {
+        io.load(step);
+        interp.time = step.time;
+        let status = interp.step(&mut io);
+        let events = interp.take_events();
+        // a read given up on because of an interrupt fails the step, but the interrupt is the news
+        let error = match interp.halted(&status) {
+            Some(_) if backend::interrupted() => Some("Interrupted".to_string()),
+            error => error,
+        };
+        let lost = io.failed.take();
+        let mut lines = recorder.step(
+            interp.time,
+            &interp.sensors,
+            lost.as_deref(),
+            &events,
+            error.as_deref(),
+        );
+        if error.is_some() {
+            // fail-safe writes can't fail in a replay, so the result is already in the log
+            let _ = interp.fail_safe(&mut io);
+            lines.extend(recorder.fail_safe(&interp.take_events()));
+        }
+
+        if stepping {
+            for line in &lines {
+                println!("{}", line);
+            }
+        }
+        if lines != step.lines {
+            let at = lines
+                .iter()
+                .zip(&step.lines)
+                .position(|(a, b)| a != b)
+                .unwrap_or_else(|| lines.len().min(step.lines.len()));
+            let nothing = "(nothing)".to_string();
+            println!("diverged at step {} ({}s):", num + 1, step.time);
+            println!("  recorded: {}", step.lines.get(at).unwrap_or(&nothing));
+            println!("  replayed: {}", lines.get(at).unwrap_or(&nothing));
+            process::exit(1);
+        }
+        if stepping && num + 1 < steps.len() {
+            let mut input = String::new();
+            if stdin.lock().read_line(&mut input).unwrap_or(0) == 0 || input.trim() == "q" {
+                return;
+            }
+        }
+    }
+    println!("Replayed {} steps with no divergence", steps.len());
+}
+
+fn print_events(ast: &AST, time: f64, events: &[Event], log: fn(&str), shown_time: &mut f64) {
+    for line in events.iter().filter_map(|e| e.show(ast)) {
+        if time != *shown_time {
+            *shown_time = time;
+            log(&format!("time {}s", time));
+        }
+        log(&line);
+    }
+}
+
+// run test files, or every test file in the given directories, tests/ if nothing's given, and
+// report what they covered of each flow they test
+fn test(args: &[String]) {
+    let mut coverage = false;
+    let mut lcov: Option<&String> = None;
+    let mut dot: Option<&String> = None;
+    let mut rest: Vec<&String> = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--coverage" => coverage = true,
+            "--lcov" => lcov = Some(iter.next().unwrap_or_else(|| fail(USAGE))),
+            "--dot" => dot = Some(iter.next().unwrap_or_else(|| fail(USAGE))),
+            _ => rest.push(arg),
+        }
+    }
+    let default = "tests".to_string();
+    if rest.is_empty() {
+        rest.push(&default);
+    }
+    let mut files = Vec::new();
+    for arg in rest {
+        let path = Path::new(arg);
+        if path.is_dir() {
+            files.extend(fltest::find(path).unwrap_or_else(|e| fail(&e)));
+        } else {
+            files.push(path.to_path_buf());
+        }
+    }
+
+    let (mut passed, mut failed) = (0, 0);
+    // coverage of each flow tested, over every file that tests it, in the order first tested
+    let mut covered: Vec<(PathBuf, Coverage)> = Vec::new();
+    for file in &files {
+        let results = match fltest::run_file(file) {
+            Ok(results) => results,
+            Err(e) => {
+                println!("FAIL {}: {}", file.display(), e);
+                failed += 1;
+                continue;
+            }
+        };
+        for outcome in &results.outcomes {
+            match &outcome.failure {
+                None => {
+                    println!("ok   {}: {}", file.display(), outcome.name);
+                    passed += 1;
+                }
+                Some(lines) => {
+                    println!("FAIL {}: {}", file.display(), outcome.name);
+                    for line in lines {
+                        println!("    {}", line);
+                    }
+                    failed += 1;
+                }
+            }
+        }
+        match covered.iter_mut().find(|(flow, _)| *flow == results.flow) {
+            Some((_, coverage)) => coverage.merge(&results.coverage),
+            None => covered.push((results.flow, results.coverage)),
+        }
+    }
+    println!("{} passed, {} failed", passed, failed);
+
+    if coverage || lcov.is_some() || dot.is_some() {
+        let mut tracefile = String::new();
+        let mut graphs = String::new();
+        for (flow, coverage) in &covered {
+            let source = fs::read_to_string(flow).expect("Couldn't open file");
+            let ast = ast::make_ast(&token::tokenize(source.clone())).unwrap_or_else(|e| fail(e));
+            let name = flow.to_string_lossy();
+            println!();
+            println!("{}: {}", name, coverage.summary(&ast));
+            for line in coverage.annotate(&ast, &source) {
+                println!("{}", line);
+            }
+            tracefile += &coverage.lcov(&ast, &name);
+            graphs += &dot::graph(&ast, &name, Some(coverage));
+        }
+        if let Some(file) = lcov {
+            if let Err(e) = fs::write(file, tracefile) {
+                fail(&format!("Couldn't write {}: {}", file, e));
+            }
+        }
+        if let Some(file) = dot {
+            if let Err(e) = fs::write(file, graphs) {
+                fail(&format!("Couldn't write {}: {}", file, e));
+            }
+        }
+    }
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+// print the block graph of a flow for Graphviz
+fn graph(args: &[String]) {
+    let path = match args {
+        [path] => path,
+        _ => fail(USAGE),
+    };
+    let ast = load(path);
+    print!("{}", dot::graph(&ast, path, None));
+}
+
+// check the properties the flow declares over every state it can get into, printing a trace of
+// readings that breaks each one that fails
+fn verify(args: &[String]) {
+    let path = match args {
+        [path] => path,
+        _ => fail(USAGE),
+    };
+    let ast = load(path);
+    if ast.properties.is_empty() {
+        println!("no properties to verify");
+        return;
+    }
+    let verification = verify::verify(&ast).unwrap_or_else(|e| fail(&e));
+    let mut failed = 0;
+    for verdict in &verification.verdicts {
+        let counterexample = match &verdict.counterexample {
+            Some(counterexample) => counterexample,
+            None => {
+                println!("ok   {}", verdict.property);
+                continue;
+            }
+        };
+        failed += 1;
+        println!("FAIL {}", verdict.property);
+        for line in &counterexample.trace {
+            println!("    {}", line);
+        }
+        if !counterexample.replays {
+            println!(
+                "    # the simulator doesn't follow this trace, so it may not be a real failure"
+            );
+        }
+    }
+    println!(
+        "{} states, {} held, {} failed",
+        verification.states,
+        verification.verdicts.len() - failed,
+        failed
+    );
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+// generate code that runs the flow without the interpreter, to stdout or a file
+fn compile(args: &[String]) {
+    let mut target: Option<&String> = None;
+    let mut output: Option<&String> = None;
+    let mut generator = false;
+    let mut rest: Vec<&String> = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--target" => target = Some(iter.next().unwrap_or_else(|| fail(USAGE))),
+            "-o" => output = Some(iter.next().unwrap_or_else(|| fail(USAGE))),
+            "--generator" => generator = true,
+            _ => rest.push(arg),
+        }
+    }
+    let path = match rest.as_slice() {
+        [path] => path,
+        _ => fail(USAGE),
+    };
+    // only the python target has a generator version
+    if generator && target.map(String::as_str) != Some("python") {
+        fail(USAGE);
+    }
+    let ast = load(path);
+    let code = match target.map(String::as_str) {
+        Some("rust") => rust::generate(&ast, path),
+        // a header to go with the source, named after it, and by default after the flow
+        Some("c") => {
+            let source = match output {
+                Some(file) => PathBuf::from(file),
+                None => PathBuf::from(Path::new(path).file_stem().unwrap()).with_extension("c"),
+            };
+            let header = source.with_extension("h");
+            let name = header.file_name().unwrap().to_string_lossy();
+            let (h, c) = c::generate(&ast, path, &name);
+            for (file, code) in [(&header, h), (&source, c)] {
+                if let Err(e) = fs::write(file, code) {
+                    fail(&format!("Couldn't write {}: {}", file.display(), e));
+                }
+            }
+            return;
+        }
+        Some("python") => python::generate(&ast, path, generator),
+        Some("st") => {
+            let name = Path::new(path).file_stem().unwrap().to_string_lossy();
+            st::generate(&ast, path, &name)
+        }
+        // binary, so written to a file named after the flow rather than printed
+        Some("bytecode") => {
+            let file = match output {
+                Some(file) => PathBuf::from(file),
+                None => PathBuf::from(Path::new(path).file_stem().unwrap()).with_extension("flbc"),
+            };
+            let program = bytecode::compile(&ast).unwrap_or_else(|e| fail(&e));
+            if let Err(e) = fs::write(&file, program.to_bytes()) {
+                fail(&format!("Couldn't write {}: {}", file.display(), e));
+            }
+            return;
+        }
+        Some(other) => fail(&format!(
+            "unknown target {}, expected rust, c, st, python or bytecode",
+            other
+        )),
+        None => fail(USAGE),
+    };
+    match output {
+        Some(file) => {
+            if let Err(e) = fs::write(file, code) {
+                fail(&format!("Couldn't write {}: {}", file, e));
+            }
+        }
+        None => print!("{}", code),
+    }
+}
+
+// a flow drawn as a flowchart, with the shapes that didn't make it into the flow reported
+fn import(args: &[String]) {
+    let mut output: Option<&String> = None;
+    let mut rest: Vec<&String> = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" => output = Some(iter.next().unwrap_or_else(|| fail(USAGE))),
+            _ => rest.push(arg),
+        }
+    }
+    let path = match rest.as_slice() {
+        [format, path] if format.as_str() == "drawio" => path,
+        _ => fail(USAGE),
+    };
+    let xml = match fs::read_to_string(path) {
+        Ok(xml) => xml,
+        Err(e) => fail(&format!("Couldn't open {}: {}", path, e)),
+    };
+    let import = drawio::import(&xml).unwrap_or_else(|e| fail(&e));
+    for shape in &import.untranslated {
+        eprintln!("couldn't translate {}", shape);
+    }
+    match output {
+        Some(file) => {
+            if let Err(e) = fs::write(file, &import.flow) {
+                fail(&format!("Couldn't write {}: {}", file, e));
+            }
+        }
+        None => print!("{}", import.flow),
+    }
+}
+
+// the flow as data for other tools to read
+fn parse(args: &[String]) {
+    let mut emit: Option<&String> = None;
+    let mut output: Option<&String> = None;
+    let mut rest: Vec<&String> = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--emit" => emit = Some(iter.next().unwrap_or_else(|| fail(USAGE))),
+            "-o" => output = Some(iter.next().unwrap_or_else(|| fail(USAGE))),
+            _ => rest.push(arg),
+        }
+    }
+    let path = match rest.as_slice() {
+        [path] => path,
+        _ => fail(USAGE),
+    };
+    let ast = load(path);
+    let data = match emit.map(String::as_str) {
+        Some("json") => to_json(&ast),
+        Some(other) => fail(&format!("unknown format {}, expected json", other)),
+        None => fail(USAGE),
+    };
+    match output {
+        Some(file) => {
+            if let Err(e) = fs::write(file, data) {
+                fail(&format!("Couldn't write {}: {}", file, e));
+            }
+        }
+        None => print!("{}", data),
+    }
+}
+
+#[cfg(feature = "json")]
+fn to_json(ast: &AST) -> String {
+    json::to_json(ast)
+}
+
+#[cfg(not(feature = "json"))]
+fn to_json(_: &AST) -> String {
+    fail("Emitting JSON needs flow built with the json feature")
+}
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::ast::{Actuator, Sensor, AST};
+use crate::backend::{self, DeviceBackend};
+
+// how long to wait on a PLC before giving up on a request
+const TIMEOUT_SECONDS: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Table {
+    Coil,
+    Discrete,
+    Input,
+    Holding,
+}
+
+impl Table {
+    fn writable(self) -> bool {
+        matches!(self, Table::Coil | Table::Holding)
+    }
+
+    fn read_function(self) -> u8 {
+        match self {
+            Table::Coil => 0x01,
+            Table::Discrete => 0x02,
+            Table::Holding => 0x03,
+            Table::Input => 0x04,
+        }
+    }
+}
+
+// where a device lives on the PLC; a register holds `(value - offset) / scale`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Register {
+    pub table: Table,
+    pub address: u16,
+    pub slave: u8,
+    pub scale: f64,
+    pub offset: f64,
+    pub signed: bool,
+}
+
+impl Register {
+    fn decode(&self, raw: u16) -> f64 {
+        let raw = if self.signed {
+            raw as i16 as f64
+        } else {
+            raw as f64
+        };
+        raw * self.scale + self.offset
+    }
+
+    fn encode(&self, value: f64) -> Option<u16> {
+        let raw = ((value - self.offset) / self.scale).round();
+        if self.signed {
+            if raw < i16::MIN as f64 || raw > i16::MAX as f64 {
+                return None;
+            }
+            Some(raw as i16 as u16)
+        } else {
+            if raw < 0.0 || raw > u16::MAX as f64 {
+                return None;
+            }
+            Some(raw as u16)
+        }
+    }
+}
+
+// one device per line as `<device> <coil|discrete|input|holding> <address>` followed by any of
+// `scale <s>`, `offset <o>`, `signed` and `slave <id>`, with # starting a comment
+pub fn parse_map(text: &str, ast: &AST) -> Result<HashMap<String, Register>, String> {
+    let mut map = HashMap::new();
+    for (num, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let err = |msg: &str| format!("line {}: {}", num + 1, msg);
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 {
+            return Err(err("expected <device> <table> <address>"));
+        }
+
+        let name = fields[0];
+        let writes = if ast.actuators().iter().any(|a| a.name == name) {
+            true
+        } else if ast.sensors().iter().any(|s| s.name == name) {
+            false
+        } else {
+            return Err(err(&format!("unknown sensor or actuator {}", name)));
+        };
+        if map.contains_key(name) {
+            return Err(err(&format!("{} is mapped more than once", name)));
+        }
+
+        let table = match fields[1] {
+            "coil" => Table::Coil,
+            "discrete" => Table::Discrete,
+            "input" => Table::Input,
+            "holding" => Table::Holding,
+            other => return Err(err(&format!("unknown table {}", other))),
+        };
+        if writes && !table.writable() {
+            return Err(err(&format!(
+                "actuator {} must be mapped to a coil or holding register",
+                name
+            )));
+        }
+        let address = match fields[2].parse::<u16>() {
+            Ok(address) => address,
+            Err(_) => return Err(err(&format!("invalid address {}", fields[2]))),
+        };
+
+        let mut reg = Register {
+            table,
+            address,
+            slave: 1,
+            scale: 1.0,
+            offset: 0.0,
+            signed: false,
+        };
+        let mut idx = 3;
+        while idx < fields.len() {
+            let arg = fields.get(idx + 1);
+            match (fields[idx], arg) {
+                ("signed", _) => {
+                    reg.signed = true;
+                    idx += 1;
+                    continue;
+                }
+                ("scale", Some(arg)) => match arg.parse::<f64>() {
+                    Ok(scale) if scale != 0.0 => reg.scale = scale,
+                    _ => return Err(err(&format!("invalid scale {}", arg))),
+                },
+                ("offset", Some(arg)) => match arg.parse::<f64>() {
+                    Ok(offset) => reg.offset = offset,
+                    _ => return Err(err(&format!("invalid offset {}", arg))),
+                },
+                ("slave", Some(arg)) => match arg.parse::<u8>() {
+                    Ok(slave) => reg.slave = slave,
+                    _ => return Err(err(&format!("invalid slave id {}", arg))),
+                },
+                (other, _) => return Err(err(&format!("unexpected {}", other))),
+            }
+            idx += 2;
+        }
+        let is_bit = matches!(table, Table::Coil | Table::Discrete);
+        if is_bit && (reg.scale != 1.0 || reg.offset != 0.0 || reg.signed) {
+            return Err(err("coils and discrete inputs can't be scaled"));
+        }
+        map.insert(name.to_string(), reg);
+    }
+
+    for sens in ast.sensors() {
+        if !map.contains_key(&sens.name) {
+            return Err(format!("no register mapped for sensor {}", sens.name));
+        }
+    }
+    for act in ast.actuators() {
+        if !map.contains_key(&act.name) {
+            return Err(format!("no register mapped for actuator {}", act.name));
+        }
+    }
+    Ok(map)
+}
+
+// a Modbus TCP client reading and writing one device per request
+pub struct Modbus {
+    stream: TcpStream,
+    map: HashMap<String, Register>,
+    transaction: u16,
+}
+
+impl Modbus {
+    pub fn connect(addr: &str, map: HashMap<String, Register>) -> Result<Modbus, String> {
+        let stream =
+            TcpStream::connect(addr).map_err(|e| format!("can't connect to {}: {}", addr, e))?;
+        let timeout = Some(Duration::from_secs(TIMEOUT_SECONDS));
+        stream
+            .set_read_timeout(timeout)
+            .map_err(|e| e.to_string())?;
+        stream
+            .set_write_timeout(timeout)
+            .map_err(|e| e.to_string())?;
+        Ok(Modbus {
+            stream,
+            map,
+            transaction: 0,
+        })
+    }
+
+    fn register(&self, name: &str) -> Result<Register, String> {
+        match self.map.get(name) {
+            Some(reg) => Ok(reg.clone()),
+            None => Err(format!("no register mapped for {}", name)),
+        }
+    }
+
+    // send one PDU and return the response PDU after the function code
+    fn request(&mut self, slave: u8, pdu: &[u8]) -> Result<Vec<u8>, String> {
+        self.transaction = self.transaction.wrapping_add(1);
+        let mut frame = Vec::with_capacity(7 + pdu.len());
+        frame.extend_from_slice(&self.transaction.to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+        frame.push(slave);
+        frame.extend_from_slice(pdu);
+        self.stream.write_all(&frame).map_err(|e| e.to_string())?;
+
+        let mut header = [0u8; 7];
+        backend::read_exact(&mut self.stream, &mut header).map_err(|e| e.to_string())?;
+        let transaction = u16::from_be_bytes([header[0], header[1]]);
+        let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if transaction != self.transaction || len < 2 {
+            return Err("malformed Modbus response".to_string());
+        }
+        let mut body = vec![0u8; len - 1];
+        backend::read_exact(&mut self.stream, &mut body).map_err(|e| e.to_string())?;
+
+        if body[0] == pdu[0] | 0x80 {
+            return Err(format!(
+                "Modbus exception {} from slave {}",
+                body.get(1).copied().unwrap_or(0),
+                slave
+            ));
+        }
+        if body[0] != pdu[0] {
+            return Err("malformed Modbus response".to_string());
+        }
+        Ok(body.split_off(1))
+    }
+
+    fn read(&mut self, name: &str) -> Result<f64, String> {
+        let reg = self.register(name)?;
+        let mut pdu = vec![reg.table.read_function()];
+        pdu.extend_from_slice(&reg.address.to_be_bytes());
+        pdu.extend_from_slice(&1u16.to_be_bytes());
+        let data = self
+            .request(reg.slave, &pdu)
+            .map_err(|e| format!("reading {}: {}", name, e))?;
+        // byte count then the packed bits or registers
+        match (reg.table, data.as_slice()) {
+            (Table::Coil, [_, bits, ..]) | (Table::Discrete, [_, bits, ..]) => {
+                Ok((bits & 1) as f64)
+            }
+            (Table::Input, [_, hi, lo, ..]) | (Table::Holding, [_, hi, lo, ..]) => {
+                Ok(reg.decode(u16::from_be_bytes([*hi, *lo])))
+            }
+            _ => Err(format!("reading {}: malformed Modbus response", name)),
+        }
+    }
+
+    fn write(&mut self, name: &str, value: f64) -> Result<(), String> {
+        let reg = self.register(name)?;
+        let (function, raw) = match reg.table {
+            Table::Coil => (0x05, if value != 0.0 { 0xff00 } else { 0x0000 }),
+            Table::Holding => match reg.encode(value) {
+                Some(raw) => (0x06, raw),
+                None => {
+                    return Err(format!(
+                        "{} doesn't fit in the register for {}",
+                        value, name
+                    ))
+                }
+            },
+            _ => return Err(format!("{} isn't mapped to a writable register", name)),
+        };
+        let mut pdu = vec![function];
+        pdu.extend_from_slice(&reg.address.to_be_bytes());
+        pdu.extend_from_slice(&raw.to_be_bytes());
+        self.request(reg.slave, &pdu)
+            .map_err(|e| format!("writing {}: {}", name, e))?;
+        Ok(())
+    }
+}
+
+impl DeviceBackend for Modbus {
+    fn read_sensor(&mut self, sensor: &Sensor) -> Result<f64, String> {
+        self.read(&sensor.name)
+    }
+
+    fn write_actuator(&mut self, actuator: &Actuator, value: f64) -> Result<(), String> {
+        self.write(&actuator.name, value)
+    }
+}
@@ -0,0 +1,473 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::ast::{Actuator, Sensor, AST};
+use crate::backend::{self, DeviceBackend};
+
+// how long to wait on the broker for a connection, an acknowledgement or a first reading
+const TIMEOUT_SECONDS: u64 = 2;
+
+// how many times to reconnect before giving up on a request, and how long to wait between tries
+const RECONNECT_ATTEMPTS: usize = 3;
+const RECONNECT_DELAY_MS: u64 = 200;
+
+// how often a wait on the broker looks for an interrupt
+const INTERRUPT_POLL_MS: u64 = 100;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const PINGREQ: u8 = 0xc0;
+const DISCONNECT: u8 = 0xe0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub broker: String,
+    pub client_id: String,
+    // 0 or 1, used for both subscriptions and publishes
+    pub qos: u8,
+    // whether actuator values are published as retained messages
+    pub retain: bool,
+    pub keep_alive: u16,
+    // the topic each sensor reads from
+    pub sensors: HashMap<String, String>,
+    // the topic each actuator is published to
+    pub actuators: HashMap<String, String>,
+}
+
+impl Config {
+    // one setting per line, with # starting a comment:
+    //   broker <host:port>, client <id>, qos <0|1>, retain <true|false>, keepalive <seconds>,
+    //   sensors <template>, actuators <template>, topic <device> <topic>
+    // templates have {name} replaced by the device name, and topic overrides a single device
+    pub fn parse(text: &str, ast: &AST) -> Result<Config, String> {
+        let mut config = Config {
+            broker: String::new(),
+            client_id: "flow".to_string(),
+            qos: 0,
+            retain: false,
+            keep_alive: 30,
+            sensors: HashMap::new(),
+            actuators: HashMap::new(),
+        };
+        let mut sensors = "flow/sensors/{name}".to_string();
+        let mut actuators = "flow/actuators/{name}".to_string();
+        let mut overrides: HashMap<String, String> = HashMap::new();
+
+        for (num, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let err = |msg: &str| format!("line {}: {}", num + 1, msg);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["broker", addr] => config.broker = addr.to_string(),
+                ["client", id] => config.client_id = id.to_string(),
+                ["qos", qos] => match *qos {
+                    "0" => config.qos = 0,
+                    "1" => config.qos = 1,
+                    _ => return Err(err(&format!("unsupported qos {}", qos))),
+                },
+                ["retain", retain] => match retain.parse() {
+                    Ok(retain) => config.retain = retain,
+                    Err(_) => return Err(err(&format!("invalid retain {}", retain))),
+                },
+                ["keepalive", secs] => match secs.parse() {
+                    Ok(secs) => config.keep_alive = secs,
+                    Err(_) => return Err(err(&format!("invalid keepalive {}", secs))),
+                },
+                ["sensors", template] => sensors = template.to_string(),
+                ["actuators", template] => actuators = template.to_string(),
+                ["topic", name, topic] => {
+                    let known = ast.sensors().iter().any(|s| s.name == *name)
+                        || ast.actuators().iter().any(|a| a.name == *name);
+                    if !known {
+                        return Err(err(&format!("unknown sensor or actuator {}", name)));
+                    }
+                    overrides.insert(name.to_string(), topic.to_string());
+                }
+                _ => return Err(err(&format!("invalid setting \"{}\"", line))),
+            }
+        }
+        if config.broker.is_empty() {
+            return Err("no broker given".to_string());
+        }
+
+        for sens in ast.sensors() {
+            let topic = match overrides.get(&sens.name) {
+                Some(topic) => topic.clone(),
+                None => sensors.replace("{name}", &sens.name),
+            };
+            config.sensors.insert(sens.name.clone(), topic);
+        }
+        for act in ast.actuators() {
+            let topic = match overrides.get(&act.name) {
+                Some(topic) => topic.clone(),
+                None => actuators.replace("{name}", &act.name),
+            };
+            config.actuators.insert(act.name.clone(), topic);
+        }
+        Ok(config)
+    }
+}
+
+// what the reader thread has picked up from the broker
+#[derive(Default)]
+struct Inbox {
+    // latest payload on each topic
+    readings: HashMap<String, String>,
+    // packet ids of publishes the broker has acknowledged
+    acks: HashSet<u16>,
+    // which connection is current, so a reader for an old one doesn't mark the new one closed
+    generation: usize,
+    connected: bool,
+}
+
+type Shared = Arc<(Mutex<Inbox>, Condvar)>;
+
+// an MQTT 3.1.1 client that subscribes to every sensor's topic and publishes actuator values,
+// reconnecting whenever the broker goes away
+pub struct Mqtt {
+    config: Config,
+    stream: Option<Arc<Mutex<TcpStream>>>,
+    inbox: Shared,
+    packet_id: u16,
+    last_sent: Instant,
+}
+
+impl Mqtt {
+    pub fn connect(config: Config) -> Result<Mqtt, String> {
+        let mut mqtt = Mqtt {
+            config,
+            stream: None,
+            inbox: Arc::new((Mutex::new(Inbox::default()), Condvar::new())),
+            packet_id: 0,
+            last_sent: Instant::now(),
+        };
+        mqtt.open()?;
+        Ok(mqtt)
+    }
+
+    fn next_id(&mut self) -> u16 {
+        // packet ids can't be 0
+        self.packet_id = self.packet_id.checked_add(1).unwrap_or(1);
+        self.packet_id
+    }
+
+    fn open(&mut self) -> Result<(), String> {
+        self.close();
+        let timeout = Some(Duration::from_secs(TIMEOUT_SECONDS));
+        let mut stream = TcpStream::connect(&self.config.broker)
+            .map_err(|e| format!("can't connect to {}: {}", self.config.broker, e))?;
+        stream
+            .set_read_timeout(timeout)
+            .map_err(|e| e.to_string())?;
+        stream
+            .set_write_timeout(timeout)
+            .map_err(|e| e.to_string())?;
+
+        let mut body = Vec::new();
+        put_string(&mut body, "MQTT");
+        // protocol level 4, clean session
+        body.extend_from_slice(&[4, 0x02]);
+        body.extend_from_slice(&self.config.keep_alive.to_be_bytes());
+        put_string(&mut body, &self.config.client_id);
+        stream
+            .write_all(&packet(CONNECT, &body))
+            .map_err(|e| e.to_string())?;
+        match read_packet(&mut stream) {
+            Ok((CONNACK, body)) if body.len() == 2 && body[1] == 0 => {}
+            Ok((CONNACK, body)) if body.len() == 2 => {
+                return Err(format!("broker refused connection with code {}", body[1]))
+            }
+            Ok(_) => return Err("expected CONNACK from broker".to_string()),
+            Err(e) => return Err(format!("connecting to broker: {}", e)),
+        }
+        stream.set_read_timeout(None).map_err(|e| e.to_string())?;
+
+        let id = self.next_id();
+        let mut topics: Vec<&String> = self.config.sensors.values().collect();
+        topics.sort();
+        topics.dedup();
+        if !topics.is_empty() {
+            let mut body = id.to_be_bytes().to_vec();
+            for topic in topics {
+                put_string(&mut body, topic);
+                body.push(self.config.qos);
+            }
+            stream
+                .write_all(&packet(SUBSCRIBE, &body))
+                .map_err(|e| e.to_string())?;
+        }
+
+        let reader = stream.try_clone().map_err(|e| e.to_string())?;
+        let writer = Arc::new(Mutex::new(stream));
+        let generation = {
+            let mut inbox = self.inbox.0.lock().unwrap();
+            inbox.generation += 1;
+            inbox.connected = true;
+            inbox.generation
+        };
+        let inbox = Arc::clone(&self.inbox);
+        let replies = Arc::clone(&writer);
+        thread::spawn(move || listen(reader, replies, inbox, generation));
+        self.stream = Some(writer);
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            let _ = stream.lock().unwrap().shutdown(Shutdown::Both);
+        }
+    }
+
+    fn connected(&self) -> bool {
+        self.stream.is_some() && self.inbox.0.lock().unwrap().connected
+    }
+
+    // reconnect and resubscribe if the connection has dropped
+    fn reconnect(&mut self) -> Result<(), String> {
+        let mut result = Ok(());
+        for attempt in 0..RECONNECT_ATTEMPTS {
+            if self.connected() {
+                return Ok(());
+            }
+            if attempt > 0 {
+                thread::sleep(Duration::from_millis(RECONNECT_DELAY_MS));
+            }
+            result = self.open();
+        }
+        result.map_err(|e| format!("lost connection to broker: {}", e))
+    }
+
+    fn send(&mut self, bytes: &[u8]) -> Result<(), String> {
+        for _ in 0..RECONNECT_ATTEMPTS {
+            self.reconnect()?;
+            let stream = Arc::clone(self.stream.as_ref().unwrap());
+            let result = stream.lock().unwrap().write_all(bytes);
+            if result.is_ok() {
+                self.last_sent = Instant::now();
+                return Ok(());
+            }
+            self.close();
+        }
+        Err("lost connection to broker".to_string())
+    }
+
+    fn publish(&mut self, topic: &str, payload: &str) -> Result<(), String> {
+        let qos = self.config.qos;
+        let flags = (qos << 1) | self.config.retain as u8;
+        if qos == 0 {
+            let mut body = Vec::new();
+            put_string(&mut body, topic);
+            body.extend_from_slice(payload.as_bytes());
+            return self.send(&packet(PUBLISH | flags, &body));
+        }
+
+        let id = self.next_id();
+        let mut body = Vec::new();
+        put_string(&mut body, topic);
+        body.extend_from_slice(&id.to_be_bytes());
+        body.extend_from_slice(payload.as_bytes());
+        for attempt in 0..RECONNECT_ATTEMPTS {
+            // resends are marked as duplicates
+            let dup = if attempt > 0 { 0x08 } else { 0 };
+            self.send(&packet(PUBLISH | flags | dup, &body))?;
+            if self.wait_for(|inbox| inbox.acks.remove(&id) || !inbox.connected) {
+                if self.connected() {
+                    return Ok(());
+                }
+            } else {
+                // no acknowledgement in time, so assume the connection is dead
+                self.close();
+            }
+        }
+        Err(format!("broker never acknowledged publish to {}", topic))
+    }
+
+    // block until `done` holds for the inbox, or the timeout runs out or the run is interrupted
+    fn wait_for(&self, mut done: impl FnMut(&mut Inbox) -> bool) -> bool {
+        let (lock, cvar) = &*self.inbox;
+        let deadline = Instant::now() + Duration::from_secs(TIMEOUT_SECONDS);
+        let mut inbox = lock.lock().unwrap();
+        loop {
+            if done(&mut inbox) {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline || backend::interrupted() {
+                return false;
+            }
+            let wait = (deadline - now).min(Duration::from_millis(INTERRUPT_POLL_MS));
+            inbox = cvar.wait_timeout(inbox, wait).unwrap().0;
+        }
+    }
+
+    fn keep_alive(&mut self) -> Result<(), String> {
+        let interval = Duration::from_secs(self.config.keep_alive as u64) / 2;
+        if self.config.keep_alive > 0 && self.last_sent.elapsed() >= interval {
+            self.send(&packet(PINGREQ, &[]))?;
+        }
+        Ok(())
+    }
+}
+
+impl DeviceBackend for Mqtt {
+    fn read_sensor(&mut self, sensor: &Sensor) -> Result<f64, String> {
+        self.reconnect()?;
+        self.keep_alive()?;
+        let topic = match self.config.sensors.get(&sensor.name) {
+            Some(topic) => topic.clone(),
+            None => return Err(format!("no topic for sensor {}", sensor.name)),
+        };
+
+        let mut payload = None;
+        self.wait_for(|inbox| {
+            payload = inbox.readings.get(&topic).cloned();
+            payload.is_some()
+        });
+        let payload = match payload {
+            Some(payload) => payload,
+            None => {
+                return Err(format!(
+                    "no reading for sensor {} on {}",
+                    sensor.name, topic
+                ))
+            }
+        };
+        match sensor.kind.parse(payload.trim()) {
+            Some(val) => Ok(val),
+            None => Err(format!(
+                "invalid reading \"{}\" for sensor {} on {}",
+                payload.trim(),
+                sensor.name,
+                topic
+            )),
+        }
+    }
+
+    fn write_actuator(&mut self, actuator: &Actuator, value: f64) -> Result<(), String> {
+        let topic = match self.config.actuators.get(&actuator.name) {
+            Some(topic) => topic.clone(),
+            None => return Err(format!("no topic for actuator {}", actuator.name)),
+        };
+        self.publish(&topic, &actuator.kind.show(value))
+    }
+}
+
+impl Drop for Mqtt {
+    fn drop(&mut self) {
+        if let Some(stream) = &self.stream {
+            let _ = stream.lock().unwrap().write_all(&packet(DISCONNECT, &[]));
+        }
+        self.close();
+    }
+}
+
+// runs on its own thread for each connection, filing away whatever the broker sends
+fn listen(mut stream: TcpStream, writer: Arc<Mutex<TcpStream>>, inbox: Shared, generation: usize) {
+    let (lock, cvar) = &*inbox;
+    while let Ok((kind, body)) = read_packet(&mut stream) {
+        match kind & 0xf0 {
+            PUBLISH => {
+                let qos = (kind >> 1) & 0x03;
+                let (topic, rest) = match take_string(&body) {
+                    Some(parsed) => parsed,
+                    None => break,
+                };
+                let payload = if qos > 0 {
+                    if rest.len() < 2 {
+                        break;
+                    }
+                    let reply = packet(PUBACK, &rest[..2]);
+                    if writer.lock().unwrap().write_all(&reply).is_err() {
+                        break;
+                    }
+                    &rest[2..]
+                } else {
+                    rest
+                };
+                let payload = String::from_utf8_lossy(payload).to_string();
+                lock.lock().unwrap().readings.insert(topic, payload);
+                cvar.notify_all();
+            }
+            PUBACK if body.len() == 2 => {
+                lock.lock()
+                    .unwrap()
+                    .acks
+                    .insert(u16::from_be_bytes([body[0], body[1]]));
+                cvar.notify_all();
+            }
+            // subscription and ping acknowledgements need no action
+            _ => {}
+        }
+    }
+    let mut inbox = lock.lock().unwrap();
+    if inbox.generation == generation {
+        inbox.connected = false;
+    }
+    cvar.notify_all();
+}
+
+fn put_string(buf: &mut Vec<u8>, text: &str) {
+    buf.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    buf.extend_from_slice(text.as_bytes());
+}
+
+fn take_string(buf: &[u8]) -> Option<(String, &[u8])> {
+    if buf.len() < 2 {
+        return None;
+    }
+    let len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+    let text = buf.get(2..2 + len)?;
+    Some((String::from_utf8_lossy(text).to_string(), &buf[2 + len..]))
+}
+
+// fixed header byte, remaining length as a variable length integer, then the body
+pub fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![kind];
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        bytes.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    bytes.extend_from_slice(body);
+    bytes
+}
+
+pub fn read_packet(stream: &mut impl Read) -> Result<(u8, Vec<u8>), String> {
+    let mut byte = [0u8; 1];
+    backend::read_exact(stream, &mut byte).map_err(|e| e.to_string())?;
+    let kind = byte[0];
+    let mut len = 0;
+    let mut shift = 0;
+    loop {
+        backend::read_exact(stream, &mut byte).map_err(|e| e.to_string())?;
+        len |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift > 21 {
+            return Err("malformed packet length".to_string());
+        }
+    }
+    let mut body = vec![0u8; len];
+    backend::read_exact(stream, &mut body).map_err(|e| e.to_string())?;
+    Ok((kind, body))
+}
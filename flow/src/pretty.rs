@@ -0,0 +1,204 @@
+use crate::ast::{Comparator, Condition, Device, Kind, Operation, Property, Statement, AST};
+use crate::units::Unit;
+
+const TAB: &str = "    ";
+
+// the flow as source that parses back into the same flow. Devices come out sorted, sensors
+// first, and values are written in the device's own unit, so nothing is lost converting them.
+pub fn print(ast: &AST) -> String {
+    let mut out = String::new();
+    for sens in ast.sensors() {
+        out += &format!("sensor {}", sens.name);
+        out += &kind(&sens.kind, sens.min, sens.max, &sens.unit);
+        if let Some(tol) = sens.tol {
+            out += &format!(" tol {}", tol);
+        }
+        out += "\n";
+    }
+    for act in ast.actuators() {
+        out += &format!("actuator {}", act.name);
+        out += &kind(&act.kind, act.min, act.max, &act.unit);
+        if let Some(init) = act.init {
+            out += &format!(" init {}", act.kind.show(init));
+        }
+        if let Some(failsafe) = act.failsafe {
+            out += &format!(" failsafe {}", act.kind.show(failsafe));
+        }
+        out += "\n";
+    }
+    let mut timers: Vec<&String> = ast
+        .devices
+        .values()
+        .filter_map(|dev| match dev {
+            Device::Timer(name) => Some(name),
+            _ => None,
+        })
+        .collect();
+    timers.sort();
+    for timer in timers {
+        out += &format!("timer {}\n", timer);
+    }
+    out += "\n";
+
+    let mut safety = String::new();
+    for interlock in &ast.interlocks {
+        safety += &format!("interlock: {} requires ", comparison(&interlock.trigger));
+        condition(&mut safety, 0, &interlock.requirement);
+    }
+    for invariant in &ast.invariants {
+        safety += "invariant: ";
+        condition(&mut safety, 0, invariant);
+    }
+    for property in &ast.properties {
+        match property {
+            Property::Always(cond) | Property::Never(cond) => {
+                let always = matches!(property, Property::Always(_));
+                safety += if always { "always: " } else { "never: " };
+                condition(&mut safety, 0, cond);
+            }
+            Property::Reaches { block, after } => {
+                safety += &format!("reaches: {}", block);
+                match after {
+                    Some(cond) => {
+                        safety += " after ";
+                        condition(&mut safety, 0, cond);
+                    }
+                    None => safety += "\n",
+                }
+            }
+        }
+    }
+    if !safety.is_empty() {
+        out += &safety;
+        out += "\n";
+    }
+
+    // the first block has to come first, and the rest keep the order they were declared in
+    let mut blocks: Vec<(&String, usize)> = ast.blocks.iter().map(|(n, b)| (n, b.line)).collect();
+    blocks.sort_by_key(|(name, line)| (**name != ast.first_block_name, *line, name.to_string()));
+    let blocks: Vec<String> = blocks
+        .iter()
+        .map(|(name, _)| {
+            let mut block = format!("block {}\n", name);
+            statements(&mut block, 1, &ast.blocks[*name].ops);
+            block + "endblock\n"
+        })
+        .collect();
+    out + &blocks.join("\n")
+}
+
+// what follows a device's name: its range or type, and its unit
+fn kind(kind: &Kind, min: f64, max: f64, unit: &Option<Unit>) -> String {
+    let mut out = match kind {
+        Kind::Float if min == f64::MIN && max == f64::MAX => String::new(),
+        Kind::Float => format!(" {}..{}", min, max),
+        Kind::Bool => String::from(": bool"),
+        Kind::Enum(variants) => format!(": enum {{{}}}", variants.join(", ")),
+    };
+    if let Some(unit) = unit {
+        out += &format!(" {}", unit.name);
+    }
+    out
+}
+
+fn statements(out: &mut String, depth: usize, stmts: &[Statement]) {
+    let indent = TAB.repeat(depth);
+    for stmt in stmts {
+        match &stmt.op {
+            Operation::Set { actuator, value } => {
+                *out += &format!(
+                    "{}set {} {}\n",
+                    indent,
+                    actuator.name,
+                    actuator.kind.show(*value)
+                )
+            }
+            Operation::Goto { dest } => *out += &format!("{}goto {}\n", indent, dest),
+            Operation::Start { timer } => *out += &format!("{}start {}\n", indent, timer),
+            Operation::Delay { duration } => *out += &format!("{}wait {}s\n", indent, duration),
+            Operation::Wait { condition: cond } => {
+                *out += &format!("{}wait:\n{}{}", indent, indent, TAB);
+                condition(out, depth + 1, cond);
+            }
+            Operation::IfElse {
+                if_condition,
+                if_actions,
+                else_actions,
+            } => {
+                *out += &format!("{}if:\n{}{}", indent, indent, TAB);
+                condition(out, depth + 1, if_condition);
+                statements(out, depth + 1, if_actions);
+                if let Some(else_actions) = else_actions {
+                    *out += &format!("{}else:\n", indent);
+                    statements(out, depth + 1, else_actions);
+                }
+            }
+        }
+    }
+}
+
+// a condition starting partway along a line that's already indented to depth, with any nested
+// conditions on the lines after it
+fn condition(out: &mut String, depth: usize, cond: &Condition) {
+    match cond {
+        Condition::All(conditions) | Condition::Any(conditions) => {
+            let all = matches!(cond, Condition::All(_));
+            *out += if all { "- all:\n" } else { "- any:\n" };
+            for inner in conditions {
+                *out += &TAB.repeat(depth + 1);
+                condition(out, depth + 1, inner);
+            }
+        }
+        _ => *out += &format!("- {}\n", comparison(cond)),
+    }
+}
+
+// a condition that fits on one line
+fn comparison(cond: &Condition) -> String {
+    match cond {
+        // a bare bool device means it's true
+        Condition::Base(sens, Comparator::EQ, val) if sens.kind == Kind::Bool && *val == 1.0 => {
+            sens.name.clone()
+        }
+        Condition::Actuator(act, Comparator::EQ, val) if act.kind == Kind::Bool && *val == 1.0 => {
+            act.name.clone()
+        }
+        Condition::Base(sens, comp, val) => {
+            format!("{} {} {}", sens.name, comp, sens.kind.show(*val))
+        }
+        Condition::Actuator(act, comp, val) => {
+            format!("{} {} {}", act.name, comp, act.kind.show(*val))
+        }
+        Condition::Time(clock, comp, val) => format!("{} {} {}s", clock, comp, val),
+        Condition::Hysteresis {
+            sensor,
+            rising,
+            threshold,
+            band,
+            ..
+        } => format!(
+            "{} {} {} hyst {}",
+            sensor.name,
+            if *rising {
+                "rises_above"
+            } else {
+                "falls_below"
+            },
+            threshold,
+            band
+        ),
+        Condition::Edge {
+            condition, rising, ..
+        } => format!(
+            "{} {}",
+            comparison(condition),
+            if *rising { "rising" } else { "falling" }
+        ),
+        Condition::For {
+            condition,
+            duration,
+            ..
+        } => format!("{} for {}s", comparison(condition), duration),
+        Condition::All(_) | Condition::Any(_) => cond.to_string(),
+    }
+}
@@ -0,0 +1,835 @@
+use std::collections::HashMap;
+
+use crate::ast::{Clock, Comparator, Condition, Kind, Operation, Statement, AST};
+use crate::interp::MAX_OPS_PER_STEP;
+use crate::lower::{self, address, conditions, line, Instr};
+
+// Generates a Python module for test rigs: a Flow class whose step() takes a dict of sensor
+// readings by name and gives back a dict of what the actuators should be set to, running the flow
+// the way Interpreter::step does. Bools are bools, enums are their values' names and actuators the
+// flow hasn't set yet are None.
+//
+// The plain version runs the lowered code a piece at a time from where the last step stopped. The
+// generator version keeps the blocks' own structure instead, with each block a generator that
+// yields at a wait or delay until the next step carries on from there.
+
+// a Python string literal
+fn quote(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            c if (c as u32) < 0x20 || c as u32 == 0x7f => out += &format!("\\x{:02x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out + "\""
+}
+
+fn number(val: f64) -> String {
+    if val.is_infinite() {
+        format!("float({})", quote(&val.to_string()))
+    } else {
+        format!("{:?}", val)
+    }
+}
+
+fn literal(kind: &Kind, val: f64) -> String {
+    match kind {
+        Kind::Float => number(val),
+        Kind::Bool if val != 0.0 => "True".to_string(),
+        Kind::Bool => "False".to_string(),
+        Kind::Enum(variants) => quote(&variants[val as usize]),
+    }
+}
+
+fn compare(lhs: &str, kind: &Kind, comp: Comparator, val: f64) -> String {
+    match (kind, comp) {
+        // bools and enums can only be compared for equality
+        (Kind::Bool, _) if val != 0.0 => lhs.to_string(),
+        (Kind::Bool, _) => format!("not {}", lhs),
+        (Kind::Enum(_), _) => format!("{} == {}", lhs, literal(kind, val)),
+        (_, Comparator::APPROX(tol)) => {
+            format!("abs({} - {}) <= {}", lhs, number(val), number(tol))
+        }
+        (_, comp) => {
+            let symbol = match comp {
+                Comparator::EQ => "==".to_string(),
+                other => other.to_string(),
+            };
+            format!("{} {} {}", lhs, symbol, number(val))
+        }
+    }
+}
+
+// which helpers the conditions need
+#[derive(Default)]
+struct Needs {
+    actuators: bool,
+    timers: bool,
+    latches: bool,
+    edges: bool,
+    held: bool,
+}
+
+fn needs(condition: &Condition, out: &mut Needs) {
+    match condition {
+        Condition::Actuator(act, ..) => out.actuators |= act.init.is_none(),
+        Condition::Time(Clock::Timer(_), ..) => out.timers = true,
+        Condition::Hysteresis { .. } => out.latches = true,
+        Condition::Edge { condition, .. } => {
+            out.edges = true;
+            needs(condition, out);
+        }
+        Condition::For { condition, .. } => {
+            out.held = true;
+            needs(condition, out);
+        }
+        Condition::All(conditions) | Condition::Any(conditions) => {
+            for c in conditions {
+                needs(c, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+struct Generator<'a> {
+    ast: &'a AST,
+    // the method evaluating each condition, by its address in the AST
+    methods: HashMap<usize, String>,
+    // whether setting an actuator has any interlocks or invariants to check
+    guarded: bool,
+    // where the plain version gets the readings from, and the generator version
+    sensors: &'static str,
+}
+
+impl Generator<'_> {
+    fn expr(&self, condition: &Condition) -> String {
+        match condition {
+            Condition::Base(sensor, comp, val) => {
+                let lhs = format!("s[{}]", quote(&sensor.name));
+                compare(&lhs, &sensor.kind, *comp, *val)
+            }
+            Condition::Actuator(act, comp, val) => {
+                let lhs = if act.init.is_some() {
+                    format!("self.actuators[{}]", quote(&act.name))
+                } else {
+                    format!("self._actuator({})", quote(&act.name))
+                };
+                compare(&lhs, &act.kind, *comp, *val)
+            }
+            Condition::Time(clock, comp, val) => {
+                let reading = match clock {
+                    Clock::Elapsed => "(self.time - self.entered)".to_string(),
+                    Clock::Now => "self.time".to_string(),
+                    Clock::Timer(name) => format!("self._timer({})", quote(name)),
+                };
+                compare(&reading, &Kind::Float, *comp, *val)
+            }
+            Condition::Hysteresis {
+                sensor,
+                rising,
+                threshold,
+                band,
+                id,
+            } => {
+                let reading = format!("s[{}]", quote(&sensor.name));
+                let (on, stays) = if *rising {
+                    (
+                        format!("{} > {}", reading, number(*threshold)),
+                        format!("{} >= {}", reading, number(threshold - band)),
+                    )
+                } else {
+                    (
+                        format!("{} < {}", reading, number(*threshold)),
+                        format!("{} <= {}", reading, number(threshold + band)),
+                    )
+                };
+                format!("self._latch({}, {}, {})", id, on, stays)
+            }
+            Condition::Edge {
+                condition,
+                rising,
+                id,
+            } => format!(
+                "self._edge({}, {}, {})",
+                id,
+                if *rising { "True" } else { "False" },
+                self.expr(condition)
+            ),
+            Condition::For {
+                condition,
+                duration,
+                id,
+            } => format!(
+                "self._held({}, {}, {})",
+                id,
+                number(*duration),
+                self.expr(condition)
+            ),
+            Condition::All(conditions) | Condition::Any(conditions) => {
+                let joiner = if let Condition::All(_) = condition {
+                    " and "
+                } else {
+                    " or "
+                };
+                let parts: Vec<String> = conditions
+                    .iter()
+                    .map(|c| format!("({})", self.expr(c)))
+                    .collect();
+                parts.join(joiner)
+            }
+        }
+    }
+
+    // a condition evaluated where the flow is, with errors saying which block it's in
+    fn call(&self, condition: &Condition) -> String {
+        format!(
+            "self._at(self.{}, {})",
+            self.methods[&address(condition)],
+            self.sensors
+        )
+    }
+
+    fn set(&self, o: &mut String, depth: usize, actuator: &crate::ast::Actuator, value: f64) {
+        let val = literal(&actuator.kind, value);
+        if self.guarded {
+            line(
+                o,
+                depth,
+                &format!(
+                    "self._set({}, {}, {}, {})",
+                    quote(&actuator.name),
+                    val,
+                    quote(&value.to_string()),
+                    self.sensors
+                ),
+            );
+        } else {
+            line(
+                o,
+                depth,
+                &format!("self.actuators[{}] = {}", quote(&actuator.name), val),
+            );
+        }
+    }
+}
+
+pub fn generate(ast: &AST, source: &str, generator: bool) -> String {
+    let blocks = lower::lower(ast);
+    let found = conditions(ast, &blocks);
+    let gen = Generator {
+        ast,
+        methods: found
+            .iter()
+            .enumerate()
+            .map(|(idx, c)| (address(c), format!("_condition_{}", idx)))
+            .collect(),
+        guarded: !ast.interlocks.is_empty() || !ast.invariants.is_empty(),
+        sensors: if generator { "self._sensors" } else { "s" },
+    };
+    let mut used = Needs::default();
+    for condition in &found {
+        needs(condition, &mut used);
+    }
+
+    let mut out = String::new();
+    let o = &mut out;
+    line(
+        o,
+        0,
+        &format!(
+            "# generated by `flow compile --target python{}` from {}; edit the flow, not this file",
+            if generator { " --generator" } else { "" },
+            source
+        ),
+    );
+    line(o, 0, "");
+    line(o, 0, &format!("MAX_OPS_PER_STEP = {}", MAX_OPS_PER_STEP));
+    line(o, 0, "");
+    line(o, 0, "");
+    line(o, 0, "class FlowError(Exception):");
+    line(o, 1, "pass");
+    line(o, 0, "");
+    line(o, 0, "");
+    line(o, 0, "class Flow:");
+    line(
+        o,
+        1,
+        "# readings are a dict by sensor name, with a reading for every sensor:",
+    );
+    for sens in ast.sensors() {
+        let what = match &sens.kind {
+            Kind::Float => match &sens.unit {
+                Some(unit) => format!("float, {}", unit.name),
+                None => "float".to_string(),
+            },
+            Kind::Bool => "bool".to_string(),
+            Kind::Enum(variants) => variants
+                .iter()
+                .map(|v| quote(v))
+                .collect::<Vec<_>>()
+                .join(" | "),
+        };
+        line(o, 1, &format!("#     {}: {}", sens.name, what));
+    }
+    line(o, 0, "");
+    line(o, 1, "def __init__(self):");
+    line(
+        o,
+        2,
+        &format!("self.block = {}", quote(&ast.first_block_name)),
+    );
+    if !generator {
+        line(o, 2, "# where in the block's code the flow is");
+        line(o, 2, "self.pc = 0");
+        line(o, 2, "self.deadline = None");
+    }
+    line(
+        o,
+        2,
+        "# seconds since the flow started, advanced by the caller before each step",
+    );
+    line(o, 2, "self.time = 0.0");
+    line(o, 2, "self.entered = 0.0");
+    line(o, 2, "self.timers = {}");
+    if used.latches {
+        line(o, 2, "self.latches = {}");
+    }
+    if used.edges {
+        line(o, 2, "self.edges = {}");
+    }
+    if used.held {
+        line(o, 2, "self.held = {}");
+    }
+    line(o, 2, "self.actuators = {");
+    for act in ast.actuators() {
+        let init = match act.init {
+            Some(val) => literal(&act.kind, val),
+            None => "None".to_string(),
+        };
+        line(o, 3, &format!("{}: {},", quote(&act.name), init));
+    }
+    line(o, 2, "}");
+    line(o, 2, "self.finished = False");
+    line(o, 2, "# why the flow stopped, if an error stopped it");
+    line(o, 2, "self.halted = None");
+    if generator {
+        line(o, 2, "self._sensors = None");
+        line(o, 2, "self._ops = 0");
+        line(o, 2, "self._run = self._main()");
+    }
+    line(o, 0, "");
+    line(
+        o,
+        1,
+        "# run until the flow waits, with the same readings throughout, and give what the actuators",
+    );
+    line(
+        o,
+        1,
+        "# should be set to. After an error the flow stops with actuators at their fail-safe values.",
+    );
+    line(o, 1, "def step(self, sensors):");
+    line(o, 2, "if self.halted is None and not self.finished:");
+    if generator {
+        line(o, 3, "self._sensors = sensors");
+        line(o, 3, "self._ops = 0");
+    }
+    line(o, 3, "try:");
+    if generator {
+        line(o, 4, "next(self._run)");
+        line(o, 3, "except StopIteration:");
+        line(o, 4, "pass");
+    } else {
+        line(o, 4, "self._steps(sensors)");
+    }
+    line(o, 3, "except FlowError as e:");
+    line(o, 4, "self.halted = str(e)");
+    line(o, 4, "self._fail_safe()");
+    line(o, 2, "return dict(self.actuators)");
+
+    if generator {
+        gen_main(&gen, o);
+    } else {
+        gen_steps(&gen, &blocks, o);
+    }
+
+    line(o, 0, "");
+    line(o, 1, "def _enter(self, block):");
+    line(o, 2, "self.block = block");
+    if !generator {
+        line(o, 2, "self.pc = 0");
+    }
+    line(o, 2, "self.entered = self.time");
+
+    line(o, 0, "");
+    line(o, 1, "def _fail_safe(self):");
+    if !generator {
+        line(o, 2, "self.deadline = None");
+    }
+    let mut resets = 0;
+    for act in ast.actuators() {
+        if let Some(val) = act.failsafe {
+            line(
+                o,
+                2,
+                &format!(
+                    "self.actuators[{}] = {}",
+                    quote(&act.name),
+                    literal(&act.kind, val)
+                ),
+            );
+            resets += 1;
+        }
+    }
+    if generator && resets == 0 {
+        line(o, 2, "pass");
+    }
+
+    gen_helpers(&gen, &used, o);
+
+    for condition in &found {
+        line(o, 0, "");
+        line(o, 1, &format!("# {}", condition));
+        line(
+            o,
+            1,
+            &format!("def {}(self, s):", gen.methods[&address(condition)]),
+        );
+        line(o, 2, &format!("return {}", gen.expr(condition)));
+    }
+    out
+}
+
+fn gen_helpers(gen: &Generator, used: &Needs, o: &mut String) {
+    let ast = gen.ast;
+    line(o, 0, "");
+    line(o, 1, "# a condition evaluated where the flow is");
+    line(o, 1, "def _at(self, condition, s):");
+    line(o, 2, "try:");
+    line(o, 3, "return condition(s)");
+    line(o, 2, "except FlowError as e:");
+    line(
+        o,
+        3,
+        "raise FlowError(\"block %s: %s\" % (self.block, e)) from None",
+    );
+
+    if gen.guarded {
+        line(o, 0, "");
+        line(
+            o,
+            1,
+            "# set an actuator unless that breaks an interlock or invariant",
+        );
+        line(o, 1, "def _set(self, name, value, shown, s):");
+        line(o, 2, "previous = self.actuators[name]");
+        line(o, 2, "self.actuators[name] = value");
+        line(o, 2, "reason = self._violation(s)");
+        line(o, 2, "if reason is not None:");
+        line(o, 3, "self.actuators[name] = previous");
+        line(o, 3, "raise FlowError(");
+        line(
+            o,
+            4,
+            "\"block %s: refused to set %s to %s: %s\" % (self.block, name, shown, reason)",
+        );
+        line(o, 3, ")");
+        line(o, 0, "");
+        line(
+            o,
+            1,
+            "# the first interlock or invariant the current values break, if any",
+        );
+        line(o, 1, "def _violation(self, s):");
+        for interlock in &ast.interlocks {
+            let shown = quote(&interlock.to_string());
+            line(o, 2, "try:");
+            line(
+                o,
+                3,
+                &format!(
+                    "tripped = self.{}(s)",
+                    gen.methods[&address(&interlock.trigger)]
+                ),
+            );
+            line(o, 2, "except FlowError:");
+            line(
+                o,
+                3,
+                "# an actuator that was never set can't trip its interlock",
+            );
+            line(o, 3, "tripped = False");
+            line(o, 2, "if tripped:");
+            line(o, 3, "try:");
+            line(
+                o,
+                4,
+                &format!(
+                    "if not self.{}(s):",
+                    gen.methods[&address(&interlock.requirement)]
+                ),
+            );
+            line(
+                o,
+                5,
+                &format!(
+                    "return {}",
+                    quote(&format!("violates interlock {}", interlock))
+                ),
+            );
+            line(o, 3, "except FlowError as e:");
+            line(
+                o,
+                4,
+                &format!("return \"can't check interlock %s: %s\" % ({}, e)", shown),
+            );
+        }
+        for invariant in &ast.invariants {
+            line(o, 2, "try:");
+            line(
+                o,
+                3,
+                &format!("if not self.{}(s):", gen.methods[&address(invariant)]),
+            );
+            line(
+                o,
+                4,
+                &format!(
+                    "return {}",
+                    quote(&format!("violates invariant {}", invariant))
+                ),
+            );
+            line(o, 2, "except FlowError as e:");
+            line(
+                o,
+                3,
+                &format!(
+                    "return \"can't check invariant %s: %s\" % ({}, e)",
+                    quote(&invariant.to_string())
+                ),
+            );
+        }
+        line(o, 2, "return None");
+    }
+
+    if used.actuators {
+        line(o, 0, "");
+        line(o, 1, "def _actuator(self, name):");
+        line(o, 2, "if self.actuators[name] is None:");
+        line(
+            o,
+            3,
+            "raise FlowError(\"actuator %s read before it was set\" % name)",
+        );
+        line(o, 2, "return self.actuators[name]");
+    }
+    if used.timers {
+        line(o, 0, "");
+        line(o, 1, "# seconds since the timer was started");
+        line(o, 1, "def _timer(self, name):");
+        line(o, 2, "if name not in self.timers:");
+        line(
+            o,
+            3,
+            "raise FlowError(\"timer %s read before it was started\" % name)",
+        );
+        line(o, 2, "return self.time - self.timers[name]");
+    }
+    if used.latches {
+        line(o, 0, "");
+        line(
+            o,
+            1,
+            "# a hysteresis condition, which switches on once it's crossed the threshold and off",
+        );
+        line(o, 1, "# again once it's come back past the band");
+        line(o, 1, "def _latch(self, id, on, stays):");
+        line(
+            o,
+            2,
+            "self.latches[id] = stays if self.latches.get(id, False) else on",
+        );
+        line(o, 2, "return self.latches[id]");
+    }
+    if used.edges {
+        line(o, 0, "");
+        line(
+            o,
+            1,
+            "# whether a condition became true (rising) or false since it was last evaluated",
+        );
+        line(o, 1, "def _edge(self, id, rising, now):");
+        line(o, 2, "before = self.edges.get(id, now)");
+        line(o, 2, "self.edges[id] = now");
+        line(
+            o,
+            2,
+            "return (not before and now) if rising else (before and not now)",
+        );
+    }
+    if used.held {
+        line(o, 0, "");
+        line(
+            o,
+            1,
+            "# whether a condition has held for at least the duration",
+        );
+        line(o, 1, "def _held(self, id, duration, now):");
+        line(o, 2, "if not now:");
+        line(o, 3, "self.held.pop(id, None)");
+        line(o, 3, "return False");
+        line(o, 2, "since = self.held.setdefault(id, self.time)");
+        line(o, 2, "return self.time - since >= duration");
+    }
+}
+
+// the plain version: a method per block running the instruction at pc, returning whether the
+// flow has to stop there for this step
+fn gen_steps(gen: &Generator, blocks: &[lower::Lowered], o: &mut String) {
+    line(o, 0, "");
+    line(o, 1, "def _steps(self, s):");
+    line(o, 2, "for _ in range(MAX_OPS_PER_STEP):");
+    for (idx, block) in blocks.iter().enumerate() {
+        let keyword = if idx == 0 { "if" } else { "elif" };
+        line(
+            o,
+            3,
+            &format!("{} self.block == {}:", keyword, quote(block.name)),
+        );
+        line(o, 4, &format!("stop = self._block_{}(s)", block.name));
+    }
+    line(o, 3, "if stop:");
+    line(o, 4, "return");
+    line(
+        o,
+        2,
+        "raise FlowError(\"timed out busy looping in block \" + self.block)",
+    );
+
+    for block in blocks {
+        line(o, 0, "");
+        line(
+            o,
+            1,
+            &format!("# block {}, from line {}", block.name, block.block.line),
+        );
+        line(o, 1, &format!("def _block_{}(self, s):", block.name));
+        let last = block.code.len() - 1;
+        for (pc, (num, instr)) in block.code.iter().enumerate() {
+            let depth = if last == 0 { 2 } else { 3 };
+            if last > 0 {
+                let test = if pc == 0 {
+                    "if self.pc == 0:".to_string()
+                } else if pc == last {
+                    "else:".to_string()
+                } else {
+                    format!("elif self.pc == {}:", pc)
+                };
+                line(o, 2, &test);
+            }
+            if let Instr::Finish = instr {
+                line(o, depth, "# the end of the block");
+            } else {
+                line(o, depth, &format!("# line {}", num));
+            }
+            let next = format!("self.pc = {}", pc + 1);
+            match instr {
+                Instr::Set { actuator, value } => {
+                    gen.set(o, depth, actuator, *value);
+                    line(o, depth, &next);
+                }
+                Instr::Wait { condition } => {
+                    line(o, depth, &format!("if not {}:", gen.call(condition)));
+                    line(o, depth + 1, "return True");
+                    line(o, depth, &next);
+                }
+                Instr::Branch { condition, target } => line(
+                    o,
+                    depth,
+                    &format!(
+                        "self.pc = {} if {} else {}",
+                        pc + 1,
+                        gen.call(condition),
+                        target
+                    ),
+                ),
+                Instr::Jump { target } => line(o, depth, &format!("self.pc = {}", target)),
+                Instr::Delay { duration } => {
+                    line(o, depth, "if self.deadline is None:");
+                    line(
+                        o,
+                        depth + 1,
+                        &format!("self.deadline = self.time + {}", number(*duration)),
+                    );
+                    line(o, depth, "if self.time < self.deadline:");
+                    line(o, depth + 1, "return True");
+                    line(o, depth, "self.deadline = None");
+                    line(o, depth, &next);
+                }
+                Instr::Start { timer } => {
+                    line(
+                        o,
+                        depth,
+                        &format!("self.timers[{}] = self.time", quote(timer)),
+                    );
+                    line(o, depth, &next);
+                }
+                Instr::Goto { block } => {
+                    line(o, depth, &format!("self._enter({})", quote(block)));
+                }
+                Instr::Forget { conditions } => {
+                    forget(conditions, depth, o);
+                    line(o, depth, &next);
+                }
+                Instr::Finish => {
+                    line(o, depth, "self.finished = True");
+                    line(o, depth, "return True");
+                }
+            }
+        }
+        line(o, 2, "return False");
+    }
+}
+
+// the generator version: a generator per block, giving the block to go to next, or None at the
+// end of one, and one running them in turn
+fn gen_main(gen: &Generator, o: &mut String) {
+    let ast = gen.ast;
+    let mut blocks: Vec<(&String, &crate::ast::Block)> = ast.blocks.iter().collect();
+    blocks.sort_by_key(|(_, block)| block.line);
+
+    line(o, 0, "");
+    line(o, 1, "def _main(self):");
+    line(o, 2, &format!("block = {}", quote(&ast.first_block_name)));
+    line(o, 2, "while block is not None:");
+    line(o, 3, "self._enter(block)");
+    for (idx, (name, _)) in blocks.iter().enumerate() {
+        let keyword = if idx == 0 { "if" } else { "elif" };
+        line(o, 3, &format!("{} block == {}:", keyword, quote(name)));
+        line(o, 4, &format!("block = yield from self._block_{}()", name));
+    }
+    line(o, 2, "self.finished = True");
+
+    line(o, 0, "");
+    line(
+        o,
+        1,
+        "# count a statement against the step's budget, so a busy loop can't hang the caller",
+    );
+    line(o, 1, "def _tick(self):");
+    line(o, 2, "self._ops += 1");
+    line(o, 2, "if self._ops > MAX_OPS_PER_STEP:");
+    line(
+        o,
+        3,
+        "raise FlowError(\"timed out busy looping in block \" + self.block)",
+    );
+
+    for (name, block) in blocks {
+        line(o, 0, "");
+        line(o, 1, &format!("# block {}, from line {}", name, block.line));
+        line(o, 1, &format!("def _block_{}(self):", name));
+        forget(&lower::remembered(&block.ops), 2, o);
+        gen_statements(gen, &block.ops, 2, o);
+        if !leaves(&block.ops) {
+            line(o, 2, "# the end of the block");
+            line(o, 2, "self._tick()");
+            line(o, 2, "return None");
+        }
+        if !waits(&block.ops) {
+            line(
+                o,
+                2,
+                "# never reached, but makes this a generator like the other blocks",
+            );
+            line(o, 2, "yield");
+        }
+    }
+}
+
+// start the block's edge and duration conditions afresh, as it's entered
+fn forget(conditions: &[&Condition], depth: usize, o: &mut String) {
+    for condition in conditions {
+        match condition {
+            Condition::Edge { id, .. } => line(o, depth, &format!("self.edges.pop({}, None)", id)),
+            Condition::For { id, .. } => line(o, depth, &format!("self.held.pop({}, None)", id)),
+            _ => {}
+        }
+    }
+}
+
+// whether the statements always end in a goto, so nothing after them runs
+fn leaves(ops: &[Statement]) -> bool {
+    match ops.last().map(|stmt| &stmt.op) {
+        Some(Operation::Goto { .. }) => true,
+        Some(Operation::IfElse {
+            if_actions,
+            else_actions: Some(else_actions),
+            ..
+        }) => leaves(if_actions) && leaves(else_actions),
+        _ => false,
+    }
+}
+
+fn waits(ops: &[Statement]) -> bool {
+    ops.iter().any(|stmt| match &stmt.op {
+        Operation::Wait { .. } | Operation::Delay { .. } => true,
+        Operation::IfElse {
+            if_actions,
+            else_actions,
+            ..
+        } => waits(if_actions) || else_actions.as_deref().is_some_and(waits),
+        _ => false,
+    })
+}
+
+fn gen_statements(gen: &Generator, ops: &[Statement], depth: usize, o: &mut String) {
+    if ops.is_empty() {
+        line(o, depth, "pass");
+    }
+    for stmt in ops {
+        line(o, depth, &format!("# line {}", stmt.line));
+        line(o, depth, "self._tick()");
+        match &stmt.op {
+            Operation::Set { actuator, value } => gen.set(o, depth, actuator, *value),
+            Operation::Wait { condition } => {
+                line(o, depth, &format!("while not {}:", gen.call(condition)));
+                line(o, depth + 1, "yield");
+                line(o, depth + 1, "self._tick()");
+            }
+            Operation::Delay { duration } => {
+                line(
+                    o,
+                    depth,
+                    &format!("deadline = self.time + {}", number(*duration)),
+                );
+                line(o, depth, "while self.time < deadline:");
+                line(o, depth + 1, "yield");
+                line(o, depth + 1, "self._tick()");
+            }
+            Operation::Start { timer } => line(
+                o,
+                depth,
+                &format!("self.timers[{}] = self.time", quote(timer)),
+            ),
+            Operation::IfElse {
+                if_condition,
+                if_actions,
+                else_actions,
+            } => {
+                line(o, depth, &format!("if {}:", gen.call(if_condition)));
+                gen_statements(gen, if_actions, depth + 1, o);
+                if let Some(actions) = else_actions {
+                    line(o, depth, "else:");
+                    gen_statements(gen, actions, depth + 1, o);
+                }
+            }
+            Operation::Goto { dest } => line(o, depth, &format!("return {}", quote(dest))),
+        }
+    }
+}
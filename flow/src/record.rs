@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+
+use crate::ast::{Actuator, Device, Sensor, AST};
+use crate::backend::DeviceBackend;
+use crate::interp::Event;
+
+// A run log holds one entry per step in which anything happened, each starting with `@ <time>`
+// and followed by, in order:
+//   read <sensor> <value>          a reading that changed since the last step
+//   lost <device>                  a sensor that couldn't be read or an actuator that couldn't
+//                                  be written
+//   enter <block>
+//   set <actuator> <value>
+//   eval <true|false> <condition>  a wait or if condition whose outcome changed
+//   error <message>                what stopped the run
+//   failsafe                       followed by the sets that drove actuators to fail-safe values
+// Steps where no reading, outcome or actuator changed are left out, since replaying them can't
+// change anything either.
+
+// passes everything through to another backend, noting which device last failed
+pub struct Tap {
+    inner: Box<dyn DeviceBackend>,
+    pub failed: Option<String>,
+}
+
+impl Tap {
+    pub fn new(inner: Box<dyn DeviceBackend>) -> Tap {
+        Tap {
+            inner,
+            failed: None,
+        }
+    }
+}
+
+impl DeviceBackend for Tap {
+    fn read_sensor(&mut self, sensor: &Sensor) -> Result<f64, String> {
+        let result = self.inner.read_sensor(sensor);
+        if result.is_err() {
+            self.failed = Some(sensor.name.clone());
+        }
+        result
+    }
+
+    fn write_actuator(&mut self, actuator: &Actuator, value: f64) -> Result<(), String> {
+        let result = self.inner.write_actuator(actuator, value);
+        if result.is_err() {
+            self.failed = Some(actuator.name.clone());
+        }
+        result
+    }
+
+    fn seek(&mut self, time: f64) {
+        self.inner.seek(time)
+    }
+
+    fn next_change(&self) -> Option<f64> {
+        self.inner.next_change()
+    }
+}
+
+// turns what happened in each step into log lines, remembering what's already been logged
+pub struct Recorder<'a> {
+    ast: &'a AST,
+    readings: HashMap<String, f64>,
+    outcomes: HashMap<String, bool>,
+}
+
+impl<'a> Recorder<'a> {
+    pub fn new(ast: &'a AST) -> Recorder<'a> {
+        Recorder {
+            ast,
+            readings: HashMap::new(),
+            outcomes: HashMap::new(),
+        }
+    }
+
+    fn show(&self, device: &str, val: f64) -> String {
+        match self.ast.devices.get(device) {
+            Some(Device::Sensor(sens)) => sens.kind.show(val),
+            Some(Device::Actuator(act)) => act.kind.show(val),
+            _ => val.to_string(),
+        }
+    }
+
+    // the lines for one step, or none if nothing changed
+    pub fn step(
+        &mut self,
+        time: f64,
+        readings: &HashMap<String, f64>,
+        lost: Option<&str>,
+        events: &[Event],
+        error: Option<&str>,
+    ) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut names: Vec<&String> = readings.keys().collect();
+        names.sort();
+        for name in names {
+            let val = readings[name];
+            if self.readings.insert(name.clone(), val) != Some(val) {
+                lines.push(format!("read {} {}", name, self.show(name, val)));
+            }
+        }
+        if let Some(name) = lost {
+            lines.push(format!("lost {}", name));
+        }
+        lines.extend(self.events(events));
+        if let Some(e) = error {
+            lines.push(format!("error {}", e));
+        }
+        if !lines.is_empty() {
+            lines.insert(0, format!("@ {}", time));
+        }
+        lines
+    }
+
+    pub fn fail_safe(&mut self, events: &[Event]) -> Vec<String> {
+        let mut lines = vec!["failsafe".to_string()];
+        lines.extend(self.events(events));
+        lines
+    }
+
+    fn events(&mut self, events: &[Event]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for event in events {
+            match event {
+                Event::Enter(block) => lines.push(format!("enter {}", block)),
+                Event::Set(act, val) => lines.push(format!("set {} {}", act, self.show(act, *val))),
+                Event::Eval(condition, holds) => {
+                    if self.outcomes.insert(condition.clone(), *holds) != Some(*holds) {
+                        lines.push(format!("eval {} {}", holds, condition));
+                    }
+                }
+            }
+        }
+        lines
+    }
+}
+
+// one logged step, with the lines it was recorded as
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub time: f64,
+    pub readings: Vec<(String, f64)>,
+    pub lost: Option<String>,
+    pub error: Option<String>,
+    pub lines: Vec<String>,
+}
+
+pub fn parse_log(text: &str, ast: &AST) -> Result<Vec<Step>, String> {
+    let mut steps: Vec<Step> = Vec::new();
+    for (num, line) in text.lines().enumerate() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let err = |msg: &str| format!("line {}: {}", num + 1, msg);
+        if let Some(time) = line.strip_prefix("@ ") {
+            let time = match time.parse::<f64>() {
+                Ok(time) => time,
+                Err(_) => return Err(err(&format!("invalid time {}", time))),
+            };
+            steps.push(Step {
+                time,
+                readings: Vec::new(),
+                lost: None,
+                error: None,
+                lines: vec![line.to_string()],
+            });
+            continue;
+        }
+        let step = match steps.last_mut() {
+            Some(step) => step,
+            None => return Err(err("expected @ <time> before the first entry")),
+        };
+        step.lines.push(line.to_string());
+
+        let (kind, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match kind {
+            "read" => {
+                let reading =
+                    rest.split_once(' ')
+                        .and_then(|(name, val)| match ast.devices.get(name) {
+                            Some(Device::Sensor(sens)) => {
+                                Some((name.to_string(), sens.kind.parse(val)?))
+                            }
+                            _ => None,
+                        });
+                match reading {
+                    Some(reading) => step.readings.push(reading),
+                    None => return Err(err(&format!("invalid reading \"{}\"", rest))),
+                }
+            }
+            "lost" => step.lost = Some(rest.to_string()),
+            "error" => step.error = Some(rest.to_string()),
+            "enter" | "set" | "eval" | "failsafe" => {}
+            _ => return Err(err(&format!("unknown entry \"{}\"", line))),
+        }
+    }
+    Ok(steps)
+}
+
+// plays back logged readings, failing whichever read or write the log says failed
+#[derive(Debug, Default)]
+pub struct Replay {
+    readings: HashMap<String, f64>,
+    lost: Option<String>,
+    error: Option<String>,
+    pub failed: Option<String>,
+}
+
+impl Replay {
+    pub fn load(&mut self, step: &Step) {
+        for (name, val) in &step.readings {
+            self.readings.insert(name.clone(), *val);
+        }
+        self.lost = step.lost.clone();
+        self.error = step.error.clone();
+    }
+
+    fn fails(&mut self, device: &str) -> Result<(), String> {
+        if self.lost.as_deref() == Some(device) {
+            // only the one access failed, so later ones like fail-safe writes go through
+            self.failed = self.lost.take();
+            return Err(self.error.clone().unwrap_or_default());
+        }
+        Ok(())
+    }
+}
+
+impl DeviceBackend for Replay {
+    fn read_sensor(&mut self, sensor: &Sensor) -> Result<f64, String> {
+        self.fails(&sensor.name)?;
+        match self.readings.get(&sensor.name) {
+            Some(val) => Ok(*val),
+            None => Err(format!("no reading for sensor {} in log", sensor.name)),
+        }
+    }
+
+    fn write_actuator(&mut self, actuator: &Actuator, _value: f64) -> Result<(), String> {
+        self.fails(&actuator.name)
+    }
+}
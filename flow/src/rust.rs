@@ -0,0 +1,837 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::ast::{Clock, Comparator, Condition, Device, Kind, AST};
+use crate::interp::MAX_OPS_PER_STEP;
+use crate::lower::{
+    self, address, conditions, line, reads_elapsed, reads_sensors, timers_read, Instr,
+};
+
+// Generates a Rust module that runs a flow without the interpreter: typed structs for the sensor
+// readings and actuator values, a State enum with a variant per block, and a Flow whose step()
+// runs the flow the way Interpreter::step does, stopping at waits rather than blocking on them.
+
+const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+    "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub",
+    "ref", "return", "static", "struct", "trait", "true", "type", "unsafe", "use", "where",
+    "while", "abstract", "become", "box", "do", "final", "gen", "macro", "override", "priv", "try",
+    "typeof", "unsized", "virtual", "yield",
+];
+
+// names the generated module uses for itself, which enum types for devices mustn't take
+const RESERVED: &[&str] = &["Flow", "State", "Sensors", "Actuators"];
+
+fn ident(name: &str) -> String {
+    if KEYWORDS.contains(&name) {
+        format!("r#{}", name)
+    } else {
+        name.to_string()
+    }
+}
+
+fn camel(name: &str) -> String {
+    let mut out: String = name
+        .split('_')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            let first = chars.next().unwrap().to_ascii_uppercase();
+            std::iter::once(first).chain(chars).collect::<String>()
+        })
+        .collect();
+    if !out.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        out.insert(0, 'V');
+    }
+    out
+}
+
+// the state conditions keep between steps, as (field, type, initial value)
+fn memory(condition: &Condition, out: &mut Vec<(String, &'static str, &'static str)>) {
+    match condition {
+        Condition::Hysteresis { id, .. } => out.push((format!("latch_{}", id), "bool", "false")),
+        Condition::Edge { condition, id, .. } => {
+            out.push((format!("edge_{}", id), "Option<bool>", "None"));
+            memory(condition, out);
+        }
+        Condition::For { condition, id, .. } => {
+            out.push((format!("held_{}", id), "Option<f64>", "None"));
+            memory(condition, out);
+        }
+        Condition::All(conditions) | Condition::Any(conditions) => {
+            for c in conditions {
+                memory(c, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+struct Generator<'a> {
+    ast: &'a AST,
+    // the enum type generated for each enum device
+    types: HashMap<String, String>,
+    // the method evaluating each condition, by its address in the AST
+    methods: HashMap<usize, String>,
+    // whether setting an actuator has any interlocks or invariants to check
+    guarded: bool,
+    // timers some condition reads
+    timers: BTreeSet<String>,
+}
+
+impl Generator<'_> {
+    fn method(&self, condition: &Condition) -> &str {
+        &self.methods[&address(condition)]
+    }
+
+    fn kind_type(&self, name: &str, kind: &Kind) -> String {
+        match kind {
+            Kind::Float => "f64".to_string(),
+            Kind::Bool => "bool".to_string(),
+            Kind::Enum(_) => self.types[name].clone(),
+        }
+    }
+
+    fn literal(&self, name: &str, kind: &Kind, val: f64) -> String {
+        match kind {
+            Kind::Float => format!("{:?}", val),
+            Kind::Bool => (val != 0.0).to_string(),
+            Kind::Enum(variants) => {
+                format!("{}::{}", self.types[name], camel(&variants[val as usize]))
+            }
+        }
+    }
+
+    fn compare(&self, lhs: &str, name: &str, kind: &Kind, comp: Comparator, val: f64) -> String {
+        let rhs = self.literal(name, kind, val);
+        match (kind, comp) {
+            // bools and enums can only be compared for equality
+            (Kind::Bool, _) if val != 0.0 => lhs.to_string(),
+            (Kind::Bool, _) => format!("!{}", lhs),
+            (Kind::Enum(_), _) => format!("{} == {}", lhs, rhs),
+            (_, Comparator::APPROX(tol)) => format!("({} - {}).abs() <= {:?}", lhs, rhs, tol),
+            (_, comp) => {
+                let symbol = match comp {
+                    Comparator::EQ => "==".to_string(),
+                    other => other.to_string(),
+                };
+                format!("{} {} {}", lhs, symbol, rhs)
+            }
+        }
+    }
+
+    fn expr(&self, condition: &Condition) -> String {
+        match condition {
+            Condition::Base(sensor, comp, val) => {
+                let lhs = format!("s.{}", ident(&sensor.name));
+                self.compare(&lhs, &sensor.name, &sensor.kind, *comp, *val)
+            }
+            Condition::Actuator(act, comp, val) => {
+                let lhs = if act.init.is_some() {
+                    format!("self.actuators.{}", ident(&act.name))
+                } else {
+                    format!(
+                        "self.actuators.{}.ok_or_else(|| {:?}.to_string())?",
+                        ident(&act.name),
+                        format!("actuator {} read before it was set", act.name)
+                    )
+                };
+                self.compare(&lhs, &act.name, &act.kind, *comp, *val)
+            }
+            Condition::Time(clock, comp, val) => {
+                let reading = match clock {
+                    Clock::Elapsed => "(self.time - self.entered)".to_string(),
+                    Clock::Now => "self.time".to_string(),
+                    Clock::Timer(name) => format!(
+                        "(self.time - self.timer_{}.ok_or_else(|| {:?}.to_string())?)",
+                        name,
+                        format!("timer {} read before it was started", name)
+                    ),
+                };
+                self.compare(&reading, "", &Kind::Float, *comp, *val)
+            }
+            Condition::Hysteresis {
+                sensor,
+                rising,
+                threshold,
+                band,
+                id,
+            } => {
+                let reading = format!("s.{}", ident(&sensor.name));
+                let (on, stays) = if *rising {
+                    (
+                        format!("{} > {:?}", reading, threshold),
+                        format!("{} >= {:?}", reading, threshold - band),
+                    )
+                } else {
+                    (
+                        format!("{} < {:?}", reading, threshold),
+                        format!("{} <= {:?}", reading, threshold + band),
+                    )
+                };
+                format!(
+                    "{{ self.latch_{id} = if self.latch_{id} {{ {} }} else {{ {} }}; self.latch_{id} }}",
+                    stays,
+                    on,
+                    id = id
+                )
+            }
+            Condition::Edge {
+                condition,
+                rising,
+                id,
+            } => format!(
+                "{{ let now = {}; let before = self.edge_{}.replace(now).unwrap_or(now); {} }}",
+                self.expr(condition),
+                id,
+                if *rising {
+                    "!before && now"
+                } else {
+                    "before && !now"
+                }
+            ),
+            Condition::For {
+                condition,
+                duration,
+                id,
+            } => format!(
+                "if {} {{ let since = *self.held_{id}.get_or_insert(self.time); self.time - since >= {:?} }} else {{ self.held_{id} = None; false }}",
+                self.expr(condition),
+                duration,
+                id = id
+            ),
+            Condition::All(conditions) | Condition::Any(conditions) => {
+                let joiner = if let Condition::All(_) = condition {
+                    " && "
+                } else {
+                    " || "
+                };
+                let parts: Vec<String> = conditions
+                    .iter()
+                    .map(|c| format!("({})", self.expr(c)))
+                    .collect();
+                parts.join(joiner)
+            }
+        }
+    }
+
+    // a condition evaluated where the flow is, with errors saying which block it's in
+    fn call(&self, condition: &Condition, block: &str) -> String {
+        format!(
+            "self.{}(s).map_err(|e| format!(\"block {}: {{}}\", e))?",
+            self.method(condition),
+            block
+        )
+    }
+}
+
+pub fn generate(ast: &AST, source: &str) -> String {
+    let blocks = lower::lower(ast);
+    let mut types = HashMap::new();
+    for (name, device) in &ast.devices {
+        let kind = match device {
+            Device::Sensor(sens) => &sens.kind,
+            Device::Actuator(act) => &act.kind,
+            Device::Timer(_) => continue,
+        };
+        if let Kind::Enum(_) = kind {
+            let mut ty = camel(name);
+            if RESERVED.contains(&ty.as_str()) {
+                ty += "Value";
+            }
+            types.insert(name.clone(), ty);
+        }
+    }
+    let found = conditions(ast, &blocks);
+    let methods = found
+        .iter()
+        .enumerate()
+        .map(|(idx, c)| (address(c), format!("condition_{}", idx)))
+        .collect();
+    let gen = Generator {
+        ast,
+        types,
+        methods,
+        guarded: !ast.interlocks.is_empty() || !ast.invariants.is_empty(),
+        timers: found
+            .iter()
+            .flat_map(|c| {
+                let mut read = BTreeSet::new();
+                timers_read(c, &mut read);
+                read.into_iter().map(str::to_string).collect::<Vec<_>>()
+            })
+            .collect(),
+    };
+    let sensors = ast.sensors();
+    let actuators = ast.actuators();
+
+    let mut out = String::new();
+    let o = &mut out;
+    line(
+        o,
+        0,
+        &format!(
+            "// generated by `flow compile --target rust` from {}; edit the flow, not this file",
+            source
+        ),
+    );
+
+    // enums for enum devices, in name order
+    let mut enums: Vec<(&String, &Vec<String>)> = sensors
+        .iter()
+        .filter_map(|s| match &s.kind {
+            Kind::Enum(variants) => Some((&s.name, variants)),
+            _ => None,
+        })
+        .chain(actuators.iter().filter_map(|a| match &a.kind {
+            Kind::Enum(variants) => Some((&a.name, variants)),
+            _ => None,
+        }))
+        .collect();
+    enums.sort();
+    for (name, variants) in enums {
+        line(o, 0, "");
+        line(o, 0, "#[derive(Debug, Clone, Copy, PartialEq, Eq)]");
+        line(o, 0, &format!("pub enum {} {{", gen.types[name]));
+        for variant in variants {
+            line(o, 1, &format!("{},", camel(variant)));
+        }
+        line(o, 0, "}");
+    }
+
+    let unit = |unit: &Option<crate::units::Unit>| match unit {
+        Some(unit) => format!(" // {}", unit.name),
+        None => String::new(),
+    };
+    line(o, 0, "");
+    line(o, 0, "// the readings a step runs with");
+    line(o, 0, "#[derive(Debug, Clone, PartialEq)]");
+    line(o, 0, "pub struct Sensors {");
+    for sens in &sensors {
+        line(
+            o,
+            1,
+            &format!(
+                "pub {}: {},{}",
+                ident(&sens.name),
+                gen.kind_type(&sens.name, &sens.kind),
+                unit(&sens.unit)
+            ),
+        );
+    }
+    line(o, 0, "}");
+
+    line(o, 0, "");
+    line(
+        o,
+        0,
+        "// what the actuators should be set to, None for ones the flow hasn't set yet",
+    );
+    line(o, 0, "#[derive(Debug, Clone, PartialEq)]");
+    line(o, 0, "pub struct Actuators {");
+    for act in &actuators {
+        let ty = gen.kind_type(&act.name, &act.kind);
+        let ty = if act.init.is_some() {
+            ty
+        } else {
+            format!("Option<{}>", ty)
+        };
+        line(
+            o,
+            1,
+            &format!("pub {}: {},{}", ident(&act.name), ty, unit(&act.unit)),
+        );
+    }
+    line(o, 0, "}");
+
+    line(o, 0, "");
+    line(o, 0, "#[derive(Debug, Clone, Copy, PartialEq, Eq)]");
+    line(o, 0, "pub enum State {");
+    for block in &blocks {
+        line(o, 1, &format!("{},", camel(block.name)));
+    }
+    line(o, 0, "}");
+    line(o, 0, "");
+    line(o, 0, "impl State {");
+    line(o, 1, "pub fn name(&self) -> &'static str {");
+    line(o, 2, "match self {");
+    for block in &blocks {
+        line(
+            o,
+            3,
+            &format!("State::{} => {:?},", camel(block.name), block.name),
+        );
+    }
+    line(o, 2, "}");
+    line(o, 1, "}");
+    line(o, 0, "}");
+
+    gen_flow(&gen, &blocks, &found, o);
+    out
+}
+
+fn gen_flow(gen: &Generator, blocks: &[lower::Lowered], conditions: &[&Condition], o: &mut String) {
+    let ast = gen.ast;
+    let actuators = ast.actuators();
+    let mut kept = Vec::new();
+    for condition in conditions {
+        memory(condition, &mut kept);
+    }
+    let elapsed = conditions.iter().any(|c| reads_elapsed(c));
+    let delays = blocks.iter().any(|b| {
+        b.code
+            .iter()
+            .any(|(_, instr)| matches!(instr, Instr::Delay { .. }))
+    });
+    let gotos = blocks.iter().any(|b| {
+        b.code
+            .iter()
+            .any(|(_, instr)| matches!(instr, Instr::Goto { .. }))
+    });
+    let first = camel(&ast.first_block_name);
+
+    line(o, 0, "");
+    line(
+        o,
+        0,
+        &format!("const MAX_OPS_PER_STEP: usize = {};", MAX_OPS_PER_STEP),
+    );
+    line(o, 0, "");
+    line(o, 0, "pub struct Flow {");
+    line(o, 1, "pub state: State,");
+    line(o, 1, "// where in the block's code the flow is");
+    line(o, 1, "pc: usize,");
+    line(
+        o,
+        1,
+        "// seconds since the flow started, advanced by the caller before each step",
+    );
+    line(o, 1, "pub time: f64,");
+    if elapsed {
+        line(o, 1, "// when the current block was entered");
+        line(o, 1, "entered: f64,");
+    }
+    if delays {
+        line(o, 1, "// when the delay currently being waited on ends");
+        line(o, 1, "deadline: Option<f64>,");
+    }
+    for timer in &gen.timers {
+        line(o, 1, &format!("timer_{}: Option<f64>,", timer));
+    }
+    for (field, ty, _) in &kept {
+        line(o, 1, &format!("{}: {},", field, ty));
+    }
+    line(o, 1, "actuators: Actuators,");
+    line(o, 1, "finished: bool,");
+    line(o, 1, "halted: Option<String>,");
+    line(o, 0, "}");
+
+    line(o, 0, "");
+    line(o, 0, "impl Default for Flow {");
+    line(o, 1, "fn default() -> Self {");
+    line(o, 2, "Self::new()");
+    line(o, 1, "}");
+    line(o, 0, "}");
+
+    line(o, 0, "");
+    line(o, 0, "impl Flow {");
+    line(o, 1, "pub fn new() -> Flow {");
+    line(o, 2, "Flow {");
+    line(o, 3, &format!("state: State::{},", first));
+    line(o, 3, "pc: 0,");
+    line(o, 3, "time: 0.0,");
+    if elapsed {
+        line(o, 3, "entered: 0.0,");
+    }
+    if delays {
+        line(o, 3, "deadline: None,");
+    }
+    for timer in &gen.timers {
+        line(o, 3, &format!("timer_{}: None,", timer));
+    }
+    for (field, _, init) in &kept {
+        line(o, 3, &format!("{}: {},", field, init));
+    }
+    line(o, 3, "actuators: Actuators {");
+    for act in &actuators {
+        let init = match act.init {
+            Some(val) => gen.literal(&act.name, &act.kind, val),
+            None => "None".to_string(),
+        };
+        line(o, 4, &format!("{}: {},", ident(&act.name), init));
+    }
+    line(o, 3, "},");
+    line(o, 3, "finished: false,");
+    line(o, 3, "halted: None,");
+    line(o, 2, "}");
+    line(o, 1, "}");
+    line(o, 0, "");
+    line(o, 1, "// why the flow stopped, if an error stopped it");
+    line(o, 1, "pub fn halted(&self) -> Option<&str> {");
+    line(o, 2, "self.halted.as_deref()");
+    line(o, 1, "}");
+    line(o, 0, "");
+    line(o, 1, "// whether the flow ran off the end of a block");
+    line(o, 1, "pub fn finished(&self) -> bool {");
+    line(o, 2, "self.finished");
+    line(o, 1, "}");
+    line(o, 0, "");
+    line(
+        o,
+        1,
+        "// run until the flow waits, with the same readings throughout, and give what the actuators",
+    );
+    line(
+        o,
+        1,
+        "// should be set to. After an error the flow stops with actuators at their fail-safe values.",
+    );
+    line(
+        o,
+        1,
+        "pub fn step(&mut self, sensors: &Sensors) -> Actuators {",
+    );
+    line(o, 2, "if self.halted.is_none() && !self.finished {");
+    line(o, 3, "if let Err(e) = self.run(sensors) {");
+    line(o, 4, "self.halted = Some(e);");
+    line(o, 4, "self.fail_safe();");
+    line(o, 3, "}");
+    line(o, 2, "}");
+    line(o, 2, "self.actuators.clone()");
+    line(o, 1, "}");
+
+    gen_run(gen, blocks, o);
+
+    if gotos {
+        line(o, 0, "");
+        line(o, 1, "fn enter(&mut self, state: State) {");
+        line(o, 2, "self.state = state;");
+        line(o, 2, "self.pc = 0;");
+        if elapsed {
+            line(o, 2, "self.entered = self.time;");
+        }
+        line(o, 1, "}");
+    }
+
+    let mut resets = Vec::new();
+    if delays {
+        resets.push("self.deadline = None;".to_string());
+    }
+    for act in &actuators {
+        if let Some(val) = act.failsafe {
+            let val = gen.literal(&act.name, &act.kind, val);
+            let val = if act.init.is_some() {
+                val
+            } else {
+                format!("Some({})", val)
+            };
+            resets.push(format!("self.actuators.{} = {};", ident(&act.name), val));
+        }
+    }
+    line(o, 0, "");
+    if resets.is_empty() {
+        line(o, 1, "fn fail_safe(&mut self) {}");
+    } else {
+        line(o, 1, "fn fail_safe(&mut self) {");
+        for reset in &resets {
+            line(o, 2, reset);
+        }
+        line(o, 1, "}");
+    }
+
+    if gen.guarded {
+        gen_safety(gen, blocks, o);
+    }
+
+    for condition in conditions {
+        let method = gen.method(condition);
+        let sensors = if reads_sensors(condition) { "s" } else { "_s" };
+        line(o, 0, "");
+        line(o, 1, &format!("// {}", condition));
+        line(
+            o,
+            1,
+            &format!(
+                "fn {}(&mut self, {}: &Sensors) -> Result<bool, String> {{",
+                method, sensors
+            ),
+        );
+        line(o, 2, &format!("Ok({})", gen.expr(condition)));
+        line(o, 1, "}");
+    }
+    line(o, 0, "}");
+}
+
+fn gen_run(gen: &Generator, blocks: &[lower::Lowered], o: &mut String) {
+    // sets only need the readings to check interlocks and invariants
+    let uses = blocks.iter().any(|b| {
+        b.code.iter().any(|(_, instr)| match instr {
+            Instr::Set { .. } => gen.guarded,
+            Instr::Wait { .. } | Instr::Branch { .. } => true,
+            _ => false,
+        })
+    });
+    line(o, 0, "");
+    line(
+        o,
+        1,
+        &format!(
+            "fn run(&mut self, {}: &Sensors) -> Result<(), String> {{",
+            if uses { "s" } else { "_s" }
+        ),
+    );
+    line(o, 2, "for _ in 0..MAX_OPS_PER_STEP {");
+    line(o, 3, "match self.state {");
+    for block in blocks {
+        line(
+            o,
+            4,
+            &format!("State::{} => match self.pc {{", camel(block.name)),
+        );
+        let last = block.code.len() - 1;
+        for (pc, (num, instr)) in block.code.iter().enumerate() {
+            let label = if pc == last {
+                "_".to_string()
+            } else {
+                pc.to_string()
+            };
+            if let Instr::Finish = instr {
+                line(o, 5, "// the end of the block");
+            } else {
+                line(o, 5, &format!("// line {}", num));
+            }
+            line(o, 5, &format!("{} => {{", label));
+            let next = format!("self.pc = {};", pc + 1);
+            match instr {
+                Instr::Set { actuator, value } => {
+                    let val = gen.literal(&actuator.name, &actuator.kind, *value);
+                    if gen.guarded {
+                        line(
+                            o,
+                            6,
+                            &format!(
+                                "self.set_{}(s, {}, {:?})?;",
+                                actuator.name,
+                                val,
+                                value.to_string()
+                            ),
+                        );
+                    } else {
+                        let val = if actuator.init.is_some() {
+                            val
+                        } else {
+                            format!("Some({})", val)
+                        };
+                        line(
+                            o,
+                            6,
+                            &format!("self.actuators.{} = {};", ident(&actuator.name), val),
+                        );
+                    }
+                    line(o, 6, &next);
+                }
+                Instr::Wait { condition } => {
+                    line(o, 6, &format!("if !{} {{", gen.call(condition, block.name)));
+                    line(o, 7, "return Ok(());");
+                    line(o, 6, "}");
+                    line(o, 6, &next);
+                }
+                Instr::Branch { condition, target } => {
+                    line(
+                        o,
+                        6,
+                        &format!(
+                            "self.pc = if {} {{ {} }} else {{ {} }};",
+                            gen.call(condition, block.name),
+                            pc + 1,
+                            target
+                        ),
+                    );
+                }
+                Instr::Jump { target } => line(o, 6, &format!("self.pc = {};", target)),
+                Instr::Delay { duration } => {
+                    line(
+                        o,
+                        6,
+                        &format!(
+                            "let deadline = *self.deadline.get_or_insert(self.time + {:?});",
+                            duration
+                        ),
+                    );
+                    line(o, 6, "if self.time < deadline {");
+                    line(o, 7, "return Ok(());");
+                    line(o, 6, "}");
+                    line(o, 6, "self.deadline = None;");
+                    line(o, 6, &next);
+                }
+                Instr::Start { timer } => {
+                    // a timer nothing reads doesn't need starting
+                    if gen.timers.contains(*timer) {
+                        line(o, 6, &format!("self.timer_{} = Some(self.time);", timer));
+                    }
+                    line(o, 6, &next);
+                }
+                Instr::Goto { block } => {
+                    line(o, 6, &format!("self.enter(State::{});", camel(block)));
+                }
+                Instr::Forget { conditions } => {
+                    for condition in conditions {
+                        match condition {
+                            Condition::Edge { id, .. } => {
+                                line(o, 6, &format!("self.edge_{} = None;", id))
+                            }
+                            Condition::For { id, .. } => {
+                                line(o, 6, &format!("self.held_{} = None;", id))
+                            }
+                            _ => {}
+                        }
+                    }
+                    line(o, 6, &next);
+                }
+                Instr::Finish => {
+                    line(o, 6, "self.finished = true;");
+                    line(o, 6, "return Ok(());");
+                }
+            }
+            line(o, 5, "}");
+        }
+        line(o, 4, "},");
+    }
+    line(o, 3, "}");
+    line(o, 2, "}");
+    line(
+        o,
+        2,
+        "Err(format!(\"timed out busy looping in block {}\", self.state.name()))",
+    );
+    line(o, 1, "}");
+}
+
+// setters for the actuators the flow sets, which refuse values breaking an interlock or invariant
+fn gen_safety(gen: &Generator, blocks: &[lower::Lowered], o: &mut String) {
+    let ast = gen.ast;
+    let mut set: Vec<&crate::ast::Actuator> = Vec::new();
+    for block in blocks {
+        for (_, instr) in &block.code {
+            if let Instr::Set { actuator, .. } = instr {
+                if !set.iter().any(|a| a.name == actuator.name) {
+                    set.push(actuator);
+                }
+            }
+        }
+    }
+    set.sort_by(|a, b| a.name.cmp(&b.name));
+    for act in set {
+        let ty = gen.kind_type(&act.name, &act.kind);
+        let (wrapped, field) = (
+            if act.init.is_some() {
+                "value".to_string()
+            } else {
+                "Some(value)".to_string()
+            },
+            ident(&act.name),
+        );
+        line(o, 0, "");
+        line(
+            o,
+            1,
+            &format!(
+                "fn set_{}(&mut self, s: &Sensors, value: {}, shown: &str) -> Result<(), String> {{",
+                act.name, ty
+            ),
+        );
+        line(
+            o,
+            2,
+            &format!(
+                "let previous = std::mem::replace(&mut self.actuators.{}, {});",
+                field, wrapped
+            ),
+        );
+        line(o, 2, "if let Some(reason) = self.violation(s) {");
+        line(o, 3, &format!("self.actuators.{} = previous;", field));
+        line(o, 3, "return Err(format!(");
+        line(
+            o,
+            4,
+            &format!("\"block {{}}: refused to set {} to {{}}: {{}}\",", act.name),
+        );
+        line(o, 4, "self.state.name(),");
+        line(o, 4, "shown,");
+        line(o, 4, "reason");
+        line(o, 3, "));");
+        line(o, 2, "}");
+        line(o, 2, "Ok(())");
+        line(o, 1, "}");
+    }
+
+    line(o, 0, "");
+    line(
+        o,
+        1,
+        "// the first interlock or invariant the current values break, if any",
+    );
+    line(
+        o,
+        1,
+        "fn violation(&mut self, s: &Sensors) -> Option<String> {",
+    );
+    for interlock in &ast.interlocks {
+        let shown = format!("{:?}", interlock.to_string());
+        line(
+            o,
+            2,
+            &format!(
+                "if let Ok(true) = self.{}(s) {{",
+                gen.method(&interlock.trigger)
+            ),
+        );
+        line(
+            o,
+            3,
+            &format!("match self.{}(s) {{", gen.method(&interlock.requirement)),
+        );
+        line(o, 4, "Ok(true) => {}");
+        line(
+            o,
+            4,
+            &format!(
+                "Ok(false) => return Some(format!(\"violates interlock {{}}\", {})),",
+                shown
+            ),
+        );
+        line(
+            o,
+            4,
+            &format!(
+                "Err(e) => return Some(format!(\"can't check interlock {{}}: {{}}\", {}, e)),",
+                shown
+            ),
+        );
+        line(o, 3, "}");
+        line(o, 2, "}");
+    }
+    for invariant in &ast.invariants {
+        let shown = format!("{:?}", invariant.to_string());
+        line(o, 2, &format!("match self.{}(s) {{", gen.method(invariant)));
+        line(o, 3, "Ok(true) => {}");
+        line(
+            o,
+            3,
+            &format!(
+                "Ok(false) => return Some(format!(\"violates invariant {{}}\", {})),",
+                shown
+            ),
+        );
+        line(
+            o,
+            3,
+            &format!(
+                "Err(e) => return Some(format!(\"can't check invariant {{}}: {{}}\", {}, e)),",
+                shown
+            ),
+        );
+        line(o, 2, "}");
+    }
+    line(o, 2, "None");
+    line(o, 1, "}");
+}
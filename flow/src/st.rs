@@ -0,0 +1,1035 @@
+use std::collections::BTreeSet;
+
+use crate::ast::{Actuator, Clock, Comparator, Condition, Kind, AST};
+use crate::interp::MAX_OPS_PER_STEP;
+use crate::lower::{self, conditions, line, reads_elapsed, timers_read, Instr};
+
+// Generates an IEC 61131-3 Structured Text FUNCTION_BLOCK that runs a flow on a PLC: sensors are
+// inputs, actuators are outputs, and each call (once a scan) runs a CASE over the blocks until the
+// flow gets to a wait, which it polls again on the next scan. There are no methods, actions or
+// enumerated types, which not every toolchain has: conditions are evaluated inline, a statement
+// at a time so they stop where the interpreter would, and enum devices are INTs.
+
+const KEYWORDS: &[&str] = &[
+    "abs",
+    "action",
+    "and",
+    "any",
+    "array",
+    "at",
+    "bool",
+    "by",
+    "byte",
+    "case",
+    "configuration",
+    "constant",
+    "date",
+    "dint",
+    "do",
+    "dt",
+    "dword",
+    "else",
+    "elsif",
+    "end_action",
+    "end_case",
+    "end_configuration",
+    "end_for",
+    "end_function",
+    "end_function_block",
+    "end_if",
+    "end_program",
+    "end_repeat",
+    "end_resource",
+    "end_step",
+    "end_struct",
+    "end_transition",
+    "end_type",
+    "end_var",
+    "end_while",
+    "exit",
+    "false",
+    "for",
+    "from",
+    "function",
+    "function_block",
+    "if",
+    "initial_step",
+    "int",
+    "interval",
+    "lint",
+    "lreal",
+    "lword",
+    "mod",
+    "not",
+    "of",
+    "on",
+    "or",
+    "priority",
+    "program",
+    "read_only",
+    "read_write",
+    "real",
+    "repeat",
+    "resource",
+    "retain",
+    "return",
+    "sint",
+    "single",
+    "step",
+    "string",
+    "struct",
+    "task",
+    "then",
+    "time",
+    "to",
+    "tod",
+    "transition",
+    "true",
+    "type",
+    "udint",
+    "uint",
+    "ulint",
+    "until",
+    "usint",
+    "var",
+    "var_access",
+    "var_config",
+    "var_external",
+    "var_global",
+    "var_in_out",
+    "var_input",
+    "var_output",
+    "var_temp",
+    "while",
+    "with",
+    "word",
+    "wstring",
+    "xor",
+];
+
+fn ident(name: &str) -> String {
+    if KEYWORDS.contains(&name.to_lowercase().as_str()) {
+        format!("{}_value", name)
+    } else {
+        name.to_string()
+    }
+}
+
+fn camel(name: &str) -> String {
+    name.split('_')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            let first = chars.next().unwrap().to_ascii_uppercase();
+            std::iter::once(first).chain(chars).collect::<String>()
+        })
+        .collect()
+}
+
+// a string literal, with $ escapes for quotes and anything outside printable ASCII
+fn string(text: &str) -> String {
+    let mut out = String::from("'");
+    for byte in text.bytes() {
+        match byte {
+            b'\'' | b'$' => {
+                out.push('$');
+                out.push(byte as char);
+            }
+            b' '..=b'~' => out.push(byte as char),
+            _ => out += &format!("${:02X}", byte),
+        }
+    }
+    out.push('\'');
+    out
+}
+
+// an LREAL literal, which always needs a decimal point, even with an exponent
+fn number(val: f64) -> String {
+    let shown = format!("{:?}", val);
+    match shown.split_once('e') {
+        Some((mantissa, exponent)) if mantissa.contains('.') => {
+            format!("{}E{}", mantissa, exponent)
+        }
+        Some((mantissa, exponent)) => format!("{}.0E{}", mantissa, exponent),
+        None => shown,
+    }
+}
+
+fn kind_type(kind: &Kind) -> &'static str {
+    match kind {
+        Kind::Float => "LREAL",
+        Kind::Bool => "BOOL",
+        Kind::Enum(_) => "INT",
+    }
+}
+
+fn literal(kind: &Kind, val: f64) -> String {
+    match kind {
+        Kind::Float => number(val),
+        Kind::Bool if val != 0.0 => "TRUE".to_string(),
+        Kind::Bool => "FALSE".to_string(),
+        Kind::Enum(_) => (val as usize).to_string(),
+    }
+}
+
+// the values of an enum device, for the comment by its declaration
+fn variants(kind: &Kind) -> Option<String> {
+    match kind {
+        Kind::Enum(variants) => Some(
+            variants
+                .iter()
+                .enumerate()
+                .map(|(idx, variant)| format!("{} {}", idx, variant))
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+        _ => None,
+    }
+}
+
+fn fault(act: &Actuator) -> String {
+    format!("actuator {} read before it was set", act.name)
+}
+
+fn timer_fault(name: &str) -> String {
+    format!("timer {} read before it was started", name)
+}
+
+// whether evaluating the condition can run into an error
+fn can_fail(condition: &Condition) -> bool {
+    match condition {
+        Condition::Actuator(act, ..) => act.init.is_none(),
+        Condition::Time(Clock::Timer(_), ..) => true,
+        Condition::Edge { condition, .. } | Condition::For { condition, .. } => can_fail(condition),
+        Condition::All(conditions) | Condition::Any(conditions) => conditions.iter().any(can_fail),
+        _ => false,
+    }
+}
+
+// the state conditions keep between scans, as declarations
+fn memory(condition: &Condition, out: &mut Vec<String>) {
+    match condition {
+        Condition::Hysteresis { id, .. } => out.push(format!("FlowLatch{} : BOOL;", id)),
+        Condition::Edge { condition, id, .. } => {
+            out.push(format!("FlowEdge{} : BOOL;", id));
+            out.push(format!(
+                "FlowEdge{}Seen : BOOL; (* whether it's been evaluated yet *)",
+                id
+            ));
+            memory(condition, out);
+        }
+        Condition::For { condition, id, .. } => {
+            out.push(format!("FlowHeld{}On : BOOL;", id));
+            out.push(format!(
+                "FlowHeld{} : LREAL; (* since when, while on *)",
+                id
+            ));
+            memory(condition, out);
+        }
+        Condition::All(conditions) | Condition::Any(conditions) => {
+            for c in conditions {
+                memory(c, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn has_edge(condition: &Condition) -> bool {
+    match condition {
+        Condition::Edge { .. } => true,
+        Condition::For { condition, .. } => has_edge(condition),
+        Condition::All(conditions) | Condition::Any(conditions) => conditions.iter().any(has_edge),
+        _ => false,
+    }
+}
+
+fn compare(lhs: &str, kind: &Kind, comp: Comparator, val: f64) -> String {
+    match (kind, comp) {
+        // bools and enums can only be compared for equality
+        (Kind::Bool, _) if val != 0.0 => lhs.to_string(),
+        (Kind::Bool, _) => format!("NOT {}", lhs),
+        (_, Comparator::APPROX(tol)) => {
+            format!("ABS({} - {}) <= {}", lhs, literal(kind, val), number(tol))
+        }
+        (_, comp) => format!("{} {} {}", lhs, comp, literal(kind, val)),
+    }
+}
+
+// statements leaving whether the condition holds in FlowCond, stopping where the interpreter
+// would: at the first part that settles an all or an any, and at the first error, which sets
+// FlowFailed and says what went wrong in FlowFault
+fn eval(condition: &Condition, depth: usize, o: &mut String) {
+    match condition {
+        Condition::Base(sensor, comp, val) => line(
+            o,
+            depth,
+            &format!(
+                "FlowCond := {};",
+                compare(&ident(&sensor.name), &sensor.kind, *comp, *val)
+            ),
+        ),
+        Condition::Actuator(act, comp, val) => {
+            let compared = compare(&ident(&act.name), &act.kind, *comp, *val);
+            if act.init.is_some() {
+                line(o, depth, &format!("FlowCond := {};", compared));
+            } else {
+                line(o, depth, &format!("IF {}_set THEN", act.name));
+                line(o, depth + 1, &format!("FlowCond := {};", compared));
+                line(o, depth, "ELSE");
+                fail(&fault(act), depth + 1, o);
+                line(o, depth, "END_IF;");
+            }
+        }
+        Condition::Time(clock, comp, val) => {
+            let compared = |reading: &str| compare(reading, &Kind::Float, *comp, *val);
+            match clock {
+                Clock::Elapsed => line(
+                    o,
+                    depth,
+                    &format!("FlowCond := {};", compared("FlowNow - FlowEntered")),
+                ),
+                Clock::Now => line(o, depth, &format!("FlowCond := {};", compared("FlowNow"))),
+                Clock::Timer(name) => {
+                    line(o, depth, &format!("IF {}_started THEN", name));
+                    line(
+                        o,
+                        depth + 1,
+                        &format!(
+                            "FlowCond := {};",
+                            compared(&format!("FlowNow - {}", ident(name)))
+                        ),
+                    );
+                    line(o, depth, "ELSE");
+                    fail(&timer_fault(name), depth + 1, o);
+                    line(o, depth, "END_IF;");
+                }
+            }
+        }
+        Condition::Hysteresis {
+            sensor,
+            rising,
+            threshold,
+            band,
+            id,
+        } => {
+            let reading = ident(&sensor.name);
+            let (on, stays) = if *rising {
+                (
+                    format!("{} > {}", reading, number(*threshold)),
+                    format!("{} >= {}", reading, number(threshold - band)),
+                )
+            } else {
+                (
+                    format!("{} < {}", reading, number(*threshold)),
+                    format!("{} <= {}", reading, number(threshold + band)),
+                )
+            };
+            line(o, depth, &format!("IF FlowLatch{} THEN", id));
+            line(o, depth + 1, &format!("FlowLatch{} := {};", id, stays));
+            line(o, depth, "ELSE");
+            line(o, depth + 1, &format!("FlowLatch{} := {};", id, on));
+            line(o, depth, "END_IF;");
+            line(o, depth, &format!("FlowCond := FlowLatch{};", id));
+        }
+        Condition::Edge {
+            condition,
+            rising,
+            id,
+        } => {
+            eval(condition, depth, o);
+            // an error leaves the edge as it was
+            let depth = if can_fail(condition) {
+                line(o, depth, "IF NOT FlowFailed THEN");
+                depth + 1
+            } else {
+                depth
+            };
+            line(o, depth, &format!("IF NOT FlowEdge{}Seen THEN", id));
+            line(o, depth + 1, &format!("FlowEdge{} := FlowCond;", id));
+            line(o, depth + 1, &format!("FlowEdge{}Seen := TRUE;", id));
+            line(o, depth, "END_IF;");
+            line(o, depth, &format!("FlowBefore := FlowEdge{};", id));
+            line(o, depth, &format!("FlowEdge{} := FlowCond;", id));
+            if *rising {
+                line(o, depth, "FlowCond := NOT FlowBefore AND FlowCond;");
+            } else {
+                line(o, depth, "FlowCond := FlowBefore AND NOT FlowCond;");
+            }
+            if can_fail(condition) {
+                line(o, depth - 1, "END_IF;");
+            }
+        }
+        Condition::For {
+            condition,
+            duration,
+            id,
+        } => {
+            eval(condition, depth, o);
+            line(o, depth, "IF FlowCond THEN");
+            line(o, depth + 1, &format!("IF NOT FlowHeld{}On THEN", id));
+            line(o, depth + 2, &format!("FlowHeld{}On := TRUE;", id));
+            line(o, depth + 2, &format!("FlowHeld{} := FlowNow;", id));
+            line(o, depth + 1, "END_IF;");
+            line(
+                o,
+                depth + 1,
+                &format!(
+                    "FlowCond := FlowNow - FlowHeld{} >= {};",
+                    id,
+                    number(*duration)
+                ),
+            );
+            // an error leaves how long it's been held as it was
+            if can_fail(condition) {
+                line(o, depth, "ELSIF NOT FlowFailed THEN");
+            } else {
+                line(o, depth, "ELSE");
+            }
+            line(o, depth + 1, &format!("FlowHeld{}On := FALSE;", id));
+            line(o, depth, "END_IF;");
+        }
+        Condition::All(conditions) | Condition::Any(conditions) => {
+            let all = matches!(condition, Condition::All(_));
+            for (idx, c) in conditions.iter().enumerate() {
+                if idx == 0 {
+                    eval(c, depth, o);
+                    continue;
+                }
+                let test = match (all, can_fail(condition)) {
+                    // a part that failed left FlowCond false, which settles an all anyway
+                    (true, _) => "IF FlowCond THEN",
+                    (false, true) => "IF NOT FlowCond AND NOT FlowFailed THEN",
+                    (false, false) => "IF NOT FlowCond THEN",
+                };
+                line(o, depth, test);
+                eval(c, depth + 1, o);
+                line(o, depth, "END_IF;");
+            }
+        }
+    }
+}
+
+fn fail(fault: &str, depth: usize, o: &mut String) {
+    line(o, depth, "FlowCond := FALSE;");
+    line(o, depth, "FlowFailed := TRUE;");
+    line(o, depth, &format!("FlowFault := {};", string(fault)));
+}
+
+// evaluate a condition, noting it and clearing any error from one evaluated before
+fn check(condition: &Condition, depth: usize, o: &mut String) {
+    line(o, depth, &format!("(* {} *)", condition));
+    if can_fail(condition) {
+        line(o, depth, "FlowFailed := FALSE;");
+    }
+    eval(condition, depth, o);
+}
+
+fn halt(reason: &str, depth: usize, o: &mut String) {
+    line(o, depth, &format!("FlowReason := {};", reason));
+    line(o, depth, "FlowHalted := TRUE;");
+}
+
+// what a set that breaks an interlock or invariant halts with, ahead of the reason
+fn refused(block: &str, act: &Actuator, val: f64) -> String {
+    format!("block {}: refused to set {} to {}: ", block, act.name, val)
+}
+
+struct Generator<'a> {
+    ast: &'a AST,
+    blocks: Vec<lower::Lowered<'a>>,
+    // timers some condition reads
+    timers: BTreeSet<String>,
+    // the actuators the flow sets, when setting them has interlocks or invariants to check
+    guarded: Vec<&'a Actuator>,
+    // whether any condition can run into an error
+    fails: bool,
+}
+
+impl Generator<'_> {
+    // the index FlowBlock takes in the block
+    fn block(&self, name: &str) -> usize {
+        self.blocks.iter().position(|b| b.name == name).unwrap()
+    }
+
+    // the longest each message can get, for the STRINGs holding them: an error evaluating a
+    // condition, what refusing a set starts with, why it was refused, and why the flow halted
+    fn sizes(&self) -> (usize, usize, usize, usize) {
+        let ast = self.ast;
+        let mut faults = vec![1];
+        for act in ast.actuators() {
+            if act.init.is_none() {
+                faults.push(fault(act).len());
+            }
+        }
+        for timer in &self.timers {
+            faults.push(timer_fault(timer).len());
+        }
+        let fault = faults.into_iter().max().unwrap();
+        let mut reasons = vec![1];
+        for interlock in &ast.interlocks {
+            let shown = interlock.to_string();
+            reasons.push(format!("violates interlock {}", shown).len());
+            reasons.push(format!("can't check interlock {}: ", shown).len() + fault);
+        }
+        for invariant in &ast.invariants {
+            reasons.push(format!("violates invariant {}", invariant).len());
+            reasons.push(format!("can't check invariant {}: ", invariant).len() + fault);
+        }
+        let why = reasons.into_iter().max().unwrap();
+        let mut refusals = vec![1];
+        let mut halts = vec![1];
+        for block in &self.blocks {
+            halts.push(format!("timed out busy looping in block {}", block.name).len());
+            halts.push(format!("block {}: ", block.name).len() + fault);
+            for (_, instr) in &block.code {
+                if let Instr::Set { actuator, value } = instr {
+                    refusals.push(refused(block.name, actuator, *value).len());
+                }
+            }
+        }
+        let refusal = refusals.into_iter().max().unwrap();
+        halts.push(refusal + why);
+        (fault, refusal, why, halts.into_iter().max().unwrap())
+    }
+}
+
+// a FUNCTION_BLOCK called `name` running the flow
+pub fn generate(ast: &AST, source: &str, name: &str) -> String {
+    let blocks = lower::lower(ast);
+    let found = conditions(ast, &blocks);
+    let mut guarded: Vec<&Actuator> = Vec::new();
+    if !ast.interlocks.is_empty() || !ast.invariants.is_empty() {
+        for block in &blocks {
+            for (_, instr) in &block.code {
+                if let Instr::Set { actuator, .. } = instr {
+                    if !guarded.iter().any(|a| a.name == actuator.name) {
+                        guarded.push(actuator);
+                    }
+                }
+            }
+        }
+        guarded.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+    let gen = Generator {
+        ast,
+        timers: found
+            .iter()
+            .flat_map(|c| {
+                let mut read = BTreeSet::new();
+                timers_read(c, &mut read);
+                read.into_iter().map(str::to_string).collect::<Vec<_>>()
+            })
+            .collect(),
+        guarded,
+        fails: found.iter().any(|c| can_fail(c)),
+        blocks,
+    };
+    let (fault_size, refused_size, why_size, reason_size) = gen.sizes();
+    let elapsed = found.iter().any(|c| reads_elapsed(c));
+    let delays = gen.blocks.iter().any(|b| {
+        b.code
+            .iter()
+            .any(|(_, instr)| matches!(instr, Instr::Delay { .. }))
+    });
+
+    let mut out = String::new();
+    let o = &mut out;
+    line(
+        o,
+        0,
+        &format!(
+            "(* generated by `flow compile --target st` from {}; edit the flow, not this file *)",
+            source
+        ),
+    );
+    line(o, 0, "");
+    line(
+        o,
+        0,
+        "(* Call once a scan, with FlowNow the seconds since the flow started. A wait polls its",
+    );
+    line(
+        o,
+        0,
+        "   condition each scan until it holds. After an error the flow stops with FlowHalted set,",
+    );
+    line(
+        o,
+        0,
+        "   FlowReason saying why and the actuators at their fail-safe values. *)",
+    );
+    line(o, 0, &format!("FUNCTION_BLOCK {}", camel(name)));
+
+    let unit = |unit: &Option<crate::units::Unit>, kind: &Kind| {
+        let notes: Vec<String> = unit
+            .iter()
+            .map(|unit| unit.name.to_string())
+            .chain(variants(kind))
+            .collect();
+        if notes.is_empty() {
+            String::new()
+        } else {
+            format!(" (* {} *)", notes.join("; "))
+        }
+    };
+    line(o, 0, "VAR_INPUT");
+    for sens in ast.sensors() {
+        line(
+            o,
+            1,
+            &format!(
+                "{} : {};{}",
+                ident(&sens.name),
+                kind_type(&sens.kind),
+                unit(&sens.unit, &sens.kind)
+            ),
+        );
+    }
+    line(
+        o,
+        1,
+        "FlowNow : LREAL; (* seconds since the flow started *)",
+    );
+    line(o, 0, "END_VAR");
+
+    line(o, 0, "VAR_OUTPUT");
+    for act in ast.actuators() {
+        let init = match act.init {
+            Some(val) => format!(" := {}", literal(&act.kind, val)),
+            None => String::new(),
+        };
+        line(
+            o,
+            1,
+            &format!(
+                "{} : {}{};{}",
+                ident(&act.name),
+                kind_type(&act.kind),
+                init,
+                unit(&act.unit, &act.kind)
+            ),
+        );
+        if act.init.is_none() {
+            line(
+                o,
+                1,
+                &format!(
+                    "{}_set : BOOL; (* whether the flow has set it yet *)",
+                    act.name
+                ),
+            );
+        }
+    }
+    let names: Vec<String> = gen
+        .blocks
+        .iter()
+        .enumerate()
+        .map(|(idx, block)| format!("{} {}", idx, block.name))
+        .collect();
+    line(
+        o,
+        1,
+        &format!(
+            "FlowBlock : INT := {}; (* {} *)",
+            gen.block(&ast.first_block_name),
+            names.join(", ")
+        ),
+    );
+    line(
+        o,
+        1,
+        "FlowFinished : BOOL; (* the flow ran off the end of a block *)",
+    );
+    line(o, 1, "FlowHalted : BOOL;");
+    line(o, 1, &format!("FlowReason : STRING({});", reason_size));
+    line(o, 0, "END_VAR");
+
+    line(o, 0, "VAR");
+    line(
+        o,
+        1,
+        "FlowPc : INT; (* where in the block's code the flow is *)",
+    );
+    if elapsed {
+        line(
+            o,
+            1,
+            "FlowEntered : LREAL; (* when the current block was entered *)",
+        );
+    }
+    if delays {
+        line(o, 1, "FlowDelaying : BOOL;");
+        line(
+            o,
+            1,
+            "FlowDeadline : LREAL; (* when the delay being waited on ends, while delaying *)",
+        );
+    }
+    for timer in &gen.timers {
+        line(o, 1, &format!("{}_started : BOOL;", timer));
+        line(
+            o,
+            1,
+            &format!("{} : LREAL; (* when it was started *)", ident(timer)),
+        );
+    }
+    let mut kept = Vec::new();
+    for condition in &found {
+        memory(condition, &mut kept);
+    }
+    for field in &kept {
+        line(o, 1, field);
+    }
+    line(o, 0, "END_VAR");
+
+    line(o, 0, "VAR_TEMP");
+    line(o, 1, "FlowOps : INT;");
+    line(o, 1, "FlowWaiting : BOOL;");
+    if !found.is_empty() {
+        line(o, 1, "FlowCond : BOOL;");
+    }
+    if found.iter().any(|c| has_edge(c)) {
+        line(o, 1, "FlowBefore : BOOL;");
+    }
+    if gen.fails {
+        line(o, 1, "FlowFailed : BOOL;");
+        line(o, 1, &format!("FlowFault : STRING({});", fault_size));
+    }
+    if !gen.guarded.is_empty() {
+        line(
+            o,
+            1,
+            "FlowChanged : INT; (* which actuator was just set, to check it *)",
+        );
+        line(o, 1, &format!("FlowRefused : STRING({});", refused_size));
+        line(o, 1, "FlowViolated : BOOL;");
+        line(o, 1, &format!("FlowWhy : STRING({});", why_size));
+        for act in &gen.guarded {
+            line(
+                o,
+                1,
+                &format!("FlowWas{} : {};", camel(&act.name), kind_type(&act.kind)),
+            );
+            if act.init.is_none() {
+                line(o, 1, &format!("FlowWas{}Set : BOOL;", camel(&act.name)));
+            }
+        }
+    }
+    line(o, 0, "END_VAR");
+
+    line(o, 0, "");
+    line(o, 0, "IF NOT FlowHalted AND NOT FlowFinished THEN");
+    line(o, 1, "FlowWaiting := FALSE;");
+    if !gen.guarded.is_empty() {
+        line(o, 1, "FlowChanged := 0;");
+    }
+    line(
+        o,
+        1,
+        &format!("FOR FlowOps := 1 TO {} DO", MAX_OPS_PER_STEP),
+    );
+    gen_blocks(&gen, elapsed, 2, o);
+    if !gen.guarded.is_empty() {
+        gen_safety(&gen, 2, o);
+    }
+    line(o, 2, "IF FlowWaiting OR FlowHalted OR FlowFinished THEN");
+    line(o, 3, "EXIT;");
+    line(o, 2, "END_IF;");
+    line(o, 1, "END_FOR;");
+    line(
+        o,
+        1,
+        "IF NOT (FlowWaiting OR FlowHalted OR FlowFinished) THEN",
+    );
+    line(o, 2, "CASE FlowBlock OF");
+    for (idx, block) in gen.blocks.iter().enumerate() {
+        line(o, 3, &format!("{}:", idx));
+        halt(
+            &string(&format!("timed out busy looping in block {}", block.name)),
+            4,
+            o,
+        );
+    }
+    line(o, 2, "END_CASE;");
+    line(o, 1, "END_IF;");
+
+    let mut resets = Vec::new();
+    if delays {
+        resets.push("FlowDelaying := FALSE;".to_string());
+    }
+    for act in ast.actuators() {
+        if let Some(val) = act.failsafe {
+            resets.push(format!(
+                "{} := {};",
+                ident(&act.name),
+                literal(&act.kind, val)
+            ));
+            if act.init.is_none() {
+                resets.push(format!("{}_set := TRUE;", act.name));
+            }
+        }
+    }
+    if !resets.is_empty() {
+        line(o, 1, "IF FlowHalted THEN");
+        for reset in &resets {
+            line(o, 2, reset);
+        }
+        line(o, 1, "END_IF;");
+    }
+    line(o, 0, "END_IF;");
+    line(o, 0, "END_FUNCTION_BLOCK");
+    out
+}
+
+fn gen_blocks(gen: &Generator, elapsed: bool, depth: usize, o: &mut String) {
+    line(o, depth, "CASE FlowBlock OF");
+    for (idx, block) in gen.blocks.iter().enumerate() {
+        line(o, depth + 1, &format!("{}: (* {} *)", idx, block.name));
+        line(o, depth + 2, "CASE FlowPc OF");
+        let d = depth + 4;
+        for (pc, (num, instr)) in block.code.iter().enumerate() {
+            let next = format!("FlowPc := {};", pc + 1);
+            if let Instr::Finish = instr {
+                line(o, depth + 2, "ELSE (* the end of the block *)");
+                line(o, depth + 3, "FlowFinished := TRUE;");
+                continue;
+            }
+            line(o, depth + 3, &format!("{}: (* line {} *)", pc, num));
+            match instr {
+                Instr::Set { actuator, value } => {
+                    let field = ident(&actuator.name);
+                    let guarded = gen.guarded.iter().position(|a| a.name == actuator.name);
+                    if guarded.is_some() {
+                        line(
+                            o,
+                            d,
+                            &format!("FlowWas{} := {};", camel(&actuator.name), field),
+                        );
+                        if actuator.init.is_none() {
+                            line(
+                                o,
+                                d,
+                                &format!(
+                                    "FlowWas{}Set := {}_set;",
+                                    camel(&actuator.name),
+                                    actuator.name
+                                ),
+                            );
+                        }
+                    }
+                    line(
+                        o,
+                        d,
+                        &format!("{} := {};", field, literal(&actuator.kind, *value)),
+                    );
+                    if actuator.init.is_none() {
+                        line(o, d, &format!("{}_set := TRUE;", actuator.name));
+                    }
+                    if let Some(changed) = guarded {
+                        line(
+                            o,
+                            d,
+                            &format!(
+                                "FlowRefused := {};",
+                                string(&refused(block.name, actuator, *value))
+                            ),
+                        );
+                        line(o, d, &format!("FlowChanged := {};", changed + 1));
+                    }
+                    line(o, d, &next);
+                }
+                Instr::Wait { condition } => {
+                    check(condition, d, o);
+                    if can_fail(condition) {
+                        line(o, d, "IF FlowFailed THEN");
+                        halt(
+                            &format!(
+                                "CONCAT({}, FlowFault)",
+                                string(&format!("block {}: ", block.name))
+                            ),
+                            d + 1,
+                            o,
+                        );
+                        line(o, d, "ELSIF FlowCond THEN");
+                    } else {
+                        line(o, d, "IF FlowCond THEN");
+                    }
+                    line(o, d + 1, &next);
+                    line(o, d, "ELSE");
+                    line(o, d + 1, "FlowWaiting := TRUE;");
+                    line(o, d, "END_IF;");
+                }
+                Instr::Branch { condition, target } => {
+                    check(condition, d, o);
+                    if can_fail(condition) {
+                        line(o, d, "IF FlowFailed THEN");
+                        halt(
+                            &format!(
+                                "CONCAT({}, FlowFault)",
+                                string(&format!("block {}: ", block.name))
+                            ),
+                            d + 1,
+                            o,
+                        );
+                        line(o, d, "ELSIF FlowCond THEN");
+                    } else {
+                        line(o, d, "IF FlowCond THEN");
+                    }
+                    line(o, d + 1, &next);
+                    line(o, d, "ELSE");
+                    line(o, d + 1, &format!("FlowPc := {};", target));
+                    line(o, d, "END_IF;");
+                }
+                Instr::Jump { target } => line(o, d, &format!("FlowPc := {};", target)),
+                Instr::Delay { duration } => {
+                    line(o, d, "IF NOT FlowDelaying THEN");
+                    line(o, d + 1, "FlowDelaying := TRUE;");
+                    line(
+                        o,
+                        d + 1,
+                        &format!("FlowDeadline := FlowNow + {};", number(*duration)),
+                    );
+                    line(o, d, "END_IF;");
+                    line(o, d, "IF FlowNow < FlowDeadline THEN");
+                    line(o, d + 1, "FlowWaiting := TRUE;");
+                    line(o, d, "ELSE");
+                    line(o, d + 1, "FlowDelaying := FALSE;");
+                    line(o, d + 1, &next);
+                    line(o, d, "END_IF;");
+                }
+                Instr::Start { timer } => {
+                    // a timer nothing reads doesn't need starting
+                    if gen.timers.contains(*timer) {
+                        line(o, d, &format!("{}_started := TRUE;", timer));
+                        line(o, d, &format!("{} := FlowNow;", ident(timer)));
+                    }
+                    line(o, d, &next);
+                }
+                Instr::Goto { block } => {
+                    line(o, d, &format!("FlowBlock := {};", gen.block(block)));
+                    line(o, d, "FlowPc := 0;");
+                    if elapsed {
+                        line(o, d, "FlowEntered := FlowNow;");
+                    }
+                }
+                Instr::Forget { conditions } => {
+                    for condition in conditions {
+                        match condition {
+                            Condition::Edge { id, .. } => {
+                                line(o, d, &format!("FlowEdge{}Seen := FALSE;", id))
+                            }
+                            Condition::For { id, .. } => {
+                                line(o, d, &format!("FlowHeld{}On := FALSE;", id))
+                            }
+                            _ => {}
+                        }
+                    }
+                    line(o, d, &next);
+                }
+                Instr::Finish => {}
+            }
+        }
+        line(o, depth + 2, "END_CASE;");
+    }
+    line(o, depth, "END_CASE;");
+}
+
+// after a set, the interlocks and invariants, putting the actuator back if one is broken
+fn gen_safety(gen: &Generator, depth: usize, o: &mut String) {
+    let ast = gen.ast;
+    let d = depth + 1;
+    line(o, depth, "IF FlowChanged > 0 THEN");
+    line(o, d, "FlowViolated := FALSE;");
+    for interlock in &ast.interlocks {
+        let shown = interlock.to_string();
+        line(o, d, "IF NOT FlowViolated THEN");
+        check(&interlock.trigger, d + 1, o);
+        if can_fail(&interlock.trigger) {
+            // an interlock whose trigger can't be checked doesn't stop anything
+            line(o, d + 1, "IF FlowCond AND NOT FlowFailed THEN");
+        } else {
+            line(o, d + 1, "IF FlowCond THEN");
+        }
+        check(&interlock.requirement, d + 2, o);
+        if can_fail(&interlock.requirement) {
+            line(o, d + 2, "IF FlowFailed THEN");
+            line(o, d + 3, "FlowViolated := TRUE;");
+            line(
+                o,
+                d + 3,
+                &format!(
+                    "FlowWhy := CONCAT({}, FlowFault);",
+                    string(&format!("can't check interlock {}: ", shown))
+                ),
+            );
+            line(o, d + 2, "ELSIF NOT FlowCond THEN");
+        } else {
+            line(o, d + 2, "IF NOT FlowCond THEN");
+        }
+        line(o, d + 3, "FlowViolated := TRUE;");
+        line(
+            o,
+            d + 3,
+            &format!(
+                "FlowWhy := {};",
+                string(&format!("violates interlock {}", shown))
+            ),
+        );
+        line(o, d + 2, "END_IF;");
+        line(o, d + 1, "END_IF;");
+        line(o, d, "END_IF;");
+    }
+    for invariant in &ast.invariants {
+        line(o, d, "IF NOT FlowViolated THEN");
+        check(invariant, d + 1, o);
+        if can_fail(invariant) {
+            line(o, d + 1, "IF FlowFailed THEN");
+            line(o, d + 2, "FlowViolated := TRUE;");
+            line(
+                o,
+                d + 2,
+                &format!(
+                    "FlowWhy := CONCAT({}, FlowFault);",
+                    string(&format!("can't check invariant {}: ", invariant))
+                ),
+            );
+            line(o, d + 1, "ELSIF NOT FlowCond THEN");
+        } else {
+            line(o, d + 1, "IF NOT FlowCond THEN");
+        }
+        line(o, d + 2, "FlowViolated := TRUE;");
+        line(
+            o,
+            d + 2,
+            &format!(
+                "FlowWhy := {};",
+                string(&format!("violates invariant {}", invariant))
+            ),
+        );
+        line(o, d + 1, "END_IF;");
+        line(o, d, "END_IF;");
+    }
+    line(o, d, "IF FlowViolated THEN");
+    line(o, d + 1, "CASE FlowChanged OF");
+    for (idx, act) in gen.guarded.iter().enumerate() {
+        line(o, d + 2, &format!("{}:", idx + 1));
+        line(
+            o,
+            d + 3,
+            &format!("{} := FlowWas{};", ident(&act.name), camel(&act.name)),
+        );
+        if act.init.is_none() {
+            line(
+                o,
+                d + 3,
+                &format!("{}_set := FlowWas{}Set;", act.name, camel(&act.name)),
+            );
+        }
+    }
+    line(o, d + 1, "END_CASE;");
+    halt("CONCAT(FlowRefused, FlowWhy)", d + 1, o);
+    line(o, d, "END_IF;");
+    line(o, d, "FlowChanged := 0;");
+    line(o, depth, "END_IF;");
+}
@@ -5,6 +5,29 @@ pub enum Token {
     Comparator(String),
     Actuator,
     Sensor,
+    Timer,
+    Range,
+    Bool,
+    Enum,
+    OpenBrace,
+    CloseBrace,
+    Comma,
+    Init,
+    Failsafe,
+    Tol,
+    RisesAbove,
+    FallsBelow,
+    Hyst,
+    Rising,
+    Falling,
+    For,
+    Interlock,
+    Requires,
+    Invariant,
+    Always,
+    Never,
+    Reaches,
+    After,
     StartBlock,
     EndBlock,
     Set,
@@ -27,6 +50,29 @@ pub fn tokenize(code: String) -> Vec<Token> {
         let to_add = match word.as_str() {
             "sensor" => Token::Sensor,
             "actuator" => Token::Actuator,
+            "timer" => Token::Timer,
+            ".." => Token::Range,
+            "bool" => Token::Bool,
+            "enum" => Token::Enum,
+            "{" => Token::OpenBrace,
+            "}" => Token::CloseBrace,
+            "," => Token::Comma,
+            "init" => Token::Init,
+            "failsafe" => Token::Failsafe,
+            "tol" => Token::Tol,
+            "rises_above" => Token::RisesAbove,
+            "falls_below" => Token::FallsBelow,
+            "hyst" => Token::Hyst,
+            "rising" => Token::Rising,
+            "falling" => Token::Falling,
+            "for" => Token::For,
+            "interlock" => Token::Interlock,
+            "requires" => Token::Requires,
+            "invariant" => Token::Invariant,
+            "always" => Token::Always,
+            "never" => Token::Never,
+            "reaches" => Token::Reaches,
+            "after" => Token::After,
             "block" => Token::StartBlock,
             "endblock" => Token::EndBlock,
             "set" => Token::Set,
@@ -40,11 +86,11 @@ pub fn tokenize(code: String) -> Vec<Token> {
             "\n" => Token::Newline,
             ":" => Token::Colon,
             "-" => Token::ConditionStart,
-            "<" | ">" | "<=" | ">=" | "=" => Token::Comparator(word),
+            "<" | ">" | "<=" | ">=" | "=" | "~=" => Token::Comparator(word),
             _ => {
                 let chars: Vec<char> = word.chars().collect();
                 let first = chars[0];
-                if first.is_digit(10) {
+                if first.is_ascii_digit() || first == '-' {
                     Token::Value(word.parse().unwrap())
                 } else {
                     Token::Identifier(word)
@@ -63,37 +109,47 @@ fn split_code(code: String) -> Vec<String> {
     while idx < code.chars().count() {
         // TODO: comparators
         match &chars[idx] {
-            '\t' | '\n' | '-' | ':' | '=' => {
-                out.push((&chars[idx]).to_string());
+            '-' if idx + 1 < chars.len() && chars[idx + 1].is_ascii_digit() => {
+                let new_idx = scan_number(&chars, idx + 1);
+                out.push(chars[idx..new_idx].iter().collect());
+                idx = new_idx;
+            }
+            '\t' | '\n' | '-' | ':' | '=' | '{' | '}' | ',' => {
+                out.push(chars[idx].to_string());
                 idx += 1;
             }
+            '~' if idx + 1 < chars.len() && chars[idx + 1] == '=' => {
+                out.push(String::from("~="));
+                idx += 2;
+            }
             '<' | '>' => {
                 if chars[idx + 1] == '=' {
-                    out.push((&chars[idx..idx + 2]).iter().collect());
+                    out.push(chars[idx..idx + 2].iter().collect());
                     idx += 2;
                 } else {
-                    out.push((&chars[idx]).to_string());
+                    out.push(chars[idx].to_string());
                     idx += 1;
                 }
             }
+            '.' if idx + 1 < chars.len() && chars[idx + 1] == '.' => {
+                out.push(String::from(".."));
+                idx += 2;
+            }
             '0'..='9' => {
-                let mut new_idx = idx;
-                while (&chars[new_idx]).is_digit(10) || chars[new_idx] == '.' {
-                    new_idx += 1;
-                }
-                out.push((&chars[idx..new_idx]).iter().collect());
+                let new_idx = scan_number(&chars, idx);
+                out.push(chars[idx..new_idx].iter().collect());
                 idx = new_idx;
             }
             'A'..='z' => {
                 let mut new_idx = idx;
-                while (&chars[new_idx]).is_alphanumeric() || chars[new_idx] == '_' {
+                while chars[new_idx].is_alphanumeric() || chars[new_idx] == '_' {
                     new_idx += 1;
                 }
-                out.push((&chars[idx..new_idx]).iter().collect());
+                out.push(chars[idx..new_idx].iter().collect());
                 idx = new_idx;
             }
             ' ' => {
-                let nextfour: String = (&chars[idx..idx + 4]).iter().collect();
+                let nextfour: String = chars[idx..chars.len().min(idx + 4)].iter().collect();
                 if nextfour.eq("    ") {
                     out.push(String::from("    "));
                     idx += 4;
@@ -108,3 +164,18 @@ fn split_code(code: String) -> Vec<String> {
     }
     out
 }
+
+fn scan_number(chars: &[char], start: usize) -> usize {
+    let mut idx = start;
+    while idx < chars.len() && chars[idx].is_ascii_digit() {
+        idx += 1;
+    }
+    // take a decimal point only if it isn't the start of a ".." range
+    if idx + 1 < chars.len() && chars[idx] == '.' && chars[idx + 1].is_ascii_digit() {
+        idx += 1;
+        while idx < chars.len() && chars[idx].is_ascii_digit() {
+            idx += 1;
+        }
+    }
+    idx
+}
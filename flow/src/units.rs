@@ -0,0 +1,102 @@
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dimension {
+    Time,
+    Temperature,
+    Pressure,
+}
+
+// a value in this unit is `value * scale + offset` in the dimension's base unit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Unit {
+    pub name: &'static str,
+    pub dimension: Dimension,
+    scale: f64,
+    offset: f64,
+}
+
+const UNITS: &[Unit] = &[
+    Unit {
+        name: "ms",
+        dimension: Dimension::Time,
+        scale: 0.001,
+        offset: 0.0,
+    },
+    Unit {
+        name: "s",
+        dimension: Dimension::Time,
+        scale: 1.0,
+        offset: 0.0,
+    },
+    Unit {
+        name: "min",
+        dimension: Dimension::Time,
+        scale: 60.0,
+        offset: 0.0,
+    },
+    Unit {
+        name: "degC",
+        dimension: Dimension::Temperature,
+        scale: 1.0,
+        offset: 0.0,
+    },
+    Unit {
+        name: "degF",
+        dimension: Dimension::Temperature,
+        scale: 5.0 / 9.0,
+        offset: -32.0 * 5.0 / 9.0,
+    },
+    Unit {
+        name: "kPa",
+        dimension: Dimension::Pressure,
+        scale: 1.0,
+        offset: 0.0,
+    },
+    Unit {
+        name: "bar",
+        dimension: Dimension::Pressure,
+        scale: 100.0,
+        offset: 0.0,
+    },
+];
+
+pub fn lookup(name: &str) -> Option<Unit> {
+    UNITS.iter().find(|u| u.name == name).copied()
+}
+
+pub fn seconds() -> Unit {
+    lookup("s").unwrap()
+}
+
+impl Unit {
+    // None if the units measure different things
+    pub fn convert(&self, val: f64, to: &Unit) -> Option<f64> {
+        if self.dimension != to.dimension {
+            return None;
+        }
+        Some((val * self.scale + self.offset - to.offset) / to.scale)
+    }
+
+    // like convert, but for a difference between two values, so offsets cancel out
+    pub fn convert_delta(&self, val: f64, to: &Unit) -> Option<f64> {
+        if self.dimension != to.dimension {
+            return None;
+        }
+        Some(val * self.scale / to.scale)
+    }
+}
+
+// a unit is written as its name, and has to be one of the known units to be read back
+#[cfg(feature = "serde")]
+impl serde::Serialize for Unit {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Unit {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        lookup(&name).ok_or_else(|| serde::de::Error::custom(format!("unknown unit {}", name)))
+    }
+}
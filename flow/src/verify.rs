@@ -0,0 +1,1101 @@
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+
+use crate::ast::{
+    Actuator, Clock, Comparator, Condition, Kind, Operation, Property, Sensor, Statement, AST,
+};
+use crate::backend::Memory;
+use crate::coverage;
+use crate::interp::{self, Interpreter, Status, MAX_OPS_PER_STEP};
+use crate::lower;
+
+// give up rather than run out of memory on flows with more states than this
+pub const MAX_STATES: usize = 200_000;
+
+// or whose sensors split into more combinations of regions than this
+pub const MAX_VALUATIONS: usize = 4096;
+
+// The flow is explored a step at a time, as the interpreter runs it, with sensors standing at one
+// value from each region of their range the flow's conditions tell apart. Whatever depends on time
+// passing, like clocks, delays and duration conditions, can go either way, so every state the
+// real flow can get into is explored, along with some it can't.
+
+// a list of statements, told apart by where it is in the flow rather than what's in it
+#[derive(Debug, Clone, Copy)]
+struct Ops<'a>(&'a [Statement]);
+
+impl PartialEq for Ops<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self.0, other.0)
+    }
+}
+
+impl Eq for Ops<'_> {}
+
+impl Hash for Ops<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_ptr().hash(state);
+        self.0.len().hash(state);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum End {
+    Paused,
+    Finished,
+    // an error stopped the flow and actuators went to their fail-safe values
+    Stopped(String),
+}
+
+// where the flow is after a step, along with everything that decides what it can do next
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Node<'a> {
+    block: String,
+    frames: Vec<(Ops<'a>, usize)>,
+    // by actuator in name order, as bits so nodes can be hashed
+    actuators: Vec<Option<u64>>,
+    // the readings the step took, by sensor in name order
+    sensors: Vec<u64>,
+    latches: BTreeMap<usize, bool>,
+    edges: BTreeMap<usize, bool>,
+    // duration conditions whose inner condition held at the end of the step
+    held: BTreeSet<usize>,
+    timers: BTreeSet<String>,
+    // whether the delay the flow is paused on has started
+    delaying: bool,
+    end: End,
+}
+
+// what happened during a step
+#[derive(Debug, Clone, Default)]
+struct Run {
+    // it needed time to have passed since the step before
+    later: bool,
+    // it stayed where it was only because not enough time had passed yet
+    deferred: bool,
+    entered: Vec<String>,
+    // always and never properties broken, with the line of the set that broke them, or None when
+    // they were broken at the end of the step
+    broken: Vec<(usize, Option<usize>)>,
+    // reaches properties whose after condition holds at the end of the step
+    triggered: Vec<usize>,
+}
+
+#[derive(Debug, Clone)]
+struct Step {
+    to: usize,
+    valuation: usize,
+    run: Run,
+}
+
+// a way for a property to fail, as readings to feed the flow
+#[derive(Debug, Clone, PartialEq)]
+pub struct Counterexample {
+    // `<seconds> <sensor> <value>` lines, as a trace file takes them, with comments saying what
+    // the flow does after each step
+    pub trace: Vec<String>,
+    // whether the interpreter, fed the readings, does what the exploration said it would
+    pub replays: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verdict {
+    pub property: String,
+    // None when the property holds
+    pub counterexample: Option<Counterexample>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verification {
+    pub states: usize,
+    pub verdicts: Vec<Verdict>,
+}
+
+pub fn verify(ast: &AST) -> Result<Verification, String> {
+    let mut verifier = Verifier::new(ast)?;
+    verifier.explore()?;
+    let verdicts = ast
+        .properties
+        .iter()
+        .enumerate()
+        .map(|(idx, property)| Verdict {
+            property: property.to_string(),
+            counterexample: verifier.check(idx, property),
+        })
+        .collect();
+    Ok(Verification {
+        states: verifier.nodes.len(),
+        verdicts,
+    })
+}
+
+// every condition anywhere in the flow
+fn conditions(ast: &AST) -> Vec<&Condition> {
+    fn statements<'c>(ops: &'c [Statement], out: &mut Vec<&'c Condition>) {
+        for stmt in ops {
+            match &stmt.op {
+                Operation::Wait { condition } => out.push(condition),
+                Operation::IfElse {
+                    if_condition,
+                    if_actions,
+                    else_actions,
+                } => {
+                    out.push(if_condition);
+                    statements(if_actions, out);
+                    statements(else_actions.as_deref().unwrap_or(&[]), out);
+                }
+                _ => {}
+            }
+        }
+    }
+    let mut out = Vec::new();
+    for block in ast.blocks.values() {
+        statements(&block.ops, &mut out);
+    }
+    for interlock in &ast.interlocks {
+        out.push(&interlock.trigger);
+        out.push(&interlock.requirement);
+    }
+    out.extend(ast.invariants.iter());
+    for property in &ast.properties {
+        match property {
+            Property::Always(condition) | Property::Never(condition) => out.push(condition),
+            Property::Reaches {
+                after: Some(condition),
+                ..
+            } => out.push(condition),
+            Property::Reaches { after: None, .. } => {}
+        }
+    }
+    out
+}
+
+// the values each sensor is compared against, where its readings start being treated differently
+fn thresholds(ast: &AST) -> HashMap<String, Vec<f64>> {
+    let mut found: HashMap<String, Vec<f64>> = HashMap::new();
+    for condition in conditions(ast) {
+        for leaf in coverage::leaves(condition)
+            .into_iter()
+            .map(coverage::compared)
+        {
+            match leaf {
+                Condition::Base(sens, comp, val) => {
+                    let points = found.entry(sens.name.clone()).or_default();
+                    points.push(*val);
+                    if let Comparator::APPROX(tol) = comp {
+                        points.extend([val - tol, val + tol]);
+                    }
+                }
+                Condition::Hysteresis {
+                    sensor,
+                    rising,
+                    threshold,
+                    band,
+                    ..
+                } => {
+                    let release = if *rising {
+                        threshold - band
+                    } else {
+                        threshold + band
+                    };
+                    found
+                        .entry(sensor.name.clone())
+                        .or_default()
+                        .extend([*threshold, release]);
+                }
+                _ => {}
+            }
+        }
+    }
+    found
+}
+
+// a value from each region of the sensor's range that the thresholds split it into: the
+// thresholds themselves, one between each pair of them, and one beyond each end
+fn representatives(sens: &Sensor, points: &[f64]) -> Vec<f64> {
+    match &sens.kind {
+        Kind::Bool => return vec![0.0, 1.0],
+        Kind::Enum(variants) => return (0..variants.len()).map(|idx| idx as f64).collect(),
+        Kind::Float => {}
+    }
+    let mut points: Vec<f64> = points
+        .iter()
+        .copied()
+        .filter(|p| *p >= sens.min && *p <= sens.max)
+        .collect();
+    points.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    points.dedup();
+    let (first, last) = match (points.first(), points.last()) {
+        (Some(first), Some(last)) => (*first, *last),
+        _ => return vec![0.0_f64.clamp(sens.min, sens.max)],
+    };
+    let mut values = Vec::new();
+    // sensors with no declared range go on forever either way
+    if first > sens.min {
+        values.push(if sens.min > f64::MIN {
+            sens.min
+        } else {
+            (first - 1.0).min(first.next_down())
+        });
+    }
+    for pair in points.windows(2) {
+        values.push(pair[0]);
+        values.push((pair[0] + pair[1]) / 2.0);
+    }
+    values.push(last);
+    if last < sens.max {
+        values.push(if sens.max < f64::MAX {
+            sens.max
+        } else {
+            (last + 1.0).max(last.next_up())
+        });
+    }
+    values
+}
+
+struct Verifier<'a> {
+    ast: &'a AST,
+    sensors: Vec<&'a Sensor>,
+    actuators: Vec<&'a Actuator>,
+    // every combination of sensor readings to try at each step, by sensor in name order
+    valuations: Vec<Vec<f64>>,
+    nodes: Vec<Node<'a>>,
+    // the steps out of each node
+    steps: Vec<Vec<Step>>,
+    // how each node was first got to, for the shortest way back to the start
+    parents: Vec<Option<(usize, usize)>>,
+}
+
+impl<'a> Verifier<'a> {
+    fn new(ast: &'a AST) -> Result<Verifier<'a>, String> {
+        let sensors = ast.sensors();
+        let found = thresholds(ast);
+        let mut valuations: Vec<Vec<f64>> = vec![Vec::new()];
+        for sens in &sensors {
+            let values = representatives(sens, found.get(&sens.name).map_or(&[], Vec::as_slice));
+            if valuations.len() * values.len() > MAX_VALUATIONS {
+                return Err(format!(
+                    "the sensors' readings split into more than {} combinations, too many to explore",
+                    MAX_VALUATIONS
+                ));
+            }
+            valuations = valuations
+                .iter()
+                .flat_map(|partial| {
+                    values.iter().map(move |val| {
+                        let mut more = partial.clone();
+                        more.push(*val);
+                        more
+                    })
+                })
+                .collect();
+        }
+        Ok(Verifier {
+            ast,
+            sensors,
+            actuators: ast.actuators(),
+            valuations,
+            nodes: Vec::new(),
+            steps: Vec::new(),
+            parents: Vec::new(),
+        })
+    }
+
+    // the flow as Interpreter::new leaves it, before its first step
+    fn initial(&self) -> Node<'a> {
+        let first = &self.ast.blocks[&self.ast.first_block_name];
+        Node {
+            block: self.ast.first_block_name.clone(),
+            frames: vec![(Ops(first.ops.as_slice()), 0)],
+            actuators: self
+                .actuators
+                .iter()
+                .map(|act| act.init.map(f64::to_bits))
+                .collect(),
+            sensors: Vec::new(),
+            latches: BTreeMap::new(),
+            edges: BTreeMap::new(),
+            held: BTreeSet::new(),
+            timers: BTreeSet::new(),
+            delaying: false,
+            end: End::Paused,
+        }
+    }
+
+    // every state the flow can get into, breadth first so the first way found to each is shortest
+    fn explore(&mut self) -> Result<(), String> {
+        let mut index: HashMap<Node<'a>, usize> = HashMap::new();
+        let initial = self.initial();
+        index.insert(initial.clone(), 0);
+        self.nodes.push(initial);
+        self.steps.push(Vec::new());
+        self.parents.push(None);
+
+        let mut queue = VecDeque::from([0]);
+        while let Some(from) = queue.pop_front() {
+            if self.nodes[from].end != End::Paused {
+                continue;
+            }
+            let mut found = Vec::new();
+            for (valuation, readings) in self.valuations.iter().enumerate() {
+                for (node, run) in self.runs(from, readings) {
+                    found.push((node, valuation, run));
+                }
+            }
+            for (node, valuation, run) in found {
+                let to = match index.get(&node) {
+                    Some(to) => *to,
+                    None => {
+                        if self.nodes.len() >= MAX_STATES {
+                            return Err(format!(
+                                "the flow has more than {} states, too many to explore",
+                                MAX_STATES
+                            ));
+                        }
+                        let to = self.nodes.len();
+                        index.insert(node.clone(), to);
+                        self.nodes.push(node);
+                        self.steps.push(Vec::new());
+                        self.parents.push(Some((from, self.steps[from].len())));
+                        queue.push_back(to);
+                        to
+                    }
+                };
+                self.steps[from].push(Step { to, valuation, run });
+            }
+        }
+        Ok(())
+    }
+
+    // every way a step from `from` can go with the given readings, trying each combination of
+    // the choices that depend on time
+    fn runs(&self, from: usize, readings: &[f64]) -> Vec<(Node<'a>, Run)> {
+        let mut choices = Vec::new();
+        let mut out = Vec::new();
+        loop {
+            let exec = Exec {
+                verifier: self,
+                node: self.nodes[from].clone(),
+                readings,
+                choices: &mut choices,
+                next: 0,
+                decided: HashMap::new(),
+                first: from == 0,
+                fresh_block: from == 0,
+                fresh_timers: BTreeSet::new(),
+                fresh_held: BTreeSet::new(),
+                run: Run::default(),
+            };
+            out.push(exec.step());
+            // on to the next combination, like counting in binary
+            while choices.last() == Some(&true) {
+                choices.pop();
+            }
+            match choices.last_mut() {
+                Some(choice) => *choice = true,
+                None => return out,
+            }
+        }
+    }
+
+    fn path(&self, node: usize) -> Vec<(usize, usize)> {
+        let mut path = Vec::new();
+        let mut at = node;
+        while let Some((from, step)) = self.parents[at] {
+            path.push((from, step));
+            at = from;
+        }
+        path.reverse();
+        path
+    }
+
+    // a counterexample for the property, or None if it holds
+    fn check(&self, idx: usize, property: &Property) -> Option<Counterexample> {
+        match property {
+            Property::Always(_) | Property::Never(_) => {
+                for (from, steps) in self.steps.iter().enumerate() {
+                    for (s, step) in steps.iter().enumerate() {
+                        if let Some((_, line)) = step.run.broken.iter().find(|(p, _)| *p == idx) {
+                            let mut path = self.path(from);
+                            path.push((from, s));
+                            let note = match line {
+                                Some(line) => format!("fails at line {}", line),
+                                None => "fails here".to_string(),
+                            };
+                            return Some(self.counterexample(&path, None, &note));
+                        }
+                    }
+                }
+                None
+            }
+            Property::Reaches { block, after } => self.check_reaches(idx, block, after.is_some()),
+        }
+    }
+
+    // a path from a node where the property applies along which the flow never enters `block`
+    // and either stops, or goes round a loop for good without waiting on time alone
+    fn check_reaches(&self, idx: usize, block: &str, after: bool) -> Option<Counterexample> {
+        let at = |node: usize| self.nodes[node].block == block;
+        let keep = |from: usize, step: &Step| {
+            !at(from) && !at(step.to) && !step.run.entered.iter().any(|b| b == block)
+        };
+        let count = self.nodes.len();
+        let mut forward: Vec<Vec<usize>> = vec![Vec::new(); count];
+        let mut backward: Vec<Vec<usize>> = vec![Vec::new(); count];
+        for (from, steps) in self.steps.iter().enumerate() {
+            for (s, step) in steps.iter().enumerate() {
+                if keep(from, step) {
+                    forward[from].push(s);
+                    backward[step.to].push(from);
+                }
+            }
+        }
+
+        // stopping or finishing without getting there
+        let mut target: Vec<bool> = (0..count)
+            .map(|node| !at(node) && self.nodes[node].end != End::Paused)
+            .collect();
+        let components = self.components(&forward);
+        for component in &components {
+            let fair = component.iter().any(|&from| {
+                forward[from].iter().any(|&s| {
+                    let step = &self.steps[from][s];
+                    component.contains(&step.to) && !step.run.deferred
+                })
+            });
+            if fair {
+                for &node in component {
+                    target[node] = true;
+                }
+            }
+        }
+
+        // everywhere that can get to a target without going through the block
+        let mut bad = target.clone();
+        let mut stack: Vec<usize> = (0..count).filter(|&node| target[node]).collect();
+        while let Some(node) = stack.pop() {
+            for &from in &backward[node] {
+                if !bad[from] {
+                    bad[from] = true;
+                    stack.push(from);
+                }
+            }
+        }
+
+        let mut path = if !after {
+            if !bad[0] {
+                return None;
+            }
+            Vec::new()
+        } else {
+            let found = self.steps.iter().enumerate().find_map(|(from, steps)| {
+                steps.iter().enumerate().find_map(|(s, step)| {
+                    let applies = step.run.triggered.contains(&idx);
+                    (applies && bad[step.to] && !at(step.to)).then_some((from, s))
+                })
+            })?;
+            let mut path = self.path(found.0);
+            path.push(found);
+            path
+        };
+        let start = path.last().map_or(0, |&(from, s)| self.steps[from][s].to);
+
+        // the nearest target, then if it's in a loop once round the loop
+        let (end, lead) = self.search(start, &forward, |node| target[node])?;
+        path.extend(lead);
+        let component = components.iter().find(|c| c.contains(&end));
+        let component = match component {
+            Some(component) if self.nodes[end].end == End::Paused => component,
+            _ => {
+                let note = match &self.nodes[end].end {
+                    End::Stopped(_) => format!("the flow stops without entering {}", block),
+                    _ => format!("the flow finishes without entering {}", block),
+                };
+                return Some(self.counterexample(&path, None, &note));
+            }
+        };
+        let within: Vec<Vec<usize>> = forward
+            .iter()
+            .enumerate()
+            .map(|(from, steps)| {
+                steps
+                    .iter()
+                    .copied()
+                    .filter(|&s| {
+                        component.contains(&from) && component.contains(&self.steps[from][s].to)
+                    })
+                    .collect()
+            })
+            .collect();
+        // going round has to take at least one step that isn't just waiting for time to pass
+        let (source, to_source) = self.search(end, &within, |node| {
+            within[node]
+                .iter()
+                .any(|&s| !self.steps[node][s].run.deferred)
+        })?;
+        let taken = within[source]
+            .iter()
+            .copied()
+            .find(|&s| !self.steps[source][s].run.deferred)?;
+        let (_, back) = self.search(self.steps[source][taken].to, &within, |node| node == end)?;
+        let looped = path.len();
+        path.extend(to_source);
+        path.push((source, taken));
+        path.extend(back);
+        let note = format!(
+            "back where the loop started, so it can go round forever without entering {}",
+            block
+        );
+        Some(self.counterexample(&path, Some(looped), &note))
+    }
+
+    // breadth first over the steps allowed in `allowed` from `start` to the first node `goal`
+    // accepts, giving the node and the steps taken to get there
+    fn search(
+        &self,
+        start: usize,
+        allowed: &[Vec<usize>],
+        goal: impl Fn(usize) -> bool,
+    ) -> Option<(usize, Vec<(usize, usize)>)> {
+        let mut parents: HashMap<usize, Option<(usize, usize)>> = HashMap::from([(start, None)]);
+        let mut queue = VecDeque::from([start]);
+        while let Some(node) = queue.pop_front() {
+            if goal(node) {
+                let mut path = Vec::new();
+                let mut at = node;
+                while let Some(Some((from, s))) = parents.get(&at) {
+                    path.push((*from, *s));
+                    at = *from;
+                }
+                path.reverse();
+                return Some((node, path));
+            }
+            for &s in &allowed[node] {
+                let to = self.steps[node][s].to;
+                if let Entry::Vacant(entry) = parents.entry(to) {
+                    entry.insert(Some((node, s)));
+                    queue.push_back(to);
+                }
+            }
+        }
+        None
+    }
+
+    // strongly connected components over the allowed steps that can be gone round, one node
+    // with a step to itself or more than one node
+    fn components(&self, allowed: &[Vec<usize>]) -> Vec<BTreeSet<usize>> {
+        let count = self.nodes.len();
+        let mut index: Vec<Option<usize>> = vec![None; count];
+        let mut low = vec![0; count];
+        let mut on_stack = vec![false; count];
+        let mut stack = Vec::new();
+        let mut found = Vec::new();
+        let mut next = 0;
+        // the recursion of Tarjan's algorithm, unrolled so big state spaces can't overflow
+        for root in 0..count {
+            if index[root].is_some() {
+                continue;
+            }
+            let mut work: Vec<(usize, usize)> = vec![(root, 0)];
+            while let Some(&mut (node, ref mut edge)) = work.last_mut() {
+                if *edge == 0 && index[node].is_none() {
+                    index[node] = Some(next);
+                    low[node] = next;
+                    next += 1;
+                    stack.push(node);
+                    on_stack[node] = true;
+                }
+                if let Some(&s) = allowed[node].get(*edge) {
+                    *edge += 1;
+                    let to = self.steps[node][s].to;
+                    match index[to] {
+                        None => work.push((to, 0)),
+                        Some(idx) if on_stack[to] => low[node] = low[node].min(idx),
+                        Some(_) => {}
+                    }
+                    continue;
+                }
+                work.pop();
+                if let Some(&(parent, _)) = work.last() {
+                    low[parent] = low[parent].min(low[node]);
+                }
+                if Some(low[node]) == index[node] {
+                    let mut component = BTreeSet::new();
+                    while let Some(member) = stack.pop() {
+                        on_stack[member] = false;
+                        component.insert(member);
+                        if member == node {
+                            break;
+                        }
+                    }
+                    let cycles = component.len() > 1
+                        || allowed[node]
+                            .iter()
+                            .any(|&s| self.steps[node][s].to == node);
+                    if cycles {
+                        found.push(component);
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    // what the flow looks like after a step, for the trace's comments
+    fn describe(&self, node: usize) -> String {
+        let node = &self.nodes[node];
+        let mut parts = vec![format!("block {}", node.block)];
+        for (act, val) in self.actuators.iter().zip(&node.actuators) {
+            if let Some(val) = val {
+                parts.push(format!("{} {}", act.name, act.show(f64::from_bits(*val))));
+            }
+        }
+        match &node.end {
+            End::Paused => {}
+            End::Finished => parts.push("finished".to_string()),
+            End::Stopped(e) => parts.push(format!("stopped: {}", e)),
+        }
+        parts.join(", ")
+    }
+
+    // whether the interpreter ended its step where the exploration did
+    fn matches(&self, interp: &Interpreter, stopped: bool, node: usize) -> bool {
+        let node = &self.nodes[node];
+        let actuators = self
+            .actuators
+            .iter()
+            .zip(&node.actuators)
+            .all(|(act, val)| interp.actuators.get(&act.name).map(|v| v.to_bits()) == *val);
+        interp.block == node.block && actuators && stopped == matches!(node.end, End::Stopped(_))
+    }
+
+    // the readings that take the flow along the path, timed by running the interpreter alongside
+    // so each step happens only once any time it needs has passed, and the simulator, skipping
+    // ahead to the next reading or wakeup, takes the same steps
+    fn counterexample(
+        &self,
+        path: &[(usize, usize)],
+        looped: Option<usize>,
+        note: &str,
+    ) -> Counterexample {
+        let mut io = Memory::default();
+        for (sens, val) in self.sensors.iter().zip(&self.valuations[0]) {
+            io.sensors.insert(sens.name.clone(), *val);
+        }
+        let mut interp = Interpreter::new(self.ast, &mut io).ok();
+        let mut replays = interp.is_some();
+        let mut trace = Vec::new();
+        let mut time = 0.0;
+        for (idx, &(from, s)) in path.iter().enumerate() {
+            let step = &self.steps[from][s];
+            if let Some(interp) = &interp {
+                let wakeup = interp.wakeup();
+                if idx > 0 {
+                    time = if step.run.later {
+                        wakeup.unwrap_or(time + 1.0)
+                    } else {
+                        let next = ((time + 0.001) * 1000.0_f64).round() / 1000.0;
+                        match wakeup {
+                            Some(wakeup) if next >= wakeup => time + (wakeup - time) / 2.0,
+                            _ => next,
+                        }
+                    };
+                }
+            }
+            if looped == Some(idx) {
+                trace.push("# from here on the flow can go round a loop".to_string());
+            }
+            for (sens, val) in self.sensors.iter().zip(&self.valuations[step.valuation]) {
+                io.sensors.insert(sens.name.clone(), *val);
+                trace.push(format!("{} {} {}", time, sens.name, sens.kind.show(*val)));
+            }
+            trace.push(format!("# {}", self.describe(step.to)));
+            if let Some(running) = &mut interp {
+                running.time = time;
+                let status = running.step(&mut io);
+                let stopped = running.halted(&status).is_some();
+                if stopped {
+                    let _ = running.fail_safe(&mut io);
+                }
+                replays &= self.matches(running, stopped, step.to);
+                if stopped || status == Ok(Status::Finished) {
+                    interp = None;
+                }
+            }
+        }
+        trace.push(format!("# {}", note));
+        Counterexample { trace, replays }
+    }
+}
+
+// a single run through a step, making each choice that depends on time as `choices` says
+struct Exec<'v, 'a> {
+    verifier: &'v Verifier<'a>,
+    node: Node<'a>,
+    readings: &'v [f64],
+    // the choices to make, in order, added to as false when new ones come up
+    choices: &'v mut Vec<bool>,
+    next: usize,
+    // how each clock comparison and duration condition went this step, since time stands still
+    // during a step
+    decided: HashMap<String, bool>,
+    // the step is the flow's first, at time zero
+    first: bool,
+    // the clocks that read zero, having started during the step
+    fresh_block: bool,
+    fresh_timers: BTreeSet<String>,
+    fresh_held: BTreeSet<usize>,
+    run: Run,
+}
+
+impl<'a> Exec<'_, 'a> {
+    fn choose(&mut self) -> bool {
+        if self.next == self.choices.len() {
+            self.choices.push(false);
+        }
+        self.next += 1;
+        self.choices[self.next - 1]
+    }
+
+    // whether enough time has passed for something, the same way each time it's asked in a step
+    fn passed(&mut self, key: String) -> bool {
+        if let Some(passed) = self.decided.get(&key) {
+            return *passed;
+        }
+        let passed = self.choose();
+        if passed {
+            self.run.later = true;
+        } else {
+            self.run.deferred = true;
+        }
+        self.decided.insert(key, passed);
+        passed
+    }
+
+    fn step(mut self) -> (Node<'a>, Run) {
+        self.node.sensors = self.readings.iter().map(|val| val.to_bits()).collect();
+        if self.first {
+            self.run.entered.push(self.node.block.clone());
+        }
+        let mut end = None;
+        for _ in 0..MAX_OPS_PER_STEP {
+            match self.exec() {
+                Ok(None) => {}
+                Ok(Some(done)) => {
+                    end = Some(done);
+                    break;
+                }
+                Err(e) => {
+                    end = Some(self.fail_safe(e));
+                    break;
+                }
+            }
+        }
+        self.node.end = match end {
+            Some(end) => end,
+            None => {
+                let e = format!("timed out busy looping in block {}", self.node.block);
+                self.fail_safe(e)
+            }
+        };
+        self.check_properties(None);
+        let ast = self.verifier.ast;
+        for (idx, property) in ast.properties.iter().enumerate() {
+            if let Property::Reaches {
+                after: Some(condition),
+                ..
+            } = property
+            {
+                if self.probe(condition).contains(&true) {
+                    self.run.triggered.push(idx);
+                }
+            }
+        }
+        (self.node, self.run)
+    }
+
+    fn fail_safe(&mut self, error: String) -> End {
+        self.node.frames.clear();
+        self.node.delaying = false;
+        for (idx, act) in self.verifier.actuators.iter().enumerate() {
+            if let Some(val) = act.failsafe {
+                self.node.actuators[idx] = Some(val.to_bits());
+            }
+        }
+        End::Stopped(error)
+    }
+
+    fn exec(&mut self) -> Result<Option<End>, String> {
+        let (ops, pc) = loop {
+            match self.node.frames.last() {
+                Some(&(ops, pc)) if pc < ops.0.len() => break (ops, pc),
+                Some(_) => {
+                    self.node.frames.pop();
+                }
+                None => return Ok(Some(End::Finished)),
+            }
+        };
+        let stmt = &ops.0[pc];
+        match &stmt.op {
+            Operation::Set { actuator, value } => {
+                self.set(actuator, *value)?;
+                self.check_properties(Some(stmt.line));
+                self.advance();
+            }
+            Operation::Wait { condition } => {
+                if !self.eval_here(condition)? {
+                    return Ok(Some(End::Paused));
+                }
+                self.advance();
+            }
+            Operation::Delay { duration } => {
+                if *duration > 0.0 {
+                    // started this step, so it can only end in a later one
+                    if !self.node.delaying {
+                        self.node.delaying = true;
+                        return Ok(Some(End::Paused));
+                    }
+                    if !self.passed("delay".to_string()) {
+                        return Ok(Some(End::Paused));
+                    }
+                }
+                self.node.delaying = false;
+                self.advance();
+            }
+            Operation::Start { timer } => {
+                self.node.timers.insert(timer.clone());
+                self.fresh_timers.insert(timer.clone());
+                self.advance();
+            }
+            Operation::IfElse {
+                if_condition,
+                if_actions,
+                else_actions,
+            } => {
+                let taken = self.eval_here(if_condition)?;
+                self.advance();
+                if taken {
+                    self.node.frames.push((Ops(if_actions.as_slice()), 0));
+                } else if let Some(actions) = else_actions {
+                    self.node.frames.push((Ops(actions.as_slice()), 0));
+                }
+            }
+            Operation::Goto { dest } => {
+                let block = match self.verifier.ast.blocks.get(dest) {
+                    Some(block) => block,
+                    None => return Err(format!("goto to unknown block {}", dest)),
+                };
+                // edges and durations start afresh, as the interpreter has them
+                for condition in lower::remembered(&block.ops) {
+                    match condition {
+                        Condition::Edge { id, .. } => {
+                            self.node.edges.remove(id);
+                        }
+                        Condition::For { id, .. } => {
+                            self.node.held.remove(id);
+                            self.fresh_held.remove(id);
+                        }
+                        _ => {}
+                    }
+                }
+                self.node.block = dest.clone();
+                self.node.frames = vec![(Ops(block.ops.as_slice()), 0)];
+                self.fresh_block = true;
+                self.run.entered.push(dest.clone());
+            }
+        }
+        Ok(None)
+    }
+
+    fn advance(&mut self) {
+        if let Some(frame) = self.node.frames.last_mut() {
+            frame.1 += 1;
+        }
+    }
+
+    fn actuator(&self, name: &str) -> usize {
+        self.verifier
+            .actuators
+            .iter()
+            .position(|act| act.name == name)
+            .unwrap()
+    }
+
+    fn set(&mut self, actuator: &Actuator, value: f64) -> Result<(), String> {
+        let idx = self.actuator(&actuator.name);
+        let previous = self.node.actuators[idx].replace(value.to_bits());
+        if let Some(reason) = self.violation() {
+            self.node.actuators[idx] = previous;
+            return Err(format!(
+                "block {}: refused to set {} to {}: {}",
+                self.node.block, actuator.name, value, reason
+            ));
+        }
+        Ok(())
+    }
+
+    // first interlock or invariant broken, as Interpreter::violation has it
+    fn violation(&mut self) -> Option<String> {
+        interp::violation(self.verifier.ast, |condition| self.eval(condition))
+    }
+
+    // how a property's condition can come out here, leaving the flow's own state alone. Edges,
+    // durations and hysteresis in properties see only what's true right now.
+    fn probe(&mut self, condition: &Condition) -> Vec<bool> {
+        let saved = (
+            self.node.latches.clone(),
+            self.node.edges.clone(),
+            self.node.held.clone(),
+            self.fresh_held.clone(),
+        );
+        let result = self.eval(condition);
+        (
+            self.node.latches,
+            self.node.edges,
+            self.node.held,
+            self.fresh_held,
+        ) = saved;
+        result.into_iter().collect()
+    }
+
+    // note the always and never properties that don't hold after a set, or at the end of a step
+    fn check_properties(&mut self, line: Option<usize>) {
+        let ast = self.verifier.ast;
+        for (idx, property) in ast.properties.iter().enumerate() {
+            let (condition, wanted) = match property {
+                Property::Always(condition) => (condition, true),
+                Property::Never(condition) => (condition, false),
+                Property::Reaches { .. } => continue,
+            };
+            if self.run.broken.iter().any(|(p, _)| *p == idx) {
+                continue;
+            }
+            if self.probe(condition).contains(&!wanted) {
+                self.run.broken.push((idx, line));
+            }
+        }
+    }
+
+    fn eval_here(&mut self, condition: &Condition) -> Result<bool, String> {
+        self.eval(condition)
+            .map_err(|e| format!("block {}: {}", self.node.block, e))
+    }
+
+    fn eval(&mut self, condition: &Condition) -> Result<bool, String> {
+        match condition {
+            Condition::Base(sensor, comp, val) => {
+                let idx = self
+                    .verifier
+                    .sensors
+                    .iter()
+                    .position(|sens| sens.name == sensor.name)
+                    .unwrap();
+                Ok(comp.holds(self.readings[idx], *val))
+            }
+            Condition::Actuator(actuator, comp, val) => {
+                match self.node.actuators[self.actuator(&actuator.name)] {
+                    Some(current) => Ok(comp.holds(f64::from_bits(current), *val)),
+                    None => Err(format!("actuator {} read before it was set", actuator.name)),
+                }
+            }
+            Condition::Time(clock, comp, val) => {
+                let fresh = match clock {
+                    Clock::Elapsed => self.fresh_block,
+                    Clock::Now => self.first,
+                    Clock::Timer(name) => {
+                        if !self.node.timers.contains(name) {
+                            return Err(format!("timer {} read before it was started", name));
+                        }
+                        self.fresh_timers.contains(name)
+                    }
+                };
+                if fresh {
+                    return Ok(comp.holds(0.0, *val));
+                }
+                // the clock has either got to the value or it hasn't
+                let passed = self.passed(format!("{} {}", clock, val));
+                Ok(match comp {
+                    Comparator::LT | Comparator::LTEQ => !passed,
+                    _ => passed,
+                })
+            }
+            Condition::Hysteresis {
+                sensor,
+                rising,
+                threshold,
+                band,
+                id,
+            } => {
+                let reading = self.readings[self
+                    .verifier
+                    .sensors
+                    .iter()
+                    .position(|sens| sens.name == sensor.name)
+                    .unwrap()];
+                let on = self.node.latches.get(id).copied().unwrap_or(false);
+                let on = match (rising, on) {
+                    (true, false) => reading > *threshold,
+                    (true, true) => reading >= threshold - band,
+                    (false, false) => reading < *threshold,
+                    (false, true) => reading <= threshold + band,
+                };
+                self.node.latches.insert(*id, on);
+                Ok(on)
+            }
+            Condition::Edge {
+                condition,
+                rising,
+                id,
+            } => {
+                let now = self.eval(condition)?;
+                let before = self.node.edges.insert(*id, now).unwrap_or(now);
+                Ok(if *rising {
+                    !before && now
+                } else {
+                    before && !now
+                })
+            }
+            Condition::For {
+                condition,
+                duration,
+                id,
+            } => {
+                if !self.eval(condition)? {
+                    self.node.held.remove(id);
+                    return Ok(false);
+                }
+                if *duration <= 0.0 {
+                    self.node.held.insert(*id);
+                    return Ok(true);
+                }
+                if self.node.held.insert(*id) {
+                    // only just started holding, so it needs time to pass
+                    self.fresh_held.insert(*id);
+                    self.run.deferred = true;
+                    return Ok(false);
+                }
+                if self.fresh_held.contains(id) {
+                    return Ok(false);
+                }
+                Ok(self.passed(format!("for {}", id)))
+            }
+            Condition::All(conditions) => {
+                for c in conditions {
+                    if !self.eval(c)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            Condition::Any(conditions) => {
+                for c in conditions {
+                    if self.eval(c)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+}
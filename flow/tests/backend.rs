@@ -0,0 +1,65 @@
+use std::io::Cursor;
+
+use flow::backend::{DeviceBackend, Stdio, Trace};
+use flow::fltest;
+use flow::interp::{Interpreter, Status};
+
+#[test]
+fn stdio_speaks_the_line_protocol() {
+    let ast = fltest::load("tests/timers.fl".as_ref()).unwrap();
+    let mut output = Vec::new();
+    {
+        let mut io = Stdio::new(Cursor::new("false\ntrue\n"), &mut output);
+        let mut interp = Interpreter::new(&ast, &mut io).unwrap();
+        assert_eq!(interp.step(&mut io), Ok(Status::Waiting));
+        interp.time = 1.0;
+        assert_eq!(interp.step(&mut io), Ok(Status::Waiting));
+        assert_eq!(
+            interp.step(&mut io),
+            Err("input closed while reading sensor door".to_string())
+        );
+    }
+    // init values, then a reading for each step and what the flow did with it
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "set alarm false
+set fan 0
+get door
+set alarm false
+get door
+set fan 1
+get door
+"
+    );
+
+    let mut io = Stdio::new(Cursor::new("ajar\n"), Vec::new());
+    let mut interp = Interpreter::new(&ast, &mut io).unwrap();
+    assert_eq!(
+        interp.step(&mut io),
+        Err("invalid reading \"ajar\" for sensor door".to_string())
+    );
+}
+
+#[test]
+fn traces_take_only_finite_times_and_readings() {
+    let ast = fltest::load("tests/interlock.fl".as_ref()).unwrap();
+    let sensors = ast.sensors();
+    for bad in [
+        "NaN temp 60",
+        "inf temp 60",
+        "-1 temp 60",
+        "0 temp NaN",
+        "0 temp -inf",
+    ] {
+        let text = format!("0 temp 20\n{}\n", bad);
+        match Trace::parse(&text, &sensors) {
+            Ok(_) => panic!("{} was taken", bad),
+            Err(e) => assert_eq!(e, format!("line 2: invalid sample \"{}\"", bad)),
+        }
+    }
+
+    let mut trace = Trace::parse("5 temp 90\n0 temp 20\n", &sensors).unwrap();
+    trace.seek(1.0);
+    assert_eq!(trace.read_sensor(sensors[0]), Ok(20.0));
+    assert_eq!(trace.next_change(), Some(5.0));
+}
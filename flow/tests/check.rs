@@ -0,0 +1,323 @@
+use flow::ast;
+use flow::check;
+use flow::token;
+
+fn warnings(code: &str) -> Vec<String> {
+    let ast = ast::make_ast(&token::tokenize(code.to_string())).unwrap();
+    check::check(&ast).iter().map(|d| d.to_string()).collect()
+}
+
+const DEVICES: &str = "sensor temp 0..100
+sensor level 0..10
+actuator pump 0..1 init 0 failsafe 0
+";
+
+fn flow(body: &str) -> String {
+    format!(
+        "{}\nblock main\n{}    wait 1s\n    goto main\nendblock\n",
+        DEVICES, body
+    )
+}
+
+#[test]
+fn finds_groups_decided_by_declared_ranges() {
+    let code = flow(
+        "    if:
+        - any:
+            - all:
+                - temp > 10
+                - temp < 5
+            - level <= 5
+            - level > 5
+        set pump 1
+",
+    );
+    assert_eq!(
+        warnings(&code),
+        [
+            "warning: line 6: block main: any(all(temp > 10, temp < 5), level <= 5, level > 5) always holds",
+            "warning: line 6: block main: all(temp > 10, temp < 5) can never hold",
+        ]
+    );
+}
+
+#[test]
+fn strict_bounds_rule_each_other_out() {
+    let code = flow(
+        "    wait:
+        - all:
+            - level < 5
+            - level >= 5
+",
+    );
+    assert_eq!(
+        warnings(&code),
+        ["warning: line 6: block main: all(level < 5, level >= 5) can never hold"]
+    );
+}
+
+#[test]
+fn finds_ifs_decided_by_what_came_before() {
+    let code = flow(
+        "    wait:
+        - temp > 50
+    if:
+        - temp < 20
+        set pump 1
+    if:
+        - temp >= 50
+        set pump 0
+    else:
+        set pump 1
+",
+    );
+    assert_eq!(
+        warnings(&code),
+        [
+            "warning: line 8: block main: temp < 20 never holds here, so the if branch never runs",
+            "warning: line 11: block main: temp >= 50 always holds here, so the else branch never runs",
+        ]
+    );
+}
+
+#[test]
+fn finds_nested_ifs_decided_by_enclosing_ones() {
+    let code = flow(
+        "    if:
+        - level > 8
+        if:
+            - level > 2
+            set pump 1
+",
+    );
+    assert_eq!(
+        warnings(&code),
+        ["warning: line 8: block main: level > 2 always holds here, so the if is redundant"]
+    );
+}
+
+#[test]
+fn finds_waits_the_previous_wait_satisfied() {
+    let code = flow(
+        "    wait:
+        - temp > 50
+    wait:
+        - temp > 40
+",
+    );
+    assert_eq!(
+        warnings(&code),
+        ["warning: line 8: block main: wait on temp > 40 already holds here, so it never waits"]
+    );
+}
+
+#[test]
+fn finds_waits_nothing_can_satisfy() {
+    // readings change while waiting, actuators don't
+    let code = flow(
+        "    set pump 0
+    wait:
+        - all:
+            - temp > 50
+            - pump = 1
+",
+    );
+    assert_eq!(
+        warnings(&code),
+        ["warning: line 7: block main: wait on all(temp > 50, pump = 1) can never be satisfied, so it waits forever"]
+    );
+}
+
+#[test]
+fn leaves_undecided_conditions_alone() {
+    let code = flow(
+        "    wait:
+        - temp > 50
+    wait:
+        - temp > 60
+    if:
+        - any:
+            - level < 2
+            - level > 8
+        set pump 1
+",
+    );
+    assert!(warnings(&code).is_empty(), "{:?}", warnings(&code));
+}
+
+#[test]
+fn finds_sets_that_break_interlocks_and_invariants() {
+    let code = format!(
+        "{}
+interlock: pump > 0 requires - level >= 2
+invariant: - pump < 1
+
+block main
+    set pump 0.5
+    wait:
+        - level < 1
+    set pump 1
+    wait 1s
+    goto main
+endblock
+",
+        DEVICES
+    );
+    assert_eq!(
+        warnings(&code),
+        [
+            "warning: line 9: block main: set pump 0.5 may violate interlock pump > 0 requires level >= 2",
+            "error: line 12: block main: set pump 1 violates interlock pump > 0 requires level >= 2",
+            "error: line 12: block main: set pump 1 violates invariant pump < 1",
+        ]
+    );
+}
+
+#[test]
+fn warns_about_actuators_read_before_they_are_set() {
+    let code = format!(
+        "{}actuator valve 0..1
+
+block main
+    if:
+        - valve = 1
+        set pump 1
+    set valve 1
+    wait 1s
+    goto main
+endblock
+",
+        DEVICES
+    );
+    assert_eq!(
+        warnings(&code),
+        ["warning: line 7: block main: actuator valve may be read before it is set, consider giving it an init value"]
+    );
+}
+
+#[test]
+fn warns_about_enum_chains_missing_a_variant() {
+    let code = "sensor mode: enum {idle, run, fault}
+actuator lamp: bool init false
+
+block main
+    if:
+        - mode = idle
+        set lamp false
+    else:
+        if:
+            - mode = run
+            set lamp true
+    wait 1s
+    goto main
+endblock
+";
+    assert_eq!(
+        warnings(code),
+        ["warning: line 5: block main: if/else chain over mode doesn't handle fault"]
+    );
+}
+
+fn parse_error(code: &str) -> &'static str {
+    ast::make_ast(&token::tokenize(code.to_string())).unwrap_err()
+}
+
+#[test]
+fn rejects_values_of_the_wrong_kind() {
+    let code = |body: &str| {
+        format!(
+            "sensor mode: enum {{idle, run}}\nactuator lamp: bool\nactuator pump 0..1\n\nblock main\n{}    wait 1s\nendblock\n",
+            body
+        )
+    };
+    assert_eq!(
+        parse_error(&code("    set pump true\n")),
+        "Expected numeric value for device"
+    );
+    assert_eq!(
+        parse_error(&code("    set lamp 1\n")),
+        "Expected true or false for bool device"
+    );
+    assert_eq!(
+        parse_error(&code("    wait:\n        - mode = stopped\n")),
+        "Expected one of the enum's variants"
+    );
+    assert_eq!(
+        parse_error(&code("    wait:\n        - mode > idle\n")),
+        "Expected \"=\" when comparing a bool or enum device"
+    );
+}
+
+#[test]
+fn rejects_units_that_dont_fit_the_device() {
+    let code = |body: &str| {
+        format!(
+            "sensor temp 0..150 degC\nsensor level 0..10\nactuator boost 0..10 bar\n\nblock main\n{}    wait 1s\nendblock\n",
+            body
+        )
+    };
+    assert!(ast::make_ast(&token::tokenize(code("    set boost 200kPa\n"))).is_ok());
+    assert_eq!(
+        parse_error(&code("    wait:\n        - temp >= 2bar\n")),
+        "Expected a temperature unit on value for a device in temperature"
+    );
+    assert_eq!(
+        parse_error(&code("    set boost 40degC\n")),
+        "Expected a pressure unit on value for a device in pressure"
+    );
+    assert_eq!(
+        parse_error(&code("    wait:\n        - level > 5bar\n")),
+        "Expected no unit on value for a device without a unit"
+    );
+    assert_eq!(
+        parse_error(&code("    wait:\n        - temp > 50 furlongs\n")),
+        "Expected a known unit after value, such as degC, bar or ms"
+    );
+}
+
+#[test]
+fn start_is_only_a_keyword_at_the_start_of_a_statement() {
+    let code = "sensor door: bool
+timer start_up
+
+block start
+    start start_up
+    wait:
+        - door
+    goto start
+endblock
+";
+    assert!(warnings(code).is_empty(), "{:?}", warnings(code));
+    assert_eq!(
+        parse_error(&code.replace("start start_up", "start door")),
+        "Expected declared timer name after \"start\""
+    );
+}
+
+#[test]
+fn rejects_interlocks_and_invariants_that_remember() {
+    let code = |safety: &str| {
+        format!(
+            "sensor temp 0..100 degC\nsensor button: bool\nactuator heater 0..100 init 0\n\n{}\n\nblock heat\n    set heater 50\n    wait 1s\nendblock\n",
+            safety
+        )
+    };
+    let parsed = |code: String| ast::make_ast(&token::tokenize(code)).map(|_| ());
+    assert_eq!(parsed(code("invariant: - heater <= 80")), Ok(()));
+    assert_eq!(
+        parse_error(&code("interlock: heater > 0 requires - button rising")),
+        "Expected an interlock without rising, falling, for or hysteresis"
+    );
+    assert_eq!(
+        parse_error(&code(
+            "interlock: heater > 0 requires - temp < 80degC for 5s"
+        )),
+        "Expected an interlock without rising, falling, for or hysteresis"
+    );
+    assert_eq!(
+        parse_error(&code(
+            "invariant: - any:\n    - heater = 0\n    - temp rises_above 60degC hyst 5degC"
+        )),
+        "Expected an invariant without rising, falling, for or hysteresis"
+    );
+}
@@ -0,0 +1,790 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use flow_runtime::program::{self as runtime, LoadError};
+use flow_runtime::vm::{Devices, Fault, Vm};
+
+use flow::ast::{Kind, AST};
+use flow::backend::Memory;
+use flow::bytecode;
+use flow::c;
+use flow::fltest;
+use flow::interp::Interpreter;
+use flow::python;
+use flow::rust;
+use flow::st;
+
+fn samples() -> Vec<(String, AST)> {
+    let mut found = Vec::new();
+    for entry in fs::read_dir("tests").unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|ext| ext == "fl") {
+            let name = path.file_stem().unwrap().to_string_lossy().to_string();
+            found.push((name, fltest::load(&path).unwrap()));
+        }
+    }
+    found.sort_by(|a, b| a.0.cmp(&b.0));
+    found
+}
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("flow-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn camel(name: &str) -> String {
+    let mut chars = name.chars();
+    let first = chars.next().unwrap().to_ascii_uppercase();
+    std::iter::once(first).chain(chars).collect()
+}
+
+// a value as the generated code writes it, with `ty` the enum type for enum devices
+fn literal(kind: &Kind, ty: &str, val: f64) -> String {
+    match kind {
+        Kind::Float => format!("{:?}", val),
+        Kind::Bool => (val != 0.0).to_string(),
+        Kind::Enum(variants) => format!("{}::{}", ty, camel(&variants[val as usize])),
+    }
+}
+
+// a value as `{:?}` shows it in the generated code
+fn shown(kind: &Kind, val: f64) -> String {
+    match kind {
+        Kind::Enum(variants) => camel(&variants[val as usize]),
+        _ => literal(kind, "", val),
+    }
+}
+
+// readings that wander over each sensor's range, and the times to take them at
+fn script(ast: &AST, steps: usize) -> Vec<(f64, Vec<f64>)> {
+    let mut seed: u64 = 12345;
+    let mut next = move |n: u64| {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (seed >> 33) % n
+    };
+    let mut time = 0.0;
+    let mut out = Vec::new();
+    for _ in 0..steps {
+        let readings = ast
+            .sensors()
+            .iter()
+            .map(|sens| match &sens.kind {
+                Kind::Float => {
+                    let lo = sens.min.max(-10.0) as i64;
+                    let hi = sens.max.min(120.0) as i64;
+                    (lo + next((hi - lo + 1) as u64) as i64) as f64
+                }
+                Kind::Bool => next(2) as f64,
+                Kind::Enum(variants) => next(variants.len() as u64) as f64,
+            })
+            .collect();
+        out.push((time, readings));
+        time += [0.25, 1.0, 5.0, 20.0, 45.0][next(5) as usize];
+    }
+    out
+}
+
+// what the interpreter leaves after a step
+struct Step {
+    time: f64,
+    block: String,
+    // each actuator's value, in declaration order, None while it's unset
+    values: Vec<Option<f64>>,
+    halted: Option<String>,
+}
+
+// what the interpreter does over the script, up to when it halts
+fn interpret(ast: &AST, script: &[(f64, Vec<f64>)]) -> Vec<Step> {
+    let sensors = ast.sensors();
+    let mut io = Memory::default();
+    let mut interp = Interpreter::new(ast, &mut io).unwrap();
+    let mut steps = Vec::new();
+    for (time, readings) in script {
+        for (sens, val) in sensors.iter().zip(readings) {
+            io.sensors.insert(sens.name.clone(), *val);
+        }
+        interp.time = *time;
+        let status = interp.step(&mut io);
+        let halted = interp.halted(&status);
+        if halted.is_some() {
+            interp.fail_safe(&mut io).unwrap();
+        }
+        let values = ast
+            .actuators()
+            .iter()
+            .map(|act| interp.actuators.get(&act.name).copied())
+            .collect();
+        let stop = halted.is_some();
+        steps.push(Step {
+            time: *time,
+            block: interp.block.clone(),
+            values,
+            halted,
+        });
+        if stop {
+            break;
+        }
+    }
+    steps
+}
+
+// the steps as the generated Rust harness prints them
+fn rust_lines(ast: &AST, steps: &[Step]) -> Vec<String> {
+    let actuators = ast.actuators();
+    steps
+        .iter()
+        .map(|step| {
+            let values: Vec<String> = actuators
+                .iter()
+                .zip(&step.values)
+                .map(|(act, val)| {
+                    let shown = match (act.init, val) {
+                        (Some(_), Some(val)) => shown(&act.kind, *val),
+                        (None, Some(val)) => format!("Some({})", shown(&act.kind, *val)),
+                        (_, None) => "None".to_string(),
+                    };
+                    format!("{}: {}", act.name, shown)
+                })
+                .collect();
+            format!(
+                "{} {} Actuators {{ {} }} {:?}",
+                step.time,
+                step.block,
+                values.join(", "),
+                step.halted
+            )
+        })
+        .collect()
+}
+
+// the steps as the generated C harness prints them, with enums and bools as numbers
+fn c_lines(ast: &AST, steps: &[Step]) -> Vec<String> {
+    let actuators = ast.actuators();
+    steps
+        .iter()
+        .map(|step| {
+            let mut out = format!("{} {}", step.time, step.block);
+            for (act, val) in actuators.iter().zip(&step.values) {
+                match val {
+                    Some(val) => out += &format!(" {}={}", act.name, val),
+                    None => out += &format!(" {}=-", act.name),
+                }
+            }
+            out + " " + step.halted.as_deref().unwrap_or("-")
+        })
+        .collect()
+}
+
+// a main that runs the generated flow over the script the same way
+fn harness(name: &str, ast: &AST, script: &[(f64, Vec<f64>)]) -> String {
+    let sensors = ast.sensors();
+    let mut out = format!(
+        "fn run_{}() {{\n    use {}::*;\n    let mut flow = Flow::new();\n",
+        name, name
+    );
+    for (time, readings) in script {
+        let fields: Vec<String> = sensors
+            .iter()
+            .zip(readings)
+            .map(|(sens, val)| {
+                format!(
+                    "{}: {}",
+                    sens.name,
+                    literal(&sens.kind, &camel(&sens.name), *val)
+                )
+            })
+            .collect();
+        out += &format!(
+            "    flow.time = {:?};\n    let actuators = flow.step(&Sensors {{ {} }});\n",
+            time,
+            fields.join(", ")
+        );
+        out += &format!(
+            "    println!(\"{} {{}} {{:?}} {{:?}}\", flow.state.name(), actuators, flow.halted());\n",
+            time
+        );
+        out += "    if flow.halted().is_some() {\n        return;\n    }\n";
+    }
+    out += "}\n";
+    out
+}
+
+#[test]
+fn generated_rust_builds_without_warnings_and_matches_the_interpreter() {
+    let dir = scratch("compile");
+    let samples = samples();
+    let mut library = String::new();
+    // the harness doesn't use all of each flow's API
+    let mut program = "#![allow(dead_code)]\n\n".to_string();
+    let mut expected = Vec::new();
+    for (name, ast) in &samples {
+        let script = script(ast, 60);
+        let module = format!("pub mod {} {{\n{}}}\n\n", name, rust::generate(ast, name));
+        library += &module;
+        program += &module;
+        program += &harness(name, ast, &script);
+        expected.push(format!("== {}", name));
+        expected.extend(rust_lines(ast, &interpret(ast, &script)));
+    }
+    let rlib = dir.join("libflows.rlib");
+    fs::write(dir.join("lib.rs"), &library).unwrap();
+    let built = Command::new("rustc")
+        .args([
+            "--edition",
+            "2021",
+            "--crate-type",
+            "lib",
+            "-D",
+            "warnings",
+            "-o",
+        ])
+        .arg(&rlib)
+        .arg(dir.join("lib.rs"))
+        .output()
+        .unwrap();
+    assert!(
+        built.status.success(),
+        "{}",
+        String::from_utf8_lossy(&built.stderr)
+    );
+
+    program += "\nfn main() {\n";
+    for (name, _) in &samples {
+        program += &format!("    println!(\"== {}\");\n    run_{}();\n", name, name);
+    }
+    program += "}\n";
+
+    let source = dir.join("flows.rs");
+    fs::write(&source, &program).unwrap();
+    let binary = dir.join("flows");
+    let built = Command::new("rustc")
+        .args(["--edition", "2021", "-D", "warnings", "-o"])
+        .arg(&binary)
+        .arg(&source)
+        .output()
+        .unwrap();
+    assert!(
+        built.status.success(),
+        "{}",
+        String::from_utf8_lossy(&built.stderr)
+    );
+    let ran = Command::new(&binary).output().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    let actual: Vec<String> = String::from_utf8_lossy(&ran.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect();
+    same(&actual, &expected);
+}
+
+// a main that runs the generated C over the script the same way
+fn c_harness(name: &str, ast: &AST, script: &[(f64, Vec<f64>)]) -> String {
+    let sensors = ast.sensors();
+    let mut out = format!(
+        "#include <stdio.h>\n\n#include \"{}.h\"\n\nstatic void show(const flow_state_t *flow, const flow_actuators_t *a)\n{{\n",
+        name
+    );
+    out += "    printf(\"%g %s\", flow->time, flow_block_name(flow->block));\n";
+    for act in ast.actuators() {
+        let value = format!("printf(\" {}=%g\", (double)a->{})", act.name, act.name);
+        if act.init.is_some() {
+            out += &format!("    {};\n", value);
+        } else {
+            out += &format!(
+                "    if (a->{}_set)\n        {};\n    else\n        printf(\" {}=-\");\n",
+                act.name, value, act.name
+            );
+        }
+    }
+    out += "    printf(\" %s\\n\", flow_halted(flow) ? flow_halted(flow) : \"-\");\n}\n\n";
+    out += "int main(void)\n{\n    flow_state_t flow;\n    flow_actuators_t a;\n    flow_init(&flow);\n";
+    for (time, readings) in script {
+        let fields: Vec<String> = sensors
+            .iter()
+            .zip(readings)
+            .map(|(sens, val)| format!(".{} = {:?}", sens.name, val))
+            .collect();
+        out += &format!(
+            "    {{\n        flow_sensors_t s = {{ {} }};\n        flow.time = {:?};\n",
+            fields.join(", "),
+            time
+        );
+        out += "        flow_step(&flow, &s, &a);\n        show(&flow, &a);\n";
+        out += "        if (flow_halted(&flow))\n            return 0;\n    }\n";
+    }
+    out += "    return 0;\n}\n";
+    out
+}
+
+#[test]
+fn generated_c_builds_without_warnings_and_matches_the_interpreter() {
+    let dir = scratch("compile-c");
+    let mut actual = Vec::new();
+    let mut expected = Vec::new();
+    for (name, ast) in samples() {
+        let script = script(&ast, 60);
+        let (header, source) = c::generate(&ast, &name, &format!("{}.h", name));
+        fs::write(dir.join(format!("{}.h", name)), header).unwrap();
+        fs::write(dir.join(format!("{}.c", name)), source).unwrap();
+        fs::write(
+            dir.join(format!("run_{}.c", name)),
+            c_harness(&name, &ast, &script),
+        )
+        .unwrap();
+        let binary = dir.join(&name);
+        let built = Command::new("cc")
+            .args(["-std=c99", "-Wall", "-Wextra", "-pedantic", "-Werror", "-o"])
+            .arg(&binary)
+            .arg(dir.join(format!("{}.c", name)))
+            .arg(dir.join(format!("run_{}.c", name)))
+            .output()
+            .unwrap();
+        assert!(
+            built.status.success(),
+            "{}: {}",
+            name,
+            String::from_utf8_lossy(&built.stderr)
+        );
+        let ran = Command::new(&binary).output().unwrap();
+        actual.push(format!("== {}", name));
+        actual.extend(
+            String::from_utf8_lossy(&ran.stdout)
+                .lines()
+                .map(str::to_string),
+        );
+        expected.push(format!("== {}", name));
+        expected.extend(c_lines(&ast, &interpret(&ast, &script)));
+    }
+    fs::remove_dir_all(&dir).unwrap();
+    same(&actual, &expected);
+}
+
+// a script that runs the generated Python over the script the same way, printing what the C
+// harness prints
+fn python_harness(name: &str, ast: &AST, script: &[(f64, Vec<f64>)]) -> String {
+    let mut out = format!("from {} import Flow\n\n", name);
+    out += "def show(val, variants):\n";
+    out += "    if val is None:\n        return \"-\"\n";
+    out += "    return \"%g\" % (variants.index(val) if variants else val)\n\n";
+    out += "flow = Flow()\nfor time, readings in [\n";
+    for (time, readings) in script {
+        let fields: Vec<String> = ast
+            .sensors()
+            .iter()
+            .zip(readings)
+            .map(|(sens, val)| {
+                let val = match &sens.kind {
+                    Kind::Float => format!("{:?}", val),
+                    Kind::Bool if *val != 0.0 => "True".to_string(),
+                    Kind::Bool => "False".to_string(),
+                    Kind::Enum(variants) => format!("{:?}", variants[*val as usize]),
+                };
+                format!("{:?}: {}", sens.name, val)
+            })
+            .collect();
+        out += &format!("    ({:?}, {{{}}}),\n", time, fields.join(", "));
+    }
+    out += "]:\n    flow.time = time\n    a = flow.step(readings)\n";
+    out += "    print(\"%g %s\" % (time, flow.block), end=\"\")\n";
+    for act in ast.actuators() {
+        let variants = match &act.kind {
+            Kind::Enum(variants) => format!("{:?}", variants),
+            _ => "None".to_string(),
+        };
+        out += &format!(
+            "    print(\" {}=\" + show(a[{:?}], {}), end=\"\")\n",
+            act.name, act.name, variants
+        );
+    }
+    out += "    print(\" \" + (flow.halted or \"-\"))\n";
+    out += "    if flow.halted:\n        break\n";
+    out
+}
+
+#[test]
+fn generated_python_matches_the_interpreter() {
+    let dir = scratch("compile-python");
+    let mut actual = Vec::new();
+    let mut expected = Vec::new();
+    for (name, ast) in samples() {
+        let script = script(&ast, 60);
+        let steps = c_lines(&ast, &interpret(&ast, &script));
+        for generator in [false, true] {
+            let module = format!("flow_{}", name);
+            let code = python::generate(&ast, &format!("{}.fl", name), generator);
+            fs::write(dir.join(format!("{}.py", module)), code).unwrap();
+            let harness = dir.join(format!("run_{}.py", name));
+            fs::write(&harness, python_harness(&module, &ast, &script)).unwrap();
+            let ran = Command::new("python3").arg(&harness).output().unwrap();
+            assert!(
+                ran.status.success(),
+                "{}: {}",
+                name,
+                String::from_utf8_lossy(&ran.stderr)
+            );
+            let version = if generator { "generator" } else { "plain" };
+            actual.push(format!("== {} {}", name, version));
+            actual.extend(
+                String::from_utf8_lossy(&ran.stdout)
+                    .lines()
+                    .map(str::to_string),
+            );
+            expected.push(format!("== {} {}", name, version));
+            expected.extend(steps.iter().cloned());
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+    same(&actual, &expected);
+}
+
+// the Structured Text for the samples and the example, against the files in tests/st, which
+// running with FLOW_BLESS set writes afresh
+#[test]
+fn generated_st_matches_the_golden_files() {
+    let bless = std::env::var_os("FLOW_BLESS").is_some();
+    let mut flows = samples();
+    flows.push((
+        "example".to_string(),
+        fltest::load(Path::new("../example.fl")).unwrap(),
+    ));
+    for (name, ast) in flows {
+        let code = st::generate(&ast, &format!("{}.fl", name), &name);
+        let golden = Path::new("tests/st").join(format!("{}.st", name));
+        if bless {
+            fs::write(&golden, &code).unwrap();
+            continue;
+        }
+        let expected = fs::read_to_string(&golden).unwrap();
+        assert!(
+            code == expected,
+            "{} is out of date, rerun with FLOW_BLESS=1 if the change is meant",
+            golden.display()
+        );
+        balanced(&name, &code);
+    }
+}
+
+// every construct that's opened gets closed, which is the mistake a PLC toolchain would trip on
+// first
+fn balanced(name: &str, code: &str) {
+    // just the keywords, without comments and strings
+    let mut words = Vec::new();
+    let mut rest = code;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("(*") {
+            rest = &after[after.find("*)").unwrap() + 2..];
+        } else if let Some(after) = rest.strip_prefix('\'') {
+            let mut end = 0;
+            let bytes = after.as_bytes();
+            while bytes[end] != b'\'' {
+                end += if bytes[end] == b'$' { 2 } else { 1 };
+            }
+            rest = &after[end + 1..];
+        } else {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len())
+                .max(1);
+            words.push(&rest[..len]);
+            rest = &rest[len..];
+        }
+    }
+    for (open, close) in [
+        ("FUNCTION_BLOCK", "END_FUNCTION_BLOCK"),
+        ("IF", "END_IF"),
+        ("CASE", "END_CASE"),
+        ("FOR", "END_FOR"),
+    ] {
+        let opened = words.iter().filter(|w| **w == open).count();
+        let closed = words.iter().filter(|w| **w == close).count();
+        assert_eq!(opened, closed, "{}: {} and {}", name, open, close);
+    }
+    let sections = words.iter().filter(|w| w.starts_with("VAR")).count();
+    let ended = words.iter().filter(|w| **w == "END_VAR").count();
+    assert_eq!(sections, ended, "{}: VAR and END_VAR", name);
+}
+
+// sensors and actuators for the runtime, by index, with a write that can be made to fail
+struct Bench {
+    readings: Vec<f64>,
+    written: Vec<Option<f64>>,
+    broken: Option<usize>,
+}
+
+impl Devices for Bench {
+    type Error = String;
+
+    fn read_sensor(&mut self, sensor: usize) -> Result<f64, String> {
+        Ok(self.readings[sensor])
+    }
+
+    fn write_actuator(&mut self, actuator: usize, value: f64) -> Result<(), String> {
+        if self.broken == Some(actuator) {
+            return Err(format!("actuator {} is broken", actuator));
+        }
+        self.written[actuator] = Some(value);
+        Ok(())
+    }
+}
+
+// what the runtime does over the script, the way interpret() runs the interpreter
+fn run_vm(program: runtime::Program, script: &[(f64, Vec<f64>)]) -> Vec<Step> {
+    let mut io = Bench {
+        readings: Vec::new(),
+        written: vec![None; program.actuators().len()],
+        broken: None,
+    };
+    let mut vm: Vm<16> = Vm::new(program, &mut io).unwrap();
+    let mut steps = Vec::new();
+    for (time, readings) in script {
+        io.readings = readings.clone();
+        vm.time = *time;
+        let status = vm.step(&mut io);
+        let halted = vm.halted(&status).map(|h| h.to_string());
+        if halted.is_some() {
+            vm.fail_safe(&mut io).unwrap();
+        }
+        let values: Vec<Option<f64>> = (0..io.written.len()).map(|idx| vm.actuator(idx)).collect();
+        assert_eq!(values, io.written);
+        let stop = halted.is_some();
+        steps.push(Step {
+            time: *time,
+            block: vm.block().to_string(),
+            values,
+            halted,
+        });
+        if stop {
+            break;
+        }
+    }
+    steps
+}
+
+#[test]
+fn bytecode_round_trips_and_runs_like_the_interpreter() {
+    for (name, ast) in samples() {
+        let program = bytecode::compile(&ast).unwrap();
+        let bytes = program.to_bytes();
+        let loaded = runtime::Program::load(&bytes).unwrap();
+        let names: Vec<&str> = loaded.sensors().collect();
+        assert_eq!(names, program.sensors, "{}", name);
+        let outputs: Vec<(&str, Option<f64>, Option<f64>)> = loaded
+            .actuators()
+            .map(|a| (a.name, a.init, a.failsafe))
+            .collect();
+        let expected: Vec<(&str, Option<f64>, Option<f64>)> = program
+            .actuators
+            .iter()
+            .map(|a| (a.name.as_str(), a.init, a.failsafe))
+            .collect();
+        assert_eq!(outputs, expected, "{}", name);
+        let blocks: Vec<(&str, u32)> = loaded.blocks().map(|b| (b.name, b.start)).collect();
+        let expected: Vec<(&str, u32)> = program
+            .blocks
+            .iter()
+            .map(|b| (b.name.as_str(), b.start))
+            .collect();
+        assert_eq!(blocks, expected, "{}", name);
+        assert_eq!(loaded.first(), program.first, "{}", name);
+        assert_eq!(
+            loaded.slots(),
+            (program.latches, program.edges, program.held),
+            "{}",
+            name
+        );
+        assert_eq!(loaded.ops() as usize, program.code.len(), "{}", name);
+        for (idx, op) in program.code.iter().enumerate() {
+            assert_eq!(loaded.op(idx as u32), *op, "{}", name);
+        }
+
+        let script = script(&ast, 60);
+        let expected = c_lines(&ast, &interpret(&ast, &script));
+        let actual = c_lines(&ast, &run_vm(loaded, &script));
+        println!("{}", name);
+        same(&actual, &expected);
+    }
+}
+
+#[test]
+fn bytecode_loading_rejects_broken_files() {
+    let ast = &samples()[0].1;
+    let bytes = bytecode::compile(ast).unwrap().to_bytes();
+    assert_eq!(
+        runtime::Program::load(b"not bytecode").unwrap_err(),
+        LoadError::NotBytecode
+    );
+    let mut newer = bytes.clone();
+    newer[4] = bytecode::VERSION + 1;
+    assert_eq!(
+        runtime::Program::load(&newer).unwrap_err(),
+        LoadError::Version(bytecode::VERSION + 1)
+    );
+    for len in 5..bytes.len() {
+        assert!(
+            runtime::Program::load(&bytes[..len]).is_err(),
+            "{} bytes",
+            len
+        );
+    }
+    let mut longer = bytes.clone();
+    longer.push(0);
+    assert_eq!(
+        runtime::Program::load(&longer).unwrap_err(),
+        LoadError::TooLong
+    );
+}
+
+#[test]
+fn runtime_survives_corrupted_bytecode() {
+    // whatever a flipped bit does, the runtime either turns the file down or runs it safely
+    for (name, ast) in samples() {
+        let bytes = bytecode::compile(&ast).unwrap().to_bytes();
+        let script = script(&ast, 10);
+        for at in 0..bytes.len() {
+            let mut corrupted = bytes.clone();
+            corrupted[at] ^= 1 << (at % 8);
+            let program = match runtime::Program::load(&corrupted) {
+                Ok(program) => program,
+                Err(_) => continue,
+            };
+            let mut io = Bench {
+                readings: vec![1.0; program.sensors().len()],
+                written: vec![None; program.actuators().len()],
+                broken: None,
+            };
+            let mut vm: Vm<16> = match Vm::new(program, &mut io) {
+                Ok(vm) => vm,
+                Err(_) => continue,
+            };
+            for (time, _) in &script {
+                vm.time = *time;
+                let status = vm.step(&mut io);
+                if let Some(halted) = vm.halted(&status) {
+                    assert!(!halted.to_string().is_empty(), "{} byte {}", name, at);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn runtime_reports_device_faults_and_running_out_of_room() {
+    let ast = fltest::load(Path::new("tests/failsafe.fl")).unwrap();
+    let bytes = bytecode::compile(&ast).unwrap().to_bytes();
+    let program = runtime::Program::load(&bytes).unwrap();
+    let mut io = Bench {
+        readings: vec![0.0; program.sensors().len()],
+        written: vec![None; program.actuators().len()],
+        broken: None,
+    };
+    assert!(matches!(Vm::<0>::new(program, &mut io), Err(Fault::TooBig)));
+
+    // every actuator broken in turn: whichever one the flow writes first halts it
+    for broken in 0..io.written.len() {
+        io.broken = Some(broken);
+        let status = Vm::<16>::new(program, &mut io).and_then(|mut vm| {
+            let status = vm.step(&mut io);
+            if let Err(Fault::Device(e)) = &status {
+                assert_eq!(vm.halted(&status).unwrap().to_string(), *e);
+            }
+            status
+        });
+        if let Err(e) = status {
+            assert_eq!(e, Fault::Device(format!("actuator {} is broken", broken)));
+        }
+    }
+}
+
+// the first step that differs, rather than the whole lot
+fn same(actual: &[String], expected: &[String]) {
+    let first = actual.iter().zip(expected).position(|(a, e)| a != e);
+    match first {
+        Some(at) => assert_eq!(actual[at], expected[at], "after {:?}", &expected[..at]),
+        None => assert_eq!(actual.len(), expected.len()),
+    }
+}
+
+#[test]
+fn cli_writes_the_generated_code() {
+    let dir = scratch("compile-cli");
+    let out = dir.join("interlock.rs");
+    let status = Command::new(env!("CARGO_BIN_EXE_flow"))
+        .args(["compile", "--target", "rust", "-o"])
+        .arg(&out)
+        .arg("tests/interlock.fl")
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let code = fs::read_to_string(&out).unwrap();
+    assert!(code.contains("pub enum State {\n    Heat,\n    Cool,\n}"));
+    assert!(code.contains("pub fn step(&mut self, sensors: &Sensors) -> Actuators {"));
+    assert!(code.contains("refused to set heater to {}: {}"));
+
+    let status = Command::new(env!("CARGO_BIN_EXE_flow"))
+        .args(["compile", "--target", "c", "-o"])
+        .arg(dir.join("interlock.c"))
+        .arg("tests/interlock.fl")
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let header = fs::read_to_string(dir.join("interlock.h")).unwrap();
+    let source = fs::read_to_string(dir.join("interlock.c")).unwrap();
+    assert!(header.contains("} flow_state_t;"));
+    assert!(header.contains("void flow_step(flow_state_t *flow, const flow_sensors_t *sensors, flow_actuators_t *actuators);"));
+    assert!(source.contains("#include \"interlock.h\""));
+    assert!(!source.contains("malloc"));
+
+    let status = Command::new(env!("CARGO_BIN_EXE_flow"))
+        .args(["compile", "--target", "st", "-o"])
+        .arg(dir.join("interlock.st"))
+        .arg("tests/interlock.fl")
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let code = fs::read_to_string(dir.join("interlock.st")).unwrap();
+    assert!(code.contains("FUNCTION_BLOCK Interlock\nVAR_INPUT\n    temp : LREAL;"));
+
+    let python = Command::new(env!("CARGO_BIN_EXE_flow"))
+        .args([
+            "compile",
+            "--target",
+            "python",
+            "--generator",
+            "tests/interlock.fl",
+        ])
+        .output()
+        .unwrap();
+    assert!(python.status.success());
+    let code = String::from_utf8_lossy(&python.stdout);
+    assert!(code.contains("class Flow:\n"));
+    assert!(code.contains("    def step(self, sensors):\n"));
+    assert!(code.contains("            yield\n"));
+
+    let status = Command::new(env!("CARGO_BIN_EXE_flow"))
+        .args(["compile", "--target", "bytecode", "-o"])
+        .arg(dir.join("interlock.flbc"))
+        .arg("tests/interlock.fl")
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let bytes = fs::read(dir.join("interlock.flbc")).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    let program = runtime::Program::load(&bytes).unwrap();
+    assert_eq!(program.sensors().collect::<Vec<_>>(), ["temp"]);
+    assert_eq!(program.interlocks().len(), 1);
+
+    let unknown = Command::new(env!("CARGO_BIN_EXE_flow"))
+        .args(["compile", "--target", "cobol", "tests/interlock.fl"])
+        .output()
+        .unwrap();
+    assert_eq!(unknown.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&unknown.stderr),
+        "unknown target cobol, expected rust, c, st, python or bytecode\n"
+    );
+}
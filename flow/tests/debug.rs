@@ -0,0 +1,89 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// run a debugging session on a flow with commands typed on stdin
+fn session(args: &[&str], commands: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_flow"))
+        .arg("debug")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(commands.as_bytes())
+        .unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success());
+    String::from_utf8(out.stdout).unwrap()
+}
+
+#[test]
+fn stops_at_breakpoints() {
+    let out = session(
+        &["tests/modbus.fl", "tank_temp=25"],
+        "b heating\nb 12\nc\nwhere\nc\nwhere\n",
+    );
+    assert!(out.contains("breakpoint at block heating\n"));
+    assert!(out.contains("breakpoint at line 12\n"));
+    assert!(
+        out.contains("breakpoint\nblock idle, line 12: set heater true\n"),
+        "{}",
+        out
+    );
+    assert!(out.contains(
+        "breakpoint\nblock heating, line 17: wait: any(lid = true, tank_temp >= 60degC)\n"
+    ));
+    assert!(!out.contains("no statement"));
+}
+
+#[test]
+fn explains_which_leaf_decided() {
+    let out = session(&["tests/modbus.fl", "tank_temp=50"], "s\n");
+    assert!(
+        out.contains(
+            "condition on line 7 doesn't hold:
+  all: false
+    lid = false: true
+    tank_temp < 40degC: false  <-
+waiting
+"
+        ),
+        "{}",
+        out
+    );
+}
+
+#[test]
+fn overrides_sensors_and_shows_actuators() {
+    let out = session(
+        &["tests/modbus.fl", "tank_temp=50"],
+        "set tank_temp 30\ns\nactuators\nsensors\nrelease tank_temp\nsensors\n",
+    );
+    assert!(out.contains("holding tank_temp at 30degC\n"));
+    assert!(out.contains("condition on line 7 holds:"));
+    assert!(
+        out.contains(
+            "heater           false  failsafe false\nsetpoint         20degC  failsafe 0degC\n"
+        ),
+        "{}",
+        out
+    );
+    assert!(out.contains("tank_temp        30degC (held)\n"));
+    assert!(out.contains("released tank_temp\n"));
+}
+
+#[test]
+fn steps_through_timed_waits() {
+    let out = session(
+        &["tests/timers.fl", "door=true"],
+        "s\ns\ns\ns\ns\ns\nadvance 30\ns\n",
+    );
+    assert!(out.contains("block opened, line 16: wait:"), "{}", out);
+    assert!(out.contains("    open_for >= 30s: false  <-\n    now >= 60s: false  <-\nwaiting\n"));
+    assert!(out.contains("time 30s\n"));
+    assert!(out.contains("    open_for >= 30s: true  <-\n"));
+}
@@ -0,0 +1,207 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use flow::coverage::Coverage;
+use flow::fltest::{self, Outcome};
+
+#[test]
+fn sample_flows_check() {
+    // every sample has to keep parsing and checking, tested or not
+    for entry in fs::read_dir("tests").unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|ext| ext == "fl") {
+            if let Err(e) = fltest::load(&path) {
+                panic!("{}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_files_pass() {
+    let files = fltest::find(Path::new("tests")).unwrap();
+    assert!(!files.is_empty());
+    let mut failures = Vec::new();
+    for file in files {
+        match fltest::run_file(&file) {
+            Ok(results) => {
+                for Outcome { name, failure } in results.outcomes {
+                    if let Some(lines) = failure {
+                        failures.push(format!(
+                            "{}: {}\n{}",
+                            file.display(),
+                            name,
+                            lines.join("\n")
+                        ));
+                    }
+                }
+            }
+            Err(e) => failures.push(format!("{}: {}", file.display(), e)),
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n\n"));
+}
+
+#[test]
+fn reports_differences() {
+    let suite = fltest::parse_suite(
+        "flow timers.fl\ntest fan runs\ninput 0 door true\nuntil 1\nexpect 0.0 enter closed\nexpect 0 set fan 0.5",
+        Path::new("tests"),
+    )
+    .unwrap();
+    let ast = fltest::load(&suite.flow).unwrap();
+    let diff = fltest::run_case(&ast, &suite.cases[0], &mut Coverage::default()).unwrap();
+    assert_eq!(
+        diff,
+        [
+            "+ 0 set alarm false",
+            "+ 0 set fan 0",
+            "  0 enter closed",
+            "- 0 set fan 0.5",
+            "+ 0 set alarm false",
+            "+ 0 enter opened",
+            "+ 0 set fan 1",
+        ]
+    );
+}
+
+#[test]
+fn rejects_bad_test_files() {
+    let parse = |text: &str| fltest::parse_suite(text, Path::new("tests"));
+    assert_eq!(parse("test x"), Err("no flow given".to_string()));
+    assert_eq!(
+        parse("flow timers.fl\ninput 0 door true"),
+        Err("line 2: input outside a test".to_string())
+    );
+    assert_eq!(
+        parse("flow timers.fl\ntest x\nexpect 1 open door"),
+        Err("line 3: invalid expect \"1 open door\"".to_string())
+    );
+    assert_eq!(
+        parse("flow timers.fl\ntest x\nuntil soon"),
+        Err("line 3: invalid time soon".to_string())
+    );
+}
+
+#[test]
+fn cli_fails_on_failing_tests() {
+    let file = scratch("wrong.fltest");
+    let flow = fs::canonicalize("tests/simple.fl").unwrap();
+    fs::write(
+        &file,
+        format!("flow {}\ntest nothing happens\n", flow.display()),
+    )
+    .unwrap();
+
+    let out = Command::new(env!("CARGO_BIN_EXE_flow"))
+        .args(["test", &file])
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(
+        stdout.contains(": nothing happens\n    + 0 enter firstblock\n"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.ends_with("0 passed, 1 failed\n"));
+}
+
+fn scratch(name: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("flow-fltest-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir.join(name).to_str().unwrap().to_string()
+}
+
+#[test]
+fn covers_branches_and_leaves() {
+    let results = fltest::run_file(Path::new("tests/kinds.fltest")).unwrap();
+    let ast = fltest::load(&results.flow).unwrap();
+    let coverage = &results.coverage;
+    assert_eq!(
+        coverage.summary(&ast),
+        "blocks 2/2, statements 12/17, branches 4/10, conditions 8/14"
+    );
+
+    let source = fs::read_to_string(&results.flow).unwrap();
+    let report = coverage.annotate(&ast, &source).join("\n");
+    assert!(report.contains("     2:    6: block begin\n"), "{}", report);
+    assert!(report.contains(
+        "     1:   10:     if:
+              branch: if 0, else 1
+              level < 3: true 0, false 1
+     -:   11:         - level < 3
+ #####:   12:         set valve open
+"
+    ));
+
+    let lcov = coverage.lcov(&ast, "tests/kinds.fl");
+    assert!(lcov.starts_with("TN:\nSF:tests/kinds.fl\nFN:6,begin\nFN:22,watch\n"));
+    assert!(lcov.contains("BRDA:10,0,0,0\nBRDA:10,0,1,1\nBRDA:10,1,0,0\nBRDA:10,1,1,1\n"));
+    // branches of statements that never ran aren't counts at all
+    assert!(lcov.contains("BRDA:31,0,0,-\n"));
+    assert!(lcov.contains("DA:12,0\n"));
+    assert!(lcov.ends_with("LF:17\nLH:12\nend_of_record\n"));
+}
+
+#[test]
+fn short_circuits_leave_leaves_unevaluated() {
+    let results = fltest::run_file(Path::new("tests/timers.fltest")).unwrap();
+    // the any on line 16 stops at the first leaf that holds, so the clock leaf is only ever seen
+    // failing
+    assert_eq!(results.coverage.leaves[&(16, 0)], [1, 4]);
+    assert_eq!(results.coverage.leaves[&(16, 1)], [1, 3]);
+    assert_eq!(results.coverage.leaves[&(16, 2)], [0, 3]);
+}
+
+#[test]
+fn overlays_coverage_on_graph() {
+    let test = scratch("fill.fltest");
+    let flow = fs::canonicalize("tests/failsafe.fl").unwrap();
+    fs::write(
+        &test,
+        format!(
+            "flow {}\ntest fills\ninput 0 level 1\nuntil 5\nexpect 0 set valve 0\nexpect 0 enter fill\nexpect 0 set pump 80\nexpect 0 set valve 1\n",
+            flow.display()
+        ),
+    )
+    .unwrap();
+    let (lcov, dot) = (scratch("fill.info"), scratch("fill.dot"));
+    let out = Command::new(env!("CARGO_BIN_EXE_flow"))
+        .args(["test", "--lcov", &lcov, "--dot", &dot, &test])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(
+        stdout.contains("failsafe.fl: blocks 1/2, statements 3/9, branches 0/2, conditions 1/6\n"),
+        "{}",
+        stdout
+    );
+
+    let graph = fs::read_to_string(&dot).unwrap();
+    assert!(graph
+        .contains("\"fill\" [label=\"fill\\nline 5\", style=filled, fillcolor=\"#d9ead3\"];\n"));
+    assert!(graph.contains("\"drain\" [label=\"drain\\nline 13\", color=red, style=\"filled,dashed\", fillcolor=\"#f4cccc\"];\n"), "{}", graph);
+    assert!(graph.contains("\"fill\" -> \"drain\" [label=\"line 10\", color=red, style=dashed];\n"));
+    assert!(fs::read_to_string(&lcov)
+        .unwrap()
+        .contains("FNDA:0,drain\n"));
+}
+
+#[test]
+fn writes_tracefile_only_when_asked() {
+    let dir = std::env::temp_dir().join(format!("flow-dot-only-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let test = fs::canonicalize("tests/failsafe.fltest").unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_flow"))
+        .current_dir(&dir)
+        .args(["test", "--dot", "failsafe.dot"])
+        .arg(&test)
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert!(dir.join("failsafe.dot").exists());
+    assert!(!dir.join("lcov.info").exists());
+}
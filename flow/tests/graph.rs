@@ -0,0 +1,120 @@
+use flow::ast;
+use flow::graph::{self, Graph};
+use flow::token;
+
+fn findings(code: &str) -> Vec<String> {
+    let ast = ast::make_ast(&token::tokenize(code.to_string())).unwrap();
+    graph::analyze(&ast).iter().map(|d| d.to_string()).collect()
+}
+
+const PUMPS: &str = "sensor level 0..10
+actuator pump 0..1 init 0 failsafe 0
+
+block fill
+    set pump 1
+    wait:
+        - level >= 8
+    goto drain
+endblock
+
+block drain
+    set pump 0
+    if:
+        - level < 2
+        goto spin
+    wait 1s
+    goto fill
+endblock
+
+block spin
+    set pump 0
+    goto spin_more
+endblock
+
+block spin_more
+    goto spin
+endblock
+
+block orphan
+    goto nowhere
+endblock
+";
+
+#[test]
+fn reports_every_finding_with_its_lines() {
+    assert_eq!(
+        findings(PUMPS),
+        [
+            "error: line 30: block orphan: goto to unknown block nowhere",
+            "warning: line 29: block orphan is never reached",
+            "warning: lines 20, 22, 25, 26: blocks spin, spin_more can loop through gotos without waiting, which never lets time pass",
+            "warning: lines 20, 25: blocks spin, spin_more form a loop the flow never leaves",
+        ]
+    );
+}
+
+#[test]
+fn builds_edges_from_gotos() {
+    let ast = ast::make_ast(&token::tokenize(PUMPS.to_string())).unwrap();
+    let graph = Graph::new(&ast);
+    assert_eq!(
+        graph.blocks,
+        ["fill", "drain", "spin", "spin_more", "orphan"]
+    );
+    let drain: Vec<(&str, usize, bool)> = graph.edges["drain"]
+        .iter()
+        .map(|e| (e.dest.as_str(), e.line, e.busy))
+        .collect();
+    // the goto inside the if comes before the delay, the one after it doesn't
+    assert_eq!(drain, [("spin", 15, true), ("fill", 17, false)]);
+    assert!(graph.terminal.is_empty());
+    assert_eq!(
+        graph.cycles(&|_| true),
+        [vec!["fill", "drain"], vec!["spin", "spin_more"]]
+    );
+}
+
+#[test]
+fn waits_on_one_path_only_still_busy_loop() {
+    let code = "sensor door: bool
+actuator lamp: bool init false
+
+block idle
+    if:
+        - door
+        wait 1s
+    set lamp true
+    goto idle
+endblock
+";
+    assert_eq!(
+        findings(code),
+        ["warning: lines 4, 9: blocks idle can loop through gotos without waiting, which never lets time pass"]
+    );
+}
+
+#[test]
+fn notes_blocks_that_can_finish() {
+    let code = "sensor door: bool
+actuator lamp: bool init false
+
+block idle
+    wait:
+        - door
+    if:
+        - door
+        goto lit
+    set lamp false
+endblock
+
+block lit
+    set lamp true
+    wait 1s
+    goto idle
+endblock
+";
+    assert_eq!(
+        findings(code),
+        ["note: line 4: block idle can finish the flow by running off its end"]
+    );
+}
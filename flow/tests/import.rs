@@ -0,0 +1,178 @@
+use std::fs;
+use std::process::Command;
+
+use flow::ast;
+use flow::check;
+use flow::drawio;
+use flow::token;
+
+const HEATER: &str = "sensor temp
+actuator fan
+actuator heater
+
+block main
+    set heater 1
+    set fan 1
+    goto step1
+endblock
+
+block step1
+    if:
+        - temp >= 50
+        set heater 0
+        wait 30s
+        goto main
+    else:
+        wait 1s
+        goto step1
+endblock
+";
+
+// a diagram with just shapes and arrows, each given as (id, style, label) or (id, from, to, label)
+fn diagram(shapes: &[(&str, &str, &str)], arrows: &[(&str, &str, &str, &str)]) -> String {
+    let mut xml =
+        String::from("<mxGraphModel><root><mxCell id=\"0\"/><mxCell id=\"1\" parent=\"0\"/>\n");
+    for (id, style, label) in shapes {
+        xml += &format!(
+            "<mxCell id=\"{}\" value=\"{}\" style=\"{}\" vertex=\"1\" parent=\"1\"><mxGeometry as=\"geometry\"/></mxCell>\n",
+            id, label, style
+        );
+    }
+    for (id, from, to, label) in arrows {
+        xml += &format!(
+            "<mxCell id=\"{}\" value=\"{}\" edge=\"1\" parent=\"1\" source=\"{}\" target=\"{}\"/>\n",
+            id, label, from, to
+        );
+    }
+    xml + "</root></mxGraphModel>\n"
+}
+
+#[test]
+fn imports_the_heater_flowchart() {
+    let xml = fs::read_to_string("tests/heater.drawio").unwrap();
+    let import = drawio::import(&xml).unwrap();
+    assert_eq!(import.flow, HEATER);
+    assert_eq!(
+        import.untranslated,
+        [
+            "shape note \"check the setpoint with operations\": isn't a process box, decision or terminator",
+            "shape tank \"Tank\": isn't a process box, decision or terminator",
+            "arrow e7: isn't connected at both ends",
+            "shape call \"call the operator\": expected set, wait or start",
+            "shape call \"call the operator\": can't be reached from the start",
+        ]
+    );
+
+    let ast = ast::make_ast(&token::tokenize(import.flow)).unwrap();
+    assert!(check::check(&ast).is_empty());
+}
+
+#[test]
+fn infers_devices_from_how_they_are_used() {
+    let xml = diagram(
+        &[
+            ("a", "", "set mode idle&#xa;start purge"),
+            ("b", "rhombus", "door"),
+            ("c", "", "set mode run&#xa;set lamp true"),
+            ("d", "", "wait purge &gt;= 2min"),
+            ("e", "rhombus", "level rises_above 3bar hyst 10kPa"),
+            ("f", "ellipse", "End"),
+        ],
+        &[
+            ("1", "a", "b", ""),
+            ("2", "b", "c", "yes"),
+            ("3", "b", "d", ""),
+            ("4", "d", "e", ""),
+            ("5", "e", "a", "No"),
+            ("6", "e", "f", "Y"),
+        ],
+    );
+    let import = drawio::import(&xml).unwrap();
+    assert!(import.untranslated.is_empty(), "{:?}", import.untranslated);
+    assert_eq!(
+        import.flow,
+        "sensor door: bool
+sensor level bar
+actuator lamp: bool
+actuator mode: enum {idle, run}
+timer purge
+
+block main
+    set mode idle
+    start purge
+    if:
+        - door
+        set mode run
+        set lamp true
+    else:
+        wait:
+            - purge >= 120s
+        if:
+            - level rises_above 3 hyst 0.1
+        else:
+            goto main
+endblock
+"
+    );
+}
+
+#[test]
+fn reports_what_it_cannot_translate() {
+    let xml = diagram(
+        &[
+            ("a", "", "set valve 1&#xa;open the valve"),
+            ("b", "rhombus", "temp ~= 20"),
+            ("c", "", "set valve 0"),
+        ],
+        &[
+            ("1", "a", "b", ""),
+            ("2", "b", "c", "maybe"),
+            ("3", "a", "c", ""),
+        ],
+    );
+    let import = drawio::import(&xml).unwrap();
+    assert_eq!(
+        import.flow,
+        "sensor temp\nactuator valve\n\nblock main\n    set valve 1\nendblock\n"
+    );
+    assert_eq!(
+        import.untranslated,
+        [
+            "arrow 2: isn't labelled yes or no",
+            "arrow 3: is a second way out of a shape",
+            "shape a \"set valve 1 / open the valve\": expected set, wait or start",
+            "shape b \"temp ~= 20\": Expected a sensor with a tol before \"~=\"",
+            "shape c \"set valve 0\": can't be reached from the start",
+        ]
+    );
+}
+
+#[test]
+fn refuses_compressed_diagrams() {
+    let xml = "<mxfile><diagram id=\"x\" name=\"Page-1\">dZHBDoIwDIafhjt0Ud</diagram></mxfile>";
+    let err = drawio::import(xml).err().unwrap();
+    assert!(err.contains("compressed"), "{}", err);
+}
+
+#[test]
+fn cli_writes_the_imported_flow() {
+    let dir = std::env::temp_dir().join(format!("flow-import-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let out = dir.join("heater.fl");
+    let result = Command::new(env!("CARGO_BIN_EXE_flow"))
+        .args(["import", "drawio", "-o"])
+        .arg(&out)
+        .arg("tests/heater.drawio")
+        .output()
+        .unwrap();
+    assert!(result.status.success());
+    assert_eq!(fs::read_to_string(&out).unwrap(), HEATER);
+    let report = String::from_utf8_lossy(&result.stderr);
+    assert!(report.contains("couldn't translate shape tank \"Tank\""));
+
+    let status = Command::new(env!("CARGO_BIN_EXE_flow"))
+        .args(["import", "visio", "tests/heater.drawio"])
+        .output()
+        .unwrap();
+    assert!(!status.status.success());
+}
@@ -0,0 +1,281 @@
+use std::fs;
+use std::process::Command;
+
+use serde_json::Value;
+
+use flow::ast::AST;
+use flow::bytecode;
+use flow::fltest;
+use flow::json;
+use flow::pretty;
+
+fn samples() -> Vec<(String, AST)> {
+    let mut found = Vec::new();
+    for entry in fs::read_dir("tests").unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|ext| ext == "fl") {
+            let name = path.file_stem().unwrap().to_string_lossy().to_string();
+            found.push((name, fltest::load(&path).unwrap()));
+        }
+    }
+    found.sort_by(|a, b| a.0.cmp(&b.0));
+    found
+}
+
+fn schema() -> Value {
+    serde_json::from_str(&fs::read_to_string("schema/flow.schema.json").unwrap()).unwrap()
+}
+
+// checks a value against the parts of JSON Schema the flow schema uses, returning where it fails
+fn conforms(root: &Value, schema: &Value, value: &Value, at: &str) -> Result<(), String> {
+    let fail = |why: &str| Err(format!("{}: {}", at, why));
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        let name = reference.strip_prefix("#/$defs/").unwrap();
+        conforms(root, &root["$defs"][name], value, at)?;
+    }
+    if let Some(types) = schema.get("type") {
+        let types: Vec<&str> = match types {
+            Value::String(t) => vec![t.as_str()],
+            _ => types
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|t| t.as_str().unwrap())
+                .collect(),
+        };
+        let ok = types.iter().any(|t| match *t {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "number" => value.is_number(),
+            "integer" => value.is_u64() || value.is_i64(),
+            "boolean" => value.is_boolean(),
+            "null" => value.is_null(),
+            other => panic!("unknown type {}", other),
+        });
+        if !ok {
+            return fail(&format!("expected {:?}", types));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if value != expected {
+            return fail(&format!("expected {}", expected));
+        }
+    }
+    if let Some(options) = schema.get("enum").and_then(Value::as_array) {
+        if !options.contains(value) {
+            return fail(&format!("expected one of {:?}", options));
+        }
+    }
+    if let (Some(minimum), Some(number)) = (schema.get("minimum"), value.as_f64()) {
+        if number < minimum.as_f64().unwrap() {
+            return fail(&format!("expected at least {}", minimum));
+        }
+    }
+    if let Some(options) = schema.get("oneOf").and_then(Value::as_array) {
+        let matched = options
+            .iter()
+            .filter(|option| conforms(root, option, value, at).is_ok())
+            .count();
+        if matched != 1 {
+            return fail(&format!("matched {} of the options in oneOf", matched));
+        }
+    }
+    if let Some(object) = value.as_object() {
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for name in schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            if !object.contains_key(name.as_str().unwrap()) {
+                return fail(&format!("missing {}", name));
+            }
+        }
+        for (name, inner) in object {
+            let at = format!("{}.{}", at, name);
+            match (
+                properties.and_then(|p| p.get(name)),
+                schema.get("additionalProperties"),
+            ) {
+                (Some(property), _) => conforms(root, property, inner, &at)?,
+                (None, Some(Value::Bool(false))) => return Err(format!("{}: unexpected", at)),
+                (None, Some(additional)) => conforms(root, additional, inner, &at)?,
+                (None, None) => {}
+            }
+        }
+    }
+    if let Some(items) = value.as_array() {
+        let prefix = schema.get("prefixItems").and_then(Value::as_array);
+        let prefix_len = prefix.map_or(0, Vec::len);
+        if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+            if (items.len() as u64) < min {
+                return fail(&format!("expected at least {} items", min));
+            }
+        }
+        for (idx, item) in items.iter().enumerate() {
+            let at = format!("{}[{}]", at, idx);
+            match (prefix.and_then(|p| p.get(idx)), schema.get("items")) {
+                (Some(inner), _) => conforms(root, inner, item, &at)?,
+                (None, Some(Value::Bool(false))) => return Err(format!("{}: unexpected", at)),
+                (None, Some(inner)) if idx >= prefix_len => conforms(root, inner, item, &at)?,
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn samples_follow_the_schema_and_load_back() {
+    let schema = schema();
+    assert_eq!(schema["properties"]["version"]["const"], json::VERSION);
+    for (name, ast) in samples() {
+        let text = json::to_json(&ast);
+        let value: Value = serde_json::from_str(&text).unwrap();
+        if let Err(e) = conforms(&schema, &schema, &value, "$") {
+            panic!("{} doesn't follow the schema at {}", name, e);
+        }
+
+        let loaded = json::from_json(&text).unwrap_or_else(|e| panic!("{}: {}", name, e));
+        assert_eq!(json::to_json(&loaded), text, "{}", name);
+        assert_eq!(pretty::print(&loaded), pretty::print(&ast), "{}", name);
+        assert_eq!(
+            bytecode::compile(&loaded).unwrap().to_bytes(),
+            bytecode::compile(&ast).unwrap().to_bytes(),
+            "{}",
+            name
+        );
+    }
+}
+
+// the JSON for a sample, changed before it's loaded back
+fn load_changed(sample: &str, change: impl FnOnce(&mut Value)) -> Result<AST, String> {
+    let ast = fltest::load(format!("tests/{}.fl", sample).as_ref()).unwrap();
+    let mut value: Value = serde_json::from_str(&json::to_json(&ast)).unwrap();
+    change(&mut value);
+    json::from_json(&value.to_string())
+}
+
+fn error(sample: &str, change: impl FnOnce(&mut Value)) -> String {
+    match load_changed(sample, change) {
+        Ok(_) => panic!("the changed {} loaded", sample),
+        Err(e) => e,
+    }
+}
+
+#[test]
+fn loading_checks_what_parsing_would() {
+    assert!(load_changed("interlock", |_| {}).is_ok());
+
+    let e = error("interlock", |v| v["version"] = Value::from(2));
+    assert_eq!(
+        e,
+        "expected version 1 of the flow schema, but the flow is version 2"
+    );
+    let e = error("interlock", |v| v["flow"]["blocks"] = Value::from(3));
+    assert!(e.starts_with("couldn't read the flow: "), "{}", e);
+    let e = error("units", |v| {
+        v["flow"]["devices"]["temp"]["sensor"]["unit"] = Value::from("furlong")
+    });
+    assert!(e.contains("unknown unit furlong"), "{}", e);
+    // a misspelled key would otherwise leave what it was meant to say out
+    let e = error("interlock", |v| {
+        let heater = &mut v["flow"]["devices"]["heater"]["actuator"];
+        let init = heater.as_object_mut().unwrap().remove("init").unwrap();
+        heater["inti"] = init;
+    });
+    assert!(e.contains("unknown field `inti`"), "{}", e);
+    let e = error("interlock", |v| {
+        let flow = v.as_object_mut().unwrap().remove("flow").unwrap();
+        v["flwo"] = flow;
+    });
+    assert!(e.contains("unknown field `flwo`"), "{}", e);
+
+    // out of range, which the parser catches
+    let e = error("interlock", |v| {
+        v["flow"]["blocks"]["heat"]["ops"][1]["op"]["set"]["value"] = Value::from(600.0)
+    });
+    assert_eq!(e, "Expected value in range of device range");
+    let e = error("interlock", |v| {
+        v["flow"]["devices"]["heater"]["actuator"]["max"] = Value::from(50.0)
+    });
+    assert_eq!(e, "actuator heater doesn't match its declaration");
+    let e = error("interlock", |v| {
+        v["flow"]["first_block_name"] = Value::from("warm")
+    });
+    assert_eq!(e, "first block warm isn't declared");
+    let e = error("interlock", |v| {
+        v["flow"]["devices"]["fan"]["actuator"]["name"] = Value::from("blower")
+    });
+    assert_eq!(e, "device fan is declared as blower");
+    let e = error("kinds", |v| {
+        let blocks = v["flow"]["blocks"].as_object_mut().unwrap();
+        let (_, block) = blocks.iter_mut().next().unwrap();
+        for stmt in block["ops"].as_array_mut().unwrap() {
+            if let Some(set) = stmt["op"].get_mut("set") {
+                if set["actuator"]["kind"] != "float" {
+                    set["value"] = Value::from(0.5);
+                    return;
+                }
+            }
+        }
+        panic!("no bool or enum actuator is set");
+    });
+    assert!(e.ends_with("can't be 0.5"), "{}", e);
+    let e = error("edges", |v| {
+        *v = serde_json::from_str(&clash_ids(&v.to_string())).unwrap()
+    });
+    assert!(e.starts_with("more than one"), "{}", e);
+}
+
+// every condition id set to 0, so they clash
+fn clash_ids(text: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(at) = rest.find("\"id\":") {
+        out += &rest[..at + 5];
+        rest = &rest[at + 5..];
+        let end = rest.find(|c: char| !c.is_ascii_digit()).unwrap();
+        out += "0";
+        rest = &rest[end..];
+    }
+    out + rest
+}
+
+#[test]
+fn cli_emits_json_and_runs_it() {
+    let dir = std::env::temp_dir().join(format!("flow-json-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let out = dir.join("verify.json");
+    let status = Command::new(env!("CARGO_BIN_EXE_flow"))
+        .args(["parse", "--emit", "json", "-o"])
+        .arg(&out)
+        .arg("tests/verify.fl")
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let ast = fltest::load("tests/verify.fl".as_ref()).unwrap();
+    assert_eq!(fs::read_to_string(&out).unwrap(), json::to_json(&ast));
+
+    let checked = Command::new(env!("CARGO_BIN_EXE_flow"))
+        .arg(&out)
+        .output()
+        .unwrap();
+    assert!(checked.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&checked.stdout),
+        "Everything checks out!\n"
+    );
+
+    let emitted = Command::new(env!("CARGO_BIN_EXE_flow"))
+        .args(["parse", "--emit", "yaml", "tests/verify.fl"])
+        .output()
+        .unwrap();
+    assert!(!emitted.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&emitted.stderr),
+        "unknown format yaml, expected json\n"
+    );
+}
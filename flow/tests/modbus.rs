@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use flow::ast::{self, AST};
+use flow::backend::DeviceBackend;
+use flow::interp::{Interpreter, Status};
+use flow::modbus::{self, Modbus};
+use flow::token;
+
+// a Modbus TCP slave holding its tables in memory; reads or writes of anything not in a table
+// get an illegal data address exception
+#[derive(Default)]
+struct Slave {
+    coils: HashMap<u16, bool>,
+    discrete: HashMap<u16, bool>,
+    input: HashMap<u16, u16>,
+    holding: HashMap<u16, u16>,
+}
+
+impl Slave {
+    fn handle(&mut self, pdu: &[u8]) -> Vec<u8> {
+        let function = pdu[0];
+        let address = u16::from_be_bytes([pdu[1], pdu[2]]);
+        let arg = u16::from_be_bytes([pdu[3], pdu[4]]);
+        let bit = |table: &HashMap<u16, bool>| table.get(&address).map(|on| vec![1, *on as u8]);
+        let word = |table: &HashMap<u16, u16>| {
+            table.get(&address).map(|val| {
+                let [hi, lo] = val.to_be_bytes();
+                vec![2, hi, lo]
+            })
+        };
+        let data = match function {
+            0x01 => bit(&self.coils),
+            0x02 => bit(&self.discrete),
+            0x03 => word(&self.holding),
+            0x04 => word(&self.input),
+            0x05 => self.coils.get_mut(&address).map(|on| {
+                *on = arg == 0xff00;
+                pdu[1..5].to_vec()
+            }),
+            0x06 => self.holding.get_mut(&address).map(|val| {
+                *val = arg;
+                pdu[1..5].to_vec()
+            }),
+            _ => return vec![function | 0x80, 1],
+        };
+        match data {
+            Some(data) => [vec![function], data].concat(),
+            None => vec![function | 0x80, 2],
+        }
+    }
+}
+
+fn serve(slave: Arc<Mutex<Slave>>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let slave = Arc::clone(&slave);
+            thread::spawn(move || talk(stream.unwrap(), slave));
+        }
+    });
+    addr
+}
+
+fn talk(mut stream: TcpStream, slave: Arc<Mutex<Slave>>) {
+    let mut header = [0u8; 7];
+    while stream.read_exact(&mut header).is_ok() {
+        let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+        let mut pdu = vec![0u8; len - 1];
+        stream.read_exact(&mut pdu).unwrap();
+        let reply = slave.lock().unwrap().handle(&pdu);
+        let mut frame = header[..4].to_vec();
+        frame.extend_from_slice(&(reply.len() as u16 + 1).to_be_bytes());
+        frame.push(header[6]);
+        frame.extend_from_slice(&reply);
+        stream.write_all(&frame).unwrap();
+    }
+}
+
+fn load(path: &str) -> AST {
+    let code = fs::read_to_string(path).unwrap();
+    ast::make_ast(&token::tokenize(code)).unwrap()
+}
+
+fn plant() -> Arc<Mutex<Slave>> {
+    let mut slave = Slave::default();
+    slave.input.insert(30, 250);
+    slave.discrete.insert(2, false);
+    slave.coils.insert(0, false);
+    slave.holding.insert(5, 0);
+    Arc::new(Mutex::new(slave))
+}
+
+#[test]
+fn runs_flow_against_simulated_plc() {
+    let ast = load("tests/modbus.fl");
+    let map = modbus::parse_map(&fs::read_to_string("tests/modbus.map").unwrap(), &ast).unwrap();
+    let slave = plant();
+    let mut plc = Modbus::connect(&serve(Arc::clone(&slave)), map).unwrap();
+
+    let mut interp = Interpreter::new(&ast, &mut plc).unwrap();
+    // init values are written before the flow starts
+    assert_eq!(slave.lock().unwrap().holding[&5], 40);
+
+    assert_eq!(interp.step(&mut plc), Ok(Status::Waiting));
+    assert_eq!(interp.block, "heating");
+    assert_eq!(interp.sensors["tank_temp"], 25.0);
+    assert!(slave.lock().unwrap().coils[&0]);
+    assert_eq!(slave.lock().unwrap().holding[&5], 120);
+
+    // nothing changes until the tank warms up
+    assert_eq!(interp.step(&mut plc), Ok(Status::Waiting));
+    assert_eq!(interp.block, "heating");
+
+    slave.lock().unwrap().input.insert(30, 600);
+    assert_eq!(interp.step(&mut plc), Ok(Status::Waiting));
+    assert_eq!(interp.block, "idle");
+    assert!(!slave.lock().unwrap().coils[&0]);
+}
+
+#[test]
+fn reads_signed_registers() {
+    let ast = load("tests/modbus.fl");
+    let map = modbus::parse_map(&fs::read_to_string("tests/modbus.map").unwrap(), &ast).unwrap();
+    let slave = plant();
+    slave.lock().unwrap().input.insert(30, (-125i16) as u16);
+    let mut plc = Modbus::connect(&serve(slave), map).unwrap();
+
+    let temp = ast
+        .sensors()
+        .into_iter()
+        .find(|s| s.name == "tank_temp")
+        .unwrap();
+    assert_eq!(plc.read_sensor(temp), Ok(-12.5));
+}
+
+#[test]
+fn fails_safe_when_plc_raises_exception() {
+    let ast = load("tests/modbus.fl");
+    let text = fs::read_to_string("tests/modbus.map")
+        .unwrap()
+        .replace("lid          discrete  2", "lid          discrete  9");
+    let map = modbus::parse_map(&text, &ast).unwrap();
+    let slave = plant();
+    slave.lock().unwrap().coils.insert(0, true);
+    let mut plc = Modbus::connect(&serve(Arc::clone(&slave)), map).unwrap();
+
+    let mut interp = Interpreter::new(&ast, &mut plc).unwrap();
+    let err = interp.step(&mut plc).unwrap_err();
+    assert!(err.contains("reading lid: Modbus exception 2"), "{}", err);
+
+    interp.fail_safe(&mut plc).unwrap();
+    assert!(!slave.lock().unwrap().coils[&0]);
+    assert_eq!(slave.lock().unwrap().holding[&5], 0);
+}
+
+#[test]
+fn rejects_bad_maps() {
+    let ast = load("tests/modbus.fl");
+    let map = fs::read_to_string("tests/modbus.map").unwrap();
+
+    let read_only = map.replace("heater       coil", "heater       input");
+    assert!(modbus::parse_map(&read_only, &ast)
+        .unwrap_err()
+        .contains("must be mapped to a coil or holding register"));
+
+    let missing: String = map
+        .lines()
+        .filter(|l| !l.starts_with("lid"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    assert_eq!(
+        modbus::parse_map(&missing, &ast),
+        Err("no register mapped for sensor lid".to_string())
+    );
+
+    let scaled_coil = map.replace("heater       coil      0", "heater coil 0 scale 2");
+    assert!(modbus::parse_map(&scaled_coil, &ast).is_err());
+}
@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use flow::ast::{self, AST};
+use flow::backend::DeviceBackend;
+use flow::interp::{Interpreter, Status};
+use flow::mqtt::{self, packet, read_packet, Mqtt};
+use flow::token;
+
+// a message a client published: topic, payload, qos and retain
+type Message = (String, String, u8, bool);
+
+// just enough of an MQTT broker to route publishes between exact topics and keep retained values
+#[derive(Default)]
+struct State {
+    retained: HashMap<String, String>,
+    received: Vec<Message>,
+    // each connected client's subscriptions and a handle to write to it
+    clients: HashMap<usize, (Vec<String>, TcpStream)>,
+    connects: usize,
+}
+
+struct Broker {
+    addr: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl Broker {
+    fn start() -> Broker {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let state = Arc::new(Mutex::new(State::default()));
+        let shared = Arc::clone(&state);
+        thread::spawn(move || {
+            for (client, stream) in listener.incoming().enumerate() {
+                let state = Arc::clone(&shared);
+                thread::spawn(move || serve(client, stream.unwrap(), state));
+            }
+        });
+        Broker { addr, state }
+    }
+
+    // publish a retained reading as if from a device
+    fn publish(&self, topic: &str, payload: &str) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .retained
+            .insert(topic.to_string(), payload.to_string());
+        deliver(&mut state, topic, payload);
+    }
+
+    // drop every client, as if the broker restarted
+    fn kick(&self) {
+        let mut state = self.state.lock().unwrap();
+        for (_, (_, stream)) in state.clients.drain() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn received(&self) -> Vec<Message> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    fn connects(&self) -> usize {
+        self.state.lock().unwrap().connects
+    }
+}
+
+fn deliver(state: &mut State, topic: &str, payload: &str) {
+    let mut body = string(topic);
+    body.extend_from_slice(payload.as_bytes());
+    for (topics, stream) in state.clients.values_mut() {
+        if topics.iter().any(|t| t == topic) {
+            let _ = stream.write_all(&packet(0x30, &body));
+        }
+    }
+}
+
+fn string(text: &str) -> Vec<u8> {
+    let mut bytes = (text.len() as u16).to_be_bytes().to_vec();
+    bytes.extend_from_slice(text.as_bytes());
+    bytes
+}
+
+fn take_string(bytes: &[u8]) -> (String, &[u8]) {
+    let len = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+    let text = String::from_utf8(bytes[2..2 + len].to_vec()).unwrap();
+    (text, &bytes[2 + len..])
+}
+
+fn serve(client: usize, mut stream: TcpStream, state: Arc<Mutex<State>>) {
+    while let Ok((kind, body)) = read_packet(&mut stream) {
+        let mut state = state.lock().unwrap();
+        match kind & 0xf0 {
+            // CONNECT
+            0x10 => {
+                state.connects += 1;
+                let writer = stream.try_clone().unwrap();
+                state.clients.insert(client, (Vec::new(), writer));
+                stream.write_all(&packet(0x20, &[0, 0])).unwrap();
+            }
+            // SUBSCRIBE, answered with the retained value of each topic
+            0x80 => {
+                let mut rest = &body[2..];
+                let mut granted = body[..2].to_vec();
+                let mut topics = Vec::new();
+                while !rest.is_empty() {
+                    let (topic, after) = take_string(rest);
+                    granted.push(after[0]);
+                    topics.push(topic);
+                    rest = &after[1..];
+                }
+                stream.write_all(&packet(0x90, &granted)).unwrap();
+                if let Some(client) = state.clients.get_mut(&client) {
+                    client.0.extend(topics.iter().cloned());
+                }
+                for topic in topics {
+                    if let Some(payload) = state.retained.get(&topic).cloned() {
+                        deliver(&mut state, &topic, &payload);
+                    }
+                }
+            }
+            // PUBLISH
+            0x30 => {
+                let qos = (kind >> 1) & 0x03;
+                let retain = kind & 0x01 == 1;
+                let (topic, mut rest) = take_string(&body);
+                if qos > 0 {
+                    stream.write_all(&packet(0x40, &rest[..2])).unwrap();
+                    rest = &rest[2..];
+                }
+                let payload = String::from_utf8(rest.to_vec()).unwrap();
+                if retain {
+                    state.retained.insert(topic.clone(), payload.clone());
+                }
+                deliver(&mut state, &topic, &payload);
+                state.received.push((topic, payload, qos, retain));
+            }
+            // PINGREQ
+            0xc0 => stream.write_all(&packet(0xd0, &[])).unwrap(),
+            _ => break,
+        }
+    }
+    state.lock().unwrap().clients.remove(&client);
+}
+
+fn load(path: &str) -> AST {
+    let code = fs::read_to_string(path).unwrap();
+    ast::make_ast(&token::tokenize(code)).unwrap()
+}
+
+fn config(ast: &AST, broker: &Broker) -> mqtt::Config {
+    let text = fs::read_to_string("tests/mqtt.conf")
+        .unwrap()
+        .replace("127.0.0.1:1883", &broker.addr);
+    mqtt::Config::parse(&text, ast).unwrap()
+}
+
+// readings arrive on another thread, so keep stepping like a live run would until they land
+fn step_until(interp: &mut Interpreter, io: &mut dyn DeviceBackend, block: &str) {
+    let started = Instant::now();
+    while interp.block != block {
+        assert_eq!(interp.step(io), Ok(Status::Waiting));
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "never reached {}",
+            block
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn published(broker: &Broker, topic: &str, payload: &str) -> bool {
+    broker
+        .received()
+        .iter()
+        .any(|(t, p, _, _)| t == topic && p == payload)
+}
+
+#[test]
+fn runs_flow_against_broker() {
+    let ast = load("tests/mqtt.fl");
+    let broker = Broker::start();
+    broker.publish("greenhouse/humidity/state", "35");
+    broker.publish("greenhouse/entrance/door", "closed");
+    let mut client = Mqtt::connect(config(&ast, &broker)).unwrap();
+
+    let mut interp = Interpreter::new(&ast, &mut client).unwrap();
+    step_until(&mut interp, &mut client, "misting");
+    let expected = [
+        ("greenhouse/fan/set", "off"),
+        ("greenhouse/mister/set", "false"),
+        ("greenhouse/mister/set", "true"),
+        ("greenhouse/fan/set", "low"),
+    ];
+    let received = broker.received();
+    assert_eq!(received.len(), expected.len());
+    for ((topic, payload, qos, retain), (want_topic, want_payload)) in
+        received.iter().zip(&expected)
+    {
+        assert_eq!(
+            (topic.as_str(), payload.as_str()),
+            (*want_topic, *want_payload)
+        );
+        assert_eq!((*qos, *retain), (1, true));
+    }
+
+    broker.publish("greenhouse/entrance/door", "open");
+    step_until(&mut interp, &mut client, "dry");
+    assert!(published(&broker, "greenhouse/fan/set", "off"));
+}
+
+#[test]
+fn reconnects_after_broker_drops() {
+    let ast = load("tests/mqtt.fl");
+    let broker = Broker::start();
+    broker.publish("greenhouse/humidity/state", "35");
+    broker.publish("greenhouse/entrance/door", "closed");
+    let mut client = Mqtt::connect(config(&ast, &broker)).unwrap();
+    let mut interp = Interpreter::new(&ast, &mut client).unwrap();
+    step_until(&mut interp, &mut client, "misting");
+
+    broker.kick();
+    broker.publish("greenhouse/humidity/state", "70");
+    step_until(&mut interp, &mut client, "dry");
+    assert_eq!(broker.connects(), 2);
+    assert!(published(&broker, "greenhouse/mister/set", "false"));
+}
+
+#[test]
+fn rejects_bad_configs() {
+    let ast = load("tests/mqtt.fl");
+    let parse = |text: &str| mqtt::Config::parse(text, &ast);
+
+    assert_eq!(parse("qos 1"), Err("no broker given".to_string()));
+    assert_eq!(
+        parse("broker localhost:1883\nqos 2"),
+        Err("line 2: unsupported qos 2".to_string())
+    );
+    assert_eq!(
+        parse("broker localhost:1883\ntopic pump plant/pump"),
+        Err("line 2: unknown sensor or actuator pump".to_string())
+    );
+
+    let config = parse("broker localhost:1883").unwrap();
+    assert_eq!(config.sensors["humidity"], "flow/sensors/humidity");
+    assert_eq!(config.actuators["fan"], "flow/actuators/fan");
+    assert_eq!((config.qos, config.retain), (0, false));
+}
@@ -0,0 +1,143 @@
+use std::fs;
+
+use flow::ast::{self, AST};
+use flow::bytecode;
+use flow::fltest;
+use flow::pretty;
+use flow::token;
+
+fn parse(code: &str) -> AST {
+    ast::make_ast(&token::tokenize(code.to_string())).unwrap()
+}
+
+#[test]
+fn printed_samples_parse_back_into_the_same_flow() {
+    let mut checked = 0;
+    for entry in fs::read_dir("tests").unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|ext| ext != "fl") {
+            continue;
+        }
+        let ast = fltest::load(&path).unwrap();
+        let printed = pretty::print(&ast);
+        let reparsed = parse(&printed);
+        assert_eq!(pretty::print(&reparsed), printed, "{}", path.display());
+        assert_eq!(
+            bytecode::compile(&reparsed).unwrap().to_bytes(),
+            bytecode::compile(&ast).unwrap().to_bytes(),
+            "{}",
+            path.display()
+        );
+        checked += 1;
+    }
+    assert!(checked > 0);
+}
+
+#[test]
+fn prints_in_the_usual_layout() {
+    let ast = fltest::load("tests/interlock.fl".as_ref()).unwrap();
+    assert_eq!(
+        pretty::print(&ast),
+        "sensor temp
+actuator fan
+actuator heater 0..100 init 0
+
+interlock: heater > 0 requires - fan >= 20
+invariant: - heater <= 80
+
+block heat
+    set fan 30
+    set heater 60
+    wait:
+        - temp >= 50
+    goto cool
+endblock
+
+block cool
+    set heater 0
+    set fan 0
+    wait:
+        - temp <= 40
+    goto heat
+endblock
+"
+    );
+}
+
+#[test]
+fn prints_every_kind_of_device_and_condition() {
+    let code = "sensor temp 0..150 degF tol 5degC
+sensor door: bool
+sensor mode: enum {idle, run}
+actuator valve: enum {shut, open} init shut failsafe shut
+actuator lamp: bool
+timer purge
+
+always: - any:
+    - lamp
+    - all:
+        - door = false
+        - temp < 100
+reaches: done after - mode = run
+
+block main
+    if:
+        - temp rises_above 120 hyst 5
+        set valve open
+        start purge
+    else:
+        if:
+            - door falling
+            set lamp false
+    wait:
+        - any:
+            - temp ~= 90 for 500ms
+            - purge >= 1min
+            - elapsed > 2
+    wait 1.5s
+    goto done
+endblock
+
+block done
+    set lamp true
+endblock
+";
+    let printed = pretty::print(&parse(code));
+    assert!(printed.starts_with(
+        "sensor door: bool
+sensor mode: enum {idle, run}
+sensor temp 0..150 degF tol 9
+actuator lamp: bool
+actuator valve: enum {shut, open} init shut failsafe shut
+timer purge
+
+always: - any:
+    - lamp
+    - all:
+        - door = false
+        - temp < 100
+reaches: done after - mode = run
+"
+    ));
+    assert!(printed.contains(
+        "block main
+    if:
+        - temp rises_above 120 hyst 5
+        set valve open
+        start purge
+    else:
+        if:
+            - door falling
+            set lamp false
+    wait:
+        - any:
+            - temp ~= 90 for 0.5s
+            - purge >= 60s
+            - elapsed > 2s
+    wait 1.5s
+    goto done
+endblock
+"
+    ));
+    assert_eq!(pretty::print(&parse(&printed)), printed);
+}
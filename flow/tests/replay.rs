@@ -0,0 +1,79 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn flow(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_flow"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn scratch(name: &str) -> String {
+    let dir: PathBuf = env::temp_dir().join(format!("flow-replay-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir.join(name).to_str().unwrap().to_string()
+}
+
+fn record(log: &str) {
+    let out = flow(&[
+        "run",
+        "--record",
+        log,
+        "--trace",
+        "tests/timers.trace",
+        "tests/timers.fl",
+    ]);
+    assert!(out.status.success());
+}
+
+#[test]
+fn replays_recorded_run() {
+    let log = scratch("same.log");
+    record(&log);
+    let text = fs::read_to_string(&log).unwrap();
+    assert!(text.starts_with("@ 0\nread door false\n"));
+
+    let out = flow(&["replay", &log, "tests/timers.fl"]);
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.contains("with no divergence"), "{}", stdout);
+}
+
+#[test]
+fn flags_divergence_after_edit() {
+    let log = scratch("edited.log");
+    record(&log);
+    let edited = scratch("edited.fl");
+    let code = fs::read_to_string("tests/timers.fl").unwrap();
+    fs::write(&edited, code.replace("set fan 1", "set fan 0.5")).unwrap();
+
+    let out = flow(&["replay", &log, &edited]);
+    assert!(!out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.starts_with("diverged at step 2 (2s):"), "{}", stdout);
+    assert!(stdout.contains("  recorded: set fan 1\n  replayed: set fan 0.5"));
+}
+
+#[test]
+fn replays_failures() {
+    // a reading the flow can't take stops the run, which has to stop at the same point on replay
+    let log = scratch("failed.log");
+    fs::write(scratch("failed.trace"), "0 temp 50\n").unwrap();
+    let out = flow(&[
+        "run",
+        "--record",
+        &log,
+        "--trace",
+        &scratch("failed.trace"),
+        "tests/hysteresis.fl",
+    ]);
+    assert!(!out.status.success());
+    let text = fs::read_to_string(&log).unwrap();
+    assert!(text.contains("lost level\n"), "{}", text);
+    assert!(text.contains("error no reading for sensor level at 0s in trace\nfailsafe\n"));
+
+    let out = flow(&["replay", &log, "tests/hysteresis.fl"]);
+    assert!(out.status.success());
+}
@@ -0,0 +1,105 @@
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+#[test]
+fn interrupting_a_live_run_leaves_actuators_at_their_fail_safe_values() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_flow"))
+        .args(["run", "--live", "tests/timers.fl", "door=false"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    let killed = Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(killed.success());
+    let status = child.wait().unwrap();
+    assert!(!status.success());
+
+    let mut stdout = String::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut stdout)
+        .unwrap();
+    let mut stderr = String::new();
+    child
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut stderr)
+        .unwrap();
+    assert_eq!(stderr, "Interrupted\n");
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(
+        lines[..4],
+        [
+            "set alarm false",
+            "set fan 0",
+            "enter closed",
+            "set alarm false"
+        ]
+    );
+    // the fail-safe values come after the time the run was interrupted at
+    assert!(lines[4].starts_with("time "), "{}", stdout);
+    assert_eq!(lines[5..], ["set alarm true", "set fan 0"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupting_a_run_blocked_on_a_reading_leaves_actuators_at_their_fail_safe_values() {
+    for signal in ["-INT", "-TERM"] {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_flow"))
+            .args(["run", "--stdio", "tests/failsafe.fl"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // wait until the run asks for a reading that never comes
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let mut lines = Vec::new();
+        while lines.last().map(String::as_str) != Some("get level") {
+            let mut line = String::new();
+            assert!(stdout.read_line(&mut line).unwrap() > 0, "{:?}", lines);
+            lines.push(line.trim_end().to_string());
+        }
+        let killed = Command::new("kill")
+            .args([signal, &child.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(killed.success());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let status = loop {
+            if let Some(status) = child.try_wait().unwrap() {
+                break status;
+            }
+            if Instant::now() > deadline {
+                child.kill().unwrap();
+                panic!("still running after {}", signal);
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+        assert!(!status.success());
+
+        let mut rest = String::new();
+        stdout.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "set pump 0\nset valve 0\n");
+        let mut stderr = String::new();
+        child
+            .stderr
+            .take()
+            .unwrap()
+            .read_to_string(&mut stderr)
+            .unwrap();
+        assert!(stderr.ends_with("Interrupted\n"), "{}", stderr);
+        drop(child.stdin.take());
+    }
+}
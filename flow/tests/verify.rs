@@ -0,0 +1,173 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use flow::ast::{self, AST};
+use flow::backend::Trace;
+use flow::coverage::Coverage;
+use flow::fltest;
+use flow::token;
+use flow::verify::{self, Counterexample};
+
+fn parse(code: &str) -> AST {
+    ast::make_ast(&token::tokenize(code.to_string())).unwrap()
+}
+
+// the counterexample for each property, in the order they're declared
+fn counterexamples(code: &str) -> Vec<Option<Counterexample>> {
+    let ast = parse(code);
+    let verification = verify::verify(&ast).unwrap();
+    verification
+        .verdicts
+        .into_iter()
+        .map(|v| v.counterexample)
+        .collect()
+}
+
+// the sample with the heater going on before the fan, and cooling that only looks at the estop
+// once it's done waiting
+fn broken() -> String {
+    fs::read_to_string("tests/verify.fl")
+        .unwrap()
+        .replace(
+            "    set fan 1\n    set heater 1\n",
+            "    set heater 1\n    set fan 1\n",
+        )
+        .replace(
+            "    wait:\n        - any:\n            - elapsed >= 30s\n            - estop\n    if:\n        - estop\n        goto shutdown\n",
+            "    wait 30s\n",
+        )
+}
+
+#[test]
+fn sample_properties_hold() {
+    let ast = fltest::load(Path::new("tests/verify.fl")).unwrap();
+    let verification = verify::verify(&ast).unwrap();
+    assert_eq!(verification.verdicts.len(), 3);
+    for verdict in &verification.verdicts {
+        assert_eq!(verdict.counterexample, None, "{}", verdict.property);
+    }
+}
+
+#[test]
+fn catches_states_between_sets() {
+    let found = counterexamples(&broken());
+    assert_eq!(found[0], None);
+    let trace = &found[1].as_ref().unwrap().trace;
+    assert_eq!(trace.last().unwrap(), "# fails at line 13");
+}
+
+#[test]
+fn counterexamples_replay_in_the_simulator() {
+    let code = broken();
+    let ast = parse(&code);
+    let counterexample = counterexamples(&code)[2].clone().unwrap();
+    assert!(counterexample.replays);
+    assert!(counterexample
+        .trace
+        .contains(&"# from here on the flow can go round a loop".to_string()));
+
+    // the estop goes on while cooling and off again before the wait is up
+    let mut trace = Trace::parse(&counterexample.trace.join("\n"), &ast.sensors()).unwrap();
+    let until = counterexample.trace[counterexample.trace.len() - 3]
+        .split(' ')
+        .next()
+        .unwrap()
+        .parse()
+        .unwrap();
+    let events = fltest::simulate(&ast, &mut trace, until, &mut Coverage::default());
+    assert!(events.iter().any(|e| e.ends_with("enter cool")));
+    assert!(!events.iter().any(|e| e.ends_with("enter shutdown")));
+}
+
+#[test]
+fn waiting_on_time_alone_ends() {
+    let code = "sensor level 0..10
+actuator pump 0..1 init 0
+
+reaches: done
+reaches: drained after - level > 5
+
+block fill
+    set pump 1
+    wait 10s
+    goto done
+endblock
+
+block done
+    set pump 0
+    wait:
+        - level <= 5
+    goto drained
+endblock
+
+block drained
+    wait 1s
+    goto fill
+endblock
+";
+    let found = counterexamples(code);
+    assert_eq!(found[0], None);
+    // but the level can stay up for good
+    let trace = &found[1].as_ref().unwrap().trace;
+    assert!(trace.contains(&"# block done, pump 0".to_string()));
+}
+
+#[test]
+fn stopping_never_reaches() {
+    let code = "sensor temp 0..100
+actuator heater 0..1 init 0 failsafe 0
+
+invariant: - heater = 0
+reaches: cool
+
+block heat
+    wait:
+        - temp < 20
+    set heater 1
+    goto cool
+endblock
+
+block cool
+    set heater 0
+    wait 1s
+    goto heat
+endblock
+";
+    let trace = counterexamples(code)[0].clone().unwrap().trace;
+    assert_eq!(
+        trace,
+        [
+            "0 temp 0",
+            "# block heat, heater 0, stopped: block heat: refused to set heater to 1: violates invariant heater = 0",
+            "# the flow stops without entering cool",
+        ]
+    );
+}
+
+#[test]
+fn cli_fails_on_failing_properties() {
+    let flow = env!("CARGO_BIN_EXE_flow");
+    let ok = Command::new(flow)
+        .args(["verify", "tests/verify.fl"])
+        .output()
+        .unwrap();
+    assert!(ok.status.success());
+    assert!(String::from_utf8_lossy(&ok.stdout).ends_with("3 held, 0 failed\n"));
+
+    let path = std::env::temp_dir().join(format!("flow-verify-{}.fl", std::process::id()));
+    fs::write(&path, broken()).unwrap();
+    let failed = Command::new(flow)
+        .args(["verify", path.to_str().unwrap()])
+        .output()
+        .unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(failed.status.code(), Some(1));
+    let out = String::from_utf8_lossy(&failed.stdout);
+    assert!(
+        out.contains("FAIL never all(heater = 1, fan = 0)"),
+        "{}",
+        out
+    );
+    assert!(out.contains("1 held, 2 failed"), "{}", out);
+}